@@ -0,0 +1,12 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use owl_panda::css::{self, CSSOrigin};
+
+// css::parse must return a style sheet for any input, it is never allowed to panic.
+// The same goes for the declaration lists of style attributes and the selectors of DOM queries.
+fuzz_target!(|source: &str| {
+    let _ = css::parse(source.to_string(), CSSOrigin::Author);
+    let _ = css::parse_declarations(source.to_string(), CSSOrigin::Author);
+    let _ = css::parse_selector_list(source);
+});
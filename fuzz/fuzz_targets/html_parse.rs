@@ -0,0 +1,13 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use owl_panda::html;
+
+// html::parse must return a tree for any input, it is never allowed to panic.
+// Raw bytes go through encoding detection first.
+fuzz_target!(|data: &[u8]| {
+    let _ = html::parse_reader(data);
+    if let Ok(source) = std::str::from_utf8(data) {
+        let _ = html::parse(source.to_string());
+    }
+});
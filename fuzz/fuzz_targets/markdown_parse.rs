@@ -0,0 +1,9 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use owl_panda::markdown;
+
+// markdown::parse_reader must build a document for any bytes
+fuzz_target!(|data: &[u8]| {
+    let _ = markdown::parse_reader(data);
+});
@@ -0,0 +1,9 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use owl_panda::xml;
+
+// xml::parse_reader must return a document for any bytes, it stops at errors but never panics
+fuzz_target!(|data: &[u8]| {
+    let _ = xml::parse_reader(data);
+});
@@ -3,10 +3,26 @@
 // 2. Id with prefixed by #
 // 3. Any number of class names prefixed by .
 // 4. Some combination of the above 3
+// 5. Attribute selectors: [attr], [attr=value], [attr~=value], [attr|=value], [attr^=value],
+//    [attr$=value] and [attr*=value]. The value is an identifier or a quoted string and can be
+//    followed by an `i` or `s` flag to compare it case-insensitively or case-sensitively.
+// 6. Pseudo-classes: :root, :empty, :first-child, :last-child, :only-child,
+//    :nth-child(an+b [of selectors]), :nth-last-child(an+b [of selectors]), :nth-of-type(an+b),
+//    :not(selectors), :is(selectors), :where(selectors) and :has(relative selectors) like
+//    :has(> img, + p)
+// 7. Combinations of those joined by combinators: descendant (space), child (>), next sibling (+)
+//    and subsequent sibling (~), like `ul > li a`
 //
 
+use std::cmp::Reverse;
+
 use phf::phf_map;
 
+use crate::{
+    diagnostics::{Diagnostic, DiagnosticKind, LineIndex, Location, Span},
+    encoding::{self, Encoding},
+};
+
 pub const INHERITED_PROPERTY: phf::Map<&'static str, bool> = phf_map! {
     "azimuth" => true,
     "border-collapse" => true,
@@ -62,11 +78,29 @@ pub struct Rule {
     pub selectors: Vec<Selector>,
     pub declarations: Vec<Declaration>,
     pub origin: CSSOrigin,
+    // from the first selector to the closing brace
+    pub span: Span,
 }
 
 #[derive(Debug)]
 pub enum Selector {
     Simple(SimpleSelector),
+    // Simple selectors joined by combinators like `ul > li a`. Each selector on the left is paired
+    // with the combinator after it, the last one is the element that is matched.
+    Complex(Vec<(SimpleSelector, Combinator)>, SimpleSelector),
+}
+
+// How the elements matched by two simple selectors in a complex selector are related
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Combinator {
+    // `a b`, b is inside a
+    Descendant,
+    // `a > b`, a is the parent of b
+    Child,
+    // `a + b`, a comes right before b
+    NextSibling,
+    // `a ~ b`, a comes somewhere before b
+    SubsequentSibling,
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -81,6 +115,72 @@ pub struct SimpleSelector {
     pub tag_name: Option<String>,
     pub id: Option<String>,
     pub class: Vec<String>,
+    pub attributes: Vec<AttributeSelector>,
+    pub pseudo_classes: Vec<PseudoClass>,
+}
+
+// `[name]` or `[name op value flag]`
+#[derive(Debug)]
+pub struct AttributeSelector {
+    pub name: String,
+    // None when any value matches
+    pub operator: Option<(AttributeOperator, String)>,
+    pub case: AttributeCase,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AttributeOperator {
+    // `=`, the value is exactly the given one
+    Equals,
+    // `~=`, one of the whitespace separated words of the value
+    Includes,
+    // `|=`, the value or the value followed by `-`, like language codes
+    DashMatch,
+    // `^=`, `$=` and `*=`, the value starts with, ends with or contains the given one
+    Prefix,
+    Suffix,
+    Substring,
+}
+
+#[derive(Debug)]
+pub enum PseudoClass {
+    // the document element
+    Root,
+    // no child elements and no text
+    Empty,
+    FirstChild,
+    LastChild,
+    OnlyChild,
+    // position among the siblings, counting only the ones matching the `of` selectors if any
+    NthChild(Nth, Vec<Selector>),
+    NthLastChild(Nth, Vec<Selector>),
+    // position among the siblings with the same name
+    NthOfType(Nth),
+    Not(Vec<Selector>),
+    Is(Vec<Selector>),
+    // like :is but without specificity
+    Where(Vec<Selector>),
+    // an element related to this one matches, like a descendant for `:has(img)` or the next
+    // sibling for `:has(+ p)`
+    Has(Vec<(Combinator, Selector)>),
+}
+
+// `an+b`, matches the positions a*n+b for any n >= 0, counting from 1
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Nth {
+    pub a: i32,
+    pub b: i32,
+}
+
+// How attribute values are compared
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AttributeCase {
+    // no flag, HTML compares the values of some of its attributes like `type` case-insensitively
+    Default,
+    // `i` flag
+    Insensitive,
+    // `s` flag
+    Sensitive,
 }
 
 // Key value pair separated by :
@@ -90,6 +190,8 @@ pub struct Declaration {
     pub value: Value,
     pub origin: CSSOrigin,
     pub is_important: bool,
+    // from the property name to the end of the value or `!important`, without the semicolon
+    pub span: Span,
 }
 
 // Supports only a subset of css value types. Later add more value types
@@ -122,8 +224,17 @@ impl Copy for ColorRGBA {}
 struct Parser {
     pos: usize,
     input: String,
+    // problems found so far, with the byte offset they were found at
+    errors: Vec<(usize, DiagnosticKind)>,
+    // line and column of a position already passed, so spans don't count lines from the start
+    location: Location,
+    // selector lists the parser is in, like the arguments of :is()
+    nesting: usize,
 }
 
+// Selector lists can be nested this deep in pseudo-classes
+const MAX_SELECTOR_NESTING: usize = 32;
+
 pub type Specificity = (usize, usize, usize);
 
 fn valid_identifier_char(c: char) -> bool {
@@ -131,23 +242,24 @@ fn valid_identifier_char(c: char) -> bool {
 }
 
 impl Parser {
-    // Read the next character without consuming it.
-    fn next_char(&self) -> char {
-        self.input[self.pos..].chars().next().unwrap()
+    // Read the next character without consuming it, None at the end of input.
+    fn next_char(&self) -> Option<char> {
+        self.input[self.pos..].chars().next()
     }
 
     // consume the character in current position & advance position
-    fn consume_char(&mut self) -> char {
-        let c = self.next_char();
+    fn consume_char(&mut self) -> Option<char> {
+        let c = self.next_char()?;
         self.pos += c.len_utf8();
-        c
+        Some(c)
     }
 
     // consume character until 'test' return results
     fn consume_while(&mut self, test: impl Fn(char) -> bool) -> String {
         let mut result = String::new();
-        while !self.eof() && test(self.next_char()) {
-            result.push(self.consume_char());
+        while let Some(c) = self.next_char().filter(|&c| test(c)) {
+            self.pos += c.len_utf8();
+            result.push(c);
         }
         result
     }
@@ -167,15 +279,26 @@ impl Parser {
         self.consume_while(char::is_whitespace);
     }
 
-    // If the next character matches the consume it.
-    fn expect_char(&mut self, c: char) -> Result<(), String> {
-        if self.consume_char() != c {
-            return Err(format!(
-                "fn expect_char: Expected {:?} at byte {} but it was not found",
-                c, self.pos
-            ));
+    fn error(&mut self, kind: DiagnosticKind) {
+        self.errors.push((self.pos, kind));
+    }
+
+    // Location of the current position, the parser never goes back
+    fn location(&mut self) -> Location {
+        self.location = self
+            .location
+            .advance(&self.input[self.location.offset..self.pos]);
+        self.location
+    }
+
+    // If the next character matches the consume it, otherwise report it.
+    fn expect_char(&mut self, c: char) -> bool {
+        if self.next_char() != Some(c) {
+            self.error(DiagnosticKind::ExpectedCharacter(c));
+            return false;
         }
-        Ok(())
+        self.consume_char();
+        true
     }
 
     // Do the next character starts with the given string?
@@ -193,16 +316,29 @@ impl Parser {
         }
     }
 
-    // Parse a single simple selector, eg: `type#id.class1.class2.class3`
-    // Some malformed input like ### or *foo* will parse successfully and produce weird results
-    fn parse_simple_selector(&mut self) -> SimpleSelector {
+    // Parse a single simple selector, eg: `type#id.class1.class2.class3[attr=value]`
+    // Some malformed input like ### or *foo* will parse successfully and produce weird results.
+    // None if an attribute selector is invalid.
+    fn parse_simple_selector(&mut self) -> Option<SimpleSelector> {
         let mut selector = SimpleSelector {
             tag_name: None,
             id: None,
             class: Vec::new(),
+            attributes: Vec::new(),
+            pseudo_classes: Vec::new(),
         };
-        while !self.eof() {
-            match self.next_char() {
+        while let Some(c) = self.next_char() {
+            match c {
+                ':' => {
+                    self.consume_char();
+                    let pseudo_class = self.parse_pseudo_class()?;
+                    selector.pseudo_classes.push(pseudo_class);
+                }
+                '[' => {
+                    self.consume_char();
+                    let attribute = self.parse_attribute_selector()?;
+                    selector.attributes.push(attribute);
+                }
                 '#' => {
                     self.consume_char();
                     selector.id = Some(self.parse_identfier());
@@ -215,13 +351,307 @@ impl Parser {
                     // universal selector
                     self.consume_char();
                 }
-                c if valid_identifier_char(c) => {
+                _ if valid_identifier_char(c) => {
                     selector.tag_name = Some(self.parse_identfier());
                 }
                 _ => break,
             }
         }
-        selector
+        Some(selector)
+    }
+
+    // Parse the inside of an attribute selector up to and including the closing bracket,
+    // eg: `data-state="open" i]`
+    fn parse_attribute_selector(&mut self) -> Option<AttributeSelector> {
+        self.consume_whitespace();
+        let name = self.parse_identfier();
+        if name.is_empty() {
+            self.unexpected_in_selector(']');
+            return None;
+        }
+        self.consume_whitespace();
+        let operator = match self.next_char() {
+            Some('=') => Some(AttributeOperator::Equals),
+            Some('~') => Some(AttributeOperator::Includes),
+            Some('|') => Some(AttributeOperator::DashMatch),
+            Some('^') => Some(AttributeOperator::Prefix),
+            Some('$') => Some(AttributeOperator::Suffix),
+            Some('*') => Some(AttributeOperator::Substring),
+            _ => None,
+        };
+        let mut selector = AttributeSelector {
+            name,
+            operator: None,
+            case: AttributeCase::Default,
+        };
+        if let Some(operator) = operator {
+            self.consume_char();
+            if operator != AttributeOperator::Equals && !self.expect_char('=') {
+                return None;
+            }
+            self.consume_whitespace();
+            // an empty value has to be quoted
+            let (value, quoted) = match self.next_char() {
+                Some(quote @ ('"' | '\'')) => (self.parse_string(quote)?, true),
+                _ => (self.parse_identfier(), false),
+            };
+            if value.is_empty() && !quoted {
+                self.unexpected_in_selector(']');
+                return None;
+            }
+            selector.operator = Some((operator, value));
+            self.consume_whitespace();
+            if let Some(flag) = self.next_char().filter(|c| c.is_ascii_alphabetic()) {
+                selector.case = match flag.to_ascii_lowercase() {
+                    'i' => AttributeCase::Insensitive,
+                    's' => AttributeCase::Sensitive,
+                    _ => {
+                        self.unexpected_in_selector(']');
+                        return None;
+                    }
+                };
+                self.consume_char();
+                self.consume_whitespace();
+            }
+        }
+        if !self.expect_char(']') {
+            return None;
+        }
+        Some(selector)
+    }
+
+    // Parse a pseudo-class after its colon, eg: `first-child` or `nth-child(2n+1 of .item)`
+    fn parse_pseudo_class(&mut self) -> Option<PseudoClass> {
+        let name_start = self.pos;
+        let name = self.parse_identfier().to_ascii_lowercase();
+        if name.is_empty() {
+            self.unexpected_in_selector('{');
+            return None;
+        }
+        if self.next_char() != Some('(') {
+            return Some(match &*name {
+                "root" => PseudoClass::Root,
+                "empty" => PseudoClass::Empty,
+                "first-child" => PseudoClass::FirstChild,
+                "last-child" => PseudoClass::LastChild,
+                "only-child" => PseudoClass::OnlyChild,
+                _ => {
+                    self.errors
+                        .push((name_start, DiagnosticKind::UnknownPseudoClass(name)));
+                    return None;
+                }
+            });
+        }
+        if !matches!(
+            &*name,
+            "nth-child" | "nth-last-child" | "nth-of-type" | "not" | "is" | "where" | "has"
+        ) {
+            self.errors
+                .push((name_start, DiagnosticKind::UnknownPseudoClass(name)));
+            return None;
+        }
+        if self.nesting == MAX_SELECTOR_NESTING {
+            self.error(DiagnosticKind::SelectorNestedTooDeeply);
+            return None;
+        }
+        self.consume_char();
+        self.consume_whitespace();
+        self.nesting += 1;
+        let pseudo_class = self.parse_pseudo_class_argument(&name);
+        self.nesting -= 1;
+        let pseudo_class = pseudo_class?;
+        self.consume_whitespace();
+        if !self.expect_char(')') {
+            return None;
+        }
+        Some(pseudo_class)
+    }
+
+    // Parse the argument of a functional pseudo-class up to its closing parenthesis
+    fn parse_pseudo_class_argument(&mut self, name: &str) -> Option<PseudoClass> {
+        Some(match name {
+            "nth-child" | "nth-last-child" => {
+                let nth = self.parse_nth()?;
+                self.consume_whitespace();
+                let mut of = Vec::new();
+                if self.starts_with("of") {
+                    self.pos += 2;
+                    self.consume_whitespace();
+                    of = self.parse_selectors(Some(')'))?;
+                }
+                if name == "nth-child" {
+                    PseudoClass::NthChild(nth, of)
+                } else {
+                    PseudoClass::NthLastChild(nth, of)
+                }
+            }
+            "nth-of-type" => PseudoClass::NthOfType(self.parse_nth()?),
+            "not" => PseudoClass::Not(self.parse_selectors(Some(')'))?),
+            "is" => PseudoClass::Is(self.parse_selectors(Some(')'))?),
+            "where" => PseudoClass::Where(self.parse_selectors(Some(')'))?),
+            _ => PseudoClass::Has(self.parse_relative_selectors()?),
+        })
+    }
+
+    // Parse `an+b`, `odd` or `even`, eg: `2n+1`, `-n + 3` or `4`
+    fn parse_nth(&mut self) -> Option<Nth> {
+        let start = self.pos;
+        let token = self
+            .consume_while(|c| c.is_ascii_alphanumeric() || c == '+' || c == '-')
+            .to_ascii_lowercase();
+        let nth = match &*token {
+            "odd" => Some(Nth { a: 2, b: 1 }),
+            "even" => Some(Nth { a: 2, b: 0 }),
+            _ => match token.split_once('n') {
+                Some((a, b)) => {
+                    let a = match a {
+                        "" | "+" => Some(1),
+                        "-" => Some(-1),
+                        a => a.parse().ok(),
+                    };
+                    a.and_then(|a| self.parse_nth_offset(b).map(|b| Nth { a, b }))
+                }
+                None => token.parse().ok().map(|b| Nth { a: 0, b }),
+            },
+        };
+        if nth.is_none() {
+            self.errors
+                .push((start, DiagnosticKind::InvalidNthArgument));
+        }
+        nth
+    }
+
+    // Parse the `+b` after `an`, `rest` is what followed the n in the same word.
+    // The sign can be separated from the n and the number by whitespace, like in `2n + 1`.
+    fn parse_nth_offset(&mut self, rest: &str) -> Option<i32> {
+        let sign = match rest {
+            "" => {
+                let before_whitespace = self.pos;
+                self.consume_whitespace();
+                match self.next_char() {
+                    Some(sign @ ('+' | '-')) => {
+                        self.consume_char();
+                        self.consume_whitespace();
+                        sign
+                    }
+                    _ => {
+                        self.pos = before_whitespace;
+                        return Some(0);
+                    }
+                }
+            }
+            "+" | "-" => {
+                self.consume_whitespace();
+                rest.chars().next()?
+            }
+            _ => return rest.starts_with(['+', '-']).then(|| rest.parse().ok())?,
+        };
+        let digits = self.consume_while(|c| c.is_ascii_digit());
+        let b: i32 = digits.parse().ok()?;
+        Some(if sign == '-' { -b } else { b })
+    }
+
+    // Parse comma separated selectors that each can start with a combinator, the arguments of
+    // :has(). Without one the selector is for descendants.
+    fn parse_relative_selectors(&mut self) -> Option<Vec<(Combinator, Selector)>> {
+        let mut selectors = Vec::new();
+        loop {
+            let combinator = match self.next_char() {
+                Some('>') => Combinator::Child,
+                Some('+') => Combinator::NextSibling,
+                Some('~') => Combinator::SubsequentSibling,
+                _ => Combinator::Descendant,
+            };
+            if combinator != Combinator::Descendant {
+                self.consume_char();
+                self.consume_whitespace();
+            }
+            let start = self.pos;
+            let selector = self.parse_selector()?;
+            if self.pos == start {
+                self.unexpected_in_selector(')');
+                return None;
+            }
+            selectors.push((combinator, selector));
+            self.consume_whitespace();
+            match self.next_char() {
+                Some(',') => {
+                    self.consume_char();
+                    self.consume_whitespace();
+                }
+                _ => return Some(selectors),
+            }
+        }
+    }
+
+    // Parse a string in `quote`s, a backslash escapes the next character
+    fn parse_string(&mut self, quote: char) -> Option<String> {
+        self.consume_char();
+        let mut value = String::new();
+        loop {
+            match self.consume_char() {
+                Some('\\') => value.extend(self.consume_char()),
+                Some(c) if c == quote => return Some(value),
+                Some(c) => value.push(c),
+                None => {
+                    self.error(DiagnosticKind::ExpectedCharacter(quote));
+                    return None;
+                }
+            }
+        }
+    }
+
+    // Report the character at the current position, or `expected` at the end of input
+    fn unexpected_in_selector(&mut self, expected: char) {
+        match self.next_char() {
+            Some(c) => self.error(DiagnosticKind::UnexpectedCharacterInSelector(c)),
+            None => self.error(DiagnosticKind::ExpectedCharacter(expected)),
+        }
+    }
+
+    // Parse simple selectors joined by combinators, eg: `ul > li a`.
+    // None if a combinator isn't followed by a selector.
+    fn parse_selector(&mut self) -> Option<Selector> {
+        let start = self.pos;
+        let mut selector = self.parse_simple_selector()?;
+        // a selector can't start with a combinator, the caller reports the character
+        if self.pos == start {
+            return Some(Selector::Simple(selector));
+        }
+        let mut context = Vec::new();
+        loop {
+            let before_whitespace = self.pos;
+            self.consume_whitespace();
+            let combinator = match self.next_char() {
+                Some('>') => Combinator::Child,
+                Some('+') => Combinator::NextSibling,
+                Some('~') => Combinator::SubsequentSibling,
+                Some(c)
+                    if self.pos > before_whitespace
+                        && (valid_identifier_char(c)
+                            || matches!(c, '#' | '.' | '*' | '[' | ':')) =>
+                {
+                    Combinator::Descendant
+                }
+                _ => break,
+            };
+            if combinator != Combinator::Descendant {
+                self.consume_char();
+                self.consume_whitespace();
+            }
+            let compound_start = self.pos;
+            let next = self.parse_simple_selector()?;
+            if self.pos == compound_start {
+                self.error(DiagnosticKind::ExpectedSelector);
+                return None;
+            }
+            context.push((std::mem::replace(&mut selector, next), combinator));
+        }
+        Some(if context.is_empty() {
+            Selector::Simple(selector)
+        } else {
+            Selector::Complex(context, selector)
+        })
     }
 
     fn parse_rules(&mut self, origin: CSSOrigin) -> Vec<Rule> {
@@ -231,6 +661,12 @@ impl Parser {
             if self.eof() {
                 break;
             }
+            // the encoding is already picked when the sheet is decoded
+            if self.starts_with("@charset") {
+                self.consume_while(|c| c != ';');
+                self.consume_char();
+                continue;
+            }
             if let Some(rule) = self.parse_rule(origin) {
                 rules.push(rule);
             }
@@ -239,91 +675,131 @@ impl Parser {
     }
 
     // Parse a rule set: `<selectors> { declarations }`
+    // A rule with an invalid selector is skipped as a whole.
     fn parse_rule(&mut self, origin: CSSOrigin) -> Option<Rule> {
-        if let (Some(s), Some(d)) = (self.parse_selectors(), self.parse_declarations(origin)) {
-            Some(Rule {
-                selectors: s,
-                declarations: d,
-                origin,
-            })
-        } else {
-            None
-        }
+        let start = self.location();
+        let Some(selectors) = self.parse_selectors(Some('{')) else {
+            self.consume_while(|c| c != '}');
+            self.consume_char();
+            return None;
+        };
+        let declarations = self.parse_declarations(origin)?;
+        Some(Rule {
+            selectors,
+            declarations,
+            origin,
+            span: Span {
+                start,
+                end: self.location(),
+            },
+        })
     }
 
-    // Parse comma separated selectors
-    fn parse_selectors(&mut self) -> Option<Vec<Selector>> {
+    // Parse comma separated selectors, up to the `end` character or the end of input when `end` is None
+    fn parse_selectors(&mut self, end: Option<char>) -> Option<Vec<Selector>> {
         let mut selectors = Vec::new();
         loop {
-            selectors.push(Selector::Simple(self.parse_simple_selector()));
+            let start = self.pos;
+            let selector = self.parse_selector()?;
+            // an empty selector like in `a, {` is invalid, other characters are reported below
+            let next = self.next_char();
+            if self.pos == start && (next == Some(',') || next == end || next.is_none()) {
+                self.error(DiagnosticKind::ExpectedSelector);
+                return None;
+            }
+            selectors.push(selector);
             self.consume_whitespace();
             match self.next_char() {
-                ',' => {
+                Some(',') => {
                     self.consume_char();
                     self.consume_whitespace();
                 }
-                '{' => break,
-                c => {
-                    println!("Unexpected character {} in selector list", c);
+                c if c == end => break,
+                Some(c) => {
+                    self.error(DiagnosticKind::UnexpectedCharacterInSelector(c));
+                    return None;
+                }
+                None => {
+                    self.error(DiagnosticKind::ExpectedCharacter(end.unwrap_or('{')));
                     return None;
                 }
             }
         }
 
         // Return selectors with highest specificity first, for use in matching.
-        selectors.sort_by_key(|k| k.specificity());
+        selectors.sort_by_key(|k| Reverse(k.specificity()));
         Some(selectors)
     }
 
     // Parse declarations enclosed in {...}
     fn parse_declarations(&mut self, origin: CSSOrigin) -> Option<Vec<Declaration>> {
-        if let Err(e) = self.expect_char('{') {
-            println!(
-                "fn parse_declaractions: Expected '{{' at position {}: \n Error: {}",
-                self.pos, e
-            );
+        if !self.expect_char('{') {
             return None;
         }
+        Some(self.parse_declaration_list(origin, true))
+    }
+
+    // Parse `;` separated declarations up to the closing brace of a block,
+    // or up to the end of input for a list that is not in a block like a style attribute.
+    fn parse_declaration_list(&mut self, origin: CSSOrigin, in_block: bool) -> Vec<Declaration> {
         let mut declarations = Vec::new();
         loop {
             self.consume_whitespace();
-            if self.next_char() == '}' {
-                self.consume_char();
-                break;
+            match self.next_char() {
+                Some('}') if in_block => {
+                    self.consume_char();
+                    break;
+                }
+                // a brace can't close anything outside of a block
+                Some('}') => {
+                    self.error(DiagnosticKind::ExpectedCharacter(';'));
+                    self.consume_char();
+                    continue;
+                }
+                // the end of input closes an unfinished block
+                None if in_block => {
+                    self.error(DiagnosticKind::ExpectedCharacter('}'));
+                    break;
+                }
+                None => break,
+                Some(_) => {}
             }
             if let Some(d) = self.parse_declaraction(origin) {
                 declarations.push(d);
             } else {
                 // If parsing failed skip to the next semicolor or brace
                 self.consume_while(|c| c != ';' && c != '}');
-                if !self.eof() && self.next_char() == ';' {
+                if self.next_char() == Some(';') {
                     self.consume_char();
                 }
             }
         }
-        Some(declarations)
+        declarations
     }
 
     // Parse a single declaration '<property>: value'.
     fn parse_declaraction(&mut self, origin: CSSOrigin) -> Option<Declaration> {
+        let start = self.location();
         let name = self.parse_identfier();
         self.consume_whitespace();
 
-        if let Err(e) = self.expect_char(':') {
-            println!("fn parse_declaraction: Error: {}", e);
+        if !self.expect_char(':') {
             return None;
         };
 
         self.consume_whitespace();
 
+        let errors = self.errors.len();
         let value = self.parse_value();
 
         if let Some(value) = value {
+            let end = self.location();
             let mut dec = Declaration {
                 name,
                 origin,
                 value,
                 is_important: false,
+                span: Span { start, end },
             };
 
             self.consume_whitespace();
@@ -331,79 +807,147 @@ impl Parser {
             // check if important
             if self.expect("!important") {
                 dec.is_important = true;
+                dec.span.end = self.location();
             }
 
             self.consume_whitespace();
 
-            if let Err(e) = self.expect_char(';') {
-                println!("{}", e);
+            // the semicolon is optional after the last declaration
+            if !matches!(self.next_char(), Some('}') | None) && !self.expect_char(';') {
                 return None;
             };
             return Some(dec);
         }
+        // values with a more specific problem are already reported
+        if self.errors.len() == errors {
+            self.error(DiagnosticKind::InvalidValue(name));
+        }
         None
     }
 
     // methods to parse a value
     fn parse_value(&mut self) -> Option<Value> {
-        match self.next_char() {
-            '0'..='9' => self.parse_length(),
+        match self.next_char()? {
+            '0'..='9' | '.' => self.parse_length(),
             '#' => self.parse_color(),
-            _ => Some(Value::Keyword(self.parse_identfier())),
+            _ => {
+                let keyword = self.parse_identfier();
+                (!keyword.is_empty()).then_some(Value::Keyword(keyword))
+            }
         }
     }
 
     fn parse_length(&mut self) -> Option<Value> {
-        let f = self.parse_float();
-        self.parse_unit().map(|u| Value::Length(f, u))
+        let f = self.parse_float()?;
+        let start = self.pos;
+        let unit = self.parse_identfier();
+        match parse_unit(&unit) {
+            Some(u) => Some(Value::Length(f, u)),
+            None => {
+                self.errors.push((start, DiagnosticKind::UnknownUnit(unit)));
+                None
+            }
+        }
     }
 
-    fn parse_float(&mut self) -> f32 {
+    // None for malformed numbers like `1.2.3`
+    fn parse_float(&mut self) -> Option<f32> {
         self.consume_while(|a| matches!(a, '0'..='9' | '.'))
             .parse()
-            .unwrap()
-    }
-
-    fn parse_unit(&mut self) -> Option<Unit> {
-        match &*self.parse_identfier().to_ascii_lowercase() {
-            "px" => Some(Unit::Px),
-            "em" => Some(Unit::Em),
-            "rem" => Some(Unit::Rem),
-            _ => None,
-        }
+            .ok()
     }
 
     fn parse_color(&mut self) -> Option<Value> {
-        if let Err(e) = self.expect_char('#') {
-            println!("fn parse_color: Invalid color input: {}", e);
+        if !self.expect_char('#') {
             self.consume_while(|c| c == ';');
             return None;
         };
+        let start = self.pos;
+        let hex = self.consume_while(valid_identifier_char);
+        if hex.len() != 6 || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+            self.errors.push((start, DiagnosticKind::InvalidColor(hex)));
+            return None;
+        }
         Some(Value::Color(ColorRGBA {
-            r: self.parse_hex_pair(),
-            g: self.parse_hex_pair(),
-            b: self.parse_hex_pair(),
+            r: parse_hex_pair(&hex[0..2]),
+            g: parse_hex_pair(&hex[2..4]),
+            b: parse_hex_pair(&hex[4..6]),
             a: 255,
         }))
     }
+}
 
-    fn parse_hex_pair(&mut self) -> u8 {
-        let s = &self.input[self.pos..self.pos + 2];
-        self.pos += 2;
-        u8::from_str_radix(s, 16).unwrap()
+// Parse two hex digits, they are validated by the caller
+fn parse_hex_pair(s: &str) -> u8 {
+    u8::from_str_radix(s, 16).unwrap_or(0)
+}
+
+fn parse_unit(unit: &str) -> Option<Unit> {
+    match &*unit.to_ascii_lowercase() {
+        "px" => Some(Unit::Px),
+        "em" => Some(Unit::Em),
+        "rem" => Some(Unit::Rem),
+        _ => None,
     }
 }
 
 impl Selector {
+    // Specificity of a complex selector is the sum over its simple selectors
+    pub fn specificity(&self) -> Specificity {
+        match self {
+            Selector::Simple(simple) => simple.specificity(),
+            Selector::Complex(context, subject) => context
+                .iter()
+                .map(|(simple, _)| simple.specificity())
+                .fold(subject.specificity(), add_specificity),
+        }
+    }
+}
+
+impl SimpleSelector {
     pub fn specificity(&self) -> Specificity {
-        let Selector::Simple(ref simple) = *self;
-        let a = simple.id.iter().count();
-        let b = simple.class.len();
-        let c = simple.tag_name.iter().count();
-        (a, b, c)
+        let a = self.id.iter().count();
+        let b = self.class.len() + self.attributes.len();
+        let c = self.tag_name.iter().count();
+        self.pseudo_classes
+            .iter()
+            .map(PseudoClass::specificity)
+            .fold((a, b, c), add_specificity)
     }
 }
 
+impl PseudoClass {
+    // Selectors Level 4: the logical pseudo-classes count as their most specific argument, or not
+    // at all for :where(). An :nth-child() with `of` selectors adds the most specific of those.
+    // https://www.w3.org/TR/selectors-4/#specificity-rules
+    pub fn specificity(&self) -> Specificity {
+        match self {
+            PseudoClass::Not(selectors) | PseudoClass::Is(selectors) => {
+                max_specificity(selectors.iter())
+            }
+            PseudoClass::Where(_) => (0, 0, 0),
+            PseudoClass::Has(selectors) => {
+                max_specificity(selectors.iter().map(|(_, selector)| selector))
+            }
+            PseudoClass::NthChild(_, of) | PseudoClass::NthLastChild(_, of) => {
+                add_specificity((0, 1, 0), max_specificity(of.iter()))
+            }
+            _ => (0, 1, 0),
+        }
+    }
+}
+
+fn add_specificity((a, b, c): Specificity, (x, y, z): Specificity) -> Specificity {
+    (a + x, b + y, c + z)
+}
+
+fn max_specificity<'a>(selectors: impl Iterator<Item = &'a Selector>) -> Specificity {
+    selectors
+        .map(Selector::specificity)
+        .max()
+        .unwrap_or_default()
+}
+
 impl Value {
     pub fn to_px(&self) -> f32 {
         match *self {
@@ -414,13 +958,81 @@ impl Value {
     }
 }
 
-pub fn parse(source: String, origin: CSSOrigin) -> StylesSheet {
+// Decode the bytes of a style sheet file. A byte order mark wins over an `@charset "...";` rule
+// at the very start, without either the sheet is in `fallback`, the encoding of the document
+// that links it, or in UTF-8.
+// https://www.w3.org/TR/css-syntax-3/#input-byte-stream
+pub fn decode(bytes: &[u8], fallback: Option<Encoding>) -> String {
+    let encoding = charset_rule(bytes).or(fallback).unwrap_or(Encoding::Utf8);
+    encoding::decode(bytes, encoding)
+}
+
+// Encoding named by an `@charset` rule, which must be written exactly as `@charset "label";`
+fn charset_rule(bytes: &[u8]) -> Option<Encoding> {
+    let rest = bytes.strip_prefix(b"@charset \"")?;
+    let len = rest.iter().take(1024).position(|&b| b == b'"')?;
+    if rest.get(len + 1) != Some(&b';') {
+        return None;
+    }
+    let encoding = Encoding::for_label(&String::from_utf8_lossy(&rest[..len]))?;
+    // the rule was read as ASCII so the sheet can't be UTF-16
+    Some(if encoding.is_utf16() {
+        Encoding::Utf8
+    } else {
+        encoding
+    })
+}
+
+// Parse a style sheet, returning it with the problems found in the source
+pub fn parse(source: String, origin: CSSOrigin) -> (StylesSheet, Vec<Diagnostic>) {
     let mut parser = Parser {
         pos: 0,
         input: source,
+        errors: Vec::new(),
+        location: Location::START,
+        nesting: 0,
     };
-    StylesSheet {
+    let sheet = StylesSheet {
         rules: parser.parse_rules(origin),
         origin,
+    };
+    let diagnostics = LineIndex::new(&parser.input).diagnostics(parser.errors);
+    (sheet, diagnostics)
+}
+
+// Parse a declaration list without braces, the contents of a style attribute
+pub fn parse_declarations(
+    source: String,
+    origin: CSSOrigin,
+) -> (Vec<Declaration>, Vec<Diagnostic>) {
+    let mut parser = Parser {
+        pos: 0,
+        input: source,
+        errors: Vec::new(),
+        location: Location::START,
+        nesting: 0,
+    };
+    let declarations = parser.parse_declaration_list(origin, false);
+    let diagnostics = LineIndex::new(&parser.input).diagnostics(parser.errors);
+    (declarations, diagnostics)
+}
+
+// Parse a selector list on its own like `div.note, #main`, the selectors of DOM queries.
+// The first problem found is returned as the error.
+pub fn parse_selector_list(source: &str) -> Result<Vec<Selector>, Diagnostic> {
+    let mut parser = Parser {
+        pos: 0,
+        input: source.to_string(),
+        errors: Vec::new(),
+        location: Location::START,
+        nesting: 0,
+    };
+    parser.consume_whitespace();
+    let selectors = parser.parse_selectors(None);
+    let index = LineIndex::new(&parser.input);
+    match (selectors, parser.errors.into_iter().next()) {
+        (Some(selectors), None) => Ok(selectors),
+        (_, Some((offset, kind))) => Err(index.diagnostic(kind, offset)),
+        (None, None) => Err(index.diagnostic(DiagnosticKind::ExpectedSelector, 0)),
     }
 }
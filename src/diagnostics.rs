@@ -0,0 +1,502 @@
+// Problems found while parsing HTML, XML and CSS.
+// The HTML and CSS parsers recover from every error, so diagnostics are only informative: each
+// one records what went wrong and where, as a line, a column and the source line it was found on.
+// The XML parser stops at the first error, as XML requires.
+// Spans record where parsed nodes, rules and declarations came from in the same terms.
+
+use std::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Severity {
+    Warning,
+    Error,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum DiagnosticKind {
+    // HTML tokenizer errors, named after the parse errors in the HTML Living Standard
+    AbruptClosingOfEmptyComment,
+    AbruptDoctypePublicIdentifier,
+    AbruptDoctypeSystemIdentifier,
+    AbsenceOfDigitsInNumericCharacterReference,
+    CdataInHtmlContent,
+    CharacterReferenceOutsideUnicodeRange,
+    ControlCharacterReference,
+    DuplicateAttribute(String),
+    EndTagWithAttributes,
+    EndTagWithTrailingSolidus,
+    EofBeforeTagName,
+    EofInCdata,
+    EofInComment,
+    EofInDoctype,
+    EofInScriptHtmlCommentLikeText,
+    EofInTag,
+    IncorrectlyClosedComment,
+    IncorrectlyOpenedComment,
+    InvalidCharacterSequenceAfterDoctypeName,
+    InvalidFirstCharacterOfTagName,
+    MissingAttributeValue,
+    MissingDoctypeName,
+    MissingDoctypePublicIdentifier,
+    MissingDoctypeSystemIdentifier,
+    MissingEndTagName,
+    MissingQuoteBeforeDoctypePublicIdentifier,
+    MissingQuoteBeforeDoctypeSystemIdentifier,
+    MissingSemicolonAfterCharacterReference,
+    MissingWhitespaceAfterDoctypePublicKeyword,
+    MissingWhitespaceAfterDoctypeSystemKeyword,
+    MissingWhitespaceBeforeDoctypeName,
+    MissingWhitespaceBetweenAttributes,
+    MissingWhitespaceBetweenDoctypePublicAndSystemIdentifiers,
+    NullCharacterReference,
+    SurrogateCharacterReference,
+    UnexpectedCharacterAfterDoctypeSystemIdentifier,
+    UnexpectedCharacterInAttributeName,
+    UnexpectedCharacterInUnquotedAttributeValue,
+    UnexpectedEqualsSignBeforeAttributeName,
+    UnexpectedNullCharacter,
+    UnexpectedQuestionMarkInsteadOfTagName,
+    UnexpectedSolidusInTag,
+    UnknownNamedCharacterReference,
+
+    // HTML tree construction errors
+    MissingDoctype,
+    NonConformingDoctype,
+    UnexpectedDoctype,
+    NonVoidElementWithTrailingSolidus(String),
+    UnexpectedStartTag(String),
+    UnexpectedEndTag(String),
+    MisnestedTag(String),
+    UnclosedElement(String),
+    UnexpectedContentInTable,
+
+    // XML well-formedness errors, the tokenizer errors above are reused where they fit
+    MismatchedEndTag(String, String),
+    NonUniqueAttribute(String),
+    UnboundPrefix(String),
+    LessThanInAttributeValue,
+    CdataEndInText,
+    DoubleHyphenInComment,
+    ContentOutsideRootElement,
+    MissingRootElement,
+    MisplacedXmlDeclaration,
+
+    // CSS errors
+    UnexpectedCharacterInSelector(char),
+    ExpectedSelector,
+    UnknownPseudoClass(String),
+    InvalidNthArgument,
+    SelectorNestedTooDeeply,
+    ExpectedCharacter(char),
+    InvalidValue(String),
+    UnknownUnit(String),
+    InvalidColor(String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub kind: DiagnosticKind,
+    // 1-based line and column, the column counts characters
+    pub line: usize,
+    pub column: usize,
+    // The part of the source line around the problem, starting at column `snippet_column`
+    pub snippet: String,
+    pub snippet_column: usize,
+}
+
+impl DiagnosticKind {
+    // Problems that don't change the resulting tree much are warnings, the others are errors
+    pub fn severity(&self) -> Severity {
+        match self {
+            DiagnosticKind::DuplicateAttribute(_)
+            | DiagnosticKind::EndTagWithTrailingSolidus
+            | DiagnosticKind::MissingSemicolonAfterCharacterReference
+            | DiagnosticKind::MissingWhitespaceAfterDoctypePublicKeyword
+            | DiagnosticKind::MissingWhitespaceAfterDoctypeSystemKeyword
+            | DiagnosticKind::MissingWhitespaceBeforeDoctypeName
+            | DiagnosticKind::MissingWhitespaceBetweenAttributes
+            | DiagnosticKind::MissingWhitespaceBetweenDoctypePublicAndSystemIdentifiers
+            | DiagnosticKind::MissingDoctype
+            | DiagnosticKind::NonConformingDoctype
+            | DiagnosticKind::NonVoidElementWithTrailingSolidus(_)
+            | DiagnosticKind::MisnestedTag(_)
+            | DiagnosticKind::UnclosedElement(_) => Severity::Warning,
+            _ => Severity::Error,
+        }
+    }
+
+    // Stable, machine readable name of the problem
+    pub fn code(&self) -> &'static str {
+        match self {
+            DiagnosticKind::AbruptClosingOfEmptyComment => "abrupt-closing-of-empty-comment",
+            DiagnosticKind::AbruptDoctypePublicIdentifier => "abrupt-doctype-public-identifier",
+            DiagnosticKind::AbruptDoctypeSystemIdentifier => "abrupt-doctype-system-identifier",
+            DiagnosticKind::AbsenceOfDigitsInNumericCharacterReference => {
+                "absence-of-digits-in-numeric-character-reference"
+            }
+            DiagnosticKind::CdataInHtmlContent => "cdata-in-html-content",
+            DiagnosticKind::CharacterReferenceOutsideUnicodeRange => {
+                "character-reference-outside-unicode-range"
+            }
+            DiagnosticKind::ControlCharacterReference => "control-character-reference",
+            DiagnosticKind::DuplicateAttribute(_) => "duplicate-attribute",
+            DiagnosticKind::EndTagWithAttributes => "end-tag-with-attributes",
+            DiagnosticKind::EndTagWithTrailingSolidus => "end-tag-with-trailing-solidus",
+            DiagnosticKind::EofBeforeTagName => "eof-before-tag-name",
+            DiagnosticKind::EofInCdata => "eof-in-cdata",
+            DiagnosticKind::EofInComment => "eof-in-comment",
+            DiagnosticKind::EofInDoctype => "eof-in-doctype",
+            DiagnosticKind::EofInScriptHtmlCommentLikeText => {
+                "eof-in-script-html-comment-like-text"
+            }
+            DiagnosticKind::EofInTag => "eof-in-tag",
+            DiagnosticKind::IncorrectlyClosedComment => "incorrectly-closed-comment",
+            DiagnosticKind::IncorrectlyOpenedComment => "incorrectly-opened-comment",
+            DiagnosticKind::InvalidCharacterSequenceAfterDoctypeName => {
+                "invalid-character-sequence-after-doctype-name"
+            }
+            DiagnosticKind::InvalidFirstCharacterOfTagName => "invalid-first-character-of-tag-name",
+            DiagnosticKind::MissingAttributeValue => "missing-attribute-value",
+            DiagnosticKind::MissingDoctypeName => "missing-doctype-name",
+            DiagnosticKind::MissingDoctypePublicIdentifier => "missing-doctype-public-identifier",
+            DiagnosticKind::MissingDoctypeSystemIdentifier => "missing-doctype-system-identifier",
+            DiagnosticKind::MissingEndTagName => "missing-end-tag-name",
+            DiagnosticKind::MissingQuoteBeforeDoctypePublicIdentifier => {
+                "missing-quote-before-doctype-public-identifier"
+            }
+            DiagnosticKind::MissingQuoteBeforeDoctypeSystemIdentifier => {
+                "missing-quote-before-doctype-system-identifier"
+            }
+            DiagnosticKind::MissingSemicolonAfterCharacterReference => {
+                "missing-semicolon-after-character-reference"
+            }
+            DiagnosticKind::MissingWhitespaceAfterDoctypePublicKeyword => {
+                "missing-whitespace-after-doctype-public-keyword"
+            }
+            DiagnosticKind::MissingWhitespaceAfterDoctypeSystemKeyword => {
+                "missing-whitespace-after-doctype-system-keyword"
+            }
+            DiagnosticKind::MissingWhitespaceBeforeDoctypeName => {
+                "missing-whitespace-before-doctype-name"
+            }
+            DiagnosticKind::MissingWhitespaceBetweenAttributes => {
+                "missing-whitespace-between-attributes"
+            }
+            DiagnosticKind::MissingWhitespaceBetweenDoctypePublicAndSystemIdentifiers => {
+                "missing-whitespace-between-doctype-public-and-system-identifiers"
+            }
+            DiagnosticKind::NullCharacterReference => "null-character-reference",
+            DiagnosticKind::SurrogateCharacterReference => "surrogate-character-reference",
+            DiagnosticKind::UnexpectedCharacterAfterDoctypeSystemIdentifier => {
+                "unexpected-character-after-doctype-system-identifier"
+            }
+            DiagnosticKind::UnexpectedCharacterInAttributeName => {
+                "unexpected-character-in-attribute-name"
+            }
+            DiagnosticKind::UnexpectedCharacterInUnquotedAttributeValue => {
+                "unexpected-character-in-unquoted-attribute-value"
+            }
+            DiagnosticKind::UnexpectedEqualsSignBeforeAttributeName => {
+                "unexpected-equals-sign-before-attribute-name"
+            }
+            DiagnosticKind::UnexpectedNullCharacter => "unexpected-null-character",
+            DiagnosticKind::UnexpectedQuestionMarkInsteadOfTagName => {
+                "unexpected-question-mark-instead-of-tag-name"
+            }
+            DiagnosticKind::UnexpectedSolidusInTag => "unexpected-solidus-in-tag",
+            DiagnosticKind::UnknownNamedCharacterReference => "unknown-named-character-reference",
+            DiagnosticKind::NonVoidElementWithTrailingSolidus(_) => {
+                "non-void-html-element-start-tag-with-trailing-solidus"
+            }
+            DiagnosticKind::MissingDoctype => "missing-doctype",
+            DiagnosticKind::NonConformingDoctype => "non-conforming-doctype",
+            DiagnosticKind::UnexpectedDoctype => "unexpected-doctype",
+            DiagnosticKind::UnexpectedStartTag(_) => "unexpected-start-tag",
+            DiagnosticKind::UnexpectedEndTag(_) => "unexpected-end-tag",
+            DiagnosticKind::MisnestedTag(_) => "misnested-tag",
+            DiagnosticKind::UnclosedElement(_) => "unclosed-element",
+            DiagnosticKind::UnexpectedContentInTable => "unexpected-content-in-table",
+            DiagnosticKind::MismatchedEndTag(..) => "mismatched-end-tag",
+            DiagnosticKind::NonUniqueAttribute(_) => "non-unique-attribute",
+            DiagnosticKind::UnboundPrefix(_) => "unbound-prefix",
+            DiagnosticKind::LessThanInAttributeValue => "less-than-in-attribute-value",
+            DiagnosticKind::CdataEndInText => "cdata-end-in-text",
+            DiagnosticKind::DoubleHyphenInComment => "double-hyphen-in-comment",
+            DiagnosticKind::ContentOutsideRootElement => "content-outside-root-element",
+            DiagnosticKind::MissingRootElement => "missing-root-element",
+            DiagnosticKind::MisplacedXmlDeclaration => "misplaced-xml-declaration",
+            DiagnosticKind::UnexpectedCharacterInSelector(_) => "unexpected-character-in-selector",
+            DiagnosticKind::ExpectedSelector => "expected-selector",
+            DiagnosticKind::UnknownPseudoClass(_) => "unknown-pseudo-class",
+            DiagnosticKind::InvalidNthArgument => "invalid-nth-argument",
+            DiagnosticKind::SelectorNestedTooDeeply => "selector-nested-too-deeply",
+            DiagnosticKind::ExpectedCharacter(_) => "expected-character",
+            DiagnosticKind::InvalidValue(_) => "invalid-value",
+            DiagnosticKind::UnknownUnit(_) => "unknown-unit",
+            DiagnosticKind::InvalidColor(_) => "invalid-color",
+        }
+    }
+}
+
+impl fmt::Display for DiagnosticKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DiagnosticKind::DuplicateAttribute(name) => {
+                write!(f, "duplicate attribute `{}`, the first one is kept", name)
+            }
+            DiagnosticKind::NonVoidElementWithTrailingSolidus(name) => {
+                write!(f, "`<{}/>` is not self-closing, the slash is ignored", name)
+            }
+            DiagnosticKind::MissingDoctype => {
+                write!(
+                    f,
+                    "missing `<!DOCTYPE html>`, the document is rendered in quirks mode"
+                )
+            }
+            DiagnosticKind::NonConformingDoctype => {
+                write!(f, "obsolete doctype, `<!DOCTYPE html>` is expected")
+            }
+            DiagnosticKind::UnexpectedDoctype => {
+                write!(f, "doctype is only allowed at the start of the document")
+            }
+            DiagnosticKind::UnexpectedStartTag(name) => {
+                write!(f, "unexpected start tag `<{}>`", name)
+            }
+            DiagnosticKind::UnexpectedEndTag(name) => write!(f, "unexpected end tag `</{}>`", name),
+            DiagnosticKind::MisnestedTag(name) => {
+                write!(f, "end tag `</{}>` closes other open elements", name)
+            }
+            DiagnosticKind::UnclosedElement(name) => {
+                write!(f, "element `<{}>` is never closed", name)
+            }
+            DiagnosticKind::MismatchedEndTag(expected, found) => {
+                write!(f, "end tag `</{}>` doesn't match `<{}>`", found, expected)
+            }
+            DiagnosticKind::NonUniqueAttribute(name) => {
+                write!(f, "attribute `{}` is given more than once", name)
+            }
+            DiagnosticKind::UnboundPrefix(prefix) => {
+                write!(f, "namespace prefix `{}` is not declared", prefix)
+            }
+            DiagnosticKind::CdataEndInText => write!(f, "`]]>` is not allowed in text"),
+            DiagnosticKind::DoubleHyphenInComment => write!(f, "`--` is not allowed in comments"),
+            DiagnosticKind::ContentOutsideRootElement => {
+                write!(
+                    f,
+                    "only one root element and no text is allowed at the top level"
+                )
+            }
+            DiagnosticKind::MisplacedXmlDeclaration => {
+                write!(
+                    f,
+                    "the XML declaration is only allowed at the start of the document"
+                )
+            }
+            DiagnosticKind::UnexpectedCharacterInSelector(c) => {
+                write!(f, "unexpected character {:?} in selector list", c)
+            }
+            DiagnosticKind::ExpectedSelector => write!(f, "expected a selector"),
+            DiagnosticKind::UnknownPseudoClass(name) => {
+                write!(f, "unknown pseudo-class `:{}`", name)
+            }
+            DiagnosticKind::InvalidNthArgument => {
+                write!(f, "expected an argument like `2n+1`, `odd` or `even`")
+            }
+            DiagnosticKind::ExpectedCharacter(c) => write!(f, "expected {:?}", c),
+            DiagnosticKind::InvalidValue(name) => {
+                write!(f, "invalid value for property `{}`", name)
+            }
+            DiagnosticKind::UnknownUnit(unit) => write!(f, "unknown unit `{}`", unit),
+            DiagnosticKind::InvalidColor(color) => write!(f, "invalid color `#{}`", color),
+            kind => write!(f, "{}", kind.code().replace('-', " ")),
+        }
+    }
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Severity::Warning => write!(f, "warning"),
+            Severity::Error => write!(f, "error"),
+        }
+    }
+}
+
+// Formats as `line:column: severity[code]: message` followed by the source line and a marker
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "{}:{}: {}[{}]: {}",
+            self.line,
+            self.column,
+            self.severity,
+            self.kind.code(),
+            self.kind
+        )?;
+        writeln!(f, "    {}", self.snippet)?;
+        write!(
+            f,
+            "    {}^",
+            " ".repeat(self.column.saturating_sub(self.snippet_column))
+        )
+    }
+}
+
+// A place in the source. Lines and columns count from 1, columns count characters.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Location {
+    // bytes before this place
+    pub offset: usize,
+    pub line: usize,
+    pub column: usize,
+}
+
+impl Location {
+    // The start of the source
+    pub const START: Location = Location {
+        offset: 0,
+        line: 1,
+        column: 1,
+    };
+
+    // The location at the end of `text`, which is the source right after this location
+    pub fn advance(self, text: &str) -> Location {
+        let offset = self.offset + text.len();
+        match text.rfind('\n') {
+            Some(newline) => Location {
+                offset,
+                line: self.line + text.matches('\n').count(),
+                column: text[newline + 1..].chars().count() + 1,
+            },
+            None => Location {
+                offset,
+                column: self.column + text.chars().count(),
+                ..self
+            },
+        }
+    }
+}
+
+// The part of the source something was parsed from, `end` is right after its last character
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Span {
+    pub start: Location,
+    pub end: Location,
+}
+
+impl fmt::Display for Span {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}:{}-{}:{}",
+            self.start.line, self.start.column, self.end.line, self.end.column
+        )
+    }
+}
+
+// Turns byte offsets into line & column numbers
+pub struct LineIndex<'a> {
+    source: &'a str,
+    // byte offset where every line starts
+    line_starts: Vec<usize>,
+}
+
+impl<'a> LineIndex<'a> {
+    pub fn new(source: &'a str) -> LineIndex<'a> {
+        let line_starts = std::iter::once(0)
+            .chain(source.match_indices('\n').map(|(i, _)| i + 1))
+            .collect();
+        LineIndex {
+            source,
+            line_starts,
+        }
+    }
+
+    // Location of byte `offset` of the source
+    fn location(&self, offset: usize) -> Location {
+        let offset = floor_char_boundary(self.source, offset);
+        let line = self.line_starts.partition_point(|&start| start <= offset);
+        let start = self.line_starts[line - 1];
+        Location {
+            offset,
+            line,
+            column: self.source[start..offset].chars().count() + 1,
+        }
+    }
+
+    // Build the diagnostic for a problem found at byte `offset`
+    pub fn diagnostic(&self, kind: DiagnosticKind, offset: usize) -> Diagnostic {
+        let location = self.location(offset);
+        diagnostic_in(
+            self.source,
+            location.offset,
+            location.line,
+            location.column,
+            kind,
+        )
+    }
+
+    // Build diagnostics for (offset, kind) pairs, ordered by their place in the source.
+    // Each location is counted on from the previous one, a long line isn't counted again for
+    // every problem on it.
+    pub fn diagnostics(&self, mut errors: Vec<(usize, DiagnosticKind)>) -> Vec<Diagnostic> {
+        errors.sort_by_key(|&(offset, _)| offset);
+        let mut location = Location::START;
+        errors
+            .into_iter()
+            .map(|(offset, kind)| {
+                let offset = floor_char_boundary(self.source, offset);
+                location = location.advance(&self.source[location.offset..offset]);
+                diagnostic_in(self.source, offset, location.line, location.column, kind)
+            })
+            .collect()
+    }
+}
+
+// Characters of the source line shown on each side of a problem, lines of minified sources
+// can be very long
+pub const SNIPPET_CONTEXT: usize = 60;
+
+// Build the diagnostic for a problem found at byte `offset` of `text`, which is at `line` and
+// `column` of the source. `text` only needs to hold the part of the line around it.
+pub fn diagnostic_in(
+    text: &str,
+    offset: usize,
+    line: usize,
+    column: usize,
+    kind: DiagnosticKind,
+) -> Diagnostic {
+    let offset = floor_char_boundary(text, offset);
+    let start = text[..offset]
+        .char_indices()
+        .rev()
+        .take_while(|&(_, c)| c != '\n')
+        .take(SNIPPET_CONTEXT)
+        .last()
+        .map_or(offset, |(i, _)| i);
+    let end = text[offset..]
+        .char_indices()
+        .take_while(|&(_, c)| c != '\n')
+        .take(SNIPPET_CONTEXT + 1)
+        .last()
+        .map_or(offset, |(i, c)| offset + i + c.len_utf8());
+    Diagnostic {
+        severity: kind.severity(),
+        kind,
+        line,
+        column,
+        snippet: text[start..end].trim_end_matches('\r').to_string(),
+        snippet_column: column - text[start..offset].chars().count(),
+    }
+}
+
+// The start of the character at byte `offset` of `text`, or the end of `text`
+fn floor_char_boundary(text: &str, offset: usize) -> usize {
+    let mut offset = offset.min(text.len());
+    while !text.is_char_boundary(offset) {
+        offset -= 1;
+    }
+    offset
+}
@@ -1,59 +1,733 @@
-use std::collections::{HashMap, HashSet};
+mod diff;
 
+use std::{
+    cmp::Ordering,
+    collections::{HashMap, HashSet},
+    error::Error,
+    fmt,
+    ops::Index,
+};
+
+use crate::{
+    css::{self, Selector},
+    diagnostics::{Diagnostic, Span},
+    encoding::Encoding,
+    style::{self, Display, StyledNode},
+};
+
+pub use self::diff::{diff, Edit, Path};
+
+// Handle of a node in its Document. Removed nodes keep their id and can be inserted again.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct NodeId(usize);
+
+// Elements nested deeper than this are inserted as siblings instead by the parsers, like
+// browsers do, so the recursive passes over the tree (style, layout, drop) can't overflow the
+// stack
+pub(crate) const MAX_DEPTH: usize = 512;
+
+// A document owns all its nodes in an arena, nodes link to each other by id
 #[derive(Debug)]
-pub struct Node {
-    // data common to all Nodes:
-    pub children: Vec<Node>,
+pub struct Document {
+    nodes: Vec<Node>,
+    // elements by their id attribute, including elements that are not in the tree
+    ids: HashMap<String, Vec<NodeId>>,
+}
 
+#[derive(Debug)]
+pub struct Node {
     // data specific to node type:
     pub node_type: NodeType,
+
+    // links to the neighbouring nodes, kept up to date by the Document
+    parent: Option<NodeId>,
+    first_child: Option<NodeId>,
+    last_child: Option<NodeId>,
+    prev_sibling: Option<NodeId>,
+    next_sibling: Option<NodeId>,
+
+    // source the node was parsed from, None for nodes created through the API
+    span: Option<Span>,
 }
 
-#[derive(Debug)]
+// Why a DOM operation failed, the tree is left unchanged
+#[derive(Debug, Clone, PartialEq)]
+pub enum DomError {
+    // The node can't be inserted there: the parent can't have children, the node is the document
+    // or it would become its own ancestor
+    HierarchyRequest,
+    // The child or reference node is not a child of the given parent
+    NotFound,
+    // The operation doesn't apply to this type of node
+    InvalidNodeType,
+    // The selectors of a query can't be parsed
+    InvalidSelector(Diagnostic),
+}
+
+#[derive(Debug, Clone)]
 pub enum NodeType {
     Text(String),
     Element(ElementData),
     Comment(String),
+    // Root of a parsed document, the root element and top level comments are its children
+    Document(DocumentData),
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Default)]
+pub struct DocumentData {
+    pub kind: DocumentKind,
+    pub doctype: Option<Doctype>,
+    pub quirks_mode: QuirksMode,
+    // encoding the source was decoded from, linked style sheets default to it
+    pub encoding: Encoding,
+}
+
+// <!DOCTYPE name PUBLIC "public_id" "system_id">, missing parts are empty
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Doctype {
+    pub name: String,
+    pub public_id: String,
+    pub system_id: String,
+}
+
+// Documents parsed as XML, XHTML included, have case-sensitive names
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum DocumentKind {
+    #[default]
+    Html,
+    Xml,
+}
+
+// Rendering mode picked from the doctype, old pages without one get quirks
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum QuirksMode {
+    #[default]
+    NoQuirks,
+    LimitedQuirks,
+    Quirks,
+}
+
+#[derive(Debug, Clone)]
 pub struct ElementData {
+    // local name, without the prefix
     pub tag_name: String,
     pub attrs: AttrMap,
+    pub namespace: Namespace,
+    // namespace prefix of an XML element like `svg:rect`, html never has one
+    pub prefix: Option<String>,
+}
+
+// Namespace of an element, the contents of <svg> and <math> are foreign elements
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum Namespace {
+    #[default]
+    Html,
+    Svg,
+    MathMl,
+    // Any other namespace of an XML document, or none. The uri is kept in the xmlns attributes.
+    Other,
 }
 
 pub type AttrMap = HashMap<String, String>;
 
-// constructor functions
-pub fn text(data: String) -> Node {
-    Node {
-        children: Vec::new(),
-        node_type: NodeType::Text(data),
+impl fmt::Display for DomError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DomError::HierarchyRequest => write!(f, "the node can't be inserted at this position"),
+            DomError::NotFound => write!(f, "the node is not a child of this parent"),
+            DomError::InvalidNodeType => write!(f, "the operation is not supported on this node"),
+            DomError::InvalidSelector(diagnostic) => write!(f, "invalid selector: {}", diagnostic),
+        }
     }
 }
 
-pub fn elem(tag_name: String, attrs: AttrMap, children: Vec<Node>) -> Node {
-    Node {
-        children,
-        node_type: NodeType::Element(ElementData { tag_name, attrs }),
+impl Error for DomError {}
+
+impl Node {
+    fn new(node_type: NodeType) -> Node {
+        Node {
+            node_type,
+            parent: None,
+            first_child: None,
+            last_child: None,
+            prev_sibling: None,
+            next_sibling: None,
+            span: None,
+        }
+    }
+
+    pub fn parent(&self) -> Option<NodeId> {
+        self.parent
+    }
+
+    pub fn first_child(&self) -> Option<NodeId> {
+        self.first_child
+    }
+
+    pub fn last_child(&self) -> Option<NodeId> {
+        self.last_child
+    }
+
+    pub fn prev_sibling(&self) -> Option<NodeId> {
+        self.prev_sibling
+    }
+
+    pub fn next_sibling(&self) -> Option<NodeId> {
+        self.next_sibling
+    }
+
+    pub fn element(&self) -> Option<&ElementData> {
+        match &self.node_type {
+            NodeType::Element(element) => Some(element),
+            _ => None,
+        }
+    }
+
+    pub fn span(&self) -> Option<Span> {
+        self.span
+    }
+
+    // Only documents and elements can have children
+    fn can_have_children(&self) -> bool {
+        matches!(self.node_type, NodeType::Document(_) | NodeType::Element(_))
     }
 }
 
-pub fn comment(data: String) -> Node {
-    Node {
-        children: Vec::new(),
-        node_type: NodeType::Comment(data),
+// Iterator over the children of a node, see Document::children
+pub struct Children<'a> {
+    document: &'a Document,
+    next: Option<NodeId>,
+}
+
+impl Iterator for Children<'_> {
+    type Item = NodeId;
+
+    fn next(&mut self) -> Option<NodeId> {
+        let id = self.next?;
+        self.next = self.document[id].next_sibling;
+        Some(id)
+    }
+}
+
+// The node of the style tree that was built for `id`
+fn find_styled_node(styled: &StyledNode, id: NodeId) -> Option<&StyledNode> {
+    if styled.node == id {
+        return Some(styled);
+    }
+    styled
+        .children
+        .iter()
+        .find_map(|child| find_styled_node(child, id))
+}
+
+// Iterator over the descendants of a node in tree order, see Document::descendants
+pub struct Descendants<'a> {
+    document: &'a Document,
+    root: NodeId,
+    next: Option<NodeId>,
+}
+
+impl Iterator for Descendants<'_> {
+    type Item = NodeId;
+
+    fn next(&mut self) -> Option<NodeId> {
+        let id = self.next?;
+        let document = self.document;
+        // go down first, then to the next sibling of the closest node that has one
+        self.next = document[id].first_child.or_else(|| {
+            let mut current = id;
+            loop {
+                if current == self.root {
+                    return None;
+                }
+                if let Some(next) = document[current].next_sibling {
+                    return Some(next);
+                }
+                current = document[current].parent?;
+            }
+        });
+        Some(id)
+    }
+}
+
+impl Index<NodeId> for Document {
+    type Output = Node;
+
+    fn index(&self, id: NodeId) -> &Node {
+        &self.nodes[id.0]
+    }
+}
+
+impl Document {
+    // An empty document, its root is the only node
+    pub fn new(data: DocumentData) -> Document {
+        Document {
+            nodes: vec![Node::new(NodeType::Document(data))],
+            ids: HashMap::new(),
+        }
+    }
+
+    // The document node, root of the tree
+    pub fn root(&self) -> NodeId {
+        NodeId(0)
+    }
+
+    pub fn data(&self) -> &DocumentData {
+        match &self[self.root()].node_type {
+            NodeType::Document(data) => data,
+            _ => unreachable!("the root of a document is the document node"),
+        }
+    }
+
+    // Quirks mode picked by the parser, documents built by hand use no-quirks
+    pub fn quirks_mode(&self) -> QuirksMode {
+        self.data().quirks_mode
+    }
+
+    // Encoding of the parsed source, documents built by hand use UTF-8
+    pub fn encoding(&self) -> Encoding {
+        self.data().encoding
+    }
+
+    // Only documents parsed as XML aren't HTML documents
+    pub fn kind(&self) -> DocumentKind {
+        self.data().kind
+    }
+
+    // Tag and attribute names of html elements in HTML documents are matched in any case,
+    // the names of XML documents and foreign elements are case-sensitive
+    pub fn has_case_insensitive_names(&self, element: &ElementData) -> bool {
+        element.namespace == Namespace::Html && self.kind() == DocumentKind::Html
+    }
+
+    // The <html> element, or whatever element is the first child of the document
+    pub fn document_element(&self) -> Option<NodeId> {
+        self.children(self.root())
+            .find(|&child| self[child].element().is_some())
+    }
+
+    pub fn element(&self, id: NodeId) -> Option<&ElementData> {
+        self[id].element()
+    }
+
+    pub fn children(&self, id: NodeId) -> Children<'_> {
+        Children {
+            document: self,
+            next: self[id].first_child,
+        }
+    }
+
+    // All nodes below `id` in tree order, `id` itself is not included
+    pub fn descendants(&self, id: NodeId) -> Descendants<'_> {
+        Descendants {
+            document: self,
+            root: id,
+            next: self[id].first_child,
+        }
+    }
+
+    // ---- queries ----
+
+    // The first element below `root` in tree order matching the selector list, like `div.note, #main`
+    pub fn query_selector(
+        &self,
+        root: NodeId,
+        selectors: &str,
+    ) -> Result<Option<NodeId>, DomError> {
+        let selectors = css::parse_selector_list(selectors).map_err(DomError::InvalidSelector)?;
+        Ok(self
+            .descendants(root)
+            .find(|&id| self.matches_any(id, &selectors)))
+    }
+
+    // Every element below `root` matching the selector list, in tree order
+    pub fn query_selector_all(
+        &self,
+        root: NodeId,
+        selectors: &str,
+    ) -> Result<Vec<NodeId>, DomError> {
+        let selectors = css::parse_selector_list(selectors).map_err(DomError::InvalidSelector)?;
+        Ok(self
+            .descendants(root)
+            .filter(|&id| self.matches_any(id, &selectors))
+            .collect())
+    }
+
+    fn matches_any(&self, id: NodeId, selectors: &[Selector]) -> bool {
+        selectors.iter().any(|s| style::matches(self, id, s))
+    }
+
+    // The first element in the document with the given id, looked up in the id index
+    pub fn get_element_by_id(&self, element_id: &str) -> Option<NodeId> {
+        self.ids
+            .get(element_id)?
+            .iter()
+            .copied()
+            .filter(|&id| self.is_connected(id))
+            .min_by(|&a, &b| self.tree_order(a, b))
+    }
+
+    // Elements below `root` that have all the space separated classes, in tree order
+    pub fn get_elements_by_class_name(&self, root: NodeId, class_names: &str) -> Vec<NodeId> {
+        let class_names: Vec<&str> = class_names.split_ascii_whitespace().collect();
+        if class_names.is_empty() {
+            return Vec::new();
+        }
+        self.descendants(root)
+            .filter(|&id| {
+                self.element(id).is_some_and(|element| {
+                    let classes = element.classes();
+                    class_names.iter().all(|name| classes.contains(name))
+                })
+            })
+            .collect()
+    }
+
+    // Elements below `root` with the tag name, or all elements for `*`, in tree order.
+    // Html elements of HTML documents match case-insensitively, foreign elements like
+    // <foreignObject> and the elements of XML documents don't.
+    pub fn get_elements_by_tag_name(&self, root: NodeId, tag_name: &str) -> Vec<NodeId> {
+        let lowercase = tag_name.to_ascii_lowercase();
+        self.descendants(root)
+            .filter(|&id| match self.element(id) {
+                Some(_) if tag_name == "*" => true,
+                Some(element) if self.has_case_insensitive_names(element) => {
+                    element.tag_name == lowercase
+                }
+                Some(element) => element.tag_name == tag_name,
+                None => false,
+            })
+            .collect()
+    }
+
+    // Text of a text or comment node, or all the text below an element or document
+    pub fn text_content(&self, id: NodeId) -> String {
+        match &self[id].node_type {
+            NodeType::Text(text) | NodeType::Comment(text) => text.clone(),
+            NodeType::Element(_) | NodeType::Document(_) => self
+                .descendants(id)
+                .filter_map(|child| match &self[child].node_type {
+                    NodeType::Text(text) => Some(text.as_str()),
+                    _ => None,
+                })
+                .collect(),
+        }
+    }
+
+    // Text the user gets to see below `id`: like text_content but the text of elements with
+    // `display: none` is left out. Without a style tree nothing is hidden.
+    // `styles` is the style tree of this document, as built by style::style_tree.
+    pub fn inner_text(&self, id: NodeId, styles: Option<&StyledNode>) -> String {
+        let Some(styled) = styles.and_then(|root| find_styled_node(root, id)) else {
+            return self.text_content(id);
+        };
+        let mut text = String::new();
+        self.collect_rendered_text(styled, &mut text);
+        text
+    }
+
+    fn collect_rendered_text(&self, styled: &StyledNode, text: &mut String) {
+        if styled.display() == Display::None {
+            return;
+        }
+        match &self[styled.node].node_type {
+            NodeType::Text(data) => text.push_str(data),
+            NodeType::Comment(_) => {}
+            NodeType::Element(_) | NodeType::Document(_) => {
+                for child in &styled.children {
+                    self.collect_rendered_text(child, text);
+                }
+            }
+        }
+    }
+
+    // Is the node in the tree of the document?
+    pub fn is_connected(&self, id: NodeId) -> bool {
+        self.is_inclusive_ancestor(self.root(), id)
+    }
+
+    // Compare the position of two nodes of the same tree, ancestors come before their descendants
+    fn tree_order(&self, a: NodeId, b: NodeId) -> Ordering {
+        let ancestors = |id| {
+            let mut chain = vec![id];
+            while let Some(parent) = self[*chain.last().unwrap()].parent {
+                chain.push(parent);
+            }
+            chain.reverse();
+            chain
+        };
+        let (a_chain, b_chain) = (ancestors(a), ancestors(b));
+        match a_chain.iter().zip(&b_chain).position(|(x, y)| x != y) {
+            // the nodes are in different branches, the first of the diverging siblings is first
+            Some(i) => {
+                let mut sibling = self[a_chain[i]].next_sibling;
+                while let Some(id) = sibling {
+                    if id == b_chain[i] {
+                        return Ordering::Less;
+                    }
+                    sibling = self[id].next_sibling;
+                }
+                Ordering::Greater
+            }
+            None => a_chain.len().cmp(&b_chain.len()),
+        }
+    }
+
+    // ---- node constructors, new nodes are not in the tree until they are inserted ----
+
+    pub fn create_node(&mut self, node_type: NodeType) -> NodeId {
+        let id = NodeId(self.nodes.len());
+        if let NodeType::Element(element) = &node_type {
+            if let Some(element_id) = element.id() {
+                self.ids.entry(element_id.clone()).or_default().push(id);
+            }
+        }
+        self.nodes.push(Node::new(node_type));
+        id
+    }
+
+    // Parsers fill in the doctype when they get to it
+    pub(crate) fn data_mut(&mut self) -> &mut DocumentData {
+        match &mut self.node_mut(NodeId(0)).node_type {
+            NodeType::Document(data) => data,
+            _ => unreachable!("the root of a document is the document node"),
+        }
+    }
+
+    // Record where a parsed node came from
+    pub(crate) fn set_span(&mut self, id: NodeId, span: Span) {
+        self.node_mut(id).span = Some(span);
+    }
+
+    pub fn create_element(&mut self, tag_name: String, attrs: AttrMap) -> NodeId {
+        self.create_node(NodeType::Element(ElementData {
+            tag_name,
+            attrs,
+            namespace: Namespace::Html,
+            prefix: None,
+        }))
+    }
+
+    pub fn create_text(&mut self, data: String) -> NodeId {
+        self.create_node(NodeType::Text(data))
+    }
+
+    pub fn create_comment(&mut self, data: String) -> NodeId {
+        self.create_node(NodeType::Comment(data))
+    }
+
+    // Copy `node` of `other` and its descendants into this document, the copy is not in the tree.
+    // Documents can't be copied.
+    pub fn import_node(&mut self, other: &Document, node: NodeId) -> Result<NodeId, DomError> {
+        if let NodeType::Document(_) = other[node].node_type {
+            return Err(DomError::InvalidNodeType);
+        }
+        let copy = self.create_node(other[node].node_type.clone());
+        let mut stack = vec![(node, copy)];
+        while let Some((from, to)) = stack.pop() {
+            for child in other.children(from) {
+                let child_copy = self.create_node(other[child].node_type.clone());
+                self.link(to, child_copy, None);
+                stack.push((child, child_copy));
+            }
+        }
+        Ok(copy)
+    }
+
+    // ---- mutations ----
+
+    // Add `child` as the last child of `parent`, moving it from where it was
+    pub fn append_child(&mut self, parent: NodeId, child: NodeId) -> Result<(), DomError> {
+        self.insert_before(parent, child, None)
+    }
+
+    // Insert `child` into `parent` before `reference`, or as the last child when there is none.
+    // A child that is already in the tree is moved.
+    pub fn insert_before(
+        &mut self,
+        parent: NodeId,
+        child: NodeId,
+        reference: Option<NodeId>,
+    ) -> Result<(), DomError> {
+        self.check_insertion(parent, child)?;
+        if reference.is_some_and(|r| self[r].parent != Some(parent)) {
+            return Err(DomError::NotFound);
+        }
+        // inserting a node before itself keeps it in place
+        let reference = if reference == Some(child) {
+            self[child].next_sibling
+        } else {
+            reference
+        };
+        self.detach(child);
+        self.link(parent, child, reference);
+        Ok(())
+    }
+
+    // Parsers build well formed trees, they insert without the checks and the walk up the
+    // ancestors of insert_before, which would make deep documents slow to parse
+    pub(crate) fn append_child_unchecked(&mut self, parent: NodeId, child: NodeId) {
+        self.insert_before_unchecked(parent, child, None);
+    }
+
+    pub(crate) fn insert_before_unchecked(
+        &mut self,
+        parent: NodeId,
+        child: NodeId,
+        reference: Option<NodeId>,
+    ) {
+        self.detach(child);
+        self.link(parent, child, reference);
+    }
+
+    // Take `child` out of the tree, it keeps its own children and can be inserted again
+    pub fn remove_child(&mut self, parent: NodeId, child: NodeId) -> Result<(), DomError> {
+        if self[child].parent != Some(parent) {
+            return Err(DomError::NotFound);
+        }
+        self.detach(child);
+        Ok(())
+    }
+
+    // Put `new_child` where `old_child` is and remove `old_child`
+    pub fn replace_child(
+        &mut self,
+        parent: NodeId,
+        new_child: NodeId,
+        old_child: NodeId,
+    ) -> Result<(), DomError> {
+        if self[old_child].parent != Some(parent) {
+            return Err(DomError::NotFound);
+        }
+        if new_child == old_child {
+            return Ok(());
+        }
+        self.insert_before(parent, new_child, Some(old_child))?;
+        self.detach(old_child);
+        Ok(())
+    }
+
+    // Set an attribute of an element, names of html elements in HTML documents are lowercased
+    pub fn set_attribute(&mut self, id: NodeId, name: &str, value: String) -> Result<(), DomError> {
+        let name = match self.element(id) {
+            Some(element) if self.has_case_insensitive_names(element) => name.to_ascii_lowercase(),
+            Some(_) => name.to_string(),
+            None => return Err(DomError::InvalidNodeType),
+        };
+        let NodeType::Element(element) = &mut self.node_mut(id).node_type else {
+            unreachable!("the node is an element");
+        };
+        let old_value = element.attrs.insert(name.clone(), value.clone());
+        if name == "id" {
+            if let Some(ids) = old_value.and_then(|old| self.ids.get_mut(&old)) {
+                ids.retain(|&other| other != id);
+            }
+            self.ids.entry(value).or_default().push(id);
+        }
+        Ok(())
+    }
+
+    // Replace the data of a text or comment node. The children of an element are replaced
+    // with a single text node, or removed when `text` is empty.
+    pub fn set_text(&mut self, id: NodeId, text: String) -> Result<(), DomError> {
+        match &mut self.node_mut(id).node_type {
+            NodeType::Text(data) | NodeType::Comment(data) => {
+                *data = text;
+                Ok(())
+            }
+            NodeType::Element(_) => {
+                while let Some(child) = self[id].first_child {
+                    self.detach(child);
+                }
+                if !text.is_empty() {
+                    let child = self.create_text(text);
+                    self.link(id, child, None);
+                }
+                Ok(())
+            }
+            NodeType::Document(_) => Err(DomError::InvalidNodeType),
+        }
+    }
+
+    // `child` can go into `parent` if that doesn't break the tree
+    fn check_insertion(&self, parent: NodeId, child: NodeId) -> Result<(), DomError> {
+        if !self[parent].can_have_children()
+            || matches!(self[child].node_type, NodeType::Document(_))
+            || self.is_inclusive_ancestor(child, parent)
+        {
+            return Err(DomError::HierarchyRequest);
+        }
+        Ok(())
+    }
+
+    fn node_mut(&mut self, id: NodeId) -> &mut Node {
+        &mut self.nodes[id.0]
+    }
+
+    fn is_inclusive_ancestor(&self, ancestor: NodeId, node: NodeId) -> bool {
+        let mut current = Some(node);
+        while let Some(id) = current {
+            if id == ancestor {
+                return true;
+            }
+            current = self[id].parent;
+        }
+        false
+    }
+
+    // Unlink a node from its parent and siblings
+    fn detach(&mut self, id: NodeId) {
+        let Some(parent) = self.node_mut(id).parent.take() else {
+            return;
+        };
+        let prev = self.node_mut(id).prev_sibling.take();
+        let next = self.node_mut(id).next_sibling.take();
+        match prev {
+            Some(prev) => self.node_mut(prev).next_sibling = next,
+            None => self.node_mut(parent).first_child = next,
+        }
+        match next {
+            Some(next) => self.node_mut(next).prev_sibling = prev,
+            None => self.node_mut(parent).last_child = prev,
+        }
+    }
+
+    // Link a detached node into `parent` before `reference`, or at the end
+    fn link(&mut self, parent: NodeId, id: NodeId, reference: Option<NodeId>) {
+        let prev = match reference {
+            Some(reference) => self[reference].prev_sibling,
+            None => self[parent].last_child,
+        };
+        self.node_mut(id).parent = Some(parent);
+        self.node_mut(id).prev_sibling = prev;
+        self.node_mut(id).next_sibling = reference;
+        match prev {
+            Some(prev) => self.node_mut(prev).next_sibling = Some(id),
+            None => self.node_mut(parent).first_child = Some(id),
+        }
+        match reference {
+            Some(reference) => self.node_mut(reference).prev_sibling = Some(id),
+            None => self.node_mut(parent).last_child = Some(id),
+        }
     }
 }
 
 impl ElementData {
+    // The name as written in the source, `prefix:tag_name` for prefixed XML elements
+    pub fn qualified_name(&self) -> String {
+        match &self.prefix {
+            Some(prefix) => format!("{}:{}", prefix, self.tag_name),
+            None => self.tag_name.clone(),
+        }
+    }
+
     pub fn id(&self) -> Option<&String> {
         self.attrs.get("id")
     }
 
     pub fn classes(&self) -> HashSet<&str> {
         match self.attrs.get("class") {
-            Some(classes) => classes.split(" ").collect(),
+            Some(classes) => classes.split_ascii_whitespace().collect(),
             None => HashSet::new(),
         }
     }
@@ -0,0 +1,470 @@
+// Structural diff between two documents, reported as a list of edits.
+// Nodes of the old and new tree are matched top-down: the children of matched nodes are lined up
+// by their heaviest common subsequence of nodes of the same kind (same tag and id for elements),
+// where a pair weighs as much as the smaller of its subtrees and identical subtrees twice that.
+// Matched nodes that differ get attribute and text edits.
+// Nodes left over are inserted or removed, unless an element with the same tag and id, or the
+// same subtree, was left over on the other side. Those are reported as moved.
+
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap},
+    fmt,
+    hash::{Hash, Hasher},
+};
+
+use super::{Document, NodeId, NodeType};
+
+// Child indices leading from the document root to a node, text and comments count
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Path(pub Vec<usize>);
+
+// One change between the old and the new tree.
+// Paths of removed nodes and of where moved nodes come from are in the old tree, all other paths
+// and node ids are in the new tree.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Edit {
+    // `node` was inserted with its descendants, except the ones moved into it
+    Insert {
+        path: Path,
+        node: NodeId,
+    },
+    // `node` of the old tree was removed with its descendants, except the ones moved out of it
+    Remove {
+        path: Path,
+        node: NodeId,
+    },
+    // The node was moved to another parent or before other siblings
+    Move {
+        from: Path,
+        to: Path,
+        node: NodeId,
+    },
+    // An attribute was set, changed or removed
+    Attribute {
+        path: Path,
+        node: NodeId,
+        name: String,
+        old: Option<String>,
+        new: Option<String>,
+    },
+    // The data of a text or comment node changed
+    Text {
+        path: Path,
+        node: NodeId,
+        old: String,
+        new: String,
+    },
+}
+
+// Child lists longer than this (after the common start and end) are only matched by moves, the
+// subsequence table would take too much memory
+const MAX_LCS_CELLS: usize = 1 << 22;
+
+impl Path {
+    // Path of a node of `document`, empty for the root
+    pub fn of(document: &Document, id: NodeId) -> Path {
+        let mut indices = Vec::new();
+        let mut current = id;
+        while let Some(parent) = document[current].parent {
+            let index = document
+                .children(parent)
+                .position(|child| child == current)
+                .unwrap_or_default();
+            indices.push(index);
+            current = parent;
+        }
+        indices.reverse();
+        Path(indices)
+    }
+}
+
+impl fmt::Display for Path {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.0.is_empty() {
+            return write!(f, "/");
+        }
+        for index in &self.0 {
+            write!(f, "/{}", index)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Edit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Edit::Insert { path, .. } => write!(f, "insert {}", path),
+            Edit::Remove { path, .. } => write!(f, "remove {}", path),
+            Edit::Move { from, to, .. } => write!(f, "move {} to {}", from, to),
+            Edit::Attribute {
+                path,
+                name,
+                old,
+                new,
+                ..
+            } => {
+                write!(f, "attribute {} {}: ", path, name)?;
+                match old {
+                    Some(old) => write!(f, "{:?}", old)?,
+                    None => write!(f, "(none)")?,
+                }
+                match new {
+                    Some(new) => write!(f, " -> {:?}", new),
+                    None => write!(f, " -> (none)"),
+                }
+            }
+            Edit::Text { path, old, new, .. } => write!(f, "text {}: {:?} -> {:?}", path, old, new),
+        }
+    }
+}
+
+// Edits that turn the tree of `old` into the tree of `new`: removals in the order of the old
+// tree, then everything else in the order of the new tree
+pub fn diff(old: &Document, new: &Document) -> Vec<Edit> {
+    let mut differ = Differ {
+        old,
+        new,
+        old_subtrees: subtrees(old),
+        new_subtrees: subtrees(new),
+        old_match: vec![None; old.nodes.len()],
+        new_match: vec![None; new.nodes.len()],
+        moved: vec![false; new.nodes.len()],
+    };
+    differ.pair(old.root(), new.root(), false);
+    differ.match_children(old.root(), new.root());
+    differ.match_id_moves();
+    differ.match_identical_moves();
+    differ.edits()
+}
+
+struct Differ<'a> {
+    old: &'a Document,
+    new: &'a Document,
+    old_subtrees: Vec<Subtree>,
+    new_subtrees: Vec<Subtree>,
+    old_match: Vec<Option<NodeId>>,
+    new_match: Vec<Option<NodeId>>,
+    // nodes of the new tree matched by a move
+    moved: Vec<bool>,
+}
+
+impl Differ<'_> {
+    fn pair(&mut self, old: NodeId, new: NodeId, moved: bool) {
+        self.old_match[old.0] = Some(new);
+        self.new_match[new.0] = Some(old);
+        self.moved[new.0] = moved;
+    }
+
+    fn identical(&self, old: NodeId, new: NodeId) -> bool {
+        self.old_subtrees[old.0].hash == self.new_subtrees[new.0].hash
+    }
+
+    // Nodes of the same kind can be matched, elements need the same tag and id
+    fn same_kind(&self, old: NodeId, new: NodeId) -> bool {
+        match (&self.old[old].node_type, &self.new[new].node_type) {
+            (NodeType::Element(a), NodeType::Element(b)) => {
+                a.tag_name == b.tag_name && a.namespace == b.namespace && a.id() == b.id()
+            }
+            (NodeType::Text(_), NodeType::Text(_)) => true,
+            (NodeType::Comment(_), NodeType::Comment(_)) => true,
+            _ => false,
+        }
+    }
+
+    // Match the descendants of two matched nodes that aren't matched yet
+    fn match_children(&mut self, old: NodeId, new: NodeId) {
+        let mut stack = vec![(old, new)];
+        while let Some((old, new)) = stack.pop() {
+            if self.identical(old, new) {
+                self.pair_identical(old, new);
+                continue;
+            }
+            let old_children: Vec<NodeId> = self
+                .old
+                .children(old)
+                .filter(|child| self.old_match[child.0].is_none())
+                .collect();
+            let new_children: Vec<NodeId> = self
+                .new
+                .children(new)
+                .filter(|child| self.new_match[child.0].is_none())
+                .collect();
+            let pairs = heaviest_common_subsequence(
+                &old_children,
+                &new_children,
+                |a, b| self.identical(a, b),
+                |a, b| {
+                    if self.identical(a, b) {
+                        2 * self.old_subtrees[a.0].size
+                    } else if self.same_kind(a, b) {
+                        self.old_subtrees[a.0].size.min(self.new_subtrees[b.0].size)
+                    } else {
+                        0
+                    }
+                },
+            );
+            for (i, j) in pairs {
+                self.pair(old_children[i], new_children[j], false);
+                stack.push((old_children[i], new_children[j]));
+            }
+        }
+    }
+
+    // Match the nodes of two identical subtrees, the roots are already matched. Nodes that were
+    // matched by a move before are left out.
+    fn pair_identical(&mut self, old: NodeId, new: NodeId) {
+        let mut stack = vec![(old, new)];
+        while let Some((old, new)) = stack.pop() {
+            for (old_child, new_child) in self.old.children(old).zip(self.new.children(new)) {
+                if self.old_match[old_child.0].is_some() || self.new_match[new_child.0].is_some() {
+                    continue;
+                }
+                self.pair(old_child, new_child, false);
+                stack.push((old_child, new_child));
+            }
+        }
+    }
+
+    // Roots of the subtrees of `document` that have no match, their parents do
+    fn unmatched_roots(&self, document: &Document, matches: &[Option<NodeId>]) -> Vec<NodeId> {
+        document
+            .descendants(document.root())
+            .filter(|&id| {
+                matches[id.0].is_none()
+                    && document[id]
+                        .parent
+                        .is_some_and(|parent| matches[parent.0].is_some())
+            })
+            .collect()
+    }
+
+    // Hand the unmatched nodes of the new tree to `take` top-down, so a subtree is moved as a
+    // whole before its parts are looked at
+    fn match_moves(&mut self, mut take: impl FnMut(&mut Self, NodeId)) {
+        let mut stack = self.unmatched_roots(self.new, &self.new_match);
+        stack.reverse();
+        while let Some(id) = stack.pop() {
+            if self.new_match[id.0].is_none() {
+                take(self, id);
+            }
+            let children: Vec<NodeId> = self.new.children(id).collect();
+            stack.extend(children.into_iter().rev());
+        }
+    }
+
+    // A subtree that was removed in one place and inserted unchanged in another was moved
+    fn match_identical_moves(&mut self) {
+        let mut removed: HashMap<u64, Vec<NodeId>> = HashMap::new();
+        for root in self.unmatched_roots(self.old, &self.old_match) {
+            for id in [root].into_iter().chain(self.old.descendants(root)) {
+                removed
+                    .entry(self.old_subtrees[id.0].hash)
+                    .or_default()
+                    .push(id);
+            }
+        }
+        for candidates in removed.values_mut() {
+            candidates.reverse();
+        }
+        self.match_moves(|differ, id| {
+            let Some(candidates) = removed.get_mut(&differ.new_subtrees[id.0].hash) else {
+                return;
+            };
+            // candidates that were moved along with an ancestor, or that had a part moved
+            // elsewhere, are skipped
+            while let Some(old) = candidates.pop() {
+                if [old]
+                    .into_iter()
+                    .chain(differ.old.descendants(old))
+                    .all(|old| differ.old_match[old.0].is_none())
+                {
+                    differ.pair(old, id, true);
+                    differ.pair_identical(old, id);
+                    return;
+                }
+            }
+        });
+    }
+
+    // Elements keep their identity through their id, even when their content changed
+    fn match_id_moves(&mut self) {
+        self.match_moves(|differ, id| {
+            let Some(element_id) = differ.new.element(id).and_then(|element| element.id()) else {
+                return;
+            };
+            let old = differ.old.ids.get(element_id).and_then(|ids| {
+                ids.iter().copied().find(|&old| {
+                    differ.old_match[old.0].is_none()
+                        && differ.old.is_connected(old)
+                        && differ.same_kind(old, id)
+                })
+            });
+            if let Some(old) = old {
+                differ.pair(old, id, true);
+                differ.match_children(old, id);
+            }
+        });
+    }
+
+    fn edits(&self) -> Vec<Edit> {
+        let mut edits = Vec::new();
+        for node in self.unmatched_roots(self.old, &self.old_match) {
+            edits.push(Edit::Remove {
+                path: Path::of(self.old, node),
+                node,
+            });
+        }
+        for node in self.new.descendants(self.new.root()) {
+            let path = || Path::of(self.new, node);
+            let Some(old) = self.new_match[node.0] else {
+                let parent = self.new[node].parent;
+                if parent.is_some_and(|parent| self.new_match[parent.0].is_some()) {
+                    edits.push(Edit::Insert { path: path(), node });
+                }
+                continue;
+            };
+            if self.moved[node.0] {
+                edits.push(Edit::Move {
+                    from: Path::of(self.old, old),
+                    to: path(),
+                    node,
+                });
+            }
+            match (&self.old[old].node_type, &self.new[node].node_type) {
+                (NodeType::Element(a), NodeType::Element(b)) => {
+                    let mut names: Vec<&String> = a.attrs.keys().chain(b.attrs.keys()).collect();
+                    names.sort();
+                    names.dedup();
+                    for name in names {
+                        let (old_value, new_value) = (a.attrs.get(name), b.attrs.get(name));
+                        if old_value != new_value {
+                            edits.push(Edit::Attribute {
+                                path: path(),
+                                node,
+                                name: name.clone(),
+                                old: old_value.cloned(),
+                                new: new_value.cloned(),
+                            });
+                        }
+                    }
+                }
+                (NodeType::Text(a), NodeType::Text(b))
+                | (NodeType::Comment(a), NodeType::Comment(b))
+                    if a != b =>
+                {
+                    edits.push(Edit::Text {
+                        path: path(),
+                        node,
+                        old: a.clone(),
+                        new: b.clone(),
+                    });
+                }
+                _ => {}
+            }
+        }
+        edits
+    }
+}
+
+// Summary of the subtree below a node, equal hashes mean identical subtrees
+#[derive(Debug, Clone, Copy, Default)]
+struct Subtree {
+    hash: u64,
+    // number of nodes
+    size: u32,
+}
+
+// Summary of the subtree of every node in the tree, indexed by node id
+fn subtrees(document: &Document) -> Vec<Subtree> {
+    let mut subtrees = vec![Subtree::default(); document.nodes.len()];
+    let root = document.root();
+    let order: Vec<NodeId> = [root]
+        .into_iter()
+        .chain(document.descendants(root))
+        .collect();
+    // children come after their parent in tree order, so the reverse has them first
+    for &id in order.iter().rev() {
+        let mut hasher = DefaultHasher::new();
+        match &document[id].node_type {
+            NodeType::Element(element) => {
+                0u8.hash(&mut hasher);
+                element.namespace.hash(&mut hasher);
+                element.tag_name.hash(&mut hasher);
+                let mut attrs: Vec<_> = element.attrs.iter().collect();
+                attrs.sort();
+                attrs.hash(&mut hasher);
+            }
+            NodeType::Text(data) => (1u8, data).hash(&mut hasher),
+            NodeType::Comment(data) => (2u8, data).hash(&mut hasher),
+            NodeType::Document(_) => 3u8.hash(&mut hasher),
+        }
+        let mut size = 1;
+        for child in document.children(id) {
+            subtrees[child.0].hash.hash(&mut hasher);
+            size += subtrees[child.0].size;
+        }
+        subtrees[id.0] = Subtree {
+            hash: hasher.finish(),
+            size,
+        };
+    }
+    subtrees
+}
+
+// Pairs of indices of `a` and `b`, in order, with the largest total weight. Pairs that weigh
+// nothing can't be used.
+// Identical pairs at the start and end are taken as they are, that keeps the table small for the
+// usual few changes in a long list.
+fn heaviest_common_subsequence(
+    a: &[NodeId],
+    b: &[NodeId],
+    identical: impl Fn(NodeId, NodeId) -> bool,
+    weight: impl Fn(NodeId, NodeId) -> u32,
+) -> Vec<(usize, usize)> {
+    let prefix = a
+        .iter()
+        .zip(b)
+        .take_while(|(&x, &y)| identical(x, y))
+        .count();
+    let suffix = a[prefix..]
+        .iter()
+        .rev()
+        .zip(b[prefix..].iter().rev())
+        .take_while(|(&x, &y)| identical(x, y))
+        .count();
+    let a_rest = &a[prefix..a.len() - suffix];
+    let b_rest = &b[prefix..b.len() - suffix];
+    let mut pairs: Vec<(usize, usize)> = (0..prefix).map(|i| (i, i)).collect();
+    if a_rest.len() * b_rest.len() <= MAX_LCS_CELLS {
+        // totals[i * width + j] is the heaviest subsequence of a_rest[i..] and b_rest[j..]
+        let width = b_rest.len() + 1;
+        let mut totals = vec![0u32; (a_rest.len() + 1) * width];
+        for i in (0..a_rest.len()).rev() {
+            for j in (0..b_rest.len()).rev() {
+                let skip = totals[(i + 1) * width + j].max(totals[i * width + j + 1]);
+                totals[i * width + j] = match weight(a_rest[i], b_rest[j]) {
+                    0 => skip,
+                    w => skip.max(totals[(i + 1) * width + j + 1] + w),
+                };
+            }
+        }
+        let (mut i, mut j) = (0, 0);
+        while i < a_rest.len() && j < b_rest.len() {
+            let total = totals[i * width + j];
+            if total == 0 {
+                break;
+            }
+            let w = weight(a_rest[i], b_rest[j]);
+            if w > 0 && total == totals[(i + 1) * width + j + 1] + w {
+                pairs.push((prefix + i, prefix + j));
+                (i, j) = (i + 1, j + 1);
+            } else if total == totals[(i + 1) * width + j] {
+                i += 1;
+            } else {
+                j += 1;
+            }
+        }
+    }
+    pairs.extend((0..suffix).map(|k| (a.len() - suffix + k, b.len() - suffix + k)));
+    pairs
+}
@@ -0,0 +1,233 @@
+// Character encodings of input files, following the WHATWG Encoding Standard
+// https://encoding.spec.whatwg.org/
+// Supported encodings are UTF-8, UTF-16LE, UTF-16BE and windows-1252. Like in browsers the
+// ISO-8859-1 and US-ASCII labels mean windows-1252, which only differs from them in the C1
+// control range where it has printable characters.
+// Decoding never fails, malformed input becomes U+FFFD.
+
+use std::{char, fmt};
+
+use phf::phf_map;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Encoding {
+    #[default]
+    Utf8,
+    Utf16Le,
+    Utf16Be,
+    Windows1252,
+}
+
+// Labels of the supported encodings, matched after trimming and lowercasing
+const LABELS: phf::Map<&'static str, Encoding> = phf_map! {
+    "unicode-1-1-utf-8" => Encoding::Utf8,
+    "unicode11utf8" => Encoding::Utf8,
+    "unicode20utf8" => Encoding::Utf8,
+    "utf-8" => Encoding::Utf8,
+    "utf8" => Encoding::Utf8,
+    "x-unicode20utf8" => Encoding::Utf8,
+    "csunicode" => Encoding::Utf16Le,
+    "iso-10646-ucs-2" => Encoding::Utf16Le,
+    "ucs-2" => Encoding::Utf16Le,
+    "unicode" => Encoding::Utf16Le,
+    "unicodefeff" => Encoding::Utf16Le,
+    "utf-16" => Encoding::Utf16Le,
+    "utf-16le" => Encoding::Utf16Le,
+    "unicodefffe" => Encoding::Utf16Be,
+    "utf-16be" => Encoding::Utf16Be,
+    "ansi_x3.4-1968" => Encoding::Windows1252,
+    "ascii" => Encoding::Windows1252,
+    "cp1252" => Encoding::Windows1252,
+    "cp819" => Encoding::Windows1252,
+    "csisolatin1" => Encoding::Windows1252,
+    "ibm819" => Encoding::Windows1252,
+    "iso-8859-1" => Encoding::Windows1252,
+    "iso-ir-100" => Encoding::Windows1252,
+    "iso8859-1" => Encoding::Windows1252,
+    "iso88591" => Encoding::Windows1252,
+    "iso_8859-1" => Encoding::Windows1252,
+    "iso_8859-1:1987" => Encoding::Windows1252,
+    "l1" => Encoding::Windows1252,
+    "latin1" => Encoding::Windows1252,
+    "us-ascii" => Encoding::Windows1252,
+    "windows-1252" => Encoding::Windows1252,
+    "x-cp1252" => Encoding::Windows1252,
+};
+
+impl Encoding {
+    // Encoding for a label like `utf-8` or `Latin1`, None for unknown and unsupported labels
+    pub fn for_label(label: &str) -> Option<Encoding> {
+        let label = label.trim_matches(|c: char| c.is_ascii_whitespace());
+        LABELS.get(label.to_ascii_lowercase().as_str()).copied()
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Encoding::Utf8 => "UTF-8",
+            Encoding::Utf16Le => "UTF-16LE",
+            Encoding::Utf16Be => "UTF-16BE",
+            Encoding::Windows1252 => "windows-1252",
+        }
+    }
+
+    pub fn is_utf16(&self) -> bool {
+        matches!(self, Encoding::Utf16Le | Encoding::Utf16Be)
+    }
+}
+
+impl fmt::Display for Encoding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+// Encoding given by a byte order mark at the start of `bytes` and the length of the mark
+pub fn sniff_bom(bytes: &[u8]) -> Option<(Encoding, usize)> {
+    match bytes {
+        [0xEF, 0xBB, 0xBF, ..] => Some((Encoding::Utf8, 3)),
+        [0xFE, 0xFF, ..] => Some((Encoding::Utf16Be, 2)),
+        [0xFF, 0xFE, ..] => Some((Encoding::Utf16Le, 2)),
+        _ => None,
+    }
+}
+
+// Decode a whole input, a byte order mark overrides `encoding` and is dropped
+pub fn decode(bytes: &[u8], encoding: Encoding) -> String {
+    let (encoding, bom_len) = sniff_bom(bytes).unwrap_or((encoding, 0));
+    let mut decoder = Decoder::new(encoding);
+    let mut text = decoder.decode(&bytes[bom_len..]);
+    text.push_str(&decoder.finish());
+    text
+}
+
+// Characters that windows-1252 puts in the C1 control range (0x80 - 0x9F).
+// The five bytes it leaves undefined are returned as None.
+pub(crate) fn windows_1252_char(byte: u8) -> Option<char> {
+    let c = match byte {
+        0x80 => '\u{20AC}',
+        0x82 => '\u{201A}',
+        0x83 => '\u{0192}',
+        0x84 => '\u{201E}',
+        0x85 => '\u{2026}',
+        0x86 => '\u{2020}',
+        0x87 => '\u{2021}',
+        0x88 => '\u{02C6}',
+        0x89 => '\u{2030}',
+        0x8A => '\u{0160}',
+        0x8B => '\u{2039}',
+        0x8C => '\u{0152}',
+        0x8E => '\u{017D}',
+        0x91 => '\u{2018}',
+        0x92 => '\u{2019}',
+        0x93 => '\u{201C}',
+        0x94 => '\u{201D}',
+        0x95 => '\u{2022}',
+        0x96 => '\u{2013}',
+        0x97 => '\u{2014}',
+        0x98 => '\u{02DC}',
+        0x99 => '\u{2122}',
+        0x9A => '\u{0161}',
+        0x9B => '\u{203A}',
+        0x9C => '\u{0153}',
+        0x9E => '\u{017E}',
+        0x9F => '\u{0178}',
+        _ => return None,
+    };
+    Some(c)
+}
+
+// Decoder for input that arrives in chunks, a character may be split between chunks
+#[derive(Debug)]
+pub struct Decoder {
+    encoding: Encoding,
+    // bytes at the end of the last chunk that don't make a whole character yet
+    incomplete: Vec<u8>,
+}
+
+impl Decoder {
+    pub fn new(encoding: Encoding) -> Decoder {
+        Decoder {
+            encoding,
+            incomplete: Vec::new(),
+        }
+    }
+
+    pub fn encoding(&self) -> Encoding {
+        self.encoding
+    }
+
+    // Decode the next chunk, the end of a character cut off by the chunk is kept for the next one
+    pub fn decode(&mut self, chunk: &[u8]) -> String {
+        let mut bytes = std::mem::take(&mut self.incomplete);
+        bytes.extend_from_slice(chunk);
+        let mut text = String::with_capacity(bytes.len());
+        let used = match self.encoding {
+            Encoding::Utf8 => decode_utf8(&bytes, &mut text),
+            Encoding::Utf16Le => decode_utf16(&bytes, u16::from_le_bytes, &mut text),
+            Encoding::Utf16Be => decode_utf16(&bytes, u16::from_be_bytes, &mut text),
+            Encoding::Windows1252 => {
+                text.extend(
+                    bytes
+                        .iter()
+                        .map(|&b| windows_1252_char(b).unwrap_or(b as char)),
+                );
+                bytes.len()
+            }
+        };
+        self.incomplete = bytes[used..].to_vec();
+        text
+    }
+
+    // A character left incomplete at the end of the input becomes U+FFFD
+    pub fn finish(&mut self) -> String {
+        if self.incomplete.is_empty() {
+            String::new()
+        } else {
+            self.incomplete.clear();
+            char::REPLACEMENT_CHARACTER.to_string()
+        }
+    }
+}
+
+// Decode UTF-8 up to a sequence cut off at the end, returning the number of bytes used.
+// Invalid sequences are replaced with U+FFFD.
+fn decode_utf8(mut bytes: &[u8], text: &mut String) -> usize {
+    let len = bytes.len();
+    loop {
+        match std::str::from_utf8(bytes) {
+            Ok(valid) => {
+                text.push_str(valid);
+                return len;
+            }
+            Err(err) => {
+                let (valid, after) = bytes.split_at(err.valid_up_to());
+                // valid_up_to is always a boundary of valid UTF-8
+                text.push_str(std::str::from_utf8(valid).unwrap_or_default());
+                match err.error_len() {
+                    Some(error_len) => {
+                        text.push(char::REPLACEMENT_CHARACTER);
+                        bytes = &after[error_len..];
+                    }
+                    None => return len - after.len(),
+                }
+            }
+        }
+    }
+}
+
+// Decode UTF-16 code units up to an odd byte or a high surrogate at the end, returning the
+// number of bytes used. Unpaired surrogates are replaced with U+FFFD.
+fn decode_utf16(bytes: &[u8], unit: fn([u8; 2]) -> u16, text: &mut String) -> usize {
+    let mut units: Vec<u16> = bytes
+        .chunks_exact(2)
+        .map(|pair| unit([pair[0], pair[1]]))
+        .collect();
+    // a high surrogate at the end may be paired with the first unit of the next chunk
+    if units.last().is_some_and(|u| (0xD800..0xDC00).contains(u)) {
+        units.pop();
+    }
+    text.extend(
+        char::decode_utf16(units.iter().copied()).map(|c| c.unwrap_or(char::REPLACEMENT_CHARACTER)),
+    );
+    units.len() * 2
+}
@@ -1,201 +1,398 @@
-// HTML parser supports only the following syntax
-// 1. Balanced tags (<p></p>)
-// 2. Attributes quoted  values: id="root"
+// HTML parser following the HTML Living Standard parsing model in two stages:
+// the tokenizer turns the source into tokens and the tree builder turns tokens into the DOM.
+// Supported syntax:
+// 1. Tags, with end tags that may be implied (<p>one<p>two, <li>one<li>two)
+// 2. Attributes with quoted, unquoted or no values: id="root" id=root disabled
 // 3. Text nodes: <em>Hello</em>
-// 4. Comments of type <!-- .. --> (the comment text cannot have '-')
+// 4. Comments of type <!-- .. -->, bogus comments like <?xml ..?> and <!foo> are kept as comments
+//    and <![CDATA[ .. ]]> is text inside svg and math
+// 5. Void elements without an end tag, written either as <br> or <br/>
+// 6. Named (&amp;) and numeric (&#169; &#x1F600;) character references in text and attribute values
+// 7. Misnested formatting elements (<b><i></b></i>) and tables
+// 8. <!DOCTYPE html> and legacy doctypes, which select no-quirks, limited-quirks or quirks mode
+// 9. Elements whose content is text: <title> and <textarea> (with character references),
+//    <style>, <script>, <xmp>, <iframe>, <noembed>, <noframes> and <plaintext>
+// Missing html, head and body elements are synthesized.
+// The serializer in serialize.rs turns a document back into markup, parse_fragment and
+// set_inner_html go the other way for the contents of a single element.
+// Bytes are decoded in the encoding given by a byte order mark or a <meta charset> in the first
+// 1024 bytes, without either UTF-8 is assumed unless those bytes aren't valid UTF-8.
 
-use std::{char, collections::HashMap};
+mod entities;
+mod foreign;
+mod prescan;
+mod serialize;
+mod tokenizer;
+mod tree_builder;
 
-use crate::dom;
+use std::{
+    char,
+    io::{self, Read},
+};
 
-struct Parser {
-    pos: usize,
-    input: String,
+use phf::phf_set;
+
+use crate::{
+    diagnostics::{Diagnostic, DiagnosticKind},
+    dom::{self, AttrMap, DomError, ElementData, Namespace},
+    encoding::{self, windows_1252_char, Decoder, Encoding},
+};
+
+pub use self::serialize::{inner_html, outer_html, SerializeOptions};
+
+use self::{
+    entities::{LONGEST_ENTITY_NAME, NAMED_ENTITIES},
+    prescan::{prescan, PRESCAN_LENGTH},
+    tokenizer::{Token, Tokenizer},
+    tree_builder::TreeBuilder,
+};
+
+// Elements that never have children and must not have an end tag
+pub const VOID_ELEMENTS: phf::Set<&'static str> = phf_set! {
+    "area",
+    "base",
+    "basefont",
+    "bgsound",
+    "br",
+    "col",
+    "embed",
+    "frame",
+    "hr",
+    "img",
+    "input",
+    "keygen",
+    "link",
+    "meta",
+    "param",
+    "source",
+    "track",
+    "wbr",
+};
+
+// Is `tag_name` one of the void elements (case-insensitive)?
+pub fn is_void_element(tag_name: &str) -> bool {
+    VOID_ELEMENTS.contains(tag_name.to_ascii_lowercase().as_str())
+}
+
+// Characters of the named reference `&name;`, XHTML documents can use them in XML
+pub(crate) fn named_char_ref(name: &str) -> Option<&'static str> {
+    NAMED_ENTITIES.get(format!("{};", name).as_str()).copied()
 }
 
-impl Parser {
-    // Read the next character without consuming it.
-    fn next_char(&self) -> char {
-        self.input[self.pos..].chars().next().unwrap()
+// Match a character reference at the start of `input`, which is the text right after a `&`.
+// Returns the decoded characters and the number of bytes of `input` the reference used.
+// Problems with the reference are added to `errors`.
+fn match_char_ref(
+    input: &str,
+    in_attribute: bool,
+    errors: &mut Vec<DiagnosticKind>,
+) -> Option<(String, usize)> {
+    if let Some(numeric) = input.strip_prefix('#') {
+        return match_numeric_char_ref(numeric, errors).map(|(c, len)| (c.to_string(), len + 1));
     }
 
-    // Do the next character starts with the given string?
-    fn starts_with(&self, s: &str) -> bool {
-        self.input[self.pos..].starts_with(s)
+    // Find the longest entity name that is a prefix of the input
+    let name_len = input
+        .char_indices()
+        .take(LONGEST_ENTITY_NAME)
+        .find(|&(_, c)| !c.is_ascii_alphanumeric())
+        .map_or(input.len().min(LONGEST_ENTITY_NAME), |(i, _)| i);
+    if name_len == 0 {
+        return None;
+    }
+    if input[name_len..].starts_with(';') {
+        if let Some(value) = NAMED_ENTITIES.get(&input[..=name_len]) {
+            return Some((value.to_string(), name_len + 1));
+        }
     }
 
-    // If the exact string `s` is found at the current position consume it.
-    fn expect(&mut self, s: &str) -> Result<(), String> {
-        if self.starts_with(s) {
-            self.pos += s.len();
-            Ok(())
-        } else {
-            Err(format!(
-                "Expected {:?} at byte {} but it was not found",
-                s, self.pos
-            ))
+    // Only the legacy names are in the table without a trailing semicolon
+    let Some((len, value)) = (1..=name_len)
+        .rev()
+        .find_map(|len| NAMED_ENTITIES.get(&input[..len]).map(|value| (len, value)))
+    else {
+        if input[name_len..].starts_with(';') {
+            errors.push(DiagnosticKind::UnknownNamedCharacterReference);
         }
+        return None;
+    };
+
+    // For historical reasons `&copy=` or `&copyright` in attribute values are not references
+    let next = input[len..].chars().next();
+    if in_attribute && next.is_some_and(|c| c == '=' || c.is_ascii_alphanumeric()) {
+        return None;
     }
+    errors.push(DiagnosticKind::MissingSemicolonAfterCharacterReference);
+    Some((value.to_string(), len))
+}
 
-    // return true if all input is consumed
-    fn eof(&self) -> bool {
-        self.pos >= self.input.len()
+// Match the `169;` or `x1F600;` part of a numeric character reference.
+fn match_numeric_char_ref(input: &str, errors: &mut Vec<DiagnosticKind>) -> Option<(char, usize)> {
+    let (radix, prefix_len) = match input.chars().next() {
+        Some('x' | 'X') => (16, 1),
+        _ => (10, 0),
+    };
+    let digits_len = input[prefix_len..]
+        .find(|c: char| !c.is_digit(radix))
+        .unwrap_or(input.len() - prefix_len);
+    if digits_len == 0 {
+        errors.push(DiagnosticKind::AbsenceOfDigitsInNumericCharacterReference);
+        return None;
     }
 
-    // consume the character in current position & advance position
-    fn consume_char(&mut self) -> char {
-        let c = self.next_char();
-        self.pos += c.len_utf8();
-        c
+    let digits = &input[prefix_len..prefix_len + digits_len];
+    let mut len = prefix_len + digits_len;
+    if input[len..].starts_with(';') {
+        len += 1;
+    } else {
+        errors.push(DiagnosticKind::MissingSemicolonAfterCharacterReference);
     }
 
-    // consume characters until `test` returns false
-    fn consume_while(&mut self, test: impl Fn(char) -> bool) -> String {
-        let mut result = String::new();
-        while !self.eof() && test(self.next_char()) {
-            result.push(self.consume_char());
+    // Out of range values saturate and become U+FFFD below
+    let code = u32::from_str_radix(digits, radix).unwrap_or(u32::MAX);
+    let c = match code {
+        0 => {
+            errors.push(DiagnosticKind::NullCharacterReference);
+            None
         }
-        result
-    }
+        0x80..=0x9F => windows_1252_char(code as u8).or(char::from_u32(code)),
+        0xD800..=0xDFFF => {
+            errors.push(DiagnosticKind::SurrogateCharacterReference);
+            None
+        }
+        0x110000.. => {
+            errors.push(DiagnosticKind::CharacterReferenceOutsideUnicodeRange);
+            None
+        }
+        _ => char::from_u32(code),
+    };
+    Some((c.unwrap_or(char::REPLACEMENT_CHARACTER), len))
+}
+
+// Parse html document, returning the document and the problems found in the source.
+// The children of the document's root are the html element and comments outside of it.
+pub fn parse(source: String) -> (dom::Document, Vec<Diagnostic>) {
+    let mut parser = StreamingParser::new();
+    parser.feed(&source);
+    drop(source);
+    parser.finish()
+}
 
-    // consume & discard zero / more whitespace chars
-    fn consume_whitespace(&mut self) {
-        self.consume_while(char::is_whitespace);
+// Parse a document read from `reader` in chunks, the source is never held in memory as a whole.
+// The encoding is sniffed from the first bytes.
+pub fn parse_reader(mut reader: impl Read) -> io::Result<(dom::Document, Vec<Diagnostic>)> {
+    let mut parser = StreamingParser::new();
+    let mut chunk = vec![0; CHUNK_SIZE];
+    loop {
+        match reader.read(&mut chunk) {
+            Ok(0) => break,
+            Ok(len) => parser.feed_bytes(&chunk[..len]),
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+            Err(err) => return Err(err),
+        }
     }
+    Ok(parser.finish())
+}
+
+const CHUNK_SIZE: usize = 64 * 1024;
+
+// Parse markup as the contents of a `context_tag` element, like the inner_html setter does.
+// The children of the returned document's root are the parsed nodes. The context decides how the
+// markup is read: <td> is a cell in a `tr` and plain text in a `textarea`.
+// `svg` and `math` contexts parse foreign content.
+pub fn parse_fragment(source: String, context_tag: &str) -> (dom::Document, Vec<Diagnostic>) {
+    let tag_name = context_tag.to_ascii_lowercase();
+    let namespace = match tag_name.as_str() {
+        "svg" => Namespace::Svg,
+        "math" => Namespace::MathMl,
+        _ => Namespace::Html,
+    };
+    let context = ElementData {
+        tag_name,
+        attrs: AttrMap::new(),
+        namespace,
+        prefix: None,
+    };
+    parse_fragment_in(source, context)
+}
+
+fn parse_fragment_in(source: String, context: ElementData) -> (dom::Document, Vec<Diagnostic>) {
+    let mut parser = StreamingParser::with_builder(TreeBuilder::new_fragment(context));
+    parser.feed(&source);
+    drop(source);
+    parser.finish()
+}
 
-    // Parse a tag or attribute name
-    fn parse_name(&mut self) -> String {
-        self.consume_while(|c| matches!(c, 'a'..='z' | 'A'..='Z' | '0'..='9'))
+// Replace the children of element `node` with `markup` parsed in its context.
+// Returns the problems found in the markup.
+pub fn set_inner_html(
+    document: &mut dom::Document,
+    node: dom::NodeId,
+    markup: &str,
+) -> Result<Vec<Diagnostic>, DomError> {
+    // the attributes are kept, the encoding of an <annotation-xml> decides how it is parsed
+    let context = document
+        .element(node)
+        .ok_or(DomError::InvalidNodeType)?
+        .clone();
+    let (fragment, diagnostics) = parse_fragment_in(markup.to_string(), context);
+    while let Some(child) = document.children(node).next() {
+        document.remove_child(node, child)?;
+    }
+    for child in fragment.children(fragment.root()) {
+        let copy = document.import_node(&fragment, child)?;
+        document.append_child(node, copy)?;
     }
+    Ok(diagnostics)
+}
 
-    // Parse a single node
-    fn parse_node(&mut self) -> Option<dom::Node> {
-        if self.starts_with("<") {
-            self.parse_element()
-        } else {
-            Some(self.parse_text())
-        }
+// Incremental parser, the document is fed in chunks as it arrives.
+// Everything that can be parsed is parsed as soon as it is fed, so a partial DOM can be looked at
+// before the input ends.
+pub struct StreamingParser {
+    tokenizer: Tokenizer,
+    builder: TreeBuilder,
+    diagnostics: Vec<Diagnostic>,
+    // None until enough bytes are fed to know their encoding
+    decoder: Option<Decoder>,
+    // bytes held back while the encoding is unknown
+    undecoded: Vec<u8>,
+}
+
+impl Default for StreamingParser {
+    fn default() -> Self {
+        Self::new()
     }
+}
 
-    // Parse text node (simplified version)
-    fn parse_text(&mut self) -> dom::Node {
-        dom::text(self.consume_while(|c| c != '<'))
+impl StreamingParser {
+    pub fn new() -> StreamingParser {
+        StreamingParser::with_builder(TreeBuilder::new())
     }
 
-    // Parse a single element (opening tag, contents & closing tag)
-    // Parse comment only when <!-- .. -->
-    fn parse_element(&mut self) -> Option<dom::Node> {
-        // Opening tag
-        match self.expect("<") {
-            Ok(()) => (),
-            Err(_) => return None,
+    fn with_builder(mut builder: TreeBuilder) -> StreamingParser {
+        let mut tokenizer = Tokenizer::new();
+        if let Some(kind) = builder.take_text_switch() {
+            tokenizer.switch_to(kind);
         }
-
-        // determine whether it is a comment or a node
-        if self.starts_with("!--") {
-            return self.parse_comment();
+        StreamingParser {
+            tokenizer,
+            builder,
+            diagnostics: Vec::new(),
+            decoder: None,
+            undecoded: Vec::new(),
         }
+    }
 
-        let tag_name = self.parse_name();
-        let attrs = self.parse_attributes();
+    // Parse the next chunk of the source
+    pub fn feed(&mut self, chunk: &str) {
+        self.tokenizer.feed(chunk);
+        self.run();
+    }
 
-        match self.expect(">") {
-            Ok(()) => (),
-            Err(_) => return None,
+    // Parse the next chunk of encoded source, a character may be split between chunks.
+    // Nothing is parsed until the encoding is known, which takes up to 1024 bytes.
+    // Invalid bytes are replaced with U+FFFD.
+    pub fn feed_bytes(&mut self, chunk: &[u8]) {
+        match &mut self.decoder {
+            Some(decoder) => {
+                let text = decoder.decode(chunk);
+                self.feed(&text);
+            }
+            None => {
+                self.undecoded.extend_from_slice(chunk);
+                if self.undecoded.len() >= PRESCAN_LENGTH
+                    || encoding::sniff_bom(&self.undecoded).is_some()
+                {
+                    self.start_decoding(false);
+                }
+            }
         }
+    }
 
-        // contents
-        let children = self.parse_nodes();
-
-        // Closing tag
-        match self.expect(&format!("</{tag_name}>")) {
-            Ok(()) => (),
-            Err(_) => return None,
-        }
+    // Pick the encoding from the bytes held back and parse them.
+    // `whole_input` is set when no more bytes will be fed.
+    fn start_decoding(&mut self, whole_input: bool) {
+        let bytes = std::mem::take(&mut self.undecoded);
+        let start = &bytes[..bytes.len().min(PRESCAN_LENGTH)];
+        let (encoding, bom_len) = encoding::sniff_bom(&bytes)
+            .or_else(|| prescan(start).map(|encoding| (encoding, 0)))
+            .unwrap_or_else(|| {
+                (
+                    guess_encoding(start, whole_input && start.len() == bytes.len()),
+                    0,
+                )
+            });
+        self.builder.set_encoding(encoding);
+        self.decoder = Some(Decoder::new(encoding));
+        self.feed_bytes(&bytes[bom_len..]);
+    }
 
-        Some(dom::elem(tag_name, attrs, children))
+    // Encoding of the bytes fed so far, None while it is still unknown
+    pub fn encoding(&self) -> Option<Encoding> {
+        self.decoder.as_ref().map(Decoder::encoding)
     }
 
-    // Parse Attributes - list of name="value" separated by whitespace
-    fn parse_attributes(&mut self) -> dom::AttrMap {
-        let mut attributes = HashMap::new();
-        loop {
-            self.consume_whitespace();
-            if self.next_char() == '>' {
-                break;
-            }
-            if let Some((name, value)) = self.parse_attr() {
-                attributes.insert(name, value);
-            };
-        }
-        attributes
+    // The document as far as it is parsed, elements that are still open are included
+    pub fn document(&self) -> dom::Document {
+        self.builder.document()
     }
 
-    // Parse name="value" attribute.
-    fn parse_attr(&mut self) -> Option<(String, String)> {
-        let name = self.parse_name();
-        match self.expect("=") {
-            Ok(()) => (),
-            Err(_) => return None,
-        }
-        let value = self.parse_attr_value();
-        Some((name, value))
+    // Problems found so far, in the order they were found
+    pub fn diagnostics(&self) -> &[Diagnostic] {
+        &self.diagnostics
     }
 
-    // Parse a "value"
-    fn parse_attr_value(&mut self) -> String {
-        let open_quote = self.consume_char();
-        assert!(open_quote == '"' || open_quote == '\'');
-        let value = self.consume_while(|c| c != open_quote);
-        let close_quote = self.consume_char();
-        assert_eq!(open_quote, close_quote);
-        value
+    // Parse what is left after the last chunk and return the document
+    pub fn finish(mut self) -> (dom::Document, Vec<Diagnostic>) {
+        if self.decoder.is_none() && !self.undecoded.is_empty() {
+            self.start_decoding(true);
+        }
+        if let Some(decoder) = &mut self.decoder {
+            let rest = decoder.finish();
+            self.tokenizer.feed(&rest);
+        }
+        self.tokenizer.end_input();
+        self.run();
+        let mut diagnostics = self.diagnostics;
+        diagnostics.sort_by_key(|d| (d.line, d.column));
+        (self.builder.finish(), diagnostics)
     }
 
-    // Parse sibling nodes
-    fn parse_nodes(&mut self) -> Vec<dom::Node> {
-        let mut nodes = Vec::new();
+    // Hand tokens to the tree builder until the tokenizer needs more input or the input ends
+    fn run(&mut self) {
         loop {
-            self.consume_whitespace();
-            if self.eof() || self.starts_with("</") {
+            self.tokenizer
+                .set_cdata_allowed(self.builder.in_foreign_element());
+            let Some((token, span)) = self.tokenizer.next_token() else {
                 break;
+            };
+            let eof = token == Token::Eof;
+            self.builder.process(token, span);
+            if let Some(kind) = self.builder.take_text_switch() {
+                self.tokenizer.switch_to(kind);
             }
-            if let Some(node) = self.parse_node() {
-                nodes.push(node);
+            self.report_errors();
+            if eof {
+                break;
             }
         }
-        nodes
     }
 
-    // Parse parse comment
-    fn parse_comment(&mut self) -> Option<dom::Node> {
-        match self.expect("!--") {
-            Ok(()) => (),
-            Err(_) => return None,
-        }
-
-        let text = self.consume_while(|c| c != '-');
-
-        match self.expect("-->") {
-            Ok(()) => (),
-            Err(_) => return None,
-        }
-
-        Some(dom::comment(text))
+    // Resolve the errors to line and column while their source lines are still there
+    fn report_errors(&mut self) {
+        self.tokenizer.add_errors(self.builder.take_errors());
+        let diagnostics = self.tokenizer.take_diagnostics();
+        self.diagnostics.extend(diagnostics);
     }
 }
 
-// Parse html document
-pub fn parse(source: String) -> dom::Node {
-    let mut nodes = Parser {
-        pos: 0,
-        input: source,
-    }
-    .parse_nodes();
-
-    if nodes.len() == 1 {
-        nodes.remove(0)
-    } else {
-        dom::elem(String::from("html"), HashMap::new(), nodes)
+// Without a declared encoding the start of the document is checked: text that isn't valid UTF-8
+// is most likely in a legacy encoding. A character cut off at the end only counts when `bytes`
+// is the whole input.
+fn guess_encoding(bytes: &[u8], whole_input: bool) -> Encoding {
+    match std::str::from_utf8(bytes) {
+        Err(err) if whole_input || err.error_len().is_some() => Encoding::Windows1252,
+        _ => Encoding::Utf8,
     }
 }
@@ -0,0 +1,146 @@
+// Tables for foreign content, the SVG and MathML elements that can be embedded in HTML
+// https://html.spec.whatwg.org/multipage/parsing.html#parsing-main-inforeign
+// The tokenizer lowercases every name, SVG names written in camel case are restored here.
+
+use phf::{phf_map, phf_set};
+
+use super::tokenizer::Tag;
+
+// HTML start tags that close all open foreign elements, <svg><p> is almost certainly a mistake
+const BREAKOUT_ELEMENTS: phf::Set<&'static str> = phf_set! {
+    "b", "big", "blockquote", "body", "br", "center", "code", "dd", "div", "dl", "dt", "em",
+    "embed", "h1", "h2", "h3", "h4", "h5", "h6", "head", "hr", "i", "img", "li", "listing", "menu",
+    "meta", "nobr", "ol", "p", "pre", "ruby", "s", "small", "span", "strong", "strike", "sub", "sup",
+    "table", "tt", "u", "ul", "var",
+};
+
+const SVG_TAG_NAMES: phf::Map<&'static str, &'static str> = phf_map! {
+    "altglyph" => "altGlyph",
+    "altglyphdef" => "altGlyphDef",
+    "altglyphitem" => "altGlyphItem",
+    "animatecolor" => "animateColor",
+    "animatemotion" => "animateMotion",
+    "animatetransform" => "animateTransform",
+    "clippath" => "clipPath",
+    "feblend" => "feBlend",
+    "fecolormatrix" => "feColorMatrix",
+    "fecomponenttransfer" => "feComponentTransfer",
+    "fecomposite" => "feComposite",
+    "feconvolvematrix" => "feConvolveMatrix",
+    "fediffuselighting" => "feDiffuseLighting",
+    "fedisplacementmap" => "feDisplacementMap",
+    "fedistantlight" => "feDistantLight",
+    "fedropshadow" => "feDropShadow",
+    "feflood" => "feFlood",
+    "fefunca" => "feFuncA",
+    "fefuncb" => "feFuncB",
+    "fefuncg" => "feFuncG",
+    "fefuncr" => "feFuncR",
+    "fegaussianblur" => "feGaussianBlur",
+    "feimage" => "feImage",
+    "femerge" => "feMerge",
+    "femergenode" => "feMergeNode",
+    "femorphology" => "feMorphology",
+    "feoffset" => "feOffset",
+    "fepointlight" => "fePointLight",
+    "fespecularlighting" => "feSpecularLighting",
+    "fespotlight" => "feSpotLight",
+    "fetile" => "feTile",
+    "feturbulence" => "feTurbulence",
+    "foreignobject" => "foreignObject",
+    "glyphref" => "glyphRef",
+    "lineargradient" => "linearGradient",
+    "radialgradient" => "radialGradient",
+    "textpath" => "textPath",
+};
+
+const SVG_ATTRIBUTES: phf::Map<&'static str, &'static str> = phf_map! {
+    "attributename" => "attributeName",
+    "attributetype" => "attributeType",
+    "basefrequency" => "baseFrequency",
+    "baseprofile" => "baseProfile",
+    "calcmode" => "calcMode",
+    "clippathunits" => "clipPathUnits",
+    "diffuseconstant" => "diffuseConstant",
+    "edgemode" => "edgeMode",
+    "filterunits" => "filterUnits",
+    "glyphref" => "glyphRef",
+    "gradienttransform" => "gradientTransform",
+    "gradientunits" => "gradientUnits",
+    "kernelmatrix" => "kernelMatrix",
+    "kernelunitlength" => "kernelUnitLength",
+    "keypoints" => "keyPoints",
+    "keysplines" => "keySplines",
+    "keytimes" => "keyTimes",
+    "lengthadjust" => "lengthAdjust",
+    "limitingconeangle" => "limitingConeAngle",
+    "markerheight" => "markerHeight",
+    "markerunits" => "markerUnits",
+    "markerwidth" => "markerWidth",
+    "maskcontentunits" => "maskContentUnits",
+    "maskunits" => "maskUnits",
+    "numoctaves" => "numOctaves",
+    "pathlength" => "pathLength",
+    "patterncontentunits" => "patternContentUnits",
+    "patterntransform" => "patternTransform",
+    "patternunits" => "patternUnits",
+    "pointsatx" => "pointsAtX",
+    "pointsaty" => "pointsAtY",
+    "pointsatz" => "pointsAtZ",
+    "preservealpha" => "preserveAlpha",
+    "preserveaspectratio" => "preserveAspectRatio",
+    "primitiveunits" => "primitiveUnits",
+    "refx" => "refX",
+    "refy" => "refY",
+    "repeatcount" => "repeatCount",
+    "repeatdur" => "repeatDur",
+    "requiredextensions" => "requiredExtensions",
+    "requiredfeatures" => "requiredFeatures",
+    "specularconstant" => "specularConstant",
+    "specularexponent" => "specularExponent",
+    "spreadmethod" => "spreadMethod",
+    "startoffset" => "startOffset",
+    "stddeviation" => "stdDeviation",
+    "stitchtiles" => "stitchTiles",
+    "surfacescale" => "surfaceScale",
+    "systemlanguage" => "systemLanguage",
+    "tablevalues" => "tableValues",
+    "targetx" => "targetX",
+    "targety" => "targetY",
+    "textlength" => "textLength",
+    "viewbox" => "viewBox",
+    "viewtarget" => "viewTarget",
+    "xchannelselector" => "xChannelSelector",
+    "ychannelselector" => "yChannelSelector",
+    "zoomandpan" => "zoomAndPan",
+};
+
+// Does the start tag leave foreign content? <font> only does with presentational attributes.
+pub fn is_breakout(tag: &Tag) -> bool {
+    BREAKOUT_ELEMENTS.contains(tag.name.as_str())
+        || (tag.name == "font"
+            && tag
+                .attrs
+                .iter()
+                .any(|(name, _)| matches!(name.as_str(), "color" | "face" | "size")))
+}
+
+// Restore the case of SVG element and attribute names
+pub fn adjust_svg_tag(tag: &mut Tag) {
+    if let Some(name) = SVG_TAG_NAMES.get(tag.name.as_str()) {
+        tag.name = name.to_string();
+    }
+    for (name, _) in &mut tag.attrs {
+        if let Some(adjusted) = SVG_ATTRIBUTES.get(name.as_str()) {
+            *name = adjusted.to_string();
+        }
+    }
+}
+
+pub fn adjust_mathml_tag(tag: &mut Tag) {
+    for (name, _) in &mut tag.attrs {
+        if name == "definitionurl" {
+            *name = String::from("definitionURL");
+        }
+    }
+}
@@ -0,0 +1,200 @@
+// Looks for the encoding of a document in its first bytes, before it can be decoded
+// https://html.spec.whatwg.org/multipage/parsing.html#prescan-a-byte-stream-to-determine-its-encoding
+// Markup is skipped roughly like the tokenizer would until a <meta charset="..."> or
+// <meta http-equiv="content-type" content="...; charset=..."> is found.
+
+use crate::encoding::Encoding;
+
+// Number of bytes the prescan looks at, the charset declaration has to be within them
+pub const PRESCAN_LENGTH: usize = 1024;
+
+fn is_whitespace(b: u8) -> bool {
+    matches!(b, b'\t' | b'\n' | b'\x0C' | b'\r' | b' ')
+}
+
+fn starts_with_ignore_case(bytes: &[u8], prefix: &[u8]) -> bool {
+    bytes.len() >= prefix.len() && bytes[..prefix.len()].eq_ignore_ascii_case(prefix)
+}
+
+// Encoding declared by a <meta> element in `bytes`
+pub fn prescan(bytes: &[u8]) -> Option<Encoding> {
+    let bytes = &bytes[..bytes.len().min(PRESCAN_LENGTH)];
+    let mut scanner = Scanner { bytes, pos: 0 };
+    while scanner.pos < bytes.len() {
+        let rest = &bytes[scanner.pos..];
+        if rest.starts_with(b"<!--") {
+            // the dashes of <!-- may be the start of --> as well, like in <!-->
+            match rest[2..].windows(3).position(|w| w == b"-->") {
+                Some(end) => scanner.pos += end + 5,
+                None => break,
+            }
+            continue;
+        } else if starts_with_ignore_case(rest, b"<meta")
+            && rest.get(5).is_some_and(|&b| is_whitespace(b) || b == b'/')
+        {
+            scanner.pos += 5;
+            if let Some(encoding) = scanner.meta() {
+                return Some(encoding);
+            }
+        } else if rest.len() > 1
+            && (rest[1].is_ascii_alphabetic()
+                || (rest[1] == b'/' && rest.get(2).is_some_and(u8::is_ascii_alphabetic)))
+            && rest[0] == b'<'
+        {
+            // skip the tag name and its attributes
+            scanner.skip_while(|b| !is_whitespace(b) && b != b'>');
+            while scanner.attribute().is_some() {}
+        } else if rest.starts_with(b"<!") || rest.starts_with(b"</") || rest.starts_with(b"<?") {
+            scanner.skip_while(|b| b != b'>');
+        }
+        scanner.pos += 1;
+    }
+    None
+}
+
+// Encoding in a `content` attribute like `text/html; charset=latin1`
+// https://html.spec.whatwg.org/multipage/urls-and-fetching.html#algorithm-for-extracting-a-character-encoding-from-a-meta-element
+fn content_charset(content: &[u8]) -> Option<Encoding> {
+    let mut pos = 0;
+    loop {
+        let found = content[pos..]
+            .windows(7)
+            .position(|w| w.eq_ignore_ascii_case(b"charset"))?;
+        pos += found + 7;
+        let skip_whitespace = |pos: usize| {
+            pos + content[pos..]
+                .iter()
+                .take_while(|&&b| is_whitespace(b))
+                .count()
+        };
+        pos = skip_whitespace(pos);
+        if content.get(pos) != Some(&b'=') {
+            continue;
+        }
+        pos = skip_whitespace(pos + 1);
+        let label = match content.get(pos)? {
+            &quote @ (b'"' | b'\'') => {
+                let value = &content[pos + 1..];
+                &value[..value.iter().position(|&b| b == quote)?]
+            }
+            _ => {
+                let value = &content[pos..];
+                let len = value
+                    .iter()
+                    .position(|&b| is_whitespace(b) || b == b';')
+                    .unwrap_or(value.len());
+                &value[..len]
+            }
+        };
+        return Encoding::for_label(&String::from_utf8_lossy(label));
+    }
+}
+
+struct Scanner<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl Scanner<'_> {
+    fn next_byte(&self) -> Option<u8> {
+        self.bytes.get(self.pos).copied()
+    }
+
+    fn skip_while(&mut self, test: impl Fn(u8) -> bool) {
+        while self.next_byte().is_some_and(&test) {
+            self.pos += 1;
+        }
+    }
+
+    // Read the attributes of a <meta> element and return the encoding it declares
+    fn meta(&mut self) -> Option<Encoding> {
+        let mut names: Vec<Vec<u8>> = Vec::new();
+        let mut got_pragma = false;
+        let mut need_pragma = None;
+        let mut charset = None;
+        while let Some((name, value)) = self.attribute() {
+            if names.contains(&name) {
+                continue;
+            }
+            match name.as_slice() {
+                b"http-equiv" => got_pragma |= value == b"content-type",
+                b"content" if charset.is_none() => {
+                    if let Some(encoding) = content_charset(&value) {
+                        charset = Some(encoding);
+                        need_pragma = Some(true);
+                    }
+                }
+                b"charset" => {
+                    charset = Encoding::for_label(&String::from_utf8_lossy(&value));
+                    need_pragma = Some(false);
+                }
+                _ => {}
+            }
+            names.push(name);
+        }
+        if need_pragma? && !got_pragma {
+            return None;
+        }
+        // a document that could be read as ASCII can't be UTF-16
+        charset.map(|c| if c.is_utf16() { Encoding::Utf8 } else { c })
+    }
+
+    // Read the next attribute of a tag, names and values are lowercased.
+    // None at the end of the tag or the input.
+    fn attribute(&mut self) -> Option<(Vec<u8>, Vec<u8>)> {
+        self.skip_while(|b| is_whitespace(b) || b == b'/');
+        let mut name = Vec::new();
+        let mut value = Vec::new();
+        match self.next_byte()? {
+            b'>' => return None,
+            // a name may start with `=`
+            b => {
+                name.push(b.to_ascii_lowercase());
+                self.pos += 1;
+            }
+        }
+        loop {
+            match self.next_byte()? {
+                b'=' => break,
+                b if is_whitespace(b) => {
+                    self.skip_while(is_whitespace);
+                    if self.next_byte()? != b'=' {
+                        return Some((name, value));
+                    }
+                    break;
+                }
+                b'/' | b'>' => return Some((name, value)),
+                b => name.push(b.to_ascii_lowercase()),
+            }
+            self.pos += 1;
+        }
+        // skip the `=` and the whitespace after it
+        self.pos += 1;
+        self.skip_while(is_whitespace);
+        match self.next_byte()? {
+            quote @ (b'"' | b'\'') => {
+                self.pos += 1;
+                loop {
+                    let b = self.next_byte()?;
+                    self.pos += 1;
+                    if b == quote {
+                        return Some((name, value));
+                    }
+                    value.push(b.to_ascii_lowercase());
+                }
+            }
+            b'>' => Some((name, value)),
+            _ => {
+                while let Some(b) = self.next_byte() {
+                    if is_whitespace(b) || b == b'>' {
+                        break;
+                    }
+                    value.push(b.to_ascii_lowercase());
+                    self.pos += 1;
+                }
+                self.next_byte()?;
+                Some((name, value))
+            }
+        }
+    }
+}
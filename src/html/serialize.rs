@@ -0,0 +1,230 @@
+// Turns the DOM back into markup, following the HTML fragment serialization algorithm
+// https://html.spec.whatwg.org/multipage/parsing.html#serialising-html-fragments
+// Attributes are written sorted by name, the attribute map doesn't keep the source order.
+
+use crate::dom::{Document, ElementData, Namespace, NodeId, NodeType};
+
+use super::is_void_element;
+
+// Elements whose text is written as it is, their content is never parsed as markup.
+// The parser runs with scripting disabled so <noscript> holds markup and is not one of them.
+const RAW_TEXT_ELEMENTS: [&str; 7] = [
+    "style",
+    "script",
+    "xmp",
+    "iframe",
+    "noembed",
+    "noframes",
+    "plaintext",
+];
+
+// Elements the parser drops a leading newline in, it is doubled so it survives a round trip
+const NEWLINE_ELEMENTS: [&str; 3] = ["pre", "textarea", "listing"];
+
+const INDENT: &str = "  ";
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SerializeOptions {
+    // Put every element and comment on its own line, indented by depth. Whitespace only text is
+    // dropped and other text is trimmed, except inside <pre>, <textarea> and raw text elements.
+    pub pretty: bool,
+}
+
+// Markup of the node itself and its descendants. For a document that is the doctype
+// followed by all its children.
+pub fn outer_html(document: &Document, node: NodeId, options: SerializeOptions) -> String {
+    let mut serializer = Serializer {
+        document,
+        options,
+        output: String::new(),
+    };
+    serializer.node(node, 0, false);
+    serializer.finish()
+}
+
+// Markup of the children of the node, the node's own tags are left out
+pub fn inner_html(document: &Document, node: NodeId, options: SerializeOptions) -> String {
+    let mut serializer = Serializer {
+        document,
+        options,
+        output: String::new(),
+    };
+    serializer.children(node, 0);
+    serializer.finish()
+}
+
+// Escape text content, `&`, no-break spaces, `<` and `>`
+fn escape_text(text: &str, output: &mut String) {
+    for c in text.chars() {
+        match c {
+            '&' => output.push_str("&amp;"),
+            '\u{A0}' => output.push_str("&nbsp;"),
+            '<' => output.push_str("&lt;"),
+            '>' => output.push_str("&gt;"),
+            _ => output.push(c),
+        }
+    }
+}
+
+// Escape a double quoted attribute value, like newer versions of the spec `<` and `>` are
+// escaped as well
+fn escape_attribute(value: &str, output: &mut String) {
+    for c in value.chars() {
+        match c {
+            '&' => output.push_str("&amp;"),
+            '\u{A0}' => output.push_str("&nbsp;"),
+            '"' => output.push_str("&quot;"),
+            '<' => output.push_str("&lt;"),
+            '>' => output.push_str("&gt;"),
+            _ => output.push(c),
+        }
+    }
+}
+
+fn is_html(element: &ElementData, names: &[&str]) -> bool {
+    element.namespace == Namespace::Html && names.contains(&element.tag_name.as_str())
+}
+
+struct Serializer<'a> {
+    document: &'a Document,
+    options: SerializeOptions,
+    output: String,
+}
+
+impl Serializer<'_> {
+    fn finish(self) -> String {
+        if self.options.pretty {
+            self.output.trim_start_matches('\n').to_string()
+        } else {
+            self.output
+        }
+    }
+
+    // Start a new indented line before a node when pretty printing
+    fn new_line(&mut self, depth: usize) {
+        if self.options.pretty {
+            self.output.push('\n');
+            self.output.push_str(&INDENT.repeat(depth));
+        }
+    }
+
+    // `verbatim` is set inside elements whose whitespace is significant
+    fn node(&mut self, id: NodeId, depth: usize, verbatim: bool) {
+        let document = self.document;
+        match &document[id].node_type {
+            NodeType::Document(data) => {
+                if let Some(doctype) = &data.doctype {
+                    self.new_line(depth);
+                    self.output.push_str("<!DOCTYPE ");
+                    self.output.push_str(&doctype.name);
+                    self.output.push('>');
+                }
+                self.children(id, depth);
+            }
+            NodeType::Element(element) => self.element(id, element, depth, verbatim),
+            NodeType::Text(text) => {
+                let raw = document[id]
+                    .parent()
+                    .and_then(|parent| document.element(parent))
+                    .is_some_and(|parent| is_html(parent, &RAW_TEXT_ELEMENTS));
+                if verbatim || !self.options.pretty {
+                    self.text(text, raw);
+                } else if !text.trim().is_empty() {
+                    self.new_line(depth);
+                    self.text(text.trim(), raw);
+                }
+            }
+            NodeType::Comment(text) => {
+                if !verbatim {
+                    self.new_line(depth);
+                }
+                self.output.push_str("<!--");
+                self.output.push_str(text);
+                self.output.push_str("-->");
+            }
+        }
+    }
+
+    fn text(&mut self, text: &str, raw: bool) {
+        if raw {
+            self.output.push_str(text);
+        } else {
+            escape_text(text, &mut self.output);
+        }
+    }
+
+    fn element(&mut self, id: NodeId, element: &ElementData, depth: usize, verbatim: bool) {
+        if !verbatim {
+            self.new_line(depth);
+        }
+        self.output.push('<');
+        self.output.push_str(&element.qualified_name());
+        let mut attrs: Vec<_> = element.attrs.iter().collect();
+        attrs.sort();
+        for (name, value) in attrs {
+            self.output.push(' ');
+            self.output.push_str(name);
+            self.output.push_str("=\"");
+            escape_attribute(value, &mut self.output);
+            self.output.push('"');
+        }
+        self.output.push('>');
+
+        if element.namespace == Namespace::Html && is_void_element(&element.tag_name) {
+            return;
+        }
+
+        let document = self.document;
+        if is_html(element, &NEWLINE_ELEMENTS) {
+            let first_text =
+                document[id]
+                    .first_child()
+                    .and_then(|child| match &document[child].node_type {
+                        NodeType::Text(text) => Some(text),
+                        _ => None,
+                    });
+            if first_text.is_some_and(|text| text.starts_with('\n')) {
+                self.output.push('\n');
+            }
+        }
+
+        // short elements like <p>text</p> stay on one line when pretty printing
+        let keeps_whitespace = verbatim
+            || is_html(element, &NEWLINE_ELEMENTS)
+            || is_html(element, &RAW_TEXT_ELEMENTS)
+            || self.is_single_text(id);
+        if keeps_whitespace {
+            for child in document.children(id) {
+                self.node(child, depth + 1, true);
+            }
+        } else {
+            self.children(id, depth + 1);
+            if document[id].first_child().is_some() {
+                self.new_line(depth);
+            }
+        }
+
+        self.output.push_str("</");
+        self.output.push_str(&element.qualified_name());
+        self.output.push('>');
+    }
+
+    fn children(&mut self, id: NodeId, depth: usize) {
+        for child in self.document.children(id) {
+            self.node(child, depth, false);
+        }
+    }
+
+    // Is the only child of the node a text node without line breaks, when pretty printing?
+    fn is_single_text(&self, id: NodeId) -> bool {
+        let document = self.document;
+        let mut children = document.children(id);
+        match (children.next(), children.next()) {
+            (Some(child), None) => matches!(
+                &document[child].node_type,
+                NodeType::Text(text) if self.options.pretty && !text.contains('\n')
+            ),
+            _ => false,
+        }
+    }
+}
@@ -0,0 +1,1311 @@
+// HTML tokenizer, a state machine modeled after the tokenization section of the HTML Living Standard
+// https://html.spec.whatwg.org/multipage/parsing.html#tokenization
+// The tokenizer turns the input into a stream of doctypes, tags, comments and characters.
+// The tree builder switches it to one of the text states for elements like <title> and <script>,
+// whose content isn't markup.
+// Input is fed in chunks. Only the lines that tokens and errors can still refer to are kept, and
+// only the end of a long line, so memory is bounded by the longest token instead of the size of
+// the source.
+// Every token comes with the span of source it was read from. The tokenizer works on input where
+// "\r\n" and "\r" are turned into "\n", the offsets of spans count the '\r' it drops.
+
+use std::collections::VecDeque;
+
+use crate::diagnostics::{self, Diagnostic, DiagnosticKind, Location, Span, SNIPPET_CONTEXT};
+
+use super::match_char_ref;
+
+#[derive(Debug, PartialEq)]
+pub enum Token {
+    Doctype(Doctype),
+    StartTag(Tag),
+    EndTag(Tag),
+    Comment(String),
+    Character(char),
+    Eof,
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub struct Tag {
+    pub name: String,
+    // attributes in source order, duplicates are already removed
+    pub attrs: Vec<(String, String)>,
+    pub self_closing: bool,
+}
+
+// Missing parts are None, which is different from an empty quoted identifier
+#[derive(Debug, Default, PartialEq)]
+pub struct Doctype {
+    pub name: Option<String>,
+    pub public_id: Option<String>,
+    pub system_id: Option<String>,
+    // set for doctypes so broken the document is rendered in quirks mode
+    pub force_quirks: bool,
+}
+
+// How the content of an element is tokenized, everything up to the matching end tag is text
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TextKind {
+    // <title>, <textarea>: character references are decoded
+    RcData,
+    // <style>, <xmp>, <iframe>, <noembed>, <noframes>: text as it is
+    RawText,
+    // <script>: like raw text, with the odd rules for <!-- --> inside scripts
+    ScriptData,
+    // <plaintext>: the rest of the document is text
+    PlainText,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum State {
+    Data,
+    RcData,
+    RawText,
+    ScriptData,
+    PlainText,
+    // `<` and end tags in the text states, they return to `text_state` when it isn't the end tag
+    TextLessThanSign,
+    TextEndTagOpen,
+    TextEndTagName,
+    ScriptDataEscapeStart,
+    ScriptDataEscapeStartDash,
+    ScriptDataEscaped,
+    ScriptDataEscapedDash,
+    ScriptDataEscapedDashDash,
+    ScriptDataEscapedLessThanSign,
+    ScriptDataDoubleEscapeStart,
+    ScriptDataDoubleEscaped,
+    ScriptDataDoubleEscapedDash,
+    ScriptDataDoubleEscapedDashDash,
+    ScriptDataDoubleEscapedLessThanSign,
+    ScriptDataDoubleEscapeEnd,
+    TagOpen,
+    EndTagOpen,
+    TagName,
+    BeforeAttributeName,
+    AttributeName,
+    AfterAttributeName,
+    BeforeAttributeValue,
+    AttributeValueDoubleQuoted,
+    AttributeValueSingleQuoted,
+    AttributeValueUnquoted,
+    AfterAttributeValueQuoted,
+    SelfClosingStartTag,
+    MarkupDeclarationOpen,
+    CommentStart,
+    CommentStartDash,
+    Comment,
+    CommentEndDash,
+    CommentEnd,
+    CommentEndBang,
+    BogusComment,
+    CdataSection,
+    CdataSectionBracket,
+    CdataSectionEnd,
+    Doctype,
+    BeforeDoctypeName,
+    DoctypeName,
+    AfterDoctypeName,
+    AfterDoctypePublicKeyword,
+    BeforeDoctypePublicIdentifier,
+    DoctypePublicIdentifierDoubleQuoted,
+    DoctypePublicIdentifierSingleQuoted,
+    AfterDoctypePublicIdentifier,
+    BetweenDoctypePublicAndSystemIdentifiers,
+    AfterDoctypeSystemKeyword,
+    BeforeDoctypeSystemIdentifier,
+    DoctypeSystemIdentifierDoubleQuoted,
+    DoctypeSystemIdentifierSingleQuoted,
+    AfterDoctypeSystemIdentifier,
+    BogusDoctype,
+}
+
+// Bytes of input a step may look at after its first character: `<!DOCTYPE`, `<![CDATA[` and
+// the longest named character reference all fit
+const LOOKAHEAD: usize = 64;
+
+// Consumed lines are only dropped from the buffer once they add up to this many bytes. A line
+// this long is also dropped in the middle, and its errors are reported before it ends.
+const MIN_DRAIN: usize = 4096;
+
+pub struct Tokenizer {
+    // input that was fed but not dropped yet, `pos` is relative to it
+    pos: usize,
+    input: String,
+    // byte offset of input[0] in the whole source
+    base: usize,
+    // start of the oldest line still needed for diagnostics, relative to `input`, its number and
+    // the column it starts at, which is past 1 when the start of a long line was dropped
+    line_start: usize,
+    line: usize,
+    line_column: usize,
+    // input before this was already searched for newlines, relative to `input`
+    scanned: usize,
+    // a '\r' ended the last chunk, a '\n' starting the next one belongs to it
+    after_cr: bool,
+    // offsets of the newlines that were "\r\n" in the source, and how many of them were dropped
+    // from the buffer
+    dropped_crs: VecDeque<usize>,
+    drained_crs: usize,
+    // no more chunks will be fed
+    input_done: bool,
+    // offset of the last newline fed, lines up to it are complete
+    last_newline: Option<usize>,
+    state: State,
+    // where the last consumed character and the current token start, offsets in the whole source
+    char_start: usize,
+    token_start: usize,
+    // end of the last token emitted, text continues from there
+    last_end: usize,
+    // location of a recent offset, new locations are counted from there
+    location: Location,
+
+    // token being built
+    tag: Tag,
+    is_end_tag: bool,
+    attr: Option<(String, String)>,
+    comment: String,
+    doctype: Doctype,
+    // set by the tree builder while the current node is an svg or math element
+    cdata_allowed: bool,
+    // text state to go back to when `</` isn't followed by the appropriate end tag
+    text_state: State,
+    // name of the last start tag, the only end tag that can close a text state
+    last_start_tag: String,
+    // characters of a possible end tag or `script` in script data, in their original case
+    temp_buffer: String,
+
+    // tokens ready to be handed to the tree builder, with the source they were read from
+    pending: VecDeque<(Token, Span)>,
+    // errors of the tokenizer and the tree builder that are not turned into diagnostics yet
+    errors: Vec<(usize, DiagnosticKind)>,
+}
+
+impl Tokenizer {
+    pub fn new() -> Tokenizer {
+        Tokenizer {
+            pos: 0,
+            input: String::new(),
+            base: 0,
+            line_start: 0,
+            line: 1,
+            line_column: 1,
+            scanned: 0,
+            after_cr: false,
+            dropped_crs: VecDeque::new(),
+            drained_crs: 0,
+            input_done: false,
+            last_newline: None,
+            state: State::Data,
+            char_start: 0,
+            token_start: 0,
+            last_end: 0,
+            location: Location::START,
+            tag: Tag::default(),
+            is_end_tag: false,
+            attr: None,
+            comment: String::new(),
+            doctype: Doctype::default(),
+            cdata_allowed: false,
+            text_state: State::Data,
+            last_start_tag: String::new(),
+            temp_buffer: String::new(),
+            pending: VecDeque::new(),
+            errors: Vec::new(),
+        }
+    }
+
+    // Add the next chunk of input
+    pub fn feed(&mut self, chunk: &str) {
+        if chunk.is_empty() {
+            return;
+        }
+        // Normalize newlines, the rest of the parser only ever sees '\n'
+        let start = self.base + self.input.len();
+        let chunk = match chunk.strip_prefix('\n') {
+            Some(rest) if self.after_cr => {
+                self.dropped_crs.push_back(start - 1);
+                rest
+            }
+            _ => chunk,
+        };
+        self.after_cr = chunk.ends_with('\r');
+        let mut normalized = String::with_capacity(chunk.len());
+        for (i, line) in chunk.split("\r\n").enumerate() {
+            if i > 0 {
+                self.dropped_crs.push_back(start + normalized.len());
+                normalized.push('\n');
+            }
+            normalized.push_str(&line.replace('\r', "\n"));
+        }
+        let chunk = normalized;
+        if let Some(newline) = chunk.rfind('\n') {
+            self.last_newline = Some(self.base + self.input.len() + newline);
+        }
+        self.input.push_str(&chunk);
+    }
+
+    // No more input will be fed, the rest of it can be tokenized up to the end
+    pub fn end_input(&mut self) {
+        self.input_done = true;
+    }
+
+    // Run the state machine until the next token is available.
+    // Returns the token and its span, or None when more input is needed.
+    pub fn next_token(&mut self) -> Option<(Token, Span)> {
+        loop {
+            if let Some(token) = self.pending.pop_front() {
+                return Some(token);
+            }
+            if !self.has_lookahead() {
+                return None;
+            }
+            self.drop_consumed_lines();
+            self.step();
+        }
+    }
+
+    // Is there enough input for the next step to see everything it may look at?
+    fn has_lookahead(&self) -> bool {
+        if self.input_done {
+            return true;
+        }
+        let rest = &self.input[self.pos..];
+        // the digits of a numeric character reference can go on for longer than the lookahead
+        match rest.strip_prefix("&#") {
+            Some(digits) => digits.contains(|c: char| !c.is_ascii_alphanumeric()),
+            None => rest.len() >= LOOKAHEAD,
+        }
+    }
+
+    // Forget the lines before the current token and the errors that wait for their line to end,
+    // nothing refers to them anymore
+    fn drop_consumed_lines(&mut self) {
+        let oldest = self
+            .errors
+            .iter()
+            .map(|&(offset, _)| offset)
+            .fold(self.token_start, usize::min);
+        let keep = (oldest.max(self.base) - self.base).min(self.pos);
+        // only the input consumed since the last time is searched, the line has no newline
+        // before `scanned`
+        if keep > self.scanned {
+            let consumed = &self.input[self.scanned..keep];
+            match consumed.rfind('\n') {
+                Some(newline) => {
+                    self.line += consumed.matches('\n').count();
+                    self.line_start = self.scanned + newline + 1;
+                    self.line_column = 1;
+                }
+                // the characters a snippet shows before the oldest error are kept
+                None if keep - self.line_start >= MIN_DRAIN => {
+                    let context: usize = self.input[..keep]
+                        .chars()
+                        .rev()
+                        .take(SNIPPET_CONTEXT)
+                        .map(char::len_utf8)
+                        .sum();
+                    let start = keep - context;
+                    self.line_column += self.input[self.line_start..start].chars().count();
+                    self.line_start = start;
+                }
+                None => {}
+            }
+            self.scanned = keep;
+        }
+        if self.line_start >= MIN_DRAIN && self.line_start * 2 >= self.input.len() {
+            self.input.drain(..self.line_start);
+            self.base += self.line_start;
+            self.pos -= self.line_start;
+            self.scanned -= self.line_start;
+            self.line_start = 0;
+            while self.dropped_crs.front().is_some_and(|&cr| cr < self.base) {
+                self.dropped_crs.pop_front();
+                self.drained_crs += 1;
+            }
+        }
+    }
+
+    // Turn a problem found at byte `offset` of the source into a diagnostic
+    // The errors are close to the tokens, so their location is found like the tokens' one.
+    fn diagnostic(&mut self, kind: DiagnosticKind, offset: usize) -> Diagnostic {
+        let location = self.move_location(offset);
+        diagnostics::diagnostic_in(
+            &self.input[self.line_start..],
+            location.offset - self.base - self.line_start,
+            location.line,
+            location.column,
+            kind,
+        )
+    }
+
+    // Tokenize what follows the start tag that was just emitted as text
+    pub fn switch_to(&mut self, kind: TextKind) {
+        self.state = match kind {
+            TextKind::RcData => State::RcData,
+            TextKind::RawText => State::RawText,
+            TextKind::ScriptData => State::ScriptData,
+            TextKind::PlainText => State::PlainText,
+        };
+    }
+
+    // <![CDATA[ .. ]]> is text in svg & math, and a bogus comment in HTML content
+    pub fn set_cdata_allowed(&mut self, allowed: bool) {
+        self.cdata_allowed = allowed;
+    }
+
+    // Add problems found by the tree builder, so they get resolved with the tokenizer's own.
+    // Their offsets count the '\r' that were dropped, like the offsets of spans.
+    pub fn add_errors(&mut self, errors: Vec<(usize, DiagnosticKind)>) {
+        for (offset, kind) in errors {
+            let offset = self.input_offset(offset);
+            self.errors.push((offset, kind));
+        }
+    }
+
+    // Offset in the normalized input of byte `offset` of the source, the inverse of location()
+    fn input_offset(&self, offset: usize) -> usize {
+        let offset = offset.saturating_sub(self.drained_crs);
+        // the i-th dropped '\r' comes before `offset` if its newline is before `offset - i`
+        let (mut low, mut high) = (0, self.dropped_crs.len());
+        while low < high {
+            let mid = (low + high) / 2;
+            if self.dropped_crs[mid] + mid < offset {
+                low = mid + 1;
+            } else {
+                high = mid;
+            }
+        }
+        offset - low
+    }
+
+    // Diagnostics for the errors whose source line has been fed completely, or is long enough
+    // to show only a part of it. The others stay until the rest of their line comes in.
+    pub fn take_diagnostics(&mut self) -> Vec<Diagnostic> {
+        let line_end = self.last_newline;
+        let input_end = self.base + self.input.len();
+        let is_complete = |&(offset, _): &(usize, DiagnosticKind)| {
+            self.input_done
+                || line_end.is_some_and(|end| offset <= end)
+                || offset + MIN_DRAIN <= input_end
+        };
+        let (complete, waiting) = std::mem::take(&mut self.errors)
+            .into_iter()
+            .partition(is_complete);
+        self.errors = waiting;
+        complete
+            .into_iter()
+            .map(|(offset, kind)| self.diagnostic(kind, offset))
+            .collect()
+    }
+
+    fn error(&mut self, kind: DiagnosticKind) {
+        self.errors.push((self.char_start, kind));
+    }
+
+    // Consume the next character, None at the end of input
+    fn consume_char(&mut self) -> Option<char> {
+        self.char_start = self.base + self.pos;
+        let c = self.input[self.pos..].chars().next()?;
+        self.pos += c.len_utf8();
+        Some(c)
+    }
+
+    // Put the character back so the next state consumes it again
+    fn reconsume(&mut self, c: Option<char>, state: State) {
+        if let Some(c) = c {
+            self.pos -= c.len_utf8();
+        }
+        self.state = state;
+    }
+
+    // If the input continues with `s` (ignoring ASCII case) consume it.
+    fn consume_if(&mut self, s: &str) -> bool {
+        let matches = self.input[self.pos..]
+            .get(..s.len())
+            .is_some_and(|next| next.eq_ignore_ascii_case(s));
+        if matches {
+            self.pos += s.len();
+        }
+        matches
+    }
+
+    // Like consume_if but case-sensitive
+    fn consume_exact(&mut self, s: &str) -> bool {
+        let matches = self.input[self.pos..].starts_with(s);
+        if matches {
+            self.pos += s.len();
+        }
+        matches
+    }
+
+    // Start a comment for markup that isn't valid, like <?xml ..?> or <!foo>
+    fn start_bogus_comment(&mut self, c: Option<char>) {
+        self.comment.clear();
+        self.reconsume(c, State::BogusComment);
+    }
+
+    // Characters span the input since the last token, so text made of them covers its source
+    // even when some characters are only emitted once the ones after them are consumed
+    fn emit(&mut self, token: Token) {
+        let end = (self.base + self.pos).max(self.last_end);
+        let start = match token {
+            Token::Character(_) | Token::Eof => self.last_end,
+            _ => self.token_start.min(end),
+        };
+        self.last_end = end;
+        let span = Span {
+            start: self.location(start),
+            end: self.location(end),
+        };
+        self.pending.push_back((token, span));
+    }
+
+    // Location in the source of byte `offset` of the input, which has to be in the buffer
+    fn location(&mut self, offset: usize) -> Location {
+        let location = self.move_location(offset);
+        let crs = self.dropped_crs.partition_point(|&cr| cr < location.offset);
+        Location {
+            offset: location.offset + self.drained_crs + crs,
+            ..location
+        }
+    }
+
+    // Move the last location to byte `offset` of the input. Tokens are emitted in source order
+    // so it only moves a short way.
+    fn move_location(&mut self, offset: usize) -> Location {
+        let buffer_start = self.base + self.line_start;
+        let offset = offset.clamp(buffer_start, self.base + self.input.len());
+        let mut from = self.location;
+        if offset < from.offset && from.offset >= buffer_start {
+            let back = &self.input[offset - self.base..from.offset - self.base];
+            if !back.contains('\n') {
+                self.location = Location {
+                    offset,
+                    column: from.column - back.chars().count(),
+                    ..from
+                };
+                return self.location;
+            }
+        }
+        if offset < from.offset || from.offset < buffer_start {
+            from = Location {
+                offset: buffer_start,
+                line: self.line,
+                column: self.line_column,
+            };
+        }
+        self.location = from.advance(&self.input[from.offset - self.base..offset - self.base]);
+        self.location
+    }
+
+    fn emit_str(&mut self, s: &str) {
+        s.chars().for_each(|c| self.emit(Token::Character(c)));
+    }
+
+    // Emit end of file, reporting the token that was cut off
+    fn emit_eof(&mut self, error: DiagnosticKind) {
+        self.error(error);
+        self.emit(Token::Eof);
+    }
+
+    fn start_tag(&mut self, is_end_tag: bool) {
+        self.tag = Tag::default();
+        self.is_end_tag = is_end_tag;
+        self.attr = None;
+    }
+
+    // Add the attribute being built to the tag, unless an attribute with the same name is already there
+    fn finish_attr(&mut self) {
+        if let Some((name, value)) = self.attr.take() {
+            if self.tag.attrs.iter().all(|(n, _)| *n != name) {
+                self.tag.attrs.push((name, value));
+            } else {
+                self.error(DiagnosticKind::DuplicateAttribute(name));
+            }
+        }
+    }
+
+    fn start_attr(&mut self, name: String) {
+        self.finish_attr();
+        self.attr = Some((name, String::new()));
+    }
+
+    fn push_attr_name(&mut self, c: char) {
+        if let Some((name, _)) = &mut self.attr {
+            name.push(c);
+        }
+    }
+
+    fn push_attr_value(&mut self, s: &str) {
+        if let Some((_, value)) = &mut self.attr {
+            value.push_str(s);
+        }
+    }
+
+    fn emit_tag(&mut self) {
+        self.finish_attr();
+        let mut tag = std::mem::take(&mut self.tag);
+        self.state = State::Data;
+        if self.is_end_tag {
+            // end tags never carry attributes
+            if !tag.attrs.is_empty() {
+                self.error(DiagnosticKind::EndTagWithAttributes);
+                tag.attrs.clear();
+            }
+            if tag.self_closing {
+                self.error(DiagnosticKind::EndTagWithTrailingSolidus);
+                tag.self_closing = false;
+            }
+            self.emit(Token::EndTag(tag));
+        } else {
+            self.last_start_tag = tag.name.clone();
+            self.emit(Token::StartTag(tag));
+        }
+    }
+
+    // Emit a character, replacing NULL which is never allowed in text
+    fn emit_text_char(&mut self, c: char) {
+        if c == '\0' {
+            self.error(DiagnosticKind::UnexpectedNullCharacter);
+            self.emit(Token::Character(char::REPLACEMENT_CHARACTER));
+        } else {
+            self.emit(Token::Character(c));
+        }
+    }
+
+    fn eof_in_script_comment(&mut self) {
+        self.emit_eof(DiagnosticKind::EofInScriptHtmlCommentLikeText);
+    }
+
+    // `</name` in a text state turned out not to be an end tag
+    fn emit_text_end_tag_prefix(&mut self, c: Option<char>) {
+        self.emit_str("</");
+        let buffer = std::mem::take(&mut self.temp_buffer);
+        self.emit_str(&buffer);
+        self.reconsume(c, self.text_state);
+    }
+
+    fn emit_comment(&mut self) {
+        let comment = std::mem::take(&mut self.comment);
+        self.emit(Token::Comment(comment));
+        self.state = State::Data;
+    }
+
+    fn emit_doctype(&mut self) {
+        let doctype = std::mem::take(&mut self.doctype);
+        self.emit(Token::Doctype(doctype));
+        self.state = State::Data;
+    }
+
+    // Emit a doctype that can't be trusted to describe the document
+    fn emit_quirks_doctype(&mut self) {
+        self.doctype.force_quirks = true;
+        self.emit_doctype();
+    }
+
+    fn eof_in_doctype(&mut self) {
+        self.error(DiagnosticKind::EofInDoctype);
+        self.emit_quirks_doctype();
+        self.emit(Token::Eof);
+    }
+
+    // Public or system identifier of the doctype being built
+    fn doctype_id(&mut self, system: bool) -> &mut Option<String> {
+        if system {
+            &mut self.doctype.system_id
+        } else {
+            &mut self.doctype.public_id
+        }
+    }
+
+    // Start a quoted public or system identifier
+    fn start_doctype_id(&mut self, system: bool, quote: char) {
+        *self.doctype_id(system) = Some(String::new());
+        self.state = match (system, quote) {
+            (false, '"') => State::DoctypePublicIdentifierDoubleQuoted,
+            (false, _) => State::DoctypePublicIdentifierSingleQuoted,
+            (true, '"') => State::DoctypeSystemIdentifierDoubleQuoted,
+            (true, _) => State::DoctypeSystemIdentifierSingleQuoted,
+        };
+    }
+
+    // Decode a character reference after a `&` has been consumed.
+    // Returns the `&` itself when the input doesn't continue with a reference.
+    fn consume_char_ref(&mut self, in_attribute: bool) -> String {
+        let mut errors = Vec::new();
+        let char_ref = match_char_ref(&self.input[self.pos..], in_attribute, &mut errors);
+        for error in errors {
+            self.error(error);
+        }
+        match char_ref {
+            Some((decoded, len)) => {
+                self.pos += len;
+                decoded
+            }
+            None => String::from("&"),
+        }
+    }
+
+    // Consume a single character (or a lookahead sequence) and act on it according to the current state.
+    fn step(&mut self) {
+        match self.state {
+            State::Data => match self.consume_char() {
+                Some('&') => {
+                    let decoded = self.consume_char_ref(false);
+                    self.emit_str(&decoded);
+                }
+                Some('<') => {
+                    self.token_start = self.char_start;
+                    self.state = State::TagOpen;
+                }
+                Some('\0') => {
+                    self.error(DiagnosticKind::UnexpectedNullCharacter);
+                    self.emit(Token::Character('\0'));
+                }
+                Some(c) => self.emit(Token::Character(c)),
+                None => self.emit(Token::Eof),
+            },
+            State::RcData | State::RawText | State::ScriptData => match self.consume_char() {
+                Some('&') if self.state == State::RcData => {
+                    let decoded = self.consume_char_ref(false);
+                    self.emit_str(&decoded);
+                }
+                Some('<') => {
+                    self.token_start = self.char_start;
+                    self.text_state = self.state;
+                    self.state = State::TextLessThanSign;
+                }
+                Some(c) => self.emit_text_char(c),
+                None => self.emit(Token::Eof),
+            },
+            State::PlainText => match self.consume_char() {
+                Some(c) => self.emit_text_char(c),
+                None => self.emit(Token::Eof),
+            },
+            State::TextLessThanSign => match self.consume_char() {
+                Some('/') => {
+                    self.temp_buffer.clear();
+                    self.state = State::TextEndTagOpen;
+                }
+                Some('!') if self.text_state == State::ScriptData => {
+                    self.emit_str("<!");
+                    self.state = State::ScriptDataEscapeStart;
+                }
+                c => {
+                    self.emit(Token::Character('<'));
+                    self.reconsume(c, self.text_state);
+                }
+            },
+            State::TextEndTagOpen => match self.consume_char() {
+                Some(c) if c.is_ascii_alphabetic() => {
+                    self.start_tag(true);
+                    self.reconsume(Some(c), State::TextEndTagName);
+                }
+                c => self.emit_text_end_tag_prefix(c),
+            },
+            State::TextEndTagName => {
+                let c = self.consume_char();
+                let appropriate = self.tag.name == self.last_start_tag;
+                match c {
+                    Some(c) if c.is_ascii_whitespace() && appropriate => {
+                        self.state = State::BeforeAttributeName;
+                    }
+                    Some('/') if appropriate => self.state = State::SelfClosingStartTag,
+                    Some('>') if appropriate => self.emit_tag(),
+                    Some(c) if c.is_ascii_alphabetic() => {
+                        self.tag.name.push(c.to_ascii_lowercase());
+                        self.temp_buffer.push(c);
+                    }
+                    c => self.emit_text_end_tag_prefix(c),
+                }
+            }
+            State::ScriptDataEscapeStart | State::ScriptDataEscapeStartDash => {
+                match self.consume_char() {
+                    Some('-') => {
+                        self.emit(Token::Character('-'));
+                        self.state = if self.state == State::ScriptDataEscapeStart {
+                            State::ScriptDataEscapeStartDash
+                        } else {
+                            State::ScriptDataEscapedDashDash
+                        };
+                    }
+                    c => self.reconsume(c, State::ScriptData),
+                }
+            }
+            State::ScriptDataEscaped
+            | State::ScriptDataEscapedDash
+            | State::ScriptDataEscapedDashDash => match self.consume_char() {
+                Some('-') => {
+                    self.emit(Token::Character('-'));
+                    self.state = match self.state {
+                        State::ScriptDataEscaped => State::ScriptDataEscapedDash,
+                        _ => State::ScriptDataEscapedDashDash,
+                    };
+                }
+                Some('<') => {
+                    self.token_start = self.char_start;
+                    self.state = State::ScriptDataEscapedLessThanSign;
+                }
+                Some('>') if self.state == State::ScriptDataEscapedDashDash => {
+                    self.emit(Token::Character('>'));
+                    self.state = State::ScriptData;
+                }
+                Some(c) => {
+                    self.emit_text_char(c);
+                    self.state = State::ScriptDataEscaped;
+                }
+                None => self.eof_in_script_comment(),
+            },
+            State::ScriptDataEscapedLessThanSign => match self.consume_char() {
+                Some('/') => {
+                    self.temp_buffer.clear();
+                    self.text_state = State::ScriptDataEscaped;
+                    self.state = State::TextEndTagOpen;
+                }
+                Some(c) if c.is_ascii_alphabetic() => {
+                    self.temp_buffer.clear();
+                    self.emit(Token::Character('<'));
+                    self.reconsume(Some(c), State::ScriptDataDoubleEscapeStart);
+                }
+                c => {
+                    self.emit(Token::Character('<'));
+                    self.reconsume(c, State::ScriptDataEscaped);
+                }
+            },
+            // `<script>` inside `<!--` in a script: its `</script>` doesn't end the outer script
+            State::ScriptDataDoubleEscapeStart | State::ScriptDataDoubleEscapeEnd => {
+                let (script, other) = if self.state == State::ScriptDataDoubleEscapeStart {
+                    (State::ScriptDataDoubleEscaped, State::ScriptDataEscaped)
+                } else {
+                    (State::ScriptDataEscaped, State::ScriptDataDoubleEscaped)
+                };
+                match self.consume_char() {
+                    Some(c) if c.is_ascii_whitespace() || c == '/' || c == '>' => {
+                        self.state = if self.temp_buffer == "script" {
+                            script
+                        } else {
+                            other
+                        };
+                        self.emit(Token::Character(c));
+                    }
+                    Some(c) if c.is_ascii_alphabetic() => {
+                        self.temp_buffer.push(c.to_ascii_lowercase());
+                        self.emit(Token::Character(c));
+                    }
+                    c => self.reconsume(c, other),
+                }
+            }
+            State::ScriptDataDoubleEscaped
+            | State::ScriptDataDoubleEscapedDash
+            | State::ScriptDataDoubleEscapedDashDash => match self.consume_char() {
+                Some('-') => {
+                    self.emit(Token::Character('-'));
+                    self.state = match self.state {
+                        State::ScriptDataDoubleEscaped => State::ScriptDataDoubleEscapedDash,
+                        _ => State::ScriptDataDoubleEscapedDashDash,
+                    };
+                }
+                Some('<') => {
+                    self.emit(Token::Character('<'));
+                    self.state = State::ScriptDataDoubleEscapedLessThanSign;
+                }
+                Some('>') if self.state == State::ScriptDataDoubleEscapedDashDash => {
+                    self.emit(Token::Character('>'));
+                    self.state = State::ScriptData;
+                }
+                Some(c) => {
+                    self.emit_text_char(c);
+                    self.state = State::ScriptDataDoubleEscaped;
+                }
+                None => self.eof_in_script_comment(),
+            },
+            State::ScriptDataDoubleEscapedLessThanSign => match self.consume_char() {
+                Some('/') => {
+                    self.temp_buffer.clear();
+                    self.emit(Token::Character('/'));
+                    self.state = State::ScriptDataDoubleEscapeEnd;
+                }
+                c => self.reconsume(c, State::ScriptDataDoubleEscaped),
+            },
+            State::TagOpen => match self.consume_char() {
+                Some('!') => self.state = State::MarkupDeclarationOpen,
+                Some('/') => self.state = State::EndTagOpen,
+                Some(c) if c.is_ascii_alphabetic() => {
+                    self.start_tag(false);
+                    self.reconsume(Some(c), State::TagName);
+                }
+                Some('?') => {
+                    self.error(DiagnosticKind::UnexpectedQuestionMarkInsteadOfTagName);
+                    self.start_bogus_comment(Some('?'));
+                }
+                None => {
+                    self.emit(Token::Character('<'));
+                    self.emit_eof(DiagnosticKind::EofBeforeTagName);
+                }
+                c => {
+                    // not a tag after all
+                    self.error(DiagnosticKind::InvalidFirstCharacterOfTagName);
+                    self.emit(Token::Character('<'));
+                    self.reconsume(c, State::Data);
+                }
+            },
+            State::EndTagOpen => match self.consume_char() {
+                Some(c) if c.is_ascii_alphabetic() => {
+                    self.start_tag(true);
+                    self.reconsume(Some(c), State::TagName);
+                }
+                // `</>` is dropped
+                Some('>') => {
+                    self.error(DiagnosticKind::MissingEndTagName);
+                    self.state = State::Data;
+                }
+                None => {
+                    self.emit_str("</");
+                    self.emit_eof(DiagnosticKind::EofBeforeTagName);
+                }
+                c => {
+                    self.error(DiagnosticKind::InvalidFirstCharacterOfTagName);
+                    self.start_bogus_comment(c);
+                }
+            },
+            State::TagName => match self.consume_char() {
+                Some(c) if c.is_ascii_whitespace() => self.state = State::BeforeAttributeName,
+                Some('/') => self.state = State::SelfClosingStartTag,
+                Some('>') => self.emit_tag(),
+                Some('\0') => {
+                    self.error(DiagnosticKind::UnexpectedNullCharacter);
+                    self.tag.name.push(char::REPLACEMENT_CHARACTER);
+                }
+                Some(c) => self.tag.name.push(c.to_ascii_lowercase()),
+                // a tag cut off by the end of input is dropped
+                None => self.emit_eof(DiagnosticKind::EofInTag),
+            },
+            State::BeforeAttributeName => match self.consume_char() {
+                Some(c) if c.is_ascii_whitespace() => {}
+                c @ (Some('/' | '>') | None) => self.reconsume(c, State::AfterAttributeName),
+                Some('=') => {
+                    self.error(DiagnosticKind::UnexpectedEqualsSignBeforeAttributeName);
+                    self.start_attr(String::from("="));
+                    self.state = State::AttributeName;
+                }
+                c => {
+                    self.start_attr(String::new());
+                    self.reconsume(c, State::AttributeName);
+                }
+            },
+            State::AttributeName => match self.consume_char() {
+                c @ (Some('/' | '>') | None) => self.reconsume(c, State::AfterAttributeName),
+                Some(c) if c.is_ascii_whitespace() => self.state = State::AfterAttributeName,
+                Some('=') => self.state = State::BeforeAttributeValue,
+                Some('\0') => {
+                    self.error(DiagnosticKind::UnexpectedNullCharacter);
+                    self.push_attr_name(char::REPLACEMENT_CHARACTER);
+                }
+                Some(c) => {
+                    if matches!(c, '"' | '\'' | '<') {
+                        self.error(DiagnosticKind::UnexpectedCharacterInAttributeName);
+                    }
+                    self.push_attr_name(c.to_ascii_lowercase());
+                }
+            },
+            State::AfterAttributeName => match self.consume_char() {
+                Some(c) if c.is_ascii_whitespace() => {}
+                Some('/') => self.state = State::SelfClosingStartTag,
+                Some('=') => self.state = State::BeforeAttributeValue,
+                Some('>') => self.emit_tag(),
+                None => self.emit_eof(DiagnosticKind::EofInTag),
+                c => {
+                    self.start_attr(String::new());
+                    self.reconsume(c, State::AttributeName);
+                }
+            },
+            State::BeforeAttributeValue => match self.consume_char() {
+                Some(c) if c.is_ascii_whitespace() => {}
+                Some('"') => self.state = State::AttributeValueDoubleQuoted,
+                Some('\'') => self.state = State::AttributeValueSingleQuoted,
+                Some('>') => {
+                    self.error(DiagnosticKind::MissingAttributeValue);
+                    self.emit_tag();
+                }
+                c => self.reconsume(c, State::AttributeValueUnquoted),
+            },
+            State::AttributeValueDoubleQuoted | State::AttributeValueSingleQuoted => {
+                let quote = match self.state {
+                    State::AttributeValueDoubleQuoted => '"',
+                    _ => '\'',
+                };
+                match self.consume_char() {
+                    Some(c) if c == quote => self.state = State::AfterAttributeValueQuoted,
+                    Some('&') => {
+                        let decoded = self.consume_char_ref(true);
+                        self.push_attr_value(&decoded);
+                    }
+                    Some('\0') => {
+                        self.error(DiagnosticKind::UnexpectedNullCharacter);
+                        self.push_attr_value("\u{FFFD}");
+                    }
+                    Some(c) => self.push_attr_value(c.encode_utf8(&mut [0; 4])),
+                    None => self.emit_eof(DiagnosticKind::EofInTag),
+                }
+            }
+            State::AttributeValueUnquoted => match self.consume_char() {
+                Some(c) if c.is_ascii_whitespace() => self.state = State::BeforeAttributeName,
+                Some('&') => {
+                    let decoded = self.consume_char_ref(true);
+                    self.push_attr_value(&decoded);
+                }
+                Some('>') => self.emit_tag(),
+                Some('\0') => {
+                    self.error(DiagnosticKind::UnexpectedNullCharacter);
+                    self.push_attr_value("\u{FFFD}");
+                }
+                Some(c) => {
+                    if matches!(c, '"' | '\'' | '<' | '=' | '`') {
+                        self.error(DiagnosticKind::UnexpectedCharacterInUnquotedAttributeValue);
+                    }
+                    self.push_attr_value(c.encode_utf8(&mut [0; 4]));
+                }
+                None => self.emit_eof(DiagnosticKind::EofInTag),
+            },
+            State::AfterAttributeValueQuoted => match self.consume_char() {
+                Some(c) if c.is_ascii_whitespace() => self.state = State::BeforeAttributeName,
+                Some('/') => self.state = State::SelfClosingStartTag,
+                Some('>') => self.emit_tag(),
+                None => self.emit_eof(DiagnosticKind::EofInTag),
+                c => {
+                    self.error(DiagnosticKind::MissingWhitespaceBetweenAttributes);
+                    self.reconsume(c, State::BeforeAttributeName);
+                }
+            },
+            State::SelfClosingStartTag => match self.consume_char() {
+                Some('>') => {
+                    self.tag.self_closing = true;
+                    self.emit_tag();
+                }
+                None => self.emit_eof(DiagnosticKind::EofInTag),
+                c => {
+                    self.error(DiagnosticKind::UnexpectedSolidusInTag);
+                    self.reconsume(c, State::BeforeAttributeName);
+                }
+            },
+            State::MarkupDeclarationOpen => {
+                if self.consume_if("--") {
+                    self.comment.clear();
+                    self.state = State::CommentStart;
+                } else if self.consume_if("DOCTYPE") {
+                    self.doctype = Doctype::default();
+                    self.state = State::Doctype;
+                } else if self.consume_exact("[CDATA[") {
+                    if self.cdata_allowed {
+                        self.state = State::CdataSection;
+                    } else {
+                        self.error(DiagnosticKind::CdataInHtmlContent);
+                        self.comment = String::from("[CDATA[");
+                        self.state = State::BogusComment;
+                    }
+                } else {
+                    self.error(DiagnosticKind::IncorrectlyOpenedComment);
+                    self.start_bogus_comment(None);
+                }
+            }
+            State::BogusComment => match self.consume_char() {
+                Some('>') => self.emit_comment(),
+                Some('\0') => {
+                    self.error(DiagnosticKind::UnexpectedNullCharacter);
+                    self.comment.push(char::REPLACEMENT_CHARACTER);
+                }
+                Some(c) => self.comment.push(c),
+                None => {
+                    self.emit_comment();
+                    self.emit(Token::Eof);
+                }
+            },
+            State::CdataSection => match self.consume_char() {
+                Some(']') => self.state = State::CdataSectionBracket,
+                Some(c) => self.emit(Token::Character(c)),
+                None => self.emit_eof(DiagnosticKind::EofInCdata),
+            },
+            State::CdataSectionBracket => match self.consume_char() {
+                Some(']') => self.state = State::CdataSectionEnd,
+                c => {
+                    self.emit(Token::Character(']'));
+                    self.reconsume(c, State::CdataSection);
+                }
+            },
+            State::CdataSectionEnd => match self.consume_char() {
+                Some(']') => self.emit(Token::Character(']')),
+                Some('>') => self.state = State::Data,
+                c => {
+                    self.emit_str("]]");
+                    self.reconsume(c, State::CdataSection);
+                }
+            },
+            State::CommentStart => match self.consume_char() {
+                Some('-') => self.state = State::CommentStartDash,
+                // `<!-->` is an empty comment
+                Some('>') => {
+                    self.error(DiagnosticKind::AbruptClosingOfEmptyComment);
+                    self.emit_comment();
+                }
+                c => self.reconsume(c, State::Comment),
+            },
+            State::CommentStartDash => match self.consume_char() {
+                Some('-') => self.state = State::CommentEnd,
+                Some('>') => {
+                    self.error(DiagnosticKind::AbruptClosingOfEmptyComment);
+                    self.emit_comment();
+                }
+                None => {
+                    self.emit_comment();
+                    self.emit_eof(DiagnosticKind::EofInComment);
+                }
+                c => {
+                    self.comment.push('-');
+                    self.reconsume(c, State::Comment);
+                }
+            },
+            State::Comment => match self.consume_char() {
+                Some('-') => self.state = State::CommentEndDash,
+                Some('\0') => {
+                    self.error(DiagnosticKind::UnexpectedNullCharacter);
+                    self.comment.push(char::REPLACEMENT_CHARACTER);
+                }
+                Some(c) => self.comment.push(c),
+                None => {
+                    self.emit_comment();
+                    self.emit_eof(DiagnosticKind::EofInComment);
+                }
+            },
+            State::CommentEndDash => match self.consume_char() {
+                Some('-') => self.state = State::CommentEnd,
+                None => {
+                    self.emit_comment();
+                    self.emit_eof(DiagnosticKind::EofInComment);
+                }
+                c => {
+                    self.comment.push('-');
+                    self.reconsume(c, State::Comment);
+                }
+            },
+            State::CommentEnd => match self.consume_char() {
+                Some('>') => self.emit_comment(),
+                Some('!') => self.state = State::CommentEndBang,
+                Some('-') => self.comment.push('-'),
+                None => {
+                    self.emit_comment();
+                    self.emit_eof(DiagnosticKind::EofInComment);
+                }
+                c => {
+                    self.comment.push_str("--");
+                    self.reconsume(c, State::Comment);
+                }
+            },
+            State::CommentEndBang => match self.consume_char() {
+                Some('-') => {
+                    self.comment.push_str("--!");
+                    self.state = State::CommentEndDash;
+                }
+                Some('>') => {
+                    self.error(DiagnosticKind::IncorrectlyClosedComment);
+                    self.emit_comment();
+                }
+                None => {
+                    self.emit_comment();
+                    self.emit_eof(DiagnosticKind::EofInComment);
+                }
+                c => {
+                    self.comment.push_str("--!");
+                    self.reconsume(c, State::Comment);
+                }
+            },
+            State::Doctype => match self.consume_char() {
+                Some(c) if c.is_ascii_whitespace() => self.state = State::BeforeDoctypeName,
+                None => self.eof_in_doctype(),
+                c => {
+                    if c != Some('>') {
+                        self.error(DiagnosticKind::MissingWhitespaceBeforeDoctypeName);
+                    }
+                    self.reconsume(c, State::BeforeDoctypeName);
+                }
+            },
+            State::BeforeDoctypeName => match self.consume_char() {
+                Some(c) if c.is_ascii_whitespace() => {}
+                Some('>') => {
+                    self.error(DiagnosticKind::MissingDoctypeName);
+                    self.emit_quirks_doctype();
+                }
+                None => self.eof_in_doctype(),
+                c => self.reconsume(c, State::DoctypeName),
+            },
+            State::DoctypeName => match self.consume_char() {
+                Some(c) if c.is_ascii_whitespace() => self.state = State::AfterDoctypeName,
+                Some('>') => self.emit_doctype(),
+                None => self.eof_in_doctype(),
+                Some(c) => {
+                    let c = if c == '\0' {
+                        self.error(DiagnosticKind::UnexpectedNullCharacter);
+                        char::REPLACEMENT_CHARACTER
+                    } else {
+                        c.to_ascii_lowercase()
+                    };
+                    self.doctype.name.get_or_insert_with(String::new).push(c);
+                }
+            },
+            State::AfterDoctypeName => {
+                if self.consume_if("PUBLIC") {
+                    self.state = State::AfterDoctypePublicKeyword;
+                } else if self.consume_if("SYSTEM") {
+                    self.state = State::AfterDoctypeSystemKeyword;
+                } else {
+                    match self.consume_char() {
+                        Some(c) if c.is_ascii_whitespace() => {}
+                        Some('>') => self.emit_doctype(),
+                        None => self.eof_in_doctype(),
+                        c => {
+                            self.error(DiagnosticKind::InvalidCharacterSequenceAfterDoctypeName);
+                            self.doctype.force_quirks = true;
+                            self.reconsume(c, State::BogusDoctype);
+                        }
+                    }
+                }
+            }
+            State::AfterDoctypePublicKeyword | State::AfterDoctypeSystemKeyword => {
+                let system = self.state == State::AfterDoctypeSystemKeyword;
+                match self.consume_char() {
+                    Some(c) if c.is_ascii_whitespace() => {
+                        self.state = if system {
+                            State::BeforeDoctypeSystemIdentifier
+                        } else {
+                            State::BeforeDoctypePublicIdentifier
+                        };
+                    }
+                    Some(quote @ ('"' | '\'')) => {
+                        self.error(if system {
+                            DiagnosticKind::MissingWhitespaceAfterDoctypeSystemKeyword
+                        } else {
+                            DiagnosticKind::MissingWhitespaceAfterDoctypePublicKeyword
+                        });
+                        self.start_doctype_id(system, quote);
+                    }
+                    c => self.reconsume(
+                        c,
+                        if system {
+                            State::BeforeDoctypeSystemIdentifier
+                        } else {
+                            State::BeforeDoctypePublicIdentifier
+                        },
+                    ),
+                }
+            }
+            State::BeforeDoctypePublicIdentifier | State::BeforeDoctypeSystemIdentifier => {
+                let system = self.state == State::BeforeDoctypeSystemIdentifier;
+                match self.consume_char() {
+                    Some(c) if c.is_ascii_whitespace() => {}
+                    Some(quote @ ('"' | '\'')) => self.start_doctype_id(system, quote),
+                    Some('>') => {
+                        self.error(if system {
+                            DiagnosticKind::MissingDoctypeSystemIdentifier
+                        } else {
+                            DiagnosticKind::MissingDoctypePublicIdentifier
+                        });
+                        self.emit_quirks_doctype();
+                    }
+                    None => self.eof_in_doctype(),
+                    c => {
+                        self.error(if system {
+                            DiagnosticKind::MissingQuoteBeforeDoctypeSystemIdentifier
+                        } else {
+                            DiagnosticKind::MissingQuoteBeforeDoctypePublicIdentifier
+                        });
+                        self.doctype.force_quirks = true;
+                        self.reconsume(c, State::BogusDoctype);
+                    }
+                }
+            }
+            State::DoctypePublicIdentifierDoubleQuoted
+            | State::DoctypePublicIdentifierSingleQuoted
+            | State::DoctypeSystemIdentifierDoubleQuoted
+            | State::DoctypeSystemIdentifierSingleQuoted => {
+                let (system, quote) = match self.state {
+                    State::DoctypePublicIdentifierDoubleQuoted => (false, '"'),
+                    State::DoctypePublicIdentifierSingleQuoted => (false, '\''),
+                    State::DoctypeSystemIdentifierDoubleQuoted => (true, '"'),
+                    _ => (true, '\''),
+                };
+                match self.consume_char() {
+                    Some(c) if c == quote => {
+                        self.state = if system {
+                            State::AfterDoctypeSystemIdentifier
+                        } else {
+                            State::AfterDoctypePublicIdentifier
+                        };
+                    }
+                    Some('>') => {
+                        self.error(if system {
+                            DiagnosticKind::AbruptDoctypeSystemIdentifier
+                        } else {
+                            DiagnosticKind::AbruptDoctypePublicIdentifier
+                        });
+                        self.emit_quirks_doctype();
+                    }
+                    None => self.eof_in_doctype(),
+                    Some(c) => {
+                        let c = if c == '\0' {
+                            self.error(DiagnosticKind::UnexpectedNullCharacter);
+                            char::REPLACEMENT_CHARACTER
+                        } else {
+                            c
+                        };
+                        self.doctype_id(system)
+                            .get_or_insert_with(String::new)
+                            .push(c);
+                    }
+                }
+            }
+            State::AfterDoctypePublicIdentifier
+            | State::BetweenDoctypePublicAndSystemIdentifiers => {
+                let after_identifier = self.state == State::AfterDoctypePublicIdentifier;
+                match self.consume_char() {
+                    Some(c) if c.is_ascii_whitespace() => {
+                        self.state = State::BetweenDoctypePublicAndSystemIdentifiers;
+                    }
+                    Some('>') => self.emit_doctype(),
+                    Some(quote @ ('"' | '\'')) => {
+                        if after_identifier {
+                            self.error(
+                                DiagnosticKind::MissingWhitespaceBetweenDoctypePublicAndSystemIdentifiers,
+                            );
+                        }
+                        self.start_doctype_id(true, quote);
+                    }
+                    None => self.eof_in_doctype(),
+                    c => {
+                        self.error(DiagnosticKind::MissingQuoteBeforeDoctypeSystemIdentifier);
+                        self.doctype.force_quirks = true;
+                        self.reconsume(c, State::BogusDoctype);
+                    }
+                }
+            }
+            State::AfterDoctypeSystemIdentifier => match self.consume_char() {
+                Some(c) if c.is_ascii_whitespace() => {}
+                Some('>') => self.emit_doctype(),
+                None => self.eof_in_doctype(),
+                // trailing garbage is ignored but doesn't force quirks mode
+                c => {
+                    self.error(DiagnosticKind::UnexpectedCharacterAfterDoctypeSystemIdentifier);
+                    self.reconsume(c, State::BogusDoctype);
+                }
+            },
+            State::BogusDoctype => match self.consume_char() {
+                Some('>') => self.emit_doctype(),
+                Some('\0') => self.error(DiagnosticKind::UnexpectedNullCharacter),
+                Some(_) => {}
+                None => {
+                    self.emit_doctype();
+                    self.emit(Token::Eof);
+                }
+            },
+        }
+    }
+}
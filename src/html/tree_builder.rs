@@ -0,0 +1,2127 @@
+// Tree construction stage, modeled after the tree construction section of the HTML Living Standard
+// https://html.spec.whatwg.org/multipage/parsing.html#tree-construction
+// It consumes tokens and builds the DOM, closing elements whose end tag is implied,
+// reparenting misnested formatting elements and synthesizing html, head & body.
+// The doctype picks the quirks mode the document is rendered in.
+// Nodes remember the span of source they were built from. An element spans from its start tag
+// to its end tag, or to the token that closed it when the end tag is implied.
+// Content of <svg> and <math> is parsed with the rules for foreign content.
+// Fragments are parsed like the children of a context element, under a root html element that
+// is left out of the result.
+// Not supported yet: templates, framesets and select lists, those elements are treated like any other element.
+
+use std::collections::HashMap;
+
+use phf::phf_set;
+
+use crate::{
+    diagnostics::{DiagnosticKind, Location, Span},
+    dom::{
+        self, Document, DocumentData, DocumentKind, ElementData, Namespace, NodeId, NodeType,
+        QuirksMode, MAX_DEPTH,
+    },
+    encoding::Encoding,
+};
+
+use super::{
+    foreign::{adjust_mathml_tag, adjust_svg_tag, is_breakout},
+    is_void_element,
+    tokenizer::{Doctype, Tag, TextKind, Token},
+};
+
+// Elements with special parsing rules, they can't be closed by an unrelated end tag
+const SPECIAL_ELEMENTS: phf::Set<&'static str> = phf_set! {
+    "address", "applet", "area", "article", "aside", "base", "basefont", "bgsound", "blockquote",
+    "body", "br", "button", "caption", "center", "col", "colgroup", "dd", "details", "dir", "div",
+    "dl", "dt", "embed", "fieldset", "figcaption", "figure", "footer", "form", "frame", "frameset",
+    "h1", "h2", "h3", "h4", "h5", "h6", "head", "header", "hgroup", "hr", "html", "iframe", "img",
+    "input", "keygen", "li", "link", "listing", "main", "marquee", "menu", "meta", "nav",
+    "noembed", "noframes", "noscript", "object", "ol", "p", "param", "plaintext", "pre", "script",
+    "search", "section", "select", "source", "style", "summary", "table", "tbody", "td",
+    "template", "textarea", "tfoot", "th", "thead", "title", "tr", "track", "ul", "wbr", "xmp",
+};
+
+const FORMATTING_ELEMENTS: phf::Set<&'static str> = phf_set! {
+    "a", "b", "big", "code", "em", "font", "i", "nobr", "s", "small", "strike", "strong", "tt", "u",
+};
+
+// Start tags that close an open <p>
+const BLOCK_ELEMENTS: phf::Set<&'static str> = phf_set! {
+    "address", "article", "aside", "blockquote", "center", "details", "dialog", "dir", "div", "dl",
+    "fieldset", "figcaption", "figure", "footer", "header", "hgroup", "main", "menu", "nav", "ol",
+    "p", "search", "section", "summary", "ul",
+};
+
+const HEADINGS: [&str; 6] = ["h1", "h2", "h3", "h4", "h5", "h6"];
+
+// Elements whose end tag can be implied
+const IMPLIED_END_TAGS: [&str; 10] = [
+    "dd", "dt", "li", "optgroup", "option", "p", "rb", "rp", "rt", "rtc",
+];
+
+// Public identifiers of legacy doctypes that switch to quirks mode, matched as prefixes
+const QUIRKS_PUBLIC_ID_PREFIXES: [&str; 55] = [
+    "+//silmaril//dtd html pro v0r11 19970101//",
+    "-//as//dtd html 3.0 aswedit + extensions//",
+    "-//advasoft ltd//dtd html 3.0 aswedit + extensions//",
+    "-//ietf//dtd html 2.0 level 1//",
+    "-//ietf//dtd html 2.0 level 2//",
+    "-//ietf//dtd html 2.0 strict level 1//",
+    "-//ietf//dtd html 2.0 strict level 2//",
+    "-//ietf//dtd html 2.0 strict//",
+    "-//ietf//dtd html 2.0//",
+    "-//ietf//dtd html 2.1e//",
+    "-//ietf//dtd html 3.0//",
+    "-//ietf//dtd html 3.2 final//",
+    "-//ietf//dtd html 3.2//",
+    "-//ietf//dtd html 3//",
+    "-//ietf//dtd html level 0//",
+    "-//ietf//dtd html level 1//",
+    "-//ietf//dtd html level 2//",
+    "-//ietf//dtd html level 3//",
+    "-//ietf//dtd html strict level 0//",
+    "-//ietf//dtd html strict level 1//",
+    "-//ietf//dtd html strict level 2//",
+    "-//ietf//dtd html strict level 3//",
+    "-//ietf//dtd html strict//",
+    "-//ietf//dtd html//",
+    "-//metrius//dtd metrius presentational//",
+    "-//microsoft//dtd internet explorer 2.0 html strict//",
+    "-//microsoft//dtd internet explorer 2.0 html//",
+    "-//microsoft//dtd internet explorer 2.0 tables//",
+    "-//microsoft//dtd internet explorer 3.0 html strict//",
+    "-//microsoft//dtd internet explorer 3.0 html//",
+    "-//microsoft//dtd internet explorer 3.0 tables//",
+    "-//netscape comm. corp.//dtd html//",
+    "-//netscape comm. corp.//dtd strict html//",
+    "-//o'reilly and associates//dtd html 2.0//",
+    "-//o'reilly and associates//dtd html extended 1.0//",
+    "-//o'reilly and associates//dtd html extended relaxed 1.0//",
+    "-//sq//dtd html 2.0 hotmetal + extensions//",
+    "-//softquad software//dtd hotmetal pro 6.0::19990601::extensions to html 4.0//",
+    "-//softquad//dtd hotmetal pro 4.0::19971010::extensions to html 4.0//",
+    "-//spyglass//dtd html 2.0 extended//",
+    "-//sun microsystems corp.//dtd hotjava html//",
+    "-//sun microsystems corp.//dtd hotjava strict html//",
+    "-//w3c//dtd html 3 1995-03-24//",
+    "-//w3c//dtd html 3.2 draft//",
+    "-//w3c//dtd html 3.2 final//",
+    "-//w3c//dtd html 3.2//",
+    "-//w3c//dtd html 3.2s draft//",
+    "-//w3c//dtd html 4.0 frameset//",
+    "-//w3c//dtd html 4.0 transitional//",
+    "-//w3c//dtd html experimental 19960712//",
+    "-//w3c//dtd html experimental 970421//",
+    "-//w3c//dtd w3 html//",
+    "-//w3o//dtd w3 html 3.0//",
+    "-//webtechs//dtd mozilla html 2.0//",
+    "-//webtechs//dtd mozilla html//",
+];
+
+// HTML 4.01 doctypes are quirky without a system identifier and limited-quirky with one
+const HTML4_PUBLIC_ID_PREFIXES: [&str; 2] = [
+    "-//w3c//dtd html 4.01 frameset//",
+    "-//w3c//dtd html 4.01 transitional//",
+];
+
+const LIMITED_QUIRKS_PUBLIC_ID_PREFIXES: [&str; 2] = [
+    "-//w3c//dtd xhtml 1.0 frameset//",
+    "-//w3c//dtd xhtml 1.0 transitional//",
+];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum InsertionMode {
+    Initial,
+    BeforeHtml,
+    BeforeHead,
+    InHead,
+    InHeadNoscript,
+    AfterHead,
+    InBody,
+    Text,
+    InTable,
+    InTableText,
+    InCaption,
+    InColumnGroup,
+    InTableBody,
+    InRow,
+    InCell,
+    AfterBody,
+    AfterAfterBody,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Scope {
+    Default,
+    ListItem,
+    Button,
+    Table,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum FormattingEntry {
+    Marker,
+    Element(usize),
+}
+
+// Index based tree so nodes can be moved around while the document is built.
+// Node 0 is the document itself and has no data.
+struct TreeNode {
+    data: Option<NodeType>,
+    parent: Option<usize>,
+    children: Vec<usize>,
+    span: Span,
+}
+
+// Where a new node goes: appended to `parent`, or inserted before `before` in `parent`.
+struct InsertionPoint {
+    parent: usize,
+    before: Option<usize>,
+}
+
+pub struct TreeBuilder {
+    nodes: Vec<TreeNode>,
+    open_elements: Vec<usize>,
+    active_formatting: Vec<FormattingEntry>,
+    mode: InsertionMode,
+    original_mode: InsertionMode,
+    head: Option<usize>,
+    foster_parenting: bool,
+    // characters seen in table context, inserted once we know if they are all whitespace
+    pending_table_text: String,
+    pending_table_text_start: Location,
+    // a newline right after <pre> is dropped
+    ignore_lf: bool,
+    // text state the tokenizer has to switch to after the start tag that was just processed
+    text_switch: Option<TextKind>,
+    doctype: Option<dom::Doctype>,
+    quirks_mode: QuirksMode,
+    encoding: Encoding,
+    // byte offset of the token being processed, for error reporting
+    position: usize,
+    // source of the token being processed and the name of it if it is an end tag
+    span: Span,
+    end_tag: Option<String>,
+    errors: Vec<(usize, DiagnosticKind)>,
+    // the element a fragment is parsed in, it isn't part of the tree
+    context: Option<usize>,
+    // node whose children are the result, the document or the root of a fragment
+    root: usize,
+}
+
+const DOCUMENT: usize = 0;
+
+fn is_whitespace(c: char) -> bool {
+    matches!(c, '\t' | '\n' | '\x0C' | '\r' | ' ')
+}
+
+// Create the nodes listed by TreeBuilder::tree_order in a new document
+fn build_document(
+    data: DocumentData,
+    order: Vec<(usize, Option<usize>)>,
+    mut node_data: impl FnMut(usize) -> (NodeType, Span),
+) -> Document {
+    let mut document = Document::new(data);
+    let mut created: Vec<NodeId> = Vec::with_capacity(order.len());
+    for (id, parent) in order {
+        let (node_type, span) = node_data(id);
+        let node = document.create_node(node_type);
+        document.set_span(node, span);
+        let parent = parent.map_or(document.root(), |index| created[index]);
+        // the builder's tree is well formed, appending can't fail
+        let _ = document.append_child(parent, node);
+        created.push(node);
+    }
+    document
+}
+
+// Pick the rendering mode for a document from its doctype
+fn quirks_mode(doctype: &Doctype) -> QuirksMode {
+    let public_id = doctype.public_id.as_deref().map(str::to_ascii_lowercase);
+    let system_id = doctype.system_id.as_deref().map(str::to_ascii_lowercase);
+    let public_starts_with = |prefixes: &[&str]| matches!(&public_id, Some(id) if prefixes.iter().any(|p| id.starts_with(p)));
+
+    if doctype.force_quirks
+        || doctype.name.as_deref() != Some("html")
+        || matches!(
+            public_id.as_deref(),
+            Some(
+                "-//w3o//dtd w3 html strict 3.0//en//"
+                    | "-/w3c/dtd html 4.0 transitional/en"
+                    | "html"
+            )
+        )
+        || system_id.as_deref()
+            == Some("http://www.ibm.com/data/dtd/v11/ibmxhtml1-transitional.dtd")
+        || public_starts_with(&QUIRKS_PUBLIC_ID_PREFIXES)
+        || (system_id.is_none() && public_starts_with(&HTML4_PUBLIC_ID_PREFIXES))
+    {
+        QuirksMode::Quirks
+    } else if public_starts_with(&LIMITED_QUIRKS_PUBLIC_ID_PREFIXES)
+        || (system_id.is_some() && public_starts_with(&HTML4_PUBLIC_ID_PREFIXES))
+    {
+        QuirksMode::LimitedQuirks
+    } else {
+        QuirksMode::NoQuirks
+    }
+}
+
+impl TreeBuilder {
+    pub fn new() -> TreeBuilder {
+        TreeBuilder {
+            nodes: vec![TreeNode {
+                data: None,
+                parent: None,
+                children: Vec::new(),
+                span: Span {
+                    start: Location::START,
+                    end: Location::START,
+                },
+            }],
+            open_elements: Vec::new(),
+            active_formatting: Vec::new(),
+            mode: InsertionMode::Initial,
+            original_mode: InsertionMode::Initial,
+            head: None,
+            foster_parenting: false,
+            pending_table_text: String::new(),
+            pending_table_text_start: Location::START,
+            ignore_lf: false,
+            text_switch: None,
+            doctype: None,
+            quirks_mode: QuirksMode::NoQuirks,
+            encoding: Encoding::Utf8,
+            position: 0,
+            span: Span {
+                start: Location::START,
+                end: Location::START,
+            },
+            end_tag: None,
+            errors: Vec::new(),
+            context: None,
+            root: DOCUMENT,
+        }
+    }
+
+    // Builder for the children of a `context` element, the tokenizer has to start in the text
+    // state returned by take_text_switch
+    pub fn new_fragment(context: ElementData) -> TreeBuilder {
+        let mut builder = TreeBuilder::new();
+        if context.namespace == Namespace::Html {
+            builder.text_switch = match context.tag_name.as_str() {
+                "title" | "textarea" => Some(TextKind::RcData),
+                "style" | "xmp" | "iframe" | "noembed" | "noframes" => Some(TextKind::RawText),
+                "script" => Some(TextKind::ScriptData),
+                "plaintext" => Some(TextKind::PlainText),
+                // scripting is disabled so <noscript> holds markup
+                _ => None,
+            };
+        }
+        let context = builder.new_node(NodeType::Element(context));
+        builder.context = Some(context);
+        let root = builder.insert_synthesized("html");
+        builder.root = root;
+        builder.reset_insertion_mode();
+        builder
+    }
+
+    // Errors found so far, with the byte offset of the token they were found at
+    pub fn take_errors(&mut self) -> Vec<(usize, DiagnosticKind)> {
+        std::mem::take(&mut self.errors)
+    }
+
+    // Record the encoding the source is decoded from in the document
+    pub fn set_encoding(&mut self, encoding: Encoding) {
+        self.encoding = encoding;
+    }
+
+    // Return the document with everything that was parsed
+    pub fn finish(mut self) -> Document {
+        while self.pop().is_some() {}
+        let order = self.tree_order();
+        let data = DocumentData {
+            kind: DocumentKind::Html,
+            doctype: self.doctype.take(),
+            quirks_mode: self.quirks_mode,
+            encoding: self.encoding,
+        };
+        build_document(data, order, |id| (self.take_data(id), self.nodes[id].span))
+    }
+
+    // A copy of the document as far as it is built, elements that are still open are included
+    pub fn document(&self) -> Document {
+        let data = DocumentData {
+            kind: DocumentKind::Html,
+            doctype: self.doctype.clone(),
+            quirks_mode: self.quirks_mode,
+            encoding: self.encoding,
+        };
+        // open elements end at the last token so far
+        let open_end = self.span.end;
+        build_document(data, self.tree_order(), |id| {
+            let node = &self.nodes[id];
+            let mut span = node.span;
+            if self.open_elements.contains(&id) {
+                span.end = span.end.max(open_end);
+            }
+            let data = node
+                .data
+                .clone()
+                .unwrap_or_else(|| NodeType::Comment(String::new()));
+            (data, span)
+        })
+    }
+
+    // The nodes in tree order, each with the position of its parent in the list or None for the
+    // document. The adoption agency can still nest deeper than MAX_DEPTH, what is below that
+    // depth is flattened into the deepest element.
+    fn tree_order(&self) -> Vec<(usize, Option<usize>)> {
+        let mut order = Vec::new();
+        let mut stack: Vec<(usize, Option<usize>, usize)> = self.nodes[self.root]
+            .children
+            .iter()
+            .rev()
+            .map(|&child| (child, None, 1))
+            .collect();
+        while let Some((id, parent, depth)) = stack.pop() {
+            order.push((id, parent));
+            let (child_parent, child_depth) = if depth <= MAX_DEPTH {
+                (Some(order.len() - 1), depth + 1)
+            } else {
+                (parent, depth)
+            };
+            stack.extend(
+                self.nodes[id]
+                    .children
+                    .iter()
+                    .rev()
+                    .map(|&child| (child, child_parent, child_depth)),
+            );
+        }
+        order
+    }
+
+    fn take_data(&mut self, id: usize) -> NodeType {
+        self.nodes[id]
+            .data
+            .take()
+            .unwrap_or_else(|| NodeType::Comment(String::new()))
+    }
+
+    // ---- tree helpers ----
+
+    // Name of an HTML element, foreign elements never match the rules for HTML elements
+    fn tag_name(&self, id: usize) -> &str {
+        match &self.nodes[id].data {
+            Some(NodeType::Element(e)) if e.namespace == Namespace::Html => &e.tag_name,
+            _ => "",
+        }
+    }
+
+    // Foreign element named `name`?
+    fn is_foreign(&self, id: usize, namespace: Namespace, names: &[&str]) -> bool {
+        self.element(id)
+            .is_some_and(|e| e.namespace == namespace && names.contains(&e.tag_name.as_str()))
+    }
+
+    // Foreign elements that contain text or HTML again, like <foreignObject> in svg
+    fn is_mathml_text_integration_point(&self, id: usize) -> bool {
+        self.is_foreign(id, Namespace::MathMl, &["mi", "mo", "mn", "ms", "mtext"])
+    }
+
+    fn is_html_integration_point(&self, id: usize) -> bool {
+        let annotation_xml_with_html = self.element(id).is_some_and(|e| {
+            e.namespace == Namespace::MathMl
+                && e.tag_name == "annotation-xml"
+                && e.attrs.get("encoding").is_some_and(|encoding| {
+                    encoding.eq_ignore_ascii_case("text/html")
+                        || encoding.eq_ignore_ascii_case("application/xhtml+xml")
+                })
+        });
+        annotation_xml_with_html
+            || self.is_foreign(id, Namespace::Svg, &["foreignObject", "desc", "title"])
+    }
+
+    // Foreign elements that bound scopes and aren't closed by unrelated end tags
+    fn is_foreign_special(&self, id: usize) -> bool {
+        self.is_mathml_text_integration_point(id)
+            || self.is_foreign(id, Namespace::MathMl, &["annotation-xml"])
+            || self.is_foreign(id, Namespace::Svg, &["foreignObject", "desc", "title"])
+    }
+
+    fn is_special(&self, id: usize) -> bool {
+        SPECIAL_ELEMENTS.contains(self.tag_name(id)) || self.is_foreign_special(id)
+    }
+
+    fn namespace(&self, id: usize) -> Namespace {
+        self.element(id).map_or(Namespace::Html, |e| e.namespace)
+    }
+
+    // The text state for the content of the element that was just inserted, if it isn't markup
+    pub fn take_text_switch(&mut self) -> Option<TextKind> {
+        self.text_switch.take()
+    }
+
+    // Is the current node an svg or math element? CDATA sections are only allowed there.
+    pub fn in_foreign_element(&self) -> bool {
+        self.adjusted_current_node()
+            .is_some_and(|id| self.namespace(id) != Namespace::Html)
+    }
+
+    fn uses_foreign_rules(&self, token: &Token) -> bool {
+        let Some(current) = self.adjusted_current_node() else {
+            return false;
+        };
+        if self.namespace(current) == Namespace::Html {
+            return false;
+        }
+        match token {
+            Token::StartTag(tag) => {
+                let text_integration = self.is_mathml_text_integration_point(current)
+                    && !matches!(tag.name.as_str(), "mglyph" | "malignmark");
+                let svg_in_annotation =
+                    self.is_foreign(current, Namespace::MathMl, &["annotation-xml"])
+                        && tag.name == "svg";
+                !(text_integration || svg_in_annotation || self.is_html_integration_point(current))
+            }
+            Token::Character(_) => {
+                !(self.is_mathml_text_integration_point(current)
+                    || self.is_html_integration_point(current))
+            }
+            Token::Eof => false,
+            _ => true,
+        }
+    }
+
+    fn element(&self, id: usize) -> Option<&ElementData> {
+        match &self.nodes[id].data {
+            Some(NodeType::Element(e)) => Some(e),
+            _ => None,
+        }
+    }
+
+    fn current_node(&self) -> usize {
+        self.open_elements.last().copied().unwrap_or(DOCUMENT)
+    }
+
+    // The context element stands in for the root of a fragment when deciding about foreign content
+    fn adjusted_current_node(&self) -> Option<usize> {
+        match self.context {
+            Some(context) if self.open_elements.len() == 1 => Some(context),
+            _ => self.open_elements.last().copied(),
+        }
+    }
+
+    fn current_is(&self, tag: &str) -> bool {
+        self.tag_name(self.current_node()) == tag
+    }
+
+    // New nodes span the token being processed
+    fn new_node(&mut self, data: NodeType) -> usize {
+        self.nodes.push(TreeNode {
+            data: Some(data),
+            parent: None,
+            children: Vec::new(),
+            span: self.span,
+        });
+        self.nodes.len() - 1
+    }
+
+    fn detach(&mut self, id: usize) {
+        if let Some(parent) = self.nodes[id].parent.take() {
+            self.nodes[parent].children.retain(|&child| child != id);
+        }
+    }
+
+    fn insert_at(&mut self, point: &InsertionPoint, id: usize) {
+        self.detach(id);
+        let siblings = &mut self.nodes[point.parent].children;
+        let index = point
+            .before
+            .and_then(|before| siblings.iter().position(|&s| s == before))
+            .unwrap_or(siblings.len());
+        siblings.insert(index, id);
+        self.nodes[id].parent = Some(point.parent);
+    }
+
+    fn append(&mut self, parent: usize, id: usize) {
+        self.insert_at(
+            &InsertionPoint {
+                parent,
+                before: None,
+            },
+            id,
+        );
+    }
+
+    // Find where the next node goes, taking foster parenting into account
+    fn appropriate_place(&self, override_target: Option<usize>) -> InsertionPoint {
+        let target = match override_target {
+            Some(target) => target,
+            None if self.open_elements.len() > MAX_DEPTH => self.open_elements[MAX_DEPTH - 1],
+            None => self.current_node(),
+        };
+        let table_related = matches!(
+            self.tag_name(target),
+            "table" | "tbody" | "tfoot" | "thead" | "tr"
+        );
+        if !(self.foster_parenting && table_related) {
+            return InsertionPoint {
+                parent: target,
+                before: None,
+            };
+        }
+
+        // foster parenting: content misplaced in a table goes right before the table
+        let table_index = self
+            .open_elements
+            .iter()
+            .rposition(|&id| self.tag_name(id) == "table");
+        match table_index {
+            Some(index) => {
+                let table = self.open_elements[index];
+                match self.nodes[table].parent {
+                    Some(parent) => InsertionPoint {
+                        parent,
+                        before: Some(table),
+                    },
+                    None => InsertionPoint {
+                        parent: self.open_elements[index.saturating_sub(1)],
+                        before: None,
+                    },
+                }
+            }
+            None => InsertionPoint {
+                parent: self.open_elements.first().copied().unwrap_or(DOCUMENT),
+                before: None,
+            },
+        }
+    }
+
+    fn create_element(&mut self, tag: &Tag, namespace: Namespace) -> usize {
+        let mut attrs = HashMap::new();
+        for (name, value) in &tag.attrs {
+            attrs.entry(name.clone()).or_insert_with(|| value.clone());
+        }
+        self.new_node(NodeType::Element(ElementData {
+            tag_name: tag.name.clone(),
+            attrs,
+            namespace,
+            prefix: None,
+        }))
+    }
+
+    // Insert an element for the tag at the appropriate place and push it on the stack of open elements
+    fn insert_element(&mut self, tag: &Tag) -> usize {
+        self.insert_foreign_element(tag, Namespace::Html)
+    }
+
+    fn insert_foreign_element(&mut self, tag: &Tag, namespace: Namespace) -> usize {
+        let id = self.create_element(tag, namespace);
+        let point = self.appropriate_place(None);
+        self.insert_at(&point, id);
+        self.open_elements.push(id);
+        id
+    }
+
+    // Insert an element that is immediately closed again
+    fn insert_void_element(&mut self, tag: &Tag) {
+        self.insert_element(tag);
+        self.pop();
+    }
+
+    // Insert an element whose start tag is implied, it starts right before the current token
+    fn insert_synthesized(&mut self, name: &str) -> usize {
+        let id = self.insert_element(&Tag {
+            name: String::from(name),
+            ..Default::default()
+        });
+        self.nodes[id].span.end = self.span.start;
+        id
+    }
+
+    fn insert_comment(&mut self, text: String, parent: Option<usize>) {
+        let id = self.new_node(NodeType::Comment(text));
+        let point = match parent {
+            Some(parent) => InsertionPoint {
+                parent,
+                before: None,
+            },
+            None => self.appropriate_place(None),
+        };
+        self.insert_at(&point, id);
+    }
+
+    fn insert_char(&mut self, c: char) {
+        let point = self.appropriate_place(None);
+        if point.parent == DOCUMENT {
+            return;
+        }
+
+        // Append to the text node right before the insertion point if there is one
+        let siblings = &self.nodes[point.parent].children;
+        let previous = match point.before {
+            Some(before) => siblings
+                .iter()
+                .position(|&s| s == before)
+                .and_then(|i| i.checked_sub(1))
+                .map(|i| siblings[i]),
+            None => siblings.last().copied(),
+        };
+        if let Some(previous) = previous {
+            if let Some(NodeType::Text(text)) = &mut self.nodes[previous].data {
+                text.push(c);
+                self.nodes[previous].span.end = self.span.end;
+                return;
+            }
+        }
+
+        let id = self.new_node(NodeType::Text(c.to_string()));
+        self.insert_at(&point, id);
+    }
+
+    // Copy attributes that the element doesn't have yet, used for repeated <html> and <body> tags
+    fn merge_attributes(&mut self, id: usize, tag: &Tag) {
+        if let Some(NodeType::Element(e)) = &mut self.nodes[id].data {
+            for (name, value) in &tag.attrs {
+                e.attrs.entry(name.clone()).or_insert_with(|| value.clone());
+            }
+        }
+    }
+
+    // ---- stack of open elements ----
+
+    fn pop(&mut self) -> Option<usize> {
+        let id = self.open_elements.pop()?;
+        self.close(id);
+        Some(id)
+    }
+
+    // Pop elements until only `len` are left
+    fn pop_to(&mut self, len: usize) {
+        while self.open_elements.len() > len {
+            self.pop();
+        }
+    }
+
+    // Record where an element ends when it leaves the stack of open elements: after its own end
+    // tag, or right before the token that closes it
+    fn close(&mut self, id: usize) {
+        let own_end_tag = match (&self.end_tag, &self.nodes[id].data) {
+            (Some(name), Some(NodeType::Element(e))) => e.tag_name.eq_ignore_ascii_case(name),
+            _ => false,
+        };
+        let end = if own_end_tag {
+            self.span.end
+        } else {
+            self.span.start
+        };
+        let span = &mut self.nodes[id].span;
+        span.end = span.end.max(end);
+    }
+
+    // Is an element matching `test` in the given scope? Like the depth of the tree, the searches
+    // of the stack of open elements are bounded: what is more than MAX_DEPTH elements down isn't
+    // found, so the tags of a deeply nested document don't walk the whole stack.
+    fn in_scope_matching(&self, scope: Scope, test: impl Fn(&str) -> bool) -> bool {
+        for &id in self.open_elements.iter().rev().take(MAX_DEPTH) {
+            let name = self.tag_name(id);
+            if test(name) {
+                return true;
+            }
+            let boundary = match scope {
+                Scope::Table => matches!(name, "html" | "table" | "template"),
+                _ => {
+                    matches!(
+                        name,
+                        "applet"
+                            | "caption"
+                            | "html"
+                            | "table"
+                            | "td"
+                            | "th"
+                            | "marquee"
+                            | "object"
+                            | "template"
+                    ) || (scope == Scope::ListItem && matches!(name, "ol" | "ul"))
+                        || (scope == Scope::Button && name == "button")
+                        || self.is_foreign_special(id)
+                }
+            };
+            if boundary {
+                return false;
+            }
+        }
+        false
+    }
+
+    fn in_scope(&self, tag: &str, scope: Scope) -> bool {
+        self.in_scope_matching(scope, |name| name == tag)
+    }
+
+    // Pop elements until one for which `test` is true has been popped
+    fn pop_until_matching(&mut self, test: impl Fn(&str) -> bool) {
+        while let Some(id) = self.pop() {
+            if test(self.tag_name(id)) {
+                break;
+            }
+        }
+    }
+
+    fn pop_until(&mut self, tag: &str) {
+        self.pop_until_matching(|name| name == tag);
+    }
+
+    fn generate_implied_end_tags(&mut self, except: Option<&str>) {
+        loop {
+            let name = self.tag_name(self.current_node());
+            if Some(name) == except || !IMPLIED_END_TAGS.contains(&name) {
+                break;
+            }
+            self.pop();
+        }
+    }
+
+    fn close_p_element(&mut self) {
+        self.generate_implied_end_tags(Some("p"));
+        self.pop_until("p");
+    }
+
+    fn close_p_if_in_button_scope(&mut self) {
+        if self.in_scope("p", Scope::Button) {
+            self.close_p_element();
+        }
+    }
+
+    // Pop elements until the current node is one of `tags` (or html)
+    fn clear_stack_back_to(&mut self, tags: &[&str]) {
+        while !tags.contains(&self.tag_name(self.current_node())) && !self.current_is("html") {
+            if self.pop().is_none() {
+                break;
+            }
+        }
+    }
+
+    fn reset_insertion_mode(&mut self) {
+        for (i, &id) in self.open_elements.iter().enumerate().rev() {
+            let last = i == 0;
+            let id = match self.context {
+                Some(context) if last => context,
+                _ => id,
+            };
+            self.mode = match self.tag_name(id) {
+                "td" | "th" if !last => InsertionMode::InCell,
+                "tr" => InsertionMode::InRow,
+                "tbody" | "thead" | "tfoot" => InsertionMode::InTableBody,
+                "caption" => InsertionMode::InCaption,
+                "colgroup" => InsertionMode::InColumnGroup,
+                "table" => InsertionMode::InTable,
+                "head" if !last => InsertionMode::InHead,
+                "body" => InsertionMode::InBody,
+                "html" if self.head.is_none() => InsertionMode::BeforeHead,
+                "html" => InsertionMode::AfterHead,
+                _ if last => InsertionMode::InBody,
+                _ => continue,
+            };
+            return;
+        }
+        self.mode = InsertionMode::InBody;
+    }
+
+    // ---- list of active formatting elements ----
+
+    fn push_formatting(&mut self, id: usize) {
+        let entries: Vec<(usize, FormattingEntry)> = self
+            .active_formatting
+            .iter()
+            .copied()
+            .enumerate()
+            .rev()
+            .take_while(|&(_, entry)| entry != FormattingEntry::Marker)
+            .collect();
+        // Noah's Ark clause: at most three identical entries after the last marker
+        let matching: Vec<usize> = entries
+            .iter()
+            .filter_map(|&(i, entry)| match entry {
+                FormattingEntry::Element(other) if self.same_element(other, id) => Some(i),
+                _ => None,
+            })
+            .collect();
+        if matching.len() >= 3 {
+            self.active_formatting.remove(matching[matching.len() - 1]);
+        } else if entries.len() >= MAX_DEPTH {
+            // Like the searches of the stack of open elements, the entries are bounded, so a
+            // run of distinct formatting elements doesn't make every push walk all of them
+            self.active_formatting.remove(entries[entries.len() - 1].0);
+        }
+        self.active_formatting.push(FormattingEntry::Element(id));
+    }
+
+    fn same_element(&self, a: usize, b: usize) -> bool {
+        match (self.element(a), self.element(b)) {
+            (Some(a), Some(b)) => a.tag_name == b.tag_name && a.attrs == b.attrs,
+            _ => false,
+        }
+    }
+
+    fn formatting_position(&self, id: usize) -> Option<usize> {
+        self.active_formatting
+            .iter()
+            .position(|&entry| entry == FormattingEntry::Element(id))
+    }
+
+    fn clear_formatting_to_last_marker(&mut self) {
+        while let Some(entry) = self.active_formatting.pop() {
+            if entry == FormattingEntry::Marker {
+                break;
+            }
+        }
+    }
+
+    // Reopen formatting elements that were implicitly closed, e.g. <b> in `<p><b>x<p>y`
+    fn reconstruct_active_formatting(&mut self) {
+        let is_open = |builder: &TreeBuilder, entry: FormattingEntry| match entry {
+            FormattingEntry::Marker => true,
+            FormattingEntry::Element(id) => {
+                builder.open_elements.iter().rev().any(|&open| open == id)
+            }
+        };
+        let Some(&last) = self.active_formatting.last() else {
+            return;
+        };
+        if is_open(self, last) {
+            return;
+        }
+
+        let mut index = self.active_formatting.len() - 1;
+        while index > 0 && !is_open(self, self.active_formatting[index - 1]) {
+            index -= 1;
+        }
+
+        for i in index..self.active_formatting.len() {
+            let FormattingEntry::Element(old) = self.active_formatting[i] else {
+                continue;
+            };
+            let tag = self.tag_for(old);
+            let id = self.insert_element(&tag);
+            self.copy_span(old, id);
+            self.active_formatting[i] = FormattingEntry::Element(id);
+        }
+    }
+
+    // Copies of formatting elements start at the start tag of the original
+    fn copy_span(&mut self, from: usize, to: usize) {
+        self.nodes[to].span.start = self.nodes[from].span.start;
+    }
+
+    // A tag that recreates the element `id`
+    fn tag_for(&self, id: usize) -> Tag {
+        match self.element(id) {
+            Some(e) => Tag {
+                name: e.tag_name.clone(),
+                attrs: e
+                    .attrs
+                    .iter()
+                    .map(|(k, v)| (k.clone(), v.clone()))
+                    .collect(),
+                self_closing: false,
+            },
+            None => Tag::default(),
+        }
+    }
+
+    // The adoption agency algorithm fixes misnested formatting elements like `<b><i></b></i>`.
+    // Returns false if the end tag should be handled like any other end tag.
+    fn adoption_agency(&mut self, subject: &str) -> bool {
+        let current = self.current_node();
+        if self.tag_name(current) == subject && self.formatting_position(current).is_none() {
+            self.pop();
+            return true;
+        }
+
+        for _ in 0..8 {
+            // the last formatting element with that tag after the last marker
+            let formatting_element = self
+                .active_formatting
+                .iter()
+                .rev()
+                .take_while(|entry| **entry != FormattingEntry::Marker)
+                .find_map(|entry| match entry {
+                    FormattingEntry::Element(id) if self.tag_name(*id) == subject => Some(*id),
+                    _ => None,
+                });
+            let Some(formatting_element) = formatting_element else {
+                return false;
+            };
+
+            let Some(stack_index) = self
+                .open_elements
+                .iter()
+                .position(|&id| id == formatting_element)
+            else {
+                self.error(DiagnosticKind::UnexpectedEndTag(subject.to_string()));
+                self.active_formatting
+                    .retain(|&entry| entry != FormattingEntry::Element(formatting_element));
+                return true;
+            };
+            if !self.in_scope_matching(Scope::Default, |name| name == subject) {
+                self.error(DiagnosticKind::UnexpectedEndTag(subject.to_string()));
+                return true;
+            }
+
+            let furthest_block = self.open_elements[stack_index + 1..]
+                .iter()
+                .copied()
+                .find(|&id| self.is_special(id));
+            if furthest_block.is_some() || stack_index + 1 != self.open_elements.len() {
+                self.error(DiagnosticKind::MisnestedTag(subject.to_string()));
+            }
+            let Some(furthest_block) = furthest_block else {
+                self.pop_to(stack_index);
+                self.active_formatting
+                    .retain(|&entry| entry != FormattingEntry::Element(formatting_element));
+                return true;
+            };
+
+            let common_ancestor = self.open_elements[stack_index.saturating_sub(1)];
+            let mut bookmark = self.formatting_position(formatting_element).unwrap_or(0);
+
+            let mut node_index = self
+                .open_elements
+                .iter()
+                .position(|&id| id == furthest_block)
+                .unwrap_or(0);
+            let mut last_node = furthest_block;
+            let mut counter = 0;
+            loop {
+                counter += 1;
+                node_index -= 1;
+                let mut node = self.open_elements[node_index];
+                if node == formatting_element {
+                    break;
+                }
+                let mut position = self.formatting_position(node);
+                if counter > 3 {
+                    if let Some(p) = position.take() {
+                        self.active_formatting.remove(p);
+                        if p < bookmark {
+                            bookmark -= 1;
+                        }
+                    }
+                }
+                let Some(position) = position else {
+                    self.open_elements.remove(node_index);
+                    self.close(node);
+                    continue;
+                };
+
+                // replace the node with a fresh copy
+                let tag = self.tag_for(node);
+                let copy = self.create_element(&tag, Namespace::Html);
+                self.copy_span(node, copy);
+                self.close(node);
+                self.active_formatting[position] = FormattingEntry::Element(copy);
+                self.open_elements[node_index] = copy;
+                node = copy;
+
+                if last_node == furthest_block {
+                    bookmark = position + 1;
+                }
+                self.append(node, last_node);
+                last_node = node;
+            }
+
+            let point = self.appropriate_place(Some(common_ancestor));
+            self.insert_at(&point, last_node);
+
+            // move the children of the furthest block into a copy of the formatting element
+            let tag = self.tag_for(formatting_element);
+            let copy = self.create_element(&tag, Namespace::Html);
+            self.copy_span(formatting_element, copy);
+            for child in std::mem::take(&mut self.nodes[furthest_block].children) {
+                self.nodes[child].parent = None;
+                self.append(copy, child);
+            }
+            self.append(furthest_block, copy);
+
+            if let Some(p) = self.formatting_position(formatting_element) {
+                self.active_formatting.remove(p);
+                if p < bookmark {
+                    bookmark -= 1;
+                }
+            }
+            let bookmark = bookmark.min(self.active_formatting.len());
+            self.active_formatting
+                .insert(bookmark, FormattingEntry::Element(copy));
+
+            self.open_elements.retain(|&id| id != formatting_element);
+            self.close(formatting_element);
+            let fb_index = self
+                .open_elements
+                .iter()
+                .position(|&id| id == furthest_block)
+                .unwrap_or(self.open_elements.len() - 1);
+            self.open_elements.insert(fb_index + 1, copy);
+        }
+        true
+    }
+
+    // ---- errors ----
+
+    fn error(&mut self, kind: DiagnosticKind) {
+        self.errors.push((self.position, kind));
+    }
+
+    // Report a token that is ignored or handled differently than it was written
+    fn unexpected(&mut self, token: &Token) {
+        match token {
+            Token::StartTag(tag) => {
+                self.error(DiagnosticKind::UnexpectedStartTag(tag.name.clone()))
+            }
+            Token::EndTag(tag) => self.error(DiagnosticKind::UnexpectedEndTag(tag.name.clone())),
+            _ => {}
+        }
+    }
+
+    // Report elements that are still open at the end of the document and had to be closed
+    fn report_unclosed_elements(&mut self) {
+        let unclosed: Vec<String> = self
+            .open_elements
+            .iter()
+            .filter_map(|&id| self.element(id))
+            .map(|e| e.tag_name.as_str())
+            .filter(|&name| {
+                !IMPLIED_END_TAGS.contains(&name)
+                    && !matches!(
+                        name,
+                        "tbody" | "td" | "tfoot" | "th" | "thead" | "tr" | "body" | "html"
+                    )
+            })
+            .map(String::from)
+            .collect();
+        for name in unclosed {
+            self.error(DiagnosticKind::UnclosedElement(name));
+        }
+    }
+
+    // ---- token dispatch ----
+
+    // Process a token read from `span` of the source
+    pub fn process(&mut self, token: Token, span: Span) {
+        self.position = span.start.offset;
+        self.span = span;
+        self.end_tag = match &token {
+            Token::EndTag(tag) => Some(tag.name.clone()),
+            _ => None,
+        };
+        if self.ignore_lf {
+            self.ignore_lf = false;
+            if token == Token::Character('\n') {
+                return;
+            }
+        }
+        if self.uses_foreign_rules(&token) {
+            self.in_foreign_content(token);
+        } else {
+            self.process_in(self.mode, token);
+        }
+    }
+
+    fn reprocess(&mut self, mode: InsertionMode, token: Token) {
+        self.mode = mode;
+        self.process_in(mode, token);
+    }
+
+    fn process_in(&mut self, mode: InsertionMode, token: Token) {
+        match mode {
+            InsertionMode::Initial => self.initial(token),
+            InsertionMode::BeforeHtml => self.before_html(token),
+            InsertionMode::BeforeHead => self.before_head(token),
+            InsertionMode::InHead => self.in_head(token),
+            InsertionMode::InHeadNoscript => self.in_head_noscript(token),
+            InsertionMode::AfterHead => self.after_head(token),
+            InsertionMode::InBody => self.in_body(token),
+            InsertionMode::Text => self.text(token),
+            InsertionMode::InTable => self.in_table(token),
+            InsertionMode::InTableText => self.in_table_text(token),
+            InsertionMode::InCaption => self.in_caption(token),
+            InsertionMode::InColumnGroup => self.in_column_group(token),
+            InsertionMode::InTableBody => self.in_table_body(token),
+            InsertionMode::InRow => self.in_row(token),
+            InsertionMode::InCell => self.in_cell(token),
+            InsertionMode::AfterBody => self.after_body(token),
+            InsertionMode::AfterAfterBody => self.after_after_body(token),
+        }
+    }
+
+    fn initial(&mut self, token: Token) {
+        match token {
+            Token::Character(c) if is_whitespace(c) => {}
+            Token::Comment(text) => self.insert_comment(text, Some(DOCUMENT)),
+            Token::Doctype(doctype) => {
+                let conforming = doctype.name.as_deref() == Some("html")
+                    && doctype.public_id.is_none()
+                    && matches!(
+                        doctype.system_id.as_deref(),
+                        None | Some("about:legacy-compat")
+                    );
+                if !conforming {
+                    self.error(DiagnosticKind::NonConformingDoctype);
+                }
+                self.quirks_mode = quirks_mode(&doctype);
+                self.doctype = Some(dom::Doctype {
+                    name: doctype.name.unwrap_or_default(),
+                    public_id: doctype.public_id.unwrap_or_default(),
+                    system_id: doctype.system_id.unwrap_or_default(),
+                });
+                self.mode = InsertionMode::BeforeHtml;
+            }
+            token => {
+                self.error(DiagnosticKind::MissingDoctype);
+                self.quirks_mode = QuirksMode::Quirks;
+                self.reprocess(InsertionMode::BeforeHtml, token);
+            }
+        }
+    }
+
+    fn before_html(&mut self, token: Token) {
+        match token {
+            Token::Character(c) if is_whitespace(c) => {}
+            Token::Comment(text) => self.insert_comment(text, Some(DOCUMENT)),
+            Token::Doctype(_) => self.error(DiagnosticKind::UnexpectedDoctype),
+            Token::StartTag(tag) if tag.name == "html" => {
+                self.insert_element(&tag);
+                self.mode = InsertionMode::BeforeHead;
+            }
+            Token::EndTag(tag) if !matches!(tag.name.as_str(), "head" | "body" | "html" | "br") => {
+                self.unexpected(&Token::EndTag(tag))
+            }
+            token => {
+                self.insert_synthesized("html");
+                self.reprocess(InsertionMode::BeforeHead, token);
+            }
+        }
+    }
+
+    fn before_head(&mut self, token: Token) {
+        match token {
+            Token::Character(c) if is_whitespace(c) => {}
+            Token::Comment(text) => self.insert_comment(text, None),
+            Token::Doctype(_) => self.error(DiagnosticKind::UnexpectedDoctype),
+            Token::StartTag(tag) if tag.name == "html" => self.in_body(Token::StartTag(tag)),
+            Token::StartTag(tag) if tag.name == "head" => {
+                self.head = Some(self.insert_element(&tag));
+                self.mode = InsertionMode::InHead;
+            }
+            Token::EndTag(tag) if !matches!(tag.name.as_str(), "head" | "body" | "html" | "br") => {
+                self.unexpected(&Token::EndTag(tag))
+            }
+            token => {
+                self.head = Some(self.insert_synthesized("head"));
+                self.reprocess(InsertionMode::InHead, token);
+            }
+        }
+    }
+
+    fn in_head(&mut self, token: Token) {
+        match token {
+            Token::Character(c) if is_whitespace(c) => self.insert_char(c),
+            Token::Comment(text) => self.insert_comment(text, None),
+            Token::Doctype(_) => self.error(DiagnosticKind::UnexpectedDoctype),
+            Token::StartTag(tag) if tag.name == "html" => self.in_body(Token::StartTag(tag)),
+            Token::StartTag(tag)
+                if matches!(
+                    tag.name.as_str(),
+                    "base" | "basefont" | "bgsound" | "link" | "meta"
+                ) =>
+            {
+                self.insert_void_element(&tag)
+            }
+            Token::StartTag(tag) if tag.name == "title" => {
+                self.insert_text_element(&tag, TextKind::RcData)
+            }
+            Token::StartTag(tag) if matches!(tag.name.as_str(), "noframes" | "style") => {
+                self.insert_text_element(&tag, TextKind::RawText)
+            }
+            Token::StartTag(tag) if tag.name == "script" => {
+                self.insert_text_element(&tag, TextKind::ScriptData)
+            }
+            // scripts never run, so <noscript> content is markup
+            Token::StartTag(tag) if tag.name == "noscript" => {
+                self.insert_element(&tag);
+                self.mode = InsertionMode::InHeadNoscript;
+            }
+            Token::StartTag(tag) if tag.name == "head" => self.unexpected(&Token::StartTag(tag)),
+            Token::EndTag(tag) if tag.name == "head" => {
+                self.pop();
+                self.mode = InsertionMode::AfterHead;
+            }
+            Token::EndTag(tag) if !matches!(tag.name.as_str(), "body" | "html" | "br") => {
+                self.unexpected(&Token::EndTag(tag))
+            }
+            token => {
+                self.pop();
+                self.reprocess(InsertionMode::AfterHead, token);
+            }
+        }
+    }
+
+    fn in_head_noscript(&mut self, token: Token) {
+        match token {
+            Token::Doctype(_) => self.error(DiagnosticKind::UnexpectedDoctype),
+            Token::StartTag(ref tag) if tag.name == "html" => self.in_body(token),
+            Token::EndTag(ref tag) if tag.name == "noscript" => {
+                self.pop();
+                self.mode = InsertionMode::InHead;
+            }
+            Token::Character(c) if is_whitespace(c) => self.in_head(token),
+            Token::Comment(_) => self.in_head(token),
+            Token::StartTag(ref tag)
+                if matches!(
+                    tag.name.as_str(),
+                    "basefont" | "bgsound" | "link" | "meta" | "noframes" | "style"
+                ) =>
+            {
+                self.in_head(token)
+            }
+            Token::StartTag(ref tag) if matches!(tag.name.as_str(), "head" | "noscript") => {
+                self.unexpected(&token)
+            }
+            Token::EndTag(ref tag) if tag.name != "br" => self.unexpected(&token),
+            token => {
+                self.unexpected(&token);
+                self.pop();
+                self.reprocess(InsertionMode::InHead, token);
+            }
+        }
+    }
+
+    fn after_head(&mut self, token: Token) {
+        match token {
+            Token::Character(c) if is_whitespace(c) => self.insert_char(c),
+            Token::Comment(text) => self.insert_comment(text, None),
+            Token::Doctype(_) => self.error(DiagnosticKind::UnexpectedDoctype),
+            Token::StartTag(tag) if tag.name == "html" => self.in_body(Token::StartTag(tag)),
+            Token::StartTag(tag) if tag.name == "body" => {
+                self.insert_element(&tag);
+                self.mode = InsertionMode::InBody;
+            }
+            Token::StartTag(tag)
+                if matches!(
+                    tag.name.as_str(),
+                    "base"
+                        | "basefont"
+                        | "bgsound"
+                        | "link"
+                        | "meta"
+                        | "noframes"
+                        | "script"
+                        | "style"
+                        | "title"
+                ) =>
+            {
+                // head content after </head> still goes into the head
+                let Some(head) = self.head else {
+                    return;
+                };
+                self.open_elements.push(head);
+                self.in_head(Token::StartTag(tag));
+                self.open_elements.retain(|&id| id != head);
+            }
+            Token::StartTag(tag) if tag.name == "head" => self.unexpected(&Token::StartTag(tag)),
+            Token::EndTag(tag) if !matches!(tag.name.as_str(), "body" | "html" | "br") => {
+                self.unexpected(&Token::EndTag(tag))
+            }
+            token => {
+                self.insert_synthesized("body");
+                self.reprocess(InsertionMode::InBody, token);
+            }
+        }
+    }
+
+    fn in_body(&mut self, token: Token) {
+        match token {
+            Token::Character('\0') => {}
+            Token::Character(c) => {
+                self.reconstruct_active_formatting();
+                self.insert_char(c);
+            }
+            Token::Comment(text) => self.insert_comment(text, None),
+            Token::Doctype(_) => self.error(DiagnosticKind::UnexpectedDoctype),
+            Token::StartTag(tag) => {
+                let foreign = matches!(tag.name.as_str(), "math" | "svg");
+                if tag.self_closing && !is_void_element(&tag.name) && !foreign {
+                    self.error(DiagnosticKind::NonVoidElementWithTrailingSolidus(
+                        tag.name.clone(),
+                    ));
+                }
+                self.in_body_start_tag(tag)
+            }
+            Token::EndTag(tag) => self.in_body_end_tag(tag),
+            Token::Eof => self.report_unclosed_elements(),
+        }
+    }
+
+    fn in_body_start_tag(&mut self, mut tag: Tag) {
+        let name = tag.name.clone();
+        match name.as_str() {
+            "html" => {
+                if let Some(&html) = self.open_elements.first() {
+                    self.merge_attributes(html, &tag);
+                }
+            }
+            "base" | "basefont" | "bgsound" | "link" | "meta" | "noframes" | "script" | "style"
+            | "title" => self.in_head(Token::StartTag(tag)),
+            "body" => {
+                if let Some(&body) = self.open_elements.get(1) {
+                    if self.tag_name(body) == "body" {
+                        self.merge_attributes(body, &tag);
+                    }
+                }
+            }
+            "form" => {
+                self.close_p_if_in_button_scope();
+                self.insert_element(&tag);
+            }
+            _ if BLOCK_ELEMENTS.contains(name.as_str()) => {
+                self.close_p_if_in_button_scope();
+                self.insert_element(&tag);
+            }
+            _ if HEADINGS.contains(&name.as_str()) => {
+                self.close_p_if_in_button_scope();
+                if HEADINGS.contains(&self.tag_name(self.current_node())) {
+                    self.pop();
+                }
+                self.insert_element(&tag);
+            }
+            "pre" | "listing" => {
+                self.close_p_if_in_button_scope();
+                self.insert_element(&tag);
+                self.ignore_lf = true;
+            }
+            "plaintext" => {
+                self.close_p_if_in_button_scope();
+                self.insert_element(&tag);
+                self.text_switch = Some(TextKind::PlainText);
+            }
+            "textarea" => {
+                self.insert_text_element(&tag, TextKind::RcData);
+                self.ignore_lf = true;
+            }
+            "xmp" => {
+                self.close_p_if_in_button_scope();
+                self.reconstruct_active_formatting();
+                self.insert_text_element(&tag, TextKind::RawText);
+            }
+            "iframe" | "noembed" => self.insert_text_element(&tag, TextKind::RawText),
+            "li" | "dd" | "dt" => {
+                // close the previous list item
+                let closes: &[&str] = if name == "li" { &["li"] } else { &["dd", "dt"] };
+                let mut open_item = None;
+                for &id in self.open_elements.iter().rev().take(MAX_DEPTH) {
+                    let node_name = self.tag_name(id);
+                    if closes.contains(&node_name) {
+                        open_item = Some(node_name.to_string());
+                        break;
+                    }
+                    if self.is_special(id) && !matches!(node_name, "address" | "div" | "p") {
+                        break;
+                    }
+                }
+                if let Some(item) = open_item {
+                    self.generate_implied_end_tags(Some(&item));
+                    self.pop_until(&item);
+                }
+                self.close_p_if_in_button_scope();
+                self.insert_element(&tag);
+            }
+            "button" => {
+                if self.in_scope("button", Scope::Default) {
+                    self.generate_implied_end_tags(None);
+                    self.pop_until("button");
+                }
+                self.reconstruct_active_formatting();
+                self.insert_element(&tag);
+            }
+            "a" => {
+                let open_a = self
+                    .active_formatting
+                    .iter()
+                    .rev()
+                    .take_while(|entry| **entry != FormattingEntry::Marker)
+                    .find_map(|entry| match entry {
+                        FormattingEntry::Element(id) if self.tag_name(*id) == "a" => Some(*id),
+                        _ => None,
+                    });
+                if let Some(open_a) = open_a {
+                    self.adoption_agency("a");
+                    self.active_formatting
+                        .retain(|&entry| entry != FormattingEntry::Element(open_a));
+                    if self.open_elements.contains(&open_a) {
+                        self.open_elements.retain(|&id| id != open_a);
+                        self.close(open_a);
+                    }
+                }
+                self.reconstruct_active_formatting();
+                let id = self.insert_element(&tag);
+                self.push_formatting(id);
+            }
+            "nobr" => {
+                self.reconstruct_active_formatting();
+                if self.in_scope("nobr", Scope::Default) {
+                    self.adoption_agency("nobr");
+                    self.reconstruct_active_formatting();
+                }
+                let id = self.insert_element(&tag);
+                self.push_formatting(id);
+            }
+            _ if FORMATTING_ELEMENTS.contains(name.as_str()) => {
+                self.reconstruct_active_formatting();
+                let id = self.insert_element(&tag);
+                self.push_formatting(id);
+            }
+            "applet" | "marquee" | "object" => {
+                self.reconstruct_active_formatting();
+                self.insert_element(&tag);
+                self.active_formatting.push(FormattingEntry::Marker);
+            }
+            "table" => {
+                self.close_p_if_in_button_scope();
+                self.insert_element(&tag);
+                self.mode = InsertionMode::InTable;
+            }
+            "hr" => {
+                self.close_p_if_in_button_scope();
+                self.insert_void_element(&tag);
+            }
+            "image" => {
+                tag.name = String::from("img");
+                self.in_body_start_tag(tag);
+            }
+            "optgroup" | "option" => {
+                if self.current_is("option") {
+                    self.pop();
+                }
+                self.reconstruct_active_formatting();
+                self.insert_element(&tag);
+            }
+            "rb" | "rtc" => {
+                if self.in_scope("ruby", Scope::Default) {
+                    self.generate_implied_end_tags(None);
+                }
+                self.insert_element(&tag);
+            }
+            "rp" | "rt" => {
+                if self.in_scope("ruby", Scope::Default) {
+                    self.generate_implied_end_tags(Some("rtc"));
+                }
+                self.insert_element(&tag);
+            }
+            "math" | "svg" => {
+                self.reconstruct_active_formatting();
+                let namespace = if name == "svg" {
+                    adjust_svg_tag(&mut tag);
+                    Namespace::Svg
+                } else {
+                    adjust_mathml_tag(&mut tag);
+                    Namespace::MathMl
+                };
+                self.insert_foreign_element(&tag, namespace);
+                if tag.self_closing {
+                    self.pop();
+                }
+            }
+            // table parts outside of a table are ignored
+            "caption" | "col" | "colgroup" | "frame" | "head" | "tbody" | "td" | "tfoot" | "th"
+            | "thead" | "tr" => self.error(DiagnosticKind::UnexpectedStartTag(name)),
+            _ => {
+                self.reconstruct_active_formatting();
+                if is_void_element(&name) {
+                    self.insert_void_element(&tag);
+                } else {
+                    self.insert_element(&tag);
+                }
+            }
+        }
+    }
+
+    fn in_body_end_tag(&mut self, tag: Tag) {
+        let name = tag.name.as_str();
+        match name {
+            "body" | "html" => {
+                if !self.in_scope("body", Scope::Default) {
+                    return;
+                }
+                self.mode = InsertionMode::AfterBody;
+                if name == "html" {
+                    self.reprocess(InsertionMode::AfterBody, Token::EndTag(tag));
+                }
+            }
+            "button" | "form" | "listing" | "pre" => self.close_block(name),
+            _ if BLOCK_ELEMENTS.contains(name) && name != "p" => self.close_block(name),
+            "p" => {
+                if !self.in_scope("p", Scope::Button) {
+                    self.insert_synthesized("p");
+                }
+                self.close_p_element();
+            }
+            "li" => {
+                if self.in_scope("li", Scope::ListItem) {
+                    self.generate_implied_end_tags(Some("li"));
+                    self.pop_until("li");
+                }
+            }
+            "dd" | "dt" => {
+                if self.in_scope(name, Scope::Default) {
+                    self.generate_implied_end_tags(Some(name));
+                    self.pop_until(name);
+                }
+            }
+            _ if HEADINGS.contains(&name) => {
+                if self.in_scope_matching(Scope::Default, |n| HEADINGS.contains(&n)) {
+                    self.generate_implied_end_tags(None);
+                    self.pop_until_matching(|n| HEADINGS.contains(&n));
+                }
+            }
+            _ if FORMATTING_ELEMENTS.contains(name) => {
+                if !self.adoption_agency(name) {
+                    self.any_other_end_tag(name);
+                }
+            }
+            "applet" | "marquee" | "object" => {
+                if self.in_scope(name, Scope::Default) {
+                    self.generate_implied_end_tags(None);
+                    self.pop_until(name);
+                    self.clear_formatting_to_last_marker();
+                }
+            }
+            // </br> is treated as <br>
+            "br" => self.in_body_start_tag(Tag {
+                name: String::from("br"),
+                ..Default::default()
+            }),
+            _ => self.any_other_end_tag(name),
+        }
+    }
+
+    fn close_block(&mut self, name: &str) {
+        if !self.in_scope(name, Scope::Default) {
+            self.error(DiagnosticKind::UnexpectedEndTag(name.to_string()));
+            return;
+        }
+        self.generate_implied_end_tags(None);
+        if !self.current_is(name) {
+            self.error(DiagnosticKind::MisnestedTag(name.to_string()));
+        }
+        self.pop_until(name);
+    }
+
+    fn any_other_end_tag(&mut self, name: &str) {
+        for index in (0..self.open_elements.len()).rev().take(MAX_DEPTH) {
+            let node_name = self.tag_name(self.open_elements[index]);
+            if node_name == name {
+                self.generate_implied_end_tags(Some(name));
+                if index + 1 != self.open_elements.len() {
+                    self.error(DiagnosticKind::MisnestedTag(name.to_string()));
+                }
+                self.pop_to(index);
+                return;
+            }
+            if self.is_special(self.open_elements[index]) {
+                break;
+            }
+        }
+        self.error(DiagnosticKind::UnexpectedEndTag(name.to_string()));
+    }
+
+    // Insert an element whose content the tokenizer reads as text, like <title> or <script>
+    fn insert_text_element(&mut self, tag: &Tag, kind: TextKind) {
+        self.insert_element(tag);
+        self.text_switch = Some(kind);
+        self.original_mode = self.mode;
+        self.mode = InsertionMode::Text;
+    }
+
+    // Contents of title, textarea, style, script & friends.
+    // The tokenizer only emits characters here, until the end tag of the element.
+    fn text(&mut self, token: Token) {
+        match token {
+            Token::Character(c) => self.insert_char(c),
+            Token::Eof => {
+                let name = self.tag_name(self.current_node()).to_string();
+                self.error(DiagnosticKind::UnclosedElement(name));
+                self.pop();
+                self.reprocess(self.original_mode, Token::Eof);
+            }
+            Token::EndTag(_) => {
+                self.pop();
+                self.mode = self.original_mode;
+            }
+            token => self.unexpected(&token),
+        }
+    }
+
+    fn in_table(&mut self, token: Token) {
+        match token {
+            Token::Character(_)
+                if matches!(
+                    self.tag_name(self.current_node()),
+                    "table" | "tbody" | "template" | "tfoot" | "thead" | "tr"
+                ) =>
+            {
+                self.pending_table_text.clear();
+                self.pending_table_text_start = self.span.start;
+                self.original_mode = self.mode;
+                self.reprocess(InsertionMode::InTableText, token);
+            }
+            Token::Comment(text) => self.insert_comment(text, None),
+            Token::Doctype(_) => self.error(DiagnosticKind::UnexpectedDoctype),
+            Token::StartTag(tag) => match tag.name.as_str() {
+                "caption" => {
+                    self.clear_stack_back_to(&["table", "template"]);
+                    self.active_formatting.push(FormattingEntry::Marker);
+                    self.insert_element(&tag);
+                    self.mode = InsertionMode::InCaption;
+                }
+                "colgroup" => {
+                    self.clear_stack_back_to(&["table", "template"]);
+                    self.insert_element(&tag);
+                    self.mode = InsertionMode::InColumnGroup;
+                }
+                "col" => {
+                    self.clear_stack_back_to(&["table", "template"]);
+                    self.insert_synthesized("colgroup");
+                    self.reprocess(InsertionMode::InColumnGroup, Token::StartTag(tag));
+                }
+                "tbody" | "tfoot" | "thead" => {
+                    self.clear_stack_back_to(&["table", "template"]);
+                    self.insert_element(&tag);
+                    self.mode = InsertionMode::InTableBody;
+                }
+                "td" | "th" | "tr" => {
+                    self.clear_stack_back_to(&["table", "template"]);
+                    self.insert_synthesized("tbody");
+                    self.reprocess(InsertionMode::InTableBody, Token::StartTag(tag));
+                }
+                "table" => {
+                    // a table inside a table closes the first one
+                    if self.in_scope("table", Scope::Table) {
+                        self.pop_until("table");
+                        self.reset_insertion_mode();
+                        self.process_in(self.mode, Token::StartTag(tag));
+                    }
+                }
+                "style" | "script" => self.in_head(Token::StartTag(tag)),
+                "input"
+                    if tag
+                        .attrs
+                        .iter()
+                        .any(|(n, v)| n == "type" && v.eq_ignore_ascii_case("hidden")) =>
+                {
+                    self.insert_void_element(&tag)
+                }
+                _ => {
+                    self.error(DiagnosticKind::UnexpectedContentInTable);
+                    self.foster_parent(Token::StartTag(tag));
+                }
+            },
+            Token::EndTag(tag) => match tag.name.as_str() {
+                "table" => {
+                    if self.in_scope("table", Scope::Table) {
+                        self.pop_until("table");
+                        self.reset_insertion_mode();
+                    }
+                }
+                "body" | "caption" | "col" | "colgroup" | "html" | "tbody" | "td" | "tfoot"
+                | "th" | "thead" | "tr" => {
+                    self.error(DiagnosticKind::UnexpectedEndTag(tag.name.clone()))
+                }
+                _ => {
+                    self.error(DiagnosticKind::UnexpectedContentInTable);
+                    self.foster_parent(Token::EndTag(tag));
+                }
+            },
+            token => self.foster_parent(token),
+        }
+    }
+
+    // Process the token like in body, but put new content in front of the table
+    fn foster_parent(&mut self, token: Token) {
+        self.foster_parenting = true;
+        self.in_body(token);
+        self.foster_parenting = false;
+    }
+
+    fn in_table_text(&mut self, token: Token) {
+        match token {
+            Token::Character('\0') => {}
+            Token::Character(c) => self.pending_table_text.push(c),
+            token => {
+                // the text spans from its first character up to this token
+                let span = self.span;
+                self.span = Span {
+                    start: self.pending_table_text_start,
+                    end: span.start,
+                };
+                let text = std::mem::take(&mut self.pending_table_text);
+                if text.chars().all(is_whitespace) {
+                    text.chars().for_each(|c| self.insert_char(c));
+                } else {
+                    self.error(DiagnosticKind::UnexpectedContentInTable);
+                    text.chars()
+                        .for_each(|c| self.foster_parent(Token::Character(c)));
+                }
+                self.span = span;
+                self.reprocess(self.original_mode, token);
+            }
+        }
+    }
+
+    fn in_caption(&mut self, token: Token) {
+        match &token {
+            Token::EndTag(tag) if tag.name == "caption" => {
+                self.close_caption();
+            }
+            Token::StartTag(tag)
+                if matches!(
+                    tag.name.as_str(),
+                    "caption"
+                        | "col"
+                        | "colgroup"
+                        | "tbody"
+                        | "td"
+                        | "tfoot"
+                        | "th"
+                        | "thead"
+                        | "tr"
+                ) =>
+            {
+                if self.close_caption() {
+                    self.process_in(self.mode, token);
+                }
+            }
+            Token::EndTag(tag) if tag.name == "table" => {
+                if self.close_caption() {
+                    self.process_in(self.mode, token);
+                }
+            }
+            Token::EndTag(tag)
+                if matches!(
+                    tag.name.as_str(),
+                    "body"
+                        | "col"
+                        | "colgroup"
+                        | "html"
+                        | "tbody"
+                        | "td"
+                        | "tfoot"
+                        | "th"
+                        | "thead"
+                        | "tr"
+                ) =>
+            {
+                self.unexpected(&token)
+            }
+            _ => self.in_body(token),
+        }
+    }
+
+    fn close_caption(&mut self) -> bool {
+        if !self.in_scope("caption", Scope::Table) {
+            return false;
+        }
+        self.generate_implied_end_tags(None);
+        self.pop_until("caption");
+        self.clear_formatting_to_last_marker();
+        self.mode = InsertionMode::InTable;
+        true
+    }
+
+    fn in_column_group(&mut self, token: Token) {
+        match token {
+            Token::Character(c) if is_whitespace(c) => self.insert_char(c),
+            Token::Comment(text) => self.insert_comment(text, None),
+            Token::Doctype(_) => self.error(DiagnosticKind::UnexpectedDoctype),
+            Token::StartTag(tag) if tag.name == "html" => self.in_body(Token::StartTag(tag)),
+            Token::StartTag(tag) if tag.name == "col" => self.insert_void_element(&tag),
+            Token::EndTag(tag) if tag.name == "col" => self.unexpected(&Token::EndTag(tag)),
+            Token::EndTag(tag) if tag.name == "colgroup" => {
+                if self.current_is("colgroup") {
+                    self.pop();
+                    self.mode = InsertionMode::InTable;
+                }
+            }
+            Token::Eof => self.in_body(token),
+            token => {
+                if self.current_is("colgroup") {
+                    self.pop();
+                    self.reprocess(InsertionMode::InTable, token);
+                }
+            }
+        }
+    }
+
+    fn in_table_body(&mut self, token: Token) {
+        const CONTEXT: [&str; 5] = ["tbody", "tfoot", "thead", "template", "html"];
+        match &token {
+            Token::StartTag(tag) if tag.name == "tr" => {
+                self.clear_stack_back_to(&CONTEXT);
+                self.insert_element(tag);
+                self.mode = InsertionMode::InRow;
+            }
+            Token::StartTag(tag) if matches!(tag.name.as_str(), "th" | "td") => {
+                self.clear_stack_back_to(&CONTEXT);
+                self.insert_synthesized("tr");
+                self.reprocess(InsertionMode::InRow, token);
+            }
+            Token::EndTag(tag) if matches!(tag.name.as_str(), "tbody" | "tfoot" | "thead") => {
+                if self.in_scope(&tag.name, Scope::Table) {
+                    self.clear_stack_back_to(&CONTEXT);
+                    self.pop();
+                    self.mode = InsertionMode::InTable;
+                }
+            }
+            Token::StartTag(tag)
+                if matches!(
+                    tag.name.as_str(),
+                    "caption" | "col" | "colgroup" | "tbody" | "tfoot" | "thead"
+                ) =>
+            {
+                self.close_table_body(token)
+            }
+            Token::EndTag(tag) if tag.name == "table" => self.close_table_body(token),
+            Token::EndTag(tag)
+                if matches!(
+                    tag.name.as_str(),
+                    "body" | "caption" | "col" | "colgroup" | "html" | "td" | "th" | "tr"
+                ) =>
+            {
+                self.unexpected(&token)
+            }
+            _ => self.in_table(token),
+        }
+    }
+
+    fn close_table_body(&mut self, token: Token) {
+        if self.in_scope_matching(Scope::Table, |n| matches!(n, "tbody" | "thead" | "tfoot")) {
+            self.clear_stack_back_to(&["tbody", "tfoot", "thead", "template", "html"]);
+            self.pop();
+            self.reprocess(InsertionMode::InTable, token);
+        }
+    }
+
+    fn in_row(&mut self, token: Token) {
+        match &token {
+            Token::StartTag(tag) if matches!(tag.name.as_str(), "th" | "td") => {
+                self.clear_stack_back_to(&["tr", "template", "html"]);
+                self.insert_element(tag);
+                self.mode = InsertionMode::InCell;
+                self.active_formatting.push(FormattingEntry::Marker);
+            }
+            Token::EndTag(tag) if tag.name == "tr" => {
+                self.close_row();
+            }
+            Token::StartTag(tag)
+                if matches!(
+                    tag.name.as_str(),
+                    "caption" | "col" | "colgroup" | "tbody" | "tfoot" | "thead" | "tr"
+                ) =>
+            {
+                if self.close_row() {
+                    self.process_in(self.mode, token);
+                }
+            }
+            Token::EndTag(tag) if tag.name == "table" => {
+                if self.close_row() {
+                    self.process_in(self.mode, token);
+                }
+            }
+            Token::EndTag(tag) if matches!(tag.name.as_str(), "tbody" | "tfoot" | "thead") => {
+                if self.in_scope(&tag.name, Scope::Table) && self.close_row() {
+                    self.process_in(self.mode, token);
+                }
+            }
+            Token::EndTag(tag)
+                if matches!(
+                    tag.name.as_str(),
+                    "body" | "caption" | "col" | "colgroup" | "html" | "td" | "th"
+                ) =>
+            {
+                self.unexpected(&token)
+            }
+            _ => self.in_table(token),
+        }
+    }
+
+    fn close_row(&mut self) -> bool {
+        if !self.in_scope("tr", Scope::Table) {
+            return false;
+        }
+        self.clear_stack_back_to(&["tr", "template", "html"]);
+        self.pop();
+        self.mode = InsertionMode::InTableBody;
+        true
+    }
+
+    fn in_cell(&mut self, token: Token) {
+        match &token {
+            Token::EndTag(tag) if matches!(tag.name.as_str(), "td" | "th") => {
+                if self.in_scope(&tag.name, Scope::Table) {
+                    self.generate_implied_end_tags(None);
+                    self.pop_until(&tag.name);
+                    self.clear_formatting_to_last_marker();
+                    self.mode = InsertionMode::InRow;
+                }
+            }
+            Token::StartTag(tag)
+                if matches!(
+                    tag.name.as_str(),
+                    "caption"
+                        | "col"
+                        | "colgroup"
+                        | "tbody"
+                        | "td"
+                        | "tfoot"
+                        | "th"
+                        | "thead"
+                        | "tr"
+                ) =>
+            {
+                if self.in_scope_matching(Scope::Table, |n| matches!(n, "td" | "th")) {
+                    self.close_cell();
+                    self.process_in(self.mode, token);
+                }
+            }
+            Token::EndTag(tag)
+                if matches!(
+                    tag.name.as_str(),
+                    "body" | "caption" | "col" | "colgroup" | "html"
+                ) =>
+            {
+                self.unexpected(&token)
+            }
+            Token::EndTag(tag)
+                if matches!(
+                    tag.name.as_str(),
+                    "table" | "tbody" | "tfoot" | "thead" | "tr"
+                ) =>
+            {
+                if self.in_scope(&tag.name, Scope::Table) {
+                    self.close_cell();
+                    self.process_in(self.mode, token);
+                }
+            }
+            _ => self.in_body(token),
+        }
+    }
+
+    fn close_cell(&mut self) {
+        self.generate_implied_end_tags(None);
+        self.pop_until_matching(|n| matches!(n, "td" | "th"));
+        self.clear_formatting_to_last_marker();
+        self.mode = InsertionMode::InRow;
+    }
+
+    // Content of <svg> and <math>, until an integration point switches back to HTML
+    fn in_foreign_content(&mut self, token: Token) {
+        match token {
+            Token::Character('\0') => {
+                self.error(DiagnosticKind::UnexpectedNullCharacter);
+                self.insert_char(char::REPLACEMENT_CHARACTER);
+            }
+            Token::Character(c) => self.insert_char(c),
+            Token::Comment(text) => self.insert_comment(text, None),
+            Token::Doctype(_) => self.error(DiagnosticKind::UnexpectedDoctype),
+            Token::StartTag(ref tag) if is_breakout(tag) => {
+                self.unexpected(&token);
+                self.close_foreign_content();
+                self.process_in(self.mode, token);
+            }
+            Token::EndTag(ref tag) if matches!(tag.name.as_str(), "br" | "p") => {
+                self.unexpected(&token);
+                self.close_foreign_content();
+                self.process_in(self.mode, token);
+            }
+            Token::StartTag(mut tag) => {
+                let namespace = self.namespace(self.adjusted_current_node().unwrap_or(DOCUMENT));
+                match namespace {
+                    Namespace::Svg => adjust_svg_tag(&mut tag),
+                    Namespace::MathMl => adjust_mathml_tag(&mut tag),
+                    Namespace::Html | Namespace::Other => {}
+                }
+                self.insert_foreign_element(&tag, namespace);
+                // foreign elements can be self-closing
+                if tag.self_closing {
+                    self.pop();
+                }
+            }
+            Token::EndTag(tag) => {
+                let matches_tag = |builder: &TreeBuilder, id: usize| {
+                    builder
+                        .element(id)
+                        .is_some_and(|e| e.tag_name.eq_ignore_ascii_case(&tag.name))
+                };
+                if !matches_tag(self, self.current_node()) {
+                    self.error(DiagnosticKind::UnexpectedEndTag(tag.name.clone()));
+                }
+                for index in (1..self.open_elements.len()).rev().take(MAX_DEPTH) {
+                    let id = self.open_elements[index];
+                    if matches_tag(self, id) {
+                        self.pop_to(index);
+                        return;
+                    }
+                    if self.namespace(self.open_elements[index - 1]) == Namespace::Html {
+                        self.process_in(self.mode, Token::EndTag(tag));
+                        return;
+                    }
+                }
+            }
+            Token::Eof => self.process_in(self.mode, Token::Eof),
+        }
+    }
+
+    // Pop foreign elements until the current node is HTML or an integration point
+    fn close_foreign_content(&mut self) {
+        while let Some(&current) = self.open_elements.last() {
+            if self.namespace(current) == Namespace::Html
+                || self.is_mathml_text_integration_point(current)
+                || self.is_html_integration_point(current)
+            {
+                break;
+            }
+            self.pop();
+        }
+    }
+
+    fn after_body(&mut self, token: Token) {
+        match token {
+            Token::Character(c) if is_whitespace(c) => self.in_body(token),
+            Token::Comment(text) => {
+                let html = self.open_elements.first().copied().unwrap_or(DOCUMENT);
+                self.insert_comment(text, Some(html));
+            }
+            Token::Doctype(_) => self.error(DiagnosticKind::UnexpectedDoctype),
+            Token::StartTag(ref tag) if tag.name == "html" => self.in_body(token),
+            Token::EndTag(ref tag) if tag.name == "html" => {
+                if self.context.is_some() {
+                    self.unexpected(&token);
+                } else {
+                    self.mode = InsertionMode::AfterAfterBody
+                }
+            }
+            Token::Eof => {}
+            token => self.reprocess(InsertionMode::InBody, token),
+        }
+    }
+
+    fn after_after_body(&mut self, token: Token) {
+        match token {
+            Token::Comment(text) => self.insert_comment(text, Some(DOCUMENT)),
+            Token::Doctype(_) => self.error(DiagnosticKind::UnexpectedDoctype),
+            Token::Character(c) if is_whitespace(c) => self.in_body(token),
+            Token::StartTag(ref tag) if tag.name == "html" => self.in_body(token),
+            Token::Eof => {}
+            token => self.reprocess(InsertionMode::InBody, token),
+        }
+    }
+}
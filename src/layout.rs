@@ -5,6 +5,7 @@ use crate::css::{
     Unit::Px,
     Value::{Keyword, Length},
 };
+use crate::dom::{Document, NodeType, QuirksMode};
 use crate::style::{Display, StyledNode};
 
 pub use self::BoxType::{AnonymousBlock, BlockNode, InlineNode};
@@ -43,11 +44,19 @@ pub struct LayoutBox<'a> {
 }
 
 pub enum BoxType<'a> {
-    BlockNode(&'a StyledNode<'a>),
-    InlineNode(&'a StyledNode<'a>),
+    BlockNode(&'a StyledNode),
+    InlineNode(&'a StyledNode),
     AnonymousBlock,
 }
 
+// Document wide values the boxes need while they are laid out
+#[derive(Clone, Copy)]
+struct LayoutContext<'a> {
+    document: &'a Document,
+    quirks_mode: QuirksMode,
+    viewport_height: f32,
+}
+
 impl<'a> LayoutBox<'a> {
     fn new(box_type: BoxType) -> LayoutBox {
         LayoutBox {
@@ -57,7 +66,7 @@ impl<'a> LayoutBox<'a> {
         }
     }
 
-    fn get_style_node(&self) -> &'a StyledNode<'a> {
+    fn get_style_node(&self) -> &'a StyledNode {
         match self.box_type {
             BlockNode(node) | InlineNode(node) => node,
             AnonymousBlock => panic!("Anonymous block box has no style node"),
@@ -87,18 +96,18 @@ impl LayoutBox<'_> {
         }
     }
 
-    fn layout(&mut self, containing_block: Dimensions) {
+    fn layout(&mut self, containing_block: Dimensions, context: LayoutContext<'_>) {
         match self.box_type {
-            BlockNode(_) | InlineNode(_) => self.layout_block(containing_block),
+            BlockNode(_) | InlineNode(_) => self.layout_block(containing_block, context),
             AnonymousBlock => {}
         }
     }
 
-    fn layout_block(&mut self, containing_block: Dimensions) {
+    fn layout_block(&mut self, containing_block: Dimensions, context: LayoutContext<'_>) {
         self.calculate_width(containing_block);
         self.calculate_position(containing_block);
-        self.layout_children();
-        self.calculate_height();
+        self.layout_children(context);
+        self.calculate_height(containing_block, context);
     }
 
     fn calculate_width(&mut self, containing_block: Dimensions) {
@@ -214,35 +223,68 @@ impl LayoutBox<'_> {
             + d.padding.top;
     }
 
-    fn layout_children(&mut self) {
+    fn layout_children(&mut self, context: LayoutContext<'_>) {
         for child in &mut self.children {
-            child.layout(self.dimensions);
+            child.layout(self.dimensions, context);
             self.dimensions.content.height += child.dimensions.margin_box().height;
         }
     }
 
-    fn calculate_height(&mut self) {
-        if let Some(Length(h, Px)) = self.get_style_node().value("height") {
+    fn calculate_height(&mut self, containing_block: Dimensions, context: LayoutContext<'_>) {
+        let style = self.get_style_node();
+        if let Some(Length(h, Px)) = style.value("height") {
             self.dimensions.content.height = h;
+            return;
+        }
+
+        // Quirks mode: html fills the viewport and body fills html, minus their margins, borders & padding
+        let fills_viewport = context
+            .document
+            .element(style.node)
+            .is_some_and(|e| matches!(e.tag_name.as_str(), "html" | "body"));
+        if context.quirks_mode == QuirksMode::Quirks && fills_viewport {
+            let d = &mut self.dimensions;
+            let edges = d.margin_box().height - d.content.height;
+            let outer_edges =
+                containing_block.margin_box().height - containing_block.content.height;
+            let min_height = context.viewport_height - outer_edges - edges;
+            d.content.height = d.content.height.max(min_height);
         }
     }
 }
 
 pub fn layout_tree<'a>(
-    node: &'a StyledNode<'a>,
+    document: &Document,
+    node: &'a StyledNode,
     mut containing_block: Dimensions,
 ) -> LayoutBox<'a> {
+    let context = LayoutContext {
+        document,
+        quirks_mode: document.quirks_mode(),
+        viewport_height: containing_block.content.height,
+    };
+
     // The layout algorithm expects the container height to start at 0.
     // TODO: Save the initial containing block height, for calculating percent heights.
     containing_block.content.height = 0.0;
 
+    // The document itself generates no box, layout starts at its root element
+    let node = match document[node.node].node_type {
+        NodeType::Document(_) => node
+            .children
+            .iter()
+            .find(|child| document.element(child.node).is_some())
+            .unwrap_or(node),
+        _ => node,
+    };
+
     let mut root_box = build_layout_tree(node);
-    root_box.layout(containing_block);
+    root_box.layout(containing_block, context);
     root_box
 }
 
 // Build the tree of LayoutBoxes without performing layout calculations
-fn build_layout_tree<'a>(style_node: &'a StyledNode<'a>) -> LayoutBox<'a> {
+fn build_layout_tree(style_node: &StyledNode) -> LayoutBox<'_> {
     let mut root = LayoutBox::new(match style_node.display() {
         Display::Block => BoxType::BlockNode(style_node),
         Display::Inline => BoxType::InlineNode(style_node),
@@ -0,0 +1,10 @@
+pub mod css;
+pub mod diagnostics;
+pub mod dom;
+pub mod encoding;
+pub mod html;
+pub mod layout;
+pub mod markdown;
+pub mod painting;
+pub mod style;
+pub mod xml;
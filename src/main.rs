@@ -1,30 +1,53 @@
 use std::{
     fs::{self, File},
     io::BufWriter,
+    path::Path,
 };
 
-mod css;
-mod dom;
-mod html;
-mod layout;
-mod painting;
-mod style;
+use owl_panda::{
+    css,
+    diagnostics::Diagnostic,
+    dom::{self, Document, NodeType},
+    html, layout, markdown, painting, style, xml,
+};
 
 fn main() {
     let mut opts = getopts::Options::new();
-    opts.optopt("h", "html", "HTML Document", "[FILENAME]");
+    opts.optopt("h", "html", "HTML, XML or Markdown Document", "[FILENAME]");
     opts.optopt("c", "css", "CSS Document", "[FILENAME]");
     opts.optopt("o", "output", "Output file", "[FILENAME]");
+    opts.optflag(
+        "x",
+        "xml",
+        "Parse the document as XML, the default for .xhtml, .xht and .xml files",
+    );
 
     let matches = opts.parse(std::env::args().skip(1)).unwrap();
+    let parse_as_xml = matches.opt_present("x");
+    if matches
+        .free
+        .first()
+        .is_some_and(|command| command == "diff")
+    {
+        match &matches.free[1..] {
+            [old_file, new_file] => diff(old_file, new_file, parse_as_xml),
+            _ => eprintln!("usage: owl-panda diff OLD.html NEW.html"),
+        }
+        return;
+    }
     let str_args = |flag: &str, default: &str| -> String {
         matches.opt_str(flag).unwrap_or(default.to_string())
     };
 
     // Read input files
-    let html = read_source(str_args("h", "files/test.html"));
-    let author_css = read_source(str_args("c", "files/test.css"));
-    let user_css = read_source(String::from("files/default.css"));
+    // The author style sheet is optional, pages can bring their own in <style> elements.
+    // Only the bundled test page is paired with test.css by default.
+    let html_file = str_args("h", "files/test.html");
+    let author_css_file = matches
+        .opt_str("c")
+        .or_else(|| (!matches.opt_present("h")).then(|| String::from("files/test.css")));
+    let user_css_file = String::from("files/default.css");
+    let user_css = read_source(user_css_file.clone());
 
     // setup a viewport due to lack of actual window
     let mut viewport: layout::Dimensions = Default::default();
@@ -32,12 +55,41 @@ fn main() {
     viewport.content.height = 600.0;
 
     // Parsing & rendering
-    let root_node = html::parse(html);
-    let author_rules = css::parse(author_css, css::CSSOrigin::Author);
-    let user_rules = css::parse(user_css, css::CSSOrigin::User);
-    let stylesheets = vec![author_rules, user_rules];
-    let style_root = style::style_tree(&root_node, &stylesheets, None);
-    let layout_root = layout::layout_tree(&style_root, viewport);
+    let document = parse_document(&html_file, parse_as_xml);
+    let mut stylesheets = Vec::new();
+    if let Some(author_css_file) = author_css_file {
+        let author_css = read_source(author_css_file.clone());
+        let (author_rules, author_diagnostics) = css::parse(author_css, css::CSSOrigin::Author);
+        report(&author_css_file, &author_diagnostics);
+        stylesheets.push(author_rules);
+    }
+    for source in style::style_sources(&document) {
+        let (css_file, css) = match source {
+            style::StyleSource::Inline(text) => (format!("{} <style>", html_file), text),
+            style::StyleSource::External(href) => {
+                let css_file = resolve_href(&html_file, &href);
+                // sheets without an encoding of their own are in the encoding of the page
+                match fs::read(&css_file) {
+                    Ok(css) => (css_file, css::decode(&css, Some(document.encoding()))),
+                    Err(err) => {
+                        eprintln!(
+                            "{}: warning: skipping style sheet {}: {}",
+                            html_file, href, err
+                        );
+                        continue;
+                    }
+                }
+            }
+        };
+        let (rules, diagnostics) = css::parse(css, css::CSSOrigin::Author);
+        report(&css_file, &diagnostics);
+        stylesheets.push(rules);
+    }
+    let (user_rules, user_diagnostics) = css::parse(user_css, css::CSSOrigin::User);
+    report(&user_css_file, &user_diagnostics);
+    stylesheets.push(user_rules);
+    let style_root = style::style_tree(&document, document.root(), &stylesheets, None);
+    let layout_root = layout::layout_tree(&document, &style_root, viewport);
 
     // create output file
     let filename = str_args("o", "output.png");
@@ -60,6 +112,101 @@ fn main() {
     }
 }
 
+// Parse a page as HTML, or as XML when `xml` is set or the file has an XML extension.
+// Markdown files become the HTML page they stand for.
+// Problems are reported, an XML document is rendered as far as it could be parsed.
+fn parse_document(file_path: &str, parse_as_xml: bool) -> Document {
+    let extension = Path::new(file_path)
+        .extension()
+        .and_then(|extension| extension.to_str());
+    let parse_as_xml = parse_as_xml || matches!(extension, Some("xhtml" | "xht" | "xml"));
+    let file = File::open(file_path).expect("File should exists");
+    // an html file is parsed as it is read, it is never held in memory as a whole
+    let (document, diagnostics) = if parse_as_xml {
+        xml::parse_reader(file)
+    } else if matches!(extension, Some("md" | "markdown")) {
+        markdown::parse_reader(file).map(|document| (document, Vec::new()))
+    } else {
+        html::parse_reader(file)
+    }
+    .expect("File should be readable");
+    report(file_path, &diagnostics);
+    document
+}
+
+// Print what changed between two pages, one edit per line with the node it applies to
+fn diff(old_file: &str, new_file: &str, parse_as_xml: bool) {
+    let old = parse_document(old_file, parse_as_xml);
+    let new = parse_document(new_file, parse_as_xml);
+    for edit in dom::diff(&old, &new) {
+        let (document, node) = match &edit {
+            dom::Edit::Remove { node, .. } => (&old, *node),
+            dom::Edit::Insert { node, .. }
+            | dom::Edit::Move { node, .. }
+            | dom::Edit::Attribute { node, .. }
+            | dom::Edit::Text { node, .. } => (&new, *node),
+        };
+        println!("{} {}", edit, node_name(document, node));
+    }
+}
+
+fn node_name(document: &Document, node: dom::NodeId) -> String {
+    match &document[node].node_type {
+        NodeType::Element(element) => format!("<{}>", element.tag_name),
+        NodeType::Text(_) => String::from("#text"),
+        NodeType::Comment(_) => String::from("#comment"),
+        NodeType::Document(_) => String::from("#document"),
+    }
+}
+
+// Print parse problems to stderr as `file:line:column: severity[code]: message`
+fn report(file_path: &str, diagnostics: &[Diagnostic]) {
+    for diagnostic in diagnostics {
+        eprintln!("{}:{}", file_path, diagnostic);
+    }
+}
+
+// Linked style sheets are looked up relative to the directory of the html file,
+// the query and fragment of the href are dropped.
+// That directory is the root of the site, a root-relative href like `/static/site.css` doesn't
+// leave it.
+fn resolve_href(html_file: &str, href: &str) -> String {
+    let path = href.split(['?', '#']).next().unwrap_or(href);
+    let path = percent_decode(path.trim_start_matches('/'));
+    Path::new(html_file)
+        .parent()
+        .unwrap_or(Path::new(""))
+        .join(path)
+        .to_string_lossy()
+        .into_owned()
+}
+
+// Decode the `%XX` escapes of a URL path, a `%` not followed by two hex digits is kept as it is
+fn percent_decode(path: &str) -> String {
+    let bytes = path.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escape = bytes
+            .get(i + 1..i + 3)
+            .filter(|hex| hex.iter().all(u8::is_ascii_hexdigit));
+        match escape {
+            Some(hex) if bytes[i] == b'%' => {
+                let hex = std::str::from_utf8(hex).unwrap();
+                decoded.push(u8::from_str_radix(hex, 16).unwrap());
+                i += 3;
+            }
+            _ => {
+                decoded.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+// Style sheets given by path are decoded on their own, they have no page to take an encoding from
 fn read_source(file_path: String) -> String {
-    fs::read_to_string(file_path).expect("File should exists")
+    let bytes = fs::read(file_path).expect("File should exists");
+    css::decode(&bytes, None)
 }
@@ -0,0 +1,1970 @@
+// Markdown parser that builds a DOM, following CommonMark
+// https://spec.commonmark.org/0.31.2/
+// Supported syntax:
+// 1. ATX headings (# Title), setext headings (a line underlined with = or -), paragraphs and
+//    thematic breaks (***, ---, ___)
+// 2. Block quotes and bullet or ordered lists, which can be nested. Lists without blank lines
+//    between their items are tight, the paragraphs of their items aren't wrapped in <p>.
+// 3. Indented and fenced code blocks, the first word of a fence's info string becomes a
+//    `language-` class of the <code> element
+// 4. Emphasis and strong emphasis with * and _, code spans, hard and soft line breaks
+// 5. Inline links and images, reference links with link reference definitions, autolinks
+// 6. Backslash escapes, and named and numeric character references
+// 7. Tables as in GitHub Flavored Markdown, a header row and a delimiter row like | :-- | --: |
+//    with the alignment of the columns, then a row per line until a blank line
+// Raw HTML isn't supported, it is kept as text.
+// The blocks are parsed first, then the inlines of every paragraph, heading and table cell are
+// parsed into the elements built for them. The document has html, head and body elements like a
+// parsed HTML page, with the content in body, so it is styled and laid out like one.
+
+use std::{
+    collections::HashMap,
+    io::{self, Read},
+};
+
+use crate::{
+    diagnostics::{Location, Span},
+    dom::{AttrMap, Document, DocumentData, DocumentKind, NodeId, NodeType, QuirksMode, MAX_DEPTH},
+    encoding::{self, Encoding},
+    html,
+};
+
+// Columns of indentation that start an indented code block
+const CODE_INDENT: usize = 4;
+// Link labels can't be longer than this, in bytes between the brackets
+const MAX_LABEL_LENGTH: usize = 999;
+// Unescaped parentheses a link destination can be nested in
+const MAX_LINK_PARENS: usize = 32;
+
+struct Block {
+    kind: BlockKind,
+    // the document is its own parent
+    parent: usize,
+    children: Vec<usize>,
+    open: bool,
+    // the last line of the block was blank, lists with blank lines between items are loose
+    last_line_blank: bool,
+    // lines of paragraphs, headings and code blocks, each ends with a newline
+    content: String,
+    start: Location,
+    end: Location,
+}
+
+enum BlockKind {
+    Document,
+    BlockQuote,
+    List { marker: ListMarker, tight: bool },
+    Item(ListMarker),
+    Paragraph,
+    Heading(usize),
+    ThematicBreak,
+    // fenced code blocks have a fence, indented ones don't
+    CodeBlock(Option<Fence>),
+    Table(Table),
+}
+
+#[derive(Clone, Copy)]
+struct ListMarker {
+    // '-', '+' or '*' of a bullet list, '.' or ')' after the number of an ordered list
+    symbol: char,
+    ordered: bool,
+    start: u32,
+    // columns before the marker, and from the marker to the content of the item
+    marker_offset: usize,
+    padding: usize,
+}
+
+struct Fence {
+    symbol: char,
+    length: usize,
+    // indentation of the opening fence, removed from the lines of the block
+    indent: usize,
+}
+
+struct Table {
+    // text-align of every column, None when the delimiter row doesn't give one
+    alignments: Vec<Option<&'static str>>,
+    header: Vec<String>,
+    rows: Vec<Vec<String>>,
+}
+
+// Destination and title of a link reference definition `[label]: destination "title"`
+struct LinkReference {
+    destination: String,
+    title: Option<String>,
+}
+
+// What happened to an open block when a line was matched against it
+enum Continuation {
+    Matched,
+    Failed,
+    // the line closed the block, a closing code fence, nothing else is on it
+    Finished,
+}
+
+// A block started on a line, containers can hold more blocks started on the same line
+enum Start {
+    Container,
+    Leaf,
+}
+
+struct BlockParser<'a> {
+    blocks: Vec<Block>,
+    // deepest open block
+    tip: usize,
+    // tip before the current line
+    old_tip: usize,
+    // deepest block continued by the current line
+    last_matched_container: usize,
+    all_closed: bool,
+    refs: HashMap<String, LinkReference>,
+    line: &'a str,
+    line_number: usize,
+    // byte offset of the line in the source
+    line_start: usize,
+    // where every line ends, used for the spans of the blocks
+    line_ends: Vec<Location>,
+    // byte offset in the line and the column it is at, tabs go to the next multiple of 4
+    offset: usize,
+    column: usize,
+    next_nonspace: usize,
+    next_nonspace_column: usize,
+    indent: usize,
+    indented: bool,
+    blank: bool,
+    // only some of the columns of the tab at offset were consumed
+    partially_consumed_tab: bool,
+}
+
+// Parse a Markdown document. Any text is valid Markdown, so there are no diagnostics.
+pub fn parse(source: String) -> Document {
+    parse_with_encoding(source, Encoding::Utf8)
+}
+
+// Parse a Markdown document read from `reader`, it is UTF-8 unless it starts with a byte order mark
+pub fn parse_reader(mut reader: impl Read) -> io::Result<Document> {
+    let mut bytes = Vec::new();
+    reader.read_to_end(&mut bytes)?;
+    let encoding = encoding::sniff_bom(&bytes).map_or(Encoding::Utf8, |(encoding, _)| encoding);
+    let source = encoding::decode(&bytes, encoding);
+    drop(bytes);
+    Ok(parse_with_encoding(source, encoding))
+}
+
+fn parse_with_encoding(source: String, encoding: Encoding) -> Document {
+    let source = if source.contains('\0') {
+        source.replace('\0', "\u{FFFD}")
+    } else {
+        source
+    };
+    let mut parser = BlockParser {
+        blocks: vec![Block::new(BlockKind::Document, 0, Location::START)],
+        tip: 0,
+        old_tip: 0,
+        last_matched_container: 0,
+        all_closed: true,
+        refs: HashMap::new(),
+        line: "",
+        line_number: 0,
+        line_start: 0,
+        line_ends: Vec::new(),
+        offset: 0,
+        column: 0,
+        next_nonspace: 0,
+        next_nonspace_column: 0,
+        indent: 0,
+        indented: false,
+        blank: false,
+        partially_consumed_tab: false,
+    };
+    let mut line_start = 0;
+    while line_start < source.len() {
+        let rest = &source[line_start..];
+        let (len, ending) = match rest.find(['\n', '\r']) {
+            Some(i) if rest[i..].starts_with("\r\n") => (i, 2),
+            Some(i) => (i, 1),
+            None => (rest.len(), 0),
+        };
+        parser.incorporate_line(&rest[..len], line_start);
+        line_start += len + ending;
+    }
+    loop {
+        let tip = parser.tip;
+        parser.finalize(tip, parser.line_number);
+        if tip == 0 {
+            break;
+        }
+    }
+
+    let mut document = Document::new(DocumentData {
+        kind: DocumentKind::Html,
+        doctype: None,
+        quirks_mode: QuirksMode::NoQuirks,
+        encoding,
+    });
+    build_document(&mut document, &parser.blocks, &parser.refs);
+    limit_depth(&mut document);
+    document
+}
+
+impl Block {
+    fn new(kind: BlockKind, parent: usize, start: Location) -> Block {
+        Block {
+            kind,
+            parent,
+            children: Vec::new(),
+            open: true,
+            last_line_blank: false,
+            content: String::new(),
+            start,
+            end: start,
+        }
+    }
+
+    fn can_contain(&self, kind: &BlockKind) -> bool {
+        match self.kind {
+            BlockKind::Document | BlockKind::BlockQuote | BlockKind::Item(_) => {
+                !matches!(kind, BlockKind::Item(_))
+            }
+            BlockKind::List { .. } => matches!(kind, BlockKind::Item(_)),
+            _ => false,
+        }
+    }
+
+    // The text of the following lines is added to the block
+    fn accepts_lines(&self) -> bool {
+        matches!(
+            self.kind,
+            BlockKind::Paragraph | BlockKind::CodeBlock(_) | BlockKind::Table(_)
+        )
+    }
+}
+
+impl<'a> BlockParser<'a> {
+    // Add a line to the tree of blocks: continue the open blocks it matches, close the ones it
+    // doesn't, start new ones and add the rest of the line to the innermost one
+    fn incorporate_line(&mut self, line: &'a str, line_start: usize) {
+        self.line = line;
+        self.line_start = line_start;
+        self.line_number += 1;
+        self.line_ends.push(Location {
+            offset: line_start + line.len(),
+            line: self.line_number,
+            column: line.chars().count() + 1,
+        });
+        self.offset = 0;
+        self.column = 0;
+        self.next_nonspace = 0;
+        self.next_nonspace_column = 0;
+        self.blank = false;
+        self.partially_consumed_tab = false;
+        self.old_tip = self.tip;
+
+        let mut container = 0;
+        while let Some(&last) = self.blocks[container].children.last() {
+            if !self.blocks[last].open {
+                break;
+            }
+            container = last;
+            self.find_next_nonspace();
+            match self.continue_block(container) {
+                Continuation::Matched => {}
+                Continuation::Failed => {
+                    container = self.blocks[container].parent;
+                    break;
+                }
+                Continuation::Finished => return,
+            }
+        }
+        self.all_closed = container == self.old_tip;
+        self.last_matched_container = container;
+
+        let mut matched_leaf = !matches!(
+            self.blocks[container].kind,
+            BlockKind::Paragraph | BlockKind::Table(_)
+        ) && self.blocks[container].accepts_lines();
+        while !matched_leaf {
+            self.find_next_nonspace();
+            let maybe_special = self.line[self.next_nonspace..].starts_with([
+                '#', '`', '~', '*', '+', '_', '=', '<', '>', '-', '|', ':', '0', '1', '2', '3',
+                '4', '5', '6', '7', '8', '9',
+            ]);
+            if !self.indented && !maybe_special {
+                self.advance_next_nonspace();
+                break;
+            }
+            match self.start_block(container) {
+                Some(Start::Container) => container = self.tip,
+                Some(Start::Leaf) => {
+                    container = self.tip;
+                    matched_leaf = true;
+                }
+                None => {
+                    self.advance_next_nonspace();
+                    break;
+                }
+            }
+        }
+
+        // a line that continues a paragraph without the markers of its containers is a lazy
+        // continuation line
+        if !self.all_closed
+            && !self.blank
+            && matches!(self.blocks[self.tip].kind, BlockKind::Paragraph)
+        {
+            self.add_line();
+            return;
+        }
+        self.close_unmatched_blocks();
+        if self.blank {
+            if let Some(&last) = self.blocks[container].children.last() {
+                self.blocks[last].last_line_blank = true;
+            }
+        }
+        let block = &self.blocks[container];
+        let last_line_blank = self.blank
+            && !match block.kind {
+                BlockKind::BlockQuote | BlockKind::CodeBlock(Some(_)) => true,
+                BlockKind::Item(_) => {
+                    block.children.is_empty() && block.start.line == self.line_number
+                }
+                _ => false,
+            };
+        let mut current = container;
+        loop {
+            self.blocks[current].last_line_blank = last_line_blank;
+            if current == 0 {
+                break;
+            }
+            current = self.blocks[current].parent;
+        }
+
+        if self.blocks[container].accepts_lines() {
+            self.add_line();
+        } else if self.offset < self.line.len() && !self.blank {
+            self.add_child(BlockKind::Paragraph, self.next_nonspace);
+            self.advance_next_nonspace();
+            self.add_line();
+        }
+    }
+
+    // Match the current line against an open block and consume its markers
+    fn continue_block(&mut self, block: usize) -> Continuation {
+        match &self.blocks[block].kind {
+            BlockKind::Document | BlockKind::List { .. } => Continuation::Matched,
+            BlockKind::BlockQuote => {
+                if !self.indented && self.peek(self.next_nonspace) == Some('>') {
+                    self.advance_next_nonspace();
+                    self.advance_offset(1, false);
+                    if matches!(self.peek(self.offset), Some(' ' | '\t')) {
+                        self.advance_offset(1, true);
+                    }
+                    Continuation::Matched
+                } else {
+                    Continuation::Failed
+                }
+            }
+            BlockKind::Item(marker) => {
+                let content_indent = marker.marker_offset + marker.padding;
+                if self.blank {
+                    // an item can't start with two blank lines
+                    if self.blocks[block].children.is_empty() {
+                        return Continuation::Failed;
+                    }
+                    self.advance_next_nonspace();
+                } else if self.indent >= content_indent {
+                    self.advance_offset(content_indent, true);
+                } else {
+                    return Continuation::Failed;
+                }
+                Continuation::Matched
+            }
+            BlockKind::Heading(_) | BlockKind::ThematicBreak => Continuation::Failed,
+            BlockKind::CodeBlock(Some(fence)) => {
+                let (symbol, length, mut indent) = (fence.symbol, fence.length, fence.indent);
+                let rest = &self.line[self.next_nonspace..];
+                let closing_length = rest.len() - rest.trim_start_matches(symbol).len();
+                if self.indent < CODE_INDENT
+                    && closing_length >= length.max(3)
+                    && rest[closing_length..]
+                        .trim_start_matches([' ', '\t'])
+                        .is_empty()
+                {
+                    self.finalize(block, self.line_number);
+                    return Continuation::Finished;
+                }
+                while indent > 0 && matches!(self.peek(self.offset), Some(' ' | '\t')) {
+                    self.advance_offset(1, true);
+                    indent -= 1;
+                }
+                Continuation::Matched
+            }
+            BlockKind::CodeBlock(None) => {
+                if self.indent >= CODE_INDENT {
+                    self.advance_offset(CODE_INDENT, true);
+                } else if self.blank {
+                    self.advance_next_nonspace();
+                } else {
+                    return Continuation::Failed;
+                }
+                Continuation::Matched
+            }
+            BlockKind::Paragraph | BlockKind::Table(_) => {
+                if self.blank {
+                    Continuation::Failed
+                } else {
+                    Continuation::Matched
+                }
+            }
+        }
+    }
+
+    // Start a new block at the first non-space character of the line if there is one
+    fn start_block(&mut self, container: usize) -> Option<Start> {
+        let rest = &self.line[self.next_nonspace..];
+        let in_paragraph = matches!(self.blocks[container].kind, BlockKind::Paragraph);
+        let in_list = matches!(self.blocks[container].kind, BlockKind::List { .. });
+
+        // block quote
+        if !self.indented && rest.starts_with('>') {
+            self.advance_next_nonspace();
+            self.advance_offset(1, false);
+            if matches!(self.peek(self.offset), Some(' ' | '\t')) {
+                self.advance_offset(1, true);
+            }
+            self.close_unmatched_blocks();
+            self.add_child(BlockKind::BlockQuote, self.next_nonspace);
+            return Some(Start::Container);
+        }
+
+        // ATX heading
+        let hashes = rest.len() - rest.trim_start_matches('#').len();
+        if !self.indented
+            && (1..=6).contains(&hashes)
+            && (rest.len() == hashes || rest[hashes..].starts_with([' ', '\t']))
+        {
+            self.advance_next_nonspace();
+            self.advance_offset(hashes, false);
+            self.close_unmatched_blocks();
+            let heading = self.add_child(BlockKind::Heading(hashes), self.next_nonspace);
+            let text = strip_closing_sequence(&self.line[self.offset..]);
+            self.blocks[heading].content = text.to_string();
+            self.advance_offset(self.line.len() - self.offset, false);
+            return Some(Start::Leaf);
+        }
+
+        // fenced code block
+        if let Some(symbol @ ('`' | '~')) = rest.chars().next() {
+            let length = rest.len() - rest.trim_start_matches(symbol).len();
+            if !self.indented && length >= 3 && !(symbol == '`' && rest[length..].contains('`')) {
+                self.close_unmatched_blocks();
+                let fence = Fence {
+                    symbol,
+                    length,
+                    indent: self.indent,
+                };
+                self.add_child(BlockKind::CodeBlock(Some(fence)), self.next_nonspace);
+                self.advance_next_nonspace();
+                self.advance_offset(length, false);
+                return Some(Start::Leaf);
+            }
+        }
+
+        // setext heading, the paragraph above the underline becomes the heading
+        if !self.indented && in_paragraph {
+            if let Some(symbol @ ('=' | '-')) = rest.chars().next() {
+                let underline = rest.trim_end_matches([' ', '\t']);
+                if underline.chars().all(|c| c == symbol) {
+                    self.close_unmatched_blocks();
+                    self.parse_references(container);
+                    if !self.blocks[container].content.is_empty() {
+                        let level = if symbol == '=' { 1 } else { 2 };
+                        self.blocks[container].kind = BlockKind::Heading(level);
+                        self.advance_offset(self.line.len() - self.offset, false);
+                        return Some(Start::Leaf);
+                    }
+                }
+            }
+        }
+
+        // table, the last line of the paragraph above the delimiter row is its header
+        if !self.indented && in_paragraph && rest.contains('|') {
+            if let Some(table) = self.start_table(container) {
+                return Some(table);
+            }
+        }
+
+        // thematic break
+        if !self.indented && is_thematic_break(rest) {
+            self.close_unmatched_blocks();
+            self.add_child(BlockKind::ThematicBreak, self.next_nonspace);
+            self.advance_offset(self.line.len() - self.offset, false);
+            return Some(Start::Leaf);
+        }
+
+        // list item
+        if !self.indented || in_list {
+            if let Some(marker) = self.parse_list_marker(in_paragraph) {
+                self.close_unmatched_blocks();
+                let same_list = match self.blocks[self.tip].kind {
+                    BlockKind::List { marker: list, .. } => {
+                        list.ordered == marker.ordered && list.symbol == marker.symbol
+                    }
+                    _ => false,
+                };
+                if !same_list {
+                    let list = BlockKind::List {
+                        marker,
+                        tight: true,
+                    };
+                    self.add_child(list, self.next_nonspace);
+                }
+                self.add_child(BlockKind::Item(marker), self.next_nonspace);
+                return Some(Start::Container);
+            }
+        }
+
+        // indented code block, it can't interrupt a paragraph
+        if self.indented
+            && !self.blank
+            && !matches!(
+                self.blocks[self.tip].kind,
+                BlockKind::Paragraph | BlockKind::Table(_)
+            )
+        {
+            self.advance_offset(CODE_INDENT, true);
+            self.close_unmatched_blocks();
+            self.add_child(BlockKind::CodeBlock(None), self.offset);
+            return Some(Start::Leaf);
+        }
+
+        None
+    }
+
+    // Turn the last line of a paragraph into the header of a table if the current line is a
+    // delimiter row with as many cells
+    fn start_table(&mut self, paragraph: usize) -> Option<Start> {
+        let delimiters = split_row(&self.line[self.next_nonspace..]);
+        let alignments = delimiters
+            .iter()
+            .map(|cell| column_alignment(cell))
+            .collect::<Option<Vec<_>>>()?;
+        let content = &self.blocks[paragraph].content;
+        let lines = content.strip_suffix('\n').unwrap_or(content);
+        let header_start = lines.rfind('\n').map_or(0, |i| i + 1);
+        let header_len = lines.len() - header_start;
+        let header = split_row(&lines[header_start..]);
+        if header.len() != alignments.len() {
+            return None;
+        }
+
+        self.close_unmatched_blocks();
+        let table = BlockKind::Table(Table {
+            alignments,
+            header,
+            rows: Vec::new(),
+        });
+        if header_start == 0 {
+            let block = &mut self.blocks[paragraph];
+            block.kind = table;
+            block.content.clear();
+        } else {
+            // the lines above the header stay a paragraph
+            self.blocks[paragraph].content.truncate(header_start);
+            let line_number = self.line_number - 1;
+            self.finalize(paragraph, line_number);
+            let start = self.line_ends[line_number - 1];
+            let table = self.add_child(table, 0);
+            self.blocks[table].start = Location {
+                offset: start.offset - header_len,
+                column: 1,
+                ..start
+            };
+        }
+        self.advance_offset(self.line.len() - self.offset, false);
+        Some(Start::Leaf)
+    }
+
+    // A list marker at the first non-space character and the columns it takes
+    fn parse_list_marker(&mut self, in_paragraph: bool) -> Option<ListMarker> {
+        if self.indent >= CODE_INDENT {
+            return None;
+        }
+        let rest = &self.line[self.next_nonspace..];
+        let digits = rest.len() - rest.trim_start_matches(|c: char| c.is_ascii_digit()).len();
+        let (marker, marker_len) = match rest[digits..].chars().next() {
+            Some(symbol @ ('*' | '+' | '-')) if digits == 0 => (
+                ListMarker {
+                    symbol,
+                    ordered: false,
+                    start: 1,
+                    marker_offset: self.indent,
+                    padding: 0,
+                },
+                1,
+            ),
+            // an ordered list can only interrupt a paragraph if it starts with 1
+            Some(symbol @ ('.' | ')'))
+                if (1..=9).contains(&digits) && (!in_paragraph || &rest[..digits] == "1") =>
+            {
+                (
+                    ListMarker {
+                        symbol,
+                        ordered: true,
+                        start: rest[..digits].parse().ok()?,
+                        marker_offset: self.indent,
+                        padding: 0,
+                    },
+                    digits + 1,
+                )
+            }
+            _ => return None,
+        };
+        // the marker has to be followed by whitespace, an empty item can't interrupt a paragraph
+        let after = &rest[marker_len..];
+        if !(after.is_empty() || after.starts_with([' ', '\t'])) {
+            return None;
+        }
+        if in_paragraph && after.trim_matches([' ', '\t']).is_empty() {
+            return None;
+        }
+
+        self.advance_next_nonspace();
+        self.advance_offset(marker_len, true);
+        let spaces_start_column = self.column;
+        let spaces_start_offset = self.offset;
+        loop {
+            self.advance_offset(1, true);
+            let next = self.peek(self.offset);
+            if self.column - spaces_start_column >= 5 || !matches!(next, Some(' ' | '\t')) {
+                break;
+            }
+        }
+        let blank_item = self.peek(self.offset).is_none();
+        let spaces_after_marker = self.column - spaces_start_column;
+        let padding = if !(1..5).contains(&spaces_after_marker) || blank_item {
+            // the content starts right after the marker and one space, more spaces are part of
+            // an indented code block
+            self.column = spaces_start_column;
+            self.offset = spaces_start_offset;
+            self.partially_consumed_tab = false;
+            if matches!(self.peek(self.offset), Some(' ' | '\t')) {
+                self.advance_offset(1, true);
+            }
+            marker_len + 1
+        } else {
+            marker_len + spaces_after_marker
+        };
+        Some(ListMarker { padding, ..marker })
+    }
+
+    // Add the rest of the line to the innermost open block
+    fn add_line(&mut self) {
+        let tip = &mut self.blocks[self.tip];
+        if self.partially_consumed_tab {
+            // the rest of the tab becomes spaces
+            self.offset += 1;
+            let columns = 4 - self.column % 4;
+            tip.content.extend(std::iter::repeat_n(' ', columns));
+        }
+        let text = &self.line[self.offset..];
+        match &mut tip.kind {
+            // the delimiter row leaves nothing to add
+            BlockKind::Table(table) => {
+                if !text.is_empty() {
+                    table.rows.push(split_row(text));
+                }
+            }
+            _ => {
+                tip.content.push_str(text);
+                tip.content.push('\n');
+            }
+        }
+    }
+
+    // Add a block to the innermost block that can contain it, closing the blocks that can't
+    fn add_child(&mut self, kind: BlockKind, offset: usize) -> usize {
+        while !self.blocks[self.tip].can_contain(&kind) {
+            self.finalize(self.tip, self.line_number - 1);
+        }
+        let start = Location {
+            offset: self.line_start + offset,
+            line: self.line_number,
+            column: self.line[..offset].chars().count() + 1,
+        };
+        let id = self.blocks.len();
+        self.blocks.push(Block::new(kind, self.tip, start));
+        self.blocks[self.tip].children.push(id);
+        self.tip = id;
+        id
+    }
+
+    // Close the blocks the current line didn't continue
+    fn close_unmatched_blocks(&mut self) {
+        if !self.all_closed {
+            while self.old_tip != self.last_matched_container {
+                let parent = self.blocks[self.old_tip].parent;
+                self.finalize(self.old_tip, self.line_number - 1);
+                self.old_tip = parent;
+            }
+            self.all_closed = true;
+        }
+    }
+
+    // Close a block that ended on line `line_number`, its parent becomes the tip
+    fn finalize(&mut self, id: usize, line_number: usize) {
+        let parent = self.blocks[id].parent;
+        let block = &mut self.blocks[id];
+        block.open = false;
+        if line_number >= block.start.line {
+            block.end = self.line_ends[line_number - 1];
+        }
+        match &mut block.kind {
+            BlockKind::Paragraph => {
+                // a paragraph of only link reference definitions goes away
+                let has_references = self.parse_references(id);
+                if has_references && self.blocks[id].content.is_empty() {
+                    self.blocks[parent].children.retain(|&child| child != id);
+                }
+            }
+            BlockKind::CodeBlock(None) => {
+                // trailing blank lines aren't part of an indented code block
+                let mut lines: Vec<&str> = block.content.lines().collect();
+                while lines.len() > 1
+                    && lines
+                        .last()
+                        .is_some_and(|line| line.trim_start_matches(' ').is_empty())
+                {
+                    lines.pop();
+                }
+                let mut content = lines.join("\n");
+                content.push('\n');
+                block.content = content;
+            }
+            BlockKind::List { .. } => {
+                let is_tight = self.is_tight_list(id);
+                if let BlockKind::List { tight, .. } = &mut self.blocks[id].kind {
+                    *tight = is_tight;
+                }
+            }
+            _ => {}
+        }
+        self.tip = parent;
+    }
+
+    // A list is loose if any of its items are separated by blank lines, or if any item directly
+    // contains two blocks with a blank line between them
+    fn is_tight_list(&self, list: usize) -> bool {
+        let items = &self.blocks[list].children;
+        for (i, &item) in items.iter().enumerate() {
+            let last_item = i + 1 == items.len();
+            if !last_item && self.ends_with_blank_line(item) {
+                return false;
+            }
+            let children = &self.blocks[item].children;
+            for (j, &child) in children.iter().enumerate() {
+                let last_child = j + 1 == children.len();
+                if (!last_item || !last_child) && self.ends_with_blank_line(child) {
+                    return false;
+                }
+            }
+        }
+        true
+    }
+
+    // The block or the last of its nested lists and items ends with a blank line
+    fn ends_with_blank_line(&self, mut id: usize) -> bool {
+        loop {
+            let block = &self.blocks[id];
+            if block.last_line_blank {
+                return true;
+            }
+            match (&block.kind, block.children.last()) {
+                (BlockKind::List { .. } | BlockKind::Item(_), Some(&last)) => id = last,
+                _ => return false,
+            }
+        }
+    }
+
+    // Take the link reference definitions at the start of a paragraph, returns whether there were
+    // any. The content of the paragraph is what comes after them.
+    fn parse_references(&mut self, paragraph: usize) -> bool {
+        let content = std::mem::take(&mut self.blocks[paragraph].content);
+        let mut rest = content.as_str();
+        let mut found = false;
+        while rest.starts_with('[') {
+            match parse_reference(rest, &mut self.refs) {
+                Some(len) => {
+                    rest = &rest[len..];
+                    found = true;
+                }
+                None => break,
+            }
+        }
+        self.blocks[paragraph].content = if rest.trim().is_empty() {
+            String::new()
+        } else {
+            rest.to_string()
+        };
+        found
+    }
+
+    fn peek(&self, offset: usize) -> Option<char> {
+        self.line[offset..].chars().next()
+    }
+
+    // Find the first non-space character from the current offset and how indented it is
+    // The whitespace before `next_nonspace` was already scanned, so the indentation of a line isn't
+    // scanned again for every container it continues
+    fn find_next_nonspace(&mut self) {
+        let (mut offset, mut column) = if self.offset <= self.next_nonspace {
+            (self.next_nonspace, self.next_nonspace_column)
+        } else {
+            (self.offset, self.column)
+        };
+        for c in self.line[offset..].chars() {
+            match c {
+                ' ' => column += 1,
+                '\t' => column += 4 - column % 4,
+                _ => break,
+            }
+            offset += 1;
+        }
+        self.blank = offset == self.line.len();
+        self.next_nonspace = offset;
+        self.next_nonspace_column = column;
+        self.indent = column - self.column;
+        self.indented = self.indent >= CODE_INDENT;
+    }
+
+    fn advance_next_nonspace(&mut self) {
+        self.offset = self.next_nonspace;
+        self.column = self.next_nonspace_column;
+        self.partially_consumed_tab = false;
+    }
+
+    // Move `count` characters forward, or `count` columns when `columns` is set. Moving by columns
+    // can stop in the middle of a tab.
+    fn advance_offset(&mut self, mut count: usize, columns: bool) {
+        while count > 0 {
+            let Some(c) = self.peek(self.offset) else {
+                break;
+            };
+            if c == '\t' {
+                let to_tab_stop = 4 - self.column % 4;
+                if columns {
+                    self.partially_consumed_tab = to_tab_stop > count;
+                    let advance = to_tab_stop.min(count);
+                    self.column += advance;
+                    if !self.partially_consumed_tab {
+                        self.offset += 1;
+                    }
+                    count -= advance;
+                } else {
+                    self.partially_consumed_tab = false;
+                    self.column += to_tab_stop;
+                    self.offset += 1;
+                    count -= 1;
+                }
+            } else {
+                self.partially_consumed_tab = false;
+                self.offset += c.len_utf8();
+                self.column += 1;
+                count -= 1;
+            }
+        }
+    }
+}
+
+// The text of an ATX heading without the closing sequence of #s
+fn strip_closing_sequence(text: &str) -> &str {
+    let text = text.trim_end_matches([' ', '\t']);
+    let without_hashes = text.trim_end_matches('#');
+    if without_hashes.len() == text.len() {
+        text
+    } else if without_hashes.trim_matches([' ', '\t']).is_empty() {
+        ""
+    } else if without_hashes.ends_with([' ', '\t']) {
+        without_hashes
+    } else {
+        // a # right after the text is part of it
+        text
+    }
+}
+
+// Three or more *, - or _ and nothing else but spaces
+fn is_thematic_break(line: &str) -> bool {
+    let mut chars = line.chars().filter(|&c| c != ' ' && c != '\t');
+    let Some(symbol @ ('*' | '-' | '_')) = chars.next() else {
+        return false;
+    };
+    let mut count = 1;
+    for c in chars {
+        if c != symbol {
+            return false;
+        }
+        count += 1;
+    }
+    count >= 3
+}
+
+// Cells of a table row, the pipes at the start and end of the row are optional.
+// An escaped pipe \| is part of the cell.
+fn split_row(line: &str) -> Vec<String> {
+    let line = line.trim_matches([' ', '\t']);
+    let line = line.strip_prefix('|').unwrap_or(line);
+    let mut cells = Vec::new();
+    let mut cell = String::new();
+    let mut chars = line.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => match chars.next() {
+                Some('|') => cell.push('|'),
+                Some(escaped) => {
+                    cell.push('\\');
+                    cell.push(escaped);
+                }
+                None => cell.push('\\'),
+            },
+            '|' => cells.push(
+                std::mem::take(&mut cell)
+                    .trim_matches([' ', '\t'])
+                    .to_string(),
+            ),
+            c => cell.push(c),
+        }
+    }
+    if !cell.trim_matches([' ', '\t']).is_empty() || cells.is_empty() {
+        cells.push(cell.trim_matches([' ', '\t']).to_string());
+    }
+    cells
+}
+
+// Alignment given by a cell of a delimiter row like :--, --: or :-:, None if it isn't one
+fn column_alignment(cell: &str) -> Option<Option<&'static str>> {
+    let left = cell.starts_with(':');
+    let right = cell.len() > 1 && cell.ends_with(':');
+    let dashes = &cell[left as usize..cell.len() - right as usize];
+    if dashes.is_empty() || !dashes.chars().all(|c| c == '-') {
+        return None;
+    }
+    Some(match (left, right) {
+        (true, true) => Some("center"),
+        (true, false) => Some("left"),
+        (false, true) => Some("right"),
+        (false, false) => None,
+    })
+}
+
+// Parse a link reference definition at the start of `text` into `refs`, returns its length.
+// The first definition of a label wins.
+fn parse_reference(text: &str, refs: &mut HashMap<String, LinkReference>) -> Option<usize> {
+    let mut scanner = Scanner::new(text);
+    let label_len = scanner.parse_link_label();
+    if label_len == 0 {
+        return None;
+    }
+    let label = &text[1..label_len - 1];
+    if scanner.peek() != Some(':') {
+        return None;
+    }
+    scanner.pos += 1;
+    scanner.spnl();
+    let destination_start = scanner.pos;
+    let destination = scanner.parse_link_destination()?;
+    if destination.is_empty() && !text[destination_start..].starts_with('<') {
+        return None;
+    }
+
+    // the title has to be separated from the destination, and nothing but spaces can follow it
+    let before_title = scanner.pos;
+    scanner.spnl();
+    let mut title = None;
+    if scanner.pos != before_title {
+        title = scanner.parse_link_title();
+    }
+    if title.is_none() || !scanner.skip_to_line_end() {
+        title = None;
+        scanner.pos = before_title;
+        if !scanner.skip_to_line_end() {
+            return None;
+        }
+    }
+
+    let label = normalize_label(label);
+    if label.is_empty() {
+        return None;
+    }
+    refs.entry(label)
+        .or_insert(LinkReference { destination, title });
+    Some(scanner.pos)
+}
+
+// Labels match case-insensitively and with any whitespace between words
+fn normalize_label(label: &str) -> String {
+    label
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+        .to_lowercase()
+        .to_uppercase()
+}
+
+// Position in a piece of inline text, with the parts of links that references share
+struct Scanner<'a> {
+    subject: &'a str,
+    pos: usize,
+}
+
+impl<'a> Scanner<'a> {
+    fn new(subject: &'a str) -> Scanner<'a> {
+        Scanner { subject, pos: 0 }
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.subject[self.pos..].chars().next()
+    }
+
+    fn rest(&self) -> &'a str {
+        &self.subject[self.pos..]
+    }
+
+    // Skip spaces and at most one line ending
+    fn spnl(&mut self) {
+        self.skip_spaces();
+        if self.peek() == Some('\n') {
+            self.pos += 1;
+            self.skip_spaces();
+        }
+    }
+
+    fn skip_spaces(&mut self) {
+        let rest = self.rest();
+        self.pos += rest.len() - rest.trim_start_matches([' ', '\t']).len();
+    }
+
+    // Skip spaces up to the end of the line, false if something else comes first
+    fn skip_to_line_end(&mut self) -> bool {
+        self.skip_spaces();
+        match self.peek() {
+            None => true,
+            Some('\n') => {
+                self.pos += 1;
+                true
+            }
+            Some(_) => false,
+        }
+    }
+
+    // Length of the link label [...] at the current position, including the brackets, 0 if there
+    // is none. Brackets in labels have to be escaped.
+    fn parse_link_label(&mut self) -> usize {
+        let rest = self.rest();
+        if !rest.starts_with('[') {
+            return 0;
+        }
+        let mut chars = rest.char_indices().skip(1);
+        while let Some((i, c)) = chars.next() {
+            if i > MAX_LABEL_LENGTH + 1 {
+                return 0;
+            }
+            match c {
+                '\\' => {
+                    chars.next();
+                }
+                '[' => return 0,
+                ']' => {
+                    self.pos += i + 1;
+                    return i + 1;
+                }
+                _ => {}
+            }
+        }
+        0
+    }
+
+    // A link destination, either in <angle brackets> or without spaces and with balanced
+    // parentheses
+    fn parse_link_destination(&mut self) -> Option<String> {
+        let rest = self.rest();
+        if let Some(inner) = rest.strip_prefix('<') {
+            let mut chars = inner.char_indices();
+            while let Some((i, c)) = chars.next() {
+                match c {
+                    // the escaped character is skipped, it can't be a line ending
+                    '\\' if chars.next().is_some_and(|(_, c)| c == '\n') => return None,
+                    '\n' | '<' => return None,
+                    '>' => {
+                        self.pos += i + 2;
+                        return Some(unescape(&inner[..i]));
+                    }
+                    _ => {}
+                }
+            }
+            return None;
+        }
+
+        let mut parens = 0;
+        let mut len = rest.len();
+        let mut chars = rest.char_indices().peekable();
+        while let Some((i, c)) = chars.next() {
+            match c {
+                '\\' => {
+                    chars.next_if(|&(_, c)| c.is_ascii_punctuation());
+                }
+                '(' => {
+                    parens += 1;
+                    if parens > MAX_LINK_PARENS {
+                        return None;
+                    }
+                }
+                ')' if parens == 0 => {
+                    len = i;
+                    break;
+                }
+                ')' => parens -= 1,
+                c if c == ' ' || c.is_ascii_control() => {
+                    len = i;
+                    break;
+                }
+                _ => {}
+            }
+        }
+        if (len == 0 && !rest.starts_with(')')) || parens != 0 {
+            return None;
+        }
+        self.pos += len;
+        Some(unescape(&rest[..len]))
+    }
+
+    // A link title in "double quotes", 'single quotes' or (parentheses)
+    fn parse_link_title(&mut self) -> Option<String> {
+        let rest = self.rest();
+        let close = match rest.chars().next()? {
+            '"' => '"',
+            '\'' => '\'',
+            '(' => ')',
+            _ => return None,
+        };
+        let mut chars = rest.char_indices().skip(1);
+        while let Some((i, c)) = chars.next() {
+            match c {
+                '\\' => {
+                    chars.next();
+                }
+                c if c == close => {
+                    self.pos += i + 1;
+                    return Some(unescape(&rest[1..i]));
+                }
+                '(' if close == ')' => return None,
+                _ => {}
+            }
+        }
+        None
+    }
+}
+
+// Delimiter run of * or _ that can open or close emphasis
+struct Delimiter {
+    // text node with the characters that are left
+    node: NodeId,
+    symbol: char,
+    // characters not used for emphasis yet, and the length of the whole run
+    count: usize,
+    run_length: usize,
+    can_open: bool,
+    can_close: bool,
+    previous: Option<usize>,
+    next: Option<usize>,
+}
+
+// [ or ![ that can start a link or an image
+struct Bracket {
+    // text node with the bracket
+    node: NodeId,
+    image: bool,
+    // links can't contain other links, the brackets before a link are deactivated
+    active: bool,
+    // byte offset right after the bracket
+    index: usize,
+    // last delimiter before the bracket, emphasis in the link text stops there
+    previous_delimiter: Option<usize>,
+    // another bracket follows, so the text isn't a shortcut reference
+    bracket_after: bool,
+}
+
+struct InlineParser<'a> {
+    scanner: Scanner<'a>,
+    document: &'a mut Document,
+    refs: &'a HashMap<String, LinkReference>,
+    // element the inlines go into
+    container: NodeId,
+    // delimiters form a list from the oldest to the last one, removed ones are unlinked
+    delimiters: Vec<Delimiter>,
+    last_delimiter: Option<usize>,
+    brackets: Vec<Bracket>,
+    // elements built for the inlines, their text is merged at the end
+    elements: Vec<NodeId>,
+}
+
+// Parse the inlines of `text` and add them to `container`
+fn parse_inlines(
+    document: &mut Document,
+    refs: &HashMap<String, LinkReference>,
+    container: NodeId,
+    text: &str,
+) {
+    let first_new = document[container].last_child();
+    let mut parser = InlineParser {
+        scanner: Scanner::new(text.trim_matches(|c: char| c.is_ascii_whitespace())),
+        document,
+        refs,
+        container,
+        delimiters: Vec::new(),
+        last_delimiter: None,
+        brackets: Vec::new(),
+        elements: Vec::new(),
+    };
+    while let Some(c) = parser.scanner.peek() {
+        parser.parse_inline(c);
+    }
+    parser.process_emphasis(None);
+
+    let document = parser.document;
+    let first = match first_new {
+        Some(last) => document[last].next_sibling(),
+        None => document[container].first_child(),
+    };
+    merge_text(document, first);
+    for element in parser.elements {
+        let first = document[element].first_child();
+        merge_text(document, first);
+    }
+}
+
+// Join adjacent text nodes from `first` on and drop empty ones, the inline parser splits text at
+// every delimiter and bracket
+fn merge_text(document: &mut Document, first: Option<NodeId>) {
+    let mut current = first;
+    while let Some(id) = current {
+        let NodeType::Text(text) = &document[id].node_type else {
+            current = document[id].next_sibling();
+            continue;
+        };
+        let mut merged = text.clone();
+        let mut next = document[id].next_sibling();
+        while let Some(sibling) = next {
+            let NodeType::Text(text) = &document[sibling].node_type else {
+                break;
+            };
+            merged.push_str(text);
+            next = document[sibling].next_sibling();
+            detach(document, sibling);
+        }
+        if merged.is_empty() {
+            detach(document, id);
+        } else {
+            document.set_text(id, merged).expect("text nodes have text");
+        }
+        current = next;
+    }
+}
+
+fn detach(document: &mut Document, id: NodeId) {
+    if let Some(parent) = document[id].parent() {
+        document
+            .remove_child(parent, id)
+            .expect("a node is a child of its parent");
+    }
+}
+
+impl InlineParser<'_> {
+    fn parse_inline(&mut self, c: char) {
+        match c {
+            '\n' => self.parse_newline(),
+            '\\' => self.parse_backslash(),
+            '`' => self.parse_backticks(),
+            '*' | '_' => self.parse_delimiters(c),
+            '[' => {
+                self.scanner.pos += 1;
+                let node = self.add_text("[");
+                self.add_bracket(node, false);
+            }
+            '!' if self.scanner.rest().starts_with("![") => {
+                self.scanner.pos += 2;
+                let node = self.add_text("![");
+                self.add_bracket(node, true);
+            }
+            ']' => self.parse_close_bracket(),
+            '<' if self.parse_autolink() => {}
+            '&' if self.parse_entity() => {}
+            _ => {
+                // text up to the next character that could start something
+                let rest = self.scanner.rest();
+                let len = rest[c.len_utf8()..]
+                    .find(['\n', '\\', '`', '*', '_', '[', ']', '!', '<', '&'])
+                    .map_or(rest.len(), |i| i + c.len_utf8());
+                self.scanner.pos += len;
+                self.add_text(&rest[..len]);
+            }
+        }
+    }
+
+    fn add_text(&mut self, text: &str) -> NodeId {
+        let node = self.document.create_text(text.to_string());
+        self.document.append_child_unchecked(self.container, node);
+        node
+    }
+
+    fn add_element(&mut self, tag_name: &str, attrs: AttrMap) -> NodeId {
+        let node = self.document.create_element(tag_name.to_string(), attrs);
+        self.document.append_child_unchecked(self.container, node);
+        self.elements.push(node);
+        node
+    }
+
+    // A line ending is a hard break after two or more spaces, the spaces around it are dropped
+    fn parse_newline(&mut self) {
+        self.scanner.pos += 1;
+        let mut hard_break = false;
+        if let Some(last) = self.document[self.container].last_child() {
+            if let NodeType::Text(text) = &self.document[last].node_type {
+                if text.ends_with(' ') {
+                    hard_break = text.ends_with("  ");
+                    let trimmed = text.trim_end_matches(' ').to_string();
+                    self.document
+                        .set_text(last, trimmed)
+                        .expect("text nodes have text");
+                }
+            }
+        }
+        if hard_break {
+            self.add_element("br", AttrMap::new());
+        } else {
+            self.add_text("\n");
+        }
+        self.scanner.skip_spaces();
+    }
+
+    // An escaped punctuation character is literal, a backslash at the end of a line is a hard break
+    fn parse_backslash(&mut self) {
+        self.scanner.pos += 1;
+        match self.scanner.peek() {
+            Some('\n') => {
+                self.scanner.pos += 1;
+                self.add_element("br", AttrMap::new());
+                self.scanner.skip_spaces();
+            }
+            Some(c) if c.is_ascii_punctuation() => {
+                self.scanner.pos += 1;
+                self.add_text(&c.to_string());
+            }
+            _ => {
+                self.add_text("\\");
+            }
+        }
+    }
+
+    // A code span ends at the next run of as many backticks, otherwise the backticks are text
+    fn parse_backticks(&mut self) {
+        let rest = self.scanner.rest();
+        let ticks = rest.len() - rest.trim_start_matches('`').len();
+        let mut search = ticks;
+        while let Some(found) = rest[search..].find('`') {
+            let start = search + found;
+            let len = rest[start..].len() - rest[start..].trim_start_matches('`').len();
+            if len == ticks {
+                let mut code = rest[ticks..start].replace('\n', " ");
+                if code.len() >= 2
+                    && code.starts_with(' ')
+                    && code.ends_with(' ')
+                    && !code.chars().all(|c| c == ' ')
+                {
+                    code = code[1..code.len() - 1].to_string();
+                }
+                self.scanner.pos += start + len;
+                let element = self.add_element("code", AttrMap::new());
+                let text = self.document.create_text(code);
+                self.document.append_child_unchecked(element, text);
+                return;
+            }
+            search = start + len;
+        }
+        self.scanner.pos += ticks;
+        self.add_text(&rest[..ticks]);
+    }
+
+    // A run of * or _, whether it can open or close emphasis depends on the characters around it
+    fn parse_delimiters(&mut self, symbol: char) {
+        let start = self.scanner.pos;
+        let rest = self.scanner.rest();
+        let run_length = rest.len() - rest.trim_start_matches(symbol).len();
+        self.scanner.pos += run_length;
+        let before = self.scanner.subject[..start]
+            .chars()
+            .next_back()
+            .unwrap_or('\n');
+        let after = self.scanner.peek().unwrap_or('\n');
+
+        let left_flanking = !after.is_whitespace()
+            && (!is_punctuation(after) || before.is_whitespace() || is_punctuation(before));
+        let right_flanking = !before.is_whitespace()
+            && (!is_punctuation(before) || after.is_whitespace() || is_punctuation(after));
+        let (can_open, can_close) = if symbol == '_' {
+            (
+                left_flanking && (!right_flanking || is_punctuation(before)),
+                right_flanking && (!left_flanking || is_punctuation(after)),
+            )
+        } else {
+            (left_flanking, right_flanking)
+        };
+
+        let node = self.add_text(&rest[..run_length]);
+        if can_open || can_close {
+            let id = self.delimiters.len();
+            self.delimiters.push(Delimiter {
+                node,
+                symbol,
+                count: run_length,
+                run_length,
+                can_open,
+                can_close,
+                previous: self.last_delimiter,
+                next: None,
+            });
+            if let Some(last) = self.last_delimiter {
+                self.delimiters[last].next = Some(id);
+            }
+            self.last_delimiter = Some(id);
+        }
+    }
+
+    fn add_bracket(&mut self, node: NodeId, image: bool) {
+        if let Some(last) = self.brackets.last_mut() {
+            last.bracket_after = true;
+        }
+        self.brackets.push(Bracket {
+            node,
+            image,
+            active: true,
+            index: self.scanner.pos,
+            previous_delimiter: self.last_delimiter,
+            bracket_after: false,
+        });
+    }
+
+    // A ] closes the last bracket into a link or an image if a destination or a known reference
+    // follows it
+    fn parse_close_bracket(&mut self) {
+        self.scanner.pos += 1;
+        let start = self.scanner.pos;
+        let Some(opener) = self.brackets.last() else {
+            self.add_text("]");
+            return;
+        };
+        if !opener.active {
+            self.brackets.pop();
+            self.add_text("]");
+            return;
+        }
+        let (opener_index, bracket_after) = (opener.index, opener.bracket_after);
+
+        // inline link (destination "title")
+        let mut link = None;
+        if self.scanner.peek() == Some('(') {
+            self.scanner.pos += 1;
+            self.scanner.spnl();
+            if let Some(destination) = self.scanner.parse_link_destination() {
+                let before_title = self.scanner.pos;
+                self.scanner.spnl();
+                let title = if self.scanner.pos != before_title {
+                    self.scanner.parse_link_title()
+                } else {
+                    None
+                };
+                self.scanner.spnl();
+                if self.scanner.peek() == Some(')') {
+                    self.scanner.pos += 1;
+                    link = Some((destination, title));
+                }
+            }
+            if link.is_none() {
+                self.scanner.pos = start;
+            }
+        }
+
+        // full [text][label], collapsed [text][] or shortcut [text] reference
+        if link.is_none() {
+            let label_start = self.scanner.pos;
+            let label_len = self.scanner.parse_link_label();
+            let label = if label_len > 2 {
+                Some(&self.scanner.subject[label_start + 1..label_start + label_len - 1])
+            } else if !bracket_after {
+                Some(&self.scanner.subject[opener_index..start - 1])
+            } else {
+                None
+            };
+            if label_len == 0 {
+                self.scanner.pos = start;
+            }
+            link = label
+                .and_then(|label| self.refs.get(&normalize_label(label)))
+                .map(|reference| (reference.destination.clone(), reference.title.clone()));
+        }
+
+        let Some((destination, title)) = link else {
+            self.brackets.pop();
+            self.scanner.pos = start;
+            self.add_text("]");
+            return;
+        };
+        let opener = self.brackets.pop().expect("the opener is on the stack");
+        let mut attrs = AttrMap::new();
+        attrs.insert(
+            String::from(if opener.image { "src" } else { "href" }),
+            destination,
+        );
+        if let Some(title) = title {
+            attrs.insert(String::from("title"), title);
+        }
+        let element = self
+            .document
+            .create_element(String::from(if opener.image { "img" } else { "a" }), attrs);
+        while let Some(next) = self.document[opener.node].next_sibling() {
+            self.document.append_child_unchecked(element, next);
+        }
+        let parent = self.document[opener.node]
+            .parent()
+            .expect("brackets are in the container");
+        self.document.append_child_unchecked(parent, element);
+        self.process_emphasis(opener.previous_delimiter);
+        detach(self.document, opener.node);
+
+        if opener.image {
+            // the text of an image is its alt text
+            let alt = self.document.text_content(element);
+            self.document
+                .set_text(element, String::new())
+                .expect("elements can have children");
+            self.document
+                .set_attribute(element, "alt", alt)
+                .expect("images are elements");
+        } else {
+            self.elements.push(element);
+            for bracket in &mut self.brackets {
+                if !bracket.image {
+                    bracket.active = false;
+                }
+            }
+        }
+    }
+
+    // <scheme:uri> and <user@example.com> are links
+    fn parse_autolink(&mut self) -> bool {
+        let rest = &self.scanner.rest()[1..];
+        let Some(end) = rest
+            .find(['>', '<'])
+            .filter(|&end| rest[end..].starts_with('>'))
+        else {
+            return false;
+        };
+        let text = &rest[..end];
+        let href = if is_uri(text) {
+            text.to_string()
+        } else if is_email(text) {
+            format!("mailto:{}", text)
+        } else {
+            return false;
+        };
+        self.scanner.pos += end + 2;
+        let mut attrs = AttrMap::new();
+        attrs.insert(String::from("href"), href);
+        let element = self.add_element("a", attrs);
+        let text = self.document.create_text(text.to_string());
+        self.document.append_child_unchecked(element, text);
+        true
+    }
+
+    fn parse_entity(&mut self) -> bool {
+        match match_entity(self.scanner.rest()) {
+            Some((decoded, len)) => {
+                self.scanner.pos += len;
+                self.add_text(&decoded);
+                true
+            }
+            None => false,
+        }
+    }
+
+    // Match the delimiters above `stack_bottom` into emphasis, the opener of each closer is the
+    // closest one before it that can open with the same character
+    fn process_emphasis(&mut self, stack_bottom: Option<usize>) {
+        // where the search for an opener stopped before, by character, whether the closer can
+        // open too and its length mod 3
+        let mut openers_bottom = [stack_bottom; 12];
+
+        let mut closer = self.last_delimiter;
+        while let Some(id) = closer {
+            if self.delimiters[id].previous == stack_bottom {
+                break;
+            }
+            closer = self.delimiters[id].previous;
+        }
+        if closer == stack_bottom {
+            closer = None;
+        }
+
+        while let Some(c) = closer {
+            let closing = &self.delimiters[c];
+            if !closing.can_close {
+                closer = closing.next;
+                continue;
+            }
+            let bottom_index = usize::from(closing.symbol == '*') * 6
+                + usize::from(closing.can_open) * 3
+                + closing.run_length % 3;
+            let mut opener = closing.previous;
+            let mut found = false;
+            while let Some(o) = opener {
+                if Some(o) == stack_bottom || Some(o) == openers_bottom[bottom_index] {
+                    break;
+                }
+                let opening = &self.delimiters[o];
+                // a run that can both open and close only matches if the lengths of the runs
+                // don't add up to a multiple of 3
+                let odd_match = (closing.can_open || opening.can_close)
+                    && !closing.run_length.is_multiple_of(3)
+                    && (opening.run_length + closing.run_length).is_multiple_of(3);
+                if opening.symbol == closing.symbol && opening.can_open && !odd_match {
+                    found = true;
+                    break;
+                }
+                opener = opening.previous;
+            }
+
+            let Some(o) = opener.filter(|_| found) else {
+                openers_bottom[bottom_index] = self.delimiters[c].previous;
+                let next = self.delimiters[c].next;
+                if !self.delimiters[c].can_open {
+                    self.remove_delimiter(c);
+                }
+                closer = next;
+                continue;
+            };
+
+            let used = if self.delimiters[o].count >= 2 && self.delimiters[c].count >= 2 {
+                2
+            } else {
+                1
+            };
+            self.delimiters[o].count -= used;
+            self.delimiters[c].count -= used;
+            let (opener_node, closer_node) = (self.delimiters[o].node, self.delimiters[c].node);
+            let symbol = self.delimiters[c].symbol;
+            for (node, count) in [
+                (opener_node, self.delimiters[o].count),
+                (closer_node, self.delimiters[c].count),
+            ] {
+                let text = symbol.to_string().repeat(count);
+                self.document
+                    .set_text(node, text)
+                    .expect("delimiters are text");
+            }
+
+            let tag_name = if used == 2 { "strong" } else { "em" };
+            let emphasis = self
+                .document
+                .create_element(tag_name.to_string(), AttrMap::new());
+            while let Some(next) = self.document[opener_node].next_sibling() {
+                if next == closer_node {
+                    break;
+                }
+                self.document.append_child_unchecked(emphasis, next);
+            }
+            let parent = self.document[closer_node]
+                .parent()
+                .expect("delimiters are in the container");
+            self.document
+                .insert_before_unchecked(parent, emphasis, Some(closer_node));
+            self.elements.push(emphasis);
+
+            // the delimiters between are inside the emphasis now
+            self.delimiters[o].next = Some(c);
+            self.delimiters[c].previous = Some(o);
+            if self.delimiters[o].count == 0 {
+                detach(self.document, opener_node);
+                self.remove_delimiter(o);
+            }
+            if self.delimiters[c].count == 0 {
+                detach(self.document, closer_node);
+                closer = self.delimiters[c].next;
+                self.remove_delimiter(c);
+            }
+        }
+
+        while let Some(last) = self.last_delimiter {
+            if Some(last) == stack_bottom {
+                break;
+            }
+            self.remove_delimiter(last);
+        }
+    }
+
+    fn remove_delimiter(&mut self, id: usize) {
+        let Delimiter { previous, next, .. } = self.delimiters[id];
+        if let Some(previous) = previous {
+            self.delimiters[previous].next = next;
+        }
+        match next {
+            Some(next) => self.delimiters[next].previous = previous,
+            None => self.last_delimiter = previous,
+        }
+    }
+}
+
+// Punctuation for the rules of emphasis: ASCII punctuation, and other characters that are neither
+// letters, digits nor whitespace
+fn is_punctuation(c: char) -> bool {
+    c.is_ascii_punctuation() || (!c.is_ascii() && !c.is_alphanumeric() && !c.is_whitespace())
+}
+
+// An absolute URI of an autolink, a scheme of 2 to 32 characters, a colon and no spaces or <>
+fn is_uri(text: &str) -> bool {
+    let Some((scheme, _)) = text.split_once(':') else {
+        return false;
+    };
+    (2..=32).contains(&scheme.len())
+        && scheme.starts_with(|c: char| c.is_ascii_alphabetic())
+        && scheme
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '+' | '.' | '-'))
+        && !text.chars().any(|c| c == ' ' || c.is_ascii_control())
+}
+
+// An email address of an autolink, the local part and the domain of the HTML spec's valid email
+fn is_email(text: &str) -> bool {
+    let Some((local, domain)) = text.split_once('@') else {
+        return false;
+    };
+    !local.is_empty()
+        && local
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || ".!#$%&'*+/=?^_`{|}~-".contains(c))
+        && domain.split('.').all(|label| {
+            (1..=63).contains(&label.len())
+                && !label.starts_with('-')
+                && !label.ends_with('-')
+                && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+        })
+}
+
+// A character reference at the start of `text`: &name;, &#digits; or &#xhex;.
+// Returns the characters and the length of the reference.
+fn match_entity(text: &str) -> Option<(String, usize)> {
+    let rest = text.strip_prefix('&')?;
+    let end = rest.find(';')?;
+    let name = &rest[..end];
+    let decoded = if let Some(number) = name.strip_prefix('#') {
+        let (digits, radix, max_len) = match number.strip_prefix(['x', 'X']) {
+            Some(hex) => (hex, 16, 6),
+            None => (number, 10, 7),
+        };
+        if !(1..=max_len).contains(&digits.len()) || !digits.chars().all(|c| c.is_digit(radix)) {
+            return None;
+        }
+        let code = u32::from_str_radix(digits, radix).ok()?;
+        let c = char::from_u32(code)
+            .filter(|&c| c != '\0')
+            .unwrap_or(char::REPLACEMENT_CHARACTER);
+        c.to_string()
+    } else {
+        if name.len() > 32 || !name.starts_with(|c: char| c.is_ascii_alphabetic()) {
+            return None;
+        }
+        html::named_char_ref(name)?.to_string()
+    };
+    Some((decoded, end + 2))
+}
+
+// Resolve backslash escapes and character references, for destinations, titles and info strings
+fn unescape(text: &str) -> String {
+    let mut result = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(i) = rest.find(['\\', '&']) {
+        result.push_str(&rest[..i]);
+        rest = &rest[i..];
+        if let Some(escaped) = rest[1..].chars().next().filter(|_| rest.starts_with('\\')) {
+            if escaped.is_ascii_punctuation() {
+                result.push(escaped);
+                rest = &rest[1 + escaped.len_utf8()..];
+                continue;
+            }
+        } else if let Some((decoded, len)) = match_entity(rest) {
+            result.push_str(&decoded);
+            rest = &rest[len..];
+            continue;
+        }
+        result.push_str(&rest[..1]);
+        rest = &rest[1..];
+    }
+    result.push_str(rest);
+    result
+}
+
+// Build the elements of the blocks into the body of `document`
+fn build_document(
+    document: &mut Document,
+    blocks: &[Block],
+    refs: &HashMap<String, LinkReference>,
+) {
+    let html = document.create_element(String::from("html"), AttrMap::new());
+    let head = document.create_element(String::from("head"), AttrMap::new());
+    let body = document.create_element(String::from("body"), AttrMap::new());
+    document.append_child_unchecked(document.root(), html);
+    document.append_child_unchecked(html, head);
+    document.append_child_unchecked(html, body);
+
+    // blocks with the element they go into and whether they are in a tight list
+    let mut stack: Vec<(usize, NodeId, bool)> = blocks[0]
+        .children
+        .iter()
+        .rev()
+        .map(|&child| (child, body, false))
+        .collect();
+    while let Some((id, parent, tight)) = stack.pop() {
+        let block = &blocks[id];
+        let element = |document: &mut Document, tag_name: &str, attrs: AttrMap| {
+            let element = document.create_element(tag_name.to_string(), attrs);
+            document.set_span(
+                element,
+                Span {
+                    start: block.start,
+                    end: block.end,
+                },
+            );
+            document.append_child_unchecked(parent, element);
+            element
+        };
+        let (container, tight) = match &block.kind {
+            BlockKind::Document => continue,
+            BlockKind::BlockQuote => (element(document, "blockquote", AttrMap::new()), false),
+            BlockKind::List { marker, tight } => {
+                let mut attrs = AttrMap::new();
+                if marker.ordered && marker.start != 1 {
+                    attrs.insert(String::from("start"), marker.start.to_string());
+                }
+                let tag_name = if marker.ordered { "ol" } else { "ul" };
+                (element(document, tag_name, attrs), *tight)
+            }
+            BlockKind::Item(_) => (element(document, "li", AttrMap::new()), tight),
+            // paragraphs of tight lists are just their text
+            BlockKind::Paragraph if tight => {
+                parse_inlines(document, refs, parent, &block.content);
+                continue;
+            }
+            BlockKind::Paragraph => {
+                let paragraph = element(document, "p", AttrMap::new());
+                parse_inlines(document, refs, paragraph, &block.content);
+                continue;
+            }
+            BlockKind::Heading(level) => {
+                let heading = element(document, &format!("h{}", level), AttrMap::new());
+                parse_inlines(document, refs, heading, &block.content);
+                continue;
+            }
+            BlockKind::ThematicBreak => {
+                element(document, "hr", AttrMap::new());
+                continue;
+            }
+            BlockKind::CodeBlock(fence) => {
+                let pre = element(document, "pre", AttrMap::new());
+                let (info, code) = match fence {
+                    Some(_) => block
+                        .content
+                        .split_once('\n')
+                        .unwrap_or((&block.content, "")),
+                    None => ("", block.content.as_str()),
+                };
+                let mut attrs = AttrMap::new();
+                if let Some(language) = unescape(info.trim()).split_whitespace().next() {
+                    attrs.insert(String::from("class"), format!("language-{}", language));
+                }
+                let code_element = document.create_element(String::from("code"), attrs);
+                document.append_child_unchecked(pre, code_element);
+                if !code.is_empty() {
+                    let text = document.create_text(code.to_string());
+                    document.append_child_unchecked(code_element, text);
+                }
+                continue;
+            }
+            BlockKind::Table(table) => {
+                let table_element = element(document, "table", AttrMap::new());
+                build_table(document, refs, table_element, table);
+                continue;
+            }
+        };
+        stack.extend(
+            block
+                .children
+                .iter()
+                .rev()
+                .map(|&child| (child, container, tight)),
+        );
+    }
+}
+
+// Block quotes, lists and emphasis can nest without limit. What is nested deeper than MAX_DEPTH
+// is moved out to follow its parent, like the HTML tree builder does, so the recursive passes
+// over the tree can't overflow the stack.
+fn limit_depth(document: &mut Document) {
+    let mut stack = vec![(document.root(), 0)];
+    while let Some((id, depth)) = stack.pop() {
+        let children: Vec<NodeId> = document.children(id).collect();
+        let depth = if depth > MAX_DEPTH && !children.is_empty() {
+            let parent = document[id]
+                .parent()
+                .expect("a node this deep has a parent");
+            let next = document[id].next_sibling();
+            for &child in &children {
+                document.insert_before_unchecked(parent, child, next);
+            }
+            depth
+        } else {
+            depth + 1
+        };
+        stack.extend(children.into_iter().map(|child| (child, depth)));
+    }
+}
+
+fn build_table(
+    document: &mut Document,
+    refs: &HashMap<String, LinkReference>,
+    table_element: NodeId,
+    table: &Table,
+) {
+    let add_row = |document: &mut Document, group: NodeId, cells: &[String], tag_name: &str| {
+        let row = document.create_element(String::from("tr"), AttrMap::new());
+        document.append_child_unchecked(group, row);
+        for (i, alignment) in table.alignments.iter().enumerate() {
+            let mut attrs = AttrMap::new();
+            if let Some(alignment) = alignment {
+                attrs.insert(String::from("style"), format!("text-align: {}", alignment));
+            }
+            let cell = document.create_element(tag_name.to_string(), attrs);
+            document.append_child_unchecked(row, cell);
+            if let Some(text) = cells.get(i) {
+                parse_inlines(document, refs, cell, text);
+            }
+        }
+    };
+    let thead = document.create_element(String::from("thead"), AttrMap::new());
+    document.append_child_unchecked(table_element, thead);
+    add_row(document, thead, &table.header, "th");
+    if !table.rows.is_empty() {
+        let tbody = document.create_element(String::from("tbody"), AttrMap::new());
+        document.append_child_unchecked(table_element, tbody);
+        for row in &table.rows {
+            add_row(document, tbody, row, "td");
+        }
+    }
+}
@@ -4,27 +4,89 @@
 // 1. Initial and/or computed values (Implementing this would require separate code for each
 //    property, based on its css specs).
 // 2. Inheritance
-// 3. The style attribute
 
 use std::{cmp::Ordering, collections::HashMap};
 
 use crate::{
     css::{
-        CSSOrigin, Declaration, Rule, Selector, SimpleSelector, Specificity, StylesSheet, Value,
-        INHERITED_PROPERTY,
+        self, AttributeCase, AttributeOperator, AttributeSelector, CSSOrigin, Combinator,
+        Declaration, Nth, PseudoClass, Rule, Selector, SimpleSelector, Specificity, StylesSheet,
+        Value, INHERITED_PROPERTY,
     },
-    dom::{ElementData, Node, NodeType},
+    dom::{Document, ElementData, NodeId, NodeType, QuirksMode},
 };
 
 // Map css property names to values
 type PropertyMap = HashMap<String, Value>;
 
+// Properties a table doesn't inherit in quirks mode, old pages expect tables to use the default font
+const QUIRKS_TABLE_NOT_INHERITED: [&str; 7] = [
+    "font-size",
+    "font-style",
+    "font-variant",
+    "font-weight",
+    "line-height",
+    "text-align",
+    "white-space",
+];
+
+// Attributes of html elements whose values attribute selectors compare case-insensitively
+// https://html.spec.whatwg.org/multipage/semantics-other.html#case-sensitivity-of-selectors
+const CASE_INSENSITIVE_ATTRIBUTES: [&str; 46] = [
+    "accept",
+    "accept-charset",
+    "align",
+    "alink",
+    "axis",
+    "bgcolor",
+    "charset",
+    "checked",
+    "clear",
+    "codetype",
+    "color",
+    "compact",
+    "declare",
+    "defer",
+    "dir",
+    "direction",
+    "disabled",
+    "enctype",
+    "face",
+    "frame",
+    "hreflang",
+    "http-equiv",
+    "lang",
+    "language",
+    "link",
+    "media",
+    "method",
+    "multiple",
+    "nohref",
+    "noresize",
+    "noshade",
+    "nowrap",
+    "readonly",
+    "rel",
+    "rev",
+    "rules",
+    "scope",
+    "scrolling",
+    "selected",
+    "shape",
+    "target",
+    "text",
+    "type",
+    "valign",
+    "valuetype",
+    "vlink",
+];
+
 // Node associated with style data
 #[derive(Debug)]
-pub struct StyledNode<'a> {
-    pub node: &'a Node,
+pub struct StyledNode {
+    pub node: NodeId,
     pub specified_values: PropertyMap,
-    pub children: Vec<StyledNode<'a>>,
+    pub children: Vec<StyledNode>,
 }
 
 #[derive(PartialEq)]
@@ -34,7 +96,7 @@ pub enum Display {
     None,
 }
 
-impl<'a> StyledNode<'a> {
+impl StyledNode {
     // Returns specified value if exist / None
     pub fn value(&self, name: &str) -> Option<Value> {
         self.specified_values.get(name).cloned()
@@ -65,43 +127,153 @@ type MatchedRule<'a> = (Specificity, CSSOrigin, &'a Rule);
 
 // If a rule matches an element return the rule else none
 fn match_rule<'a>(
-    element: &ElementData,
+    document: &Document,
+    id: NodeId,
     origin: CSSOrigin,
     rule: &'a Rule,
 ) -> Option<MatchedRule<'a>> {
     rule.selectors
         .iter()
-        .find(|selector| matches(element, selector))
+        .find(|selector| matches(document, id, selector))
         .map(|selector| (selector.specificity(), origin, rule))
 }
 
 // find all css rules maching given element
 fn matching_rules<'a>(
-    element: &ElementData,
+    document: &Document,
+    id: NodeId,
     origin: CSSOrigin,
     stylesheet: &'a StylesSheet,
 ) -> Vec<MatchedRule<'a>> {
     stylesheet
         .rules
         .iter()
-        .filter_map(|rule| match_rule(element, origin, rule))
+        .filter_map(|rule| match_rule(document, id, origin, rule))
         .collect()
 }
 
-// Matches the selector by looking into the element
-fn matches(element: &ElementData, selector: &Selector) -> bool {
+// Matches the selector against the element `id`. Complex selectors are matched from right to
+// left, starting with the element and going to its ancestors and previous siblings.
+pub(crate) fn matches(document: &Document, id: NodeId, selector: &Selector) -> bool {
+    matches_selector(document, id, selector, None)
+}
+
+// The element `id` matches the selector. With a `scope`, the leftmost element of the selector
+// also has to be related to the scope element by the combinator, for the relative selectors of
+// :has().
+fn matches_selector(
+    document: &Document,
+    id: NodeId,
+    selector: &Selector,
+    scope: Option<(NodeId, Combinator)>,
+) -> bool {
+    if document.element(id).is_none() {
+        return false;
+    }
     match selector {
-        Selector::Simple(s) => matches_simple_selector(element, s),
+        Selector::Simple(s) => {
+            matches_simple_selector(document, id, s) && is_related_to_scope(document, id, scope)
+        }
+        Selector::Complex(context, subject) => {
+            matches_simple_selector(document, id, subject)
+                && matches_context(document, id, context, scope)
+        }
     }
 }
 
-fn matches_simple_selector(element: &ElementData, selector: &SimpleSelector) -> bool {
-    // Check tag name selector
-    if selector
-        .tag_name
-        .iter()
-        .any(|name| element.tag_name != *name)
-    {
+// Does an element related to `id` by the last combinator match the selectors on the left?
+// The other candidates are tried when the rest of the selector fails for one.
+fn matches_context(
+    document: &Document,
+    id: NodeId,
+    context: &[(SimpleSelector, Combinator)],
+    scope: Option<(NodeId, Combinator)>,
+) -> bool {
+    let Some(((selector, combinator), rest)) = context.split_last() else {
+        return is_related_to_scope(document, id, scope);
+    };
+    let matches_here = |candidate: NodeId| {
+        matches_simple_selector(document, candidate, selector)
+            && matches_context(document, candidate, rest, scope)
+    };
+    match combinator {
+        Combinator::Descendant => ancestor_elements(document, id).any(matches_here),
+        Combinator::Child => parent_element(document, id).is_some_and(matches_here),
+        Combinator::NextSibling => previous_element(document, id).is_some_and(matches_here),
+        Combinator::SubsequentSibling => previous_elements(document, id).any(matches_here),
+    }
+}
+
+fn is_related_to_scope(
+    document: &Document,
+    id: NodeId,
+    scope: Option<(NodeId, Combinator)>,
+) -> bool {
+    let Some((scope, combinator)) = scope else {
+        return true;
+    };
+    match combinator {
+        Combinator::Descendant => ancestor_elements(document, id).any(|a| a == scope),
+        Combinator::Child => parent_element(document, id) == Some(scope),
+        Combinator::NextSibling => previous_element(document, id) == Some(scope),
+        Combinator::SubsequentSibling => previous_elements(document, id).any(|s| s == scope),
+    }
+}
+
+fn parent_element(document: &Document, id: NodeId) -> Option<NodeId> {
+    document[id]
+        .parent()
+        .filter(|&parent| document.element(parent).is_some())
+}
+
+fn ancestor_elements(document: &Document, id: NodeId) -> impl Iterator<Item = NodeId> + '_ {
+    std::iter::successors(parent_element(document, id), |&parent| {
+        parent_element(document, parent)
+    })
+}
+
+fn previous_element(document: &Document, id: NodeId) -> Option<NodeId> {
+    std::iter::successors(document[id].prev_sibling(), |&sibling| {
+        document[sibling].prev_sibling()
+    })
+    .find(|&sibling| document.element(sibling).is_some())
+}
+
+fn next_element(document: &Document, id: NodeId) -> Option<NodeId> {
+    std::iter::successors(document[id].next_sibling(), |&sibling| {
+        document[sibling].next_sibling()
+    })
+    .find(|&sibling| document.element(sibling).is_some())
+}
+
+// Element siblings before `id`, closest first
+fn previous_elements(document: &Document, id: NodeId) -> impl Iterator<Item = NodeId> + '_ {
+    std::iter::successors(previous_element(document, id), |&sibling| {
+        previous_element(document, sibling)
+    })
+}
+
+fn next_elements(document: &Document, id: NodeId) -> impl Iterator<Item = NodeId> + '_ {
+    std::iter::successors(next_element(document, id), |&sibling| {
+        next_element(document, sibling)
+    })
+}
+
+fn matches_simple_selector(document: &Document, id: NodeId, selector: &SimpleSelector) -> bool {
+    let Some(element) = document.element(id) else {
+        return false;
+    };
+
+    // Check tag name selector, html tag names in HTML documents are lowercase and match in any
+    // case
+    let matches_tag_name = |name: &String| {
+        if document.has_case_insensitive_names(element) {
+            element.tag_name.eq_ignore_ascii_case(name)
+        } else {
+            element.tag_name == *name
+        }
+    };
+    if !selector.tag_name.iter().all(matches_tag_name) {
         return false;
     }
 
@@ -117,14 +289,199 @@ fn matches_simple_selector(element: &ElementData, selector: &SimpleSelector) ->
         return false;
     }
 
-    true
+    if !selector
+        .attributes
+        .iter()
+        .all(|attribute| matches_attribute_selector(document, element, attribute))
+    {
+        return false;
+    }
+
+    selector
+        .pseudo_classes
+        .iter()
+        .all(|pseudo_class| matches_pseudo_class(document, id, element, pseudo_class))
+}
+
+fn matches_pseudo_class(
+    document: &Document,
+    id: NodeId,
+    element: &ElementData,
+    pseudo_class: &PseudoClass,
+) -> bool {
+    let matches_any =
+        |id: NodeId, selectors: &[Selector]| selectors.iter().any(|s| matches(document, id, s));
+    // siblings counted by :nth-child(an+b of S)
+    let counted = |sibling: NodeId, of: &[Selector]| of.is_empty() || matches_any(sibling, of);
+    match pseudo_class {
+        PseudoClass::Root => document[id].parent() == Some(document.root()),
+        PseudoClass::Empty => document
+            .children(id)
+            .all(|child| match &document[child].node_type {
+                NodeType::Element(_) => false,
+                NodeType::Text(text) => text.is_empty(),
+                NodeType::Comment(_) | NodeType::Document(_) => true,
+            }),
+        PseudoClass::FirstChild => previous_element(document, id).is_none(),
+        PseudoClass::LastChild => next_element(document, id).is_none(),
+        PseudoClass::OnlyChild => {
+            previous_element(document, id).is_none() && next_element(document, id).is_none()
+        }
+        PseudoClass::NthChild(nth, of) => {
+            counted(id, of)
+                && nth_matches(
+                    *nth,
+                    previous_elements(document, id)
+                        .filter(|&sibling| counted(sibling, of))
+                        .count(),
+                )
+        }
+        PseudoClass::NthLastChild(nth, of) => {
+            counted(id, of)
+                && nth_matches(
+                    *nth,
+                    next_elements(document, id)
+                        .filter(|&sibling| counted(sibling, of))
+                        .count(),
+                )
+        }
+        PseudoClass::NthOfType(nth) => nth_matches(
+            *nth,
+            previous_elements(document, id)
+                .filter(|&sibling| {
+                    document.element(sibling).is_some_and(|other| {
+                        other.tag_name == element.tag_name && other.namespace == element.namespace
+                    })
+                })
+                .count(),
+        ),
+        PseudoClass::Not(selectors) => !matches_any(id, selectors),
+        PseudoClass::Is(selectors) | PseudoClass::Where(selectors) => matches_any(id, selectors),
+        PseudoClass::Has(selectors) => selectors
+            .iter()
+            .any(|(combinator, selector)| has_relative(document, id, *combinator, selector)),
+    }
+}
+
+// Is there an element matching the relative selector `combinator selector` from `id`?
+fn has_relative(
+    document: &Document,
+    id: NodeId,
+    combinator: Combinator,
+    selector: &Selector,
+) -> bool {
+    let scope = Some((id, combinator));
+    match combinator {
+        Combinator::Descendant | Combinator::Child => document
+            .descendants(id)
+            .any(|candidate| matches_selector(document, candidate, selector, scope)),
+        // the rest of the selector can go down into the siblings
+        Combinator::NextSibling | Combinator::SubsequentSibling => {
+            next_elements(document, id).any(|sibling| {
+                std::iter::once(sibling)
+                    .chain(document.descendants(sibling))
+                    .any(|candidate| matches_selector(document, candidate, selector, scope))
+            })
+        }
+    }
+}
+
+// Is the element at position `preceding + 1` among the counted siblings one of `an+b`?
+fn nth_matches(nth: Nth, preceding: usize) -> bool {
+    let offset = preceding as i64 + 1 - nth.b as i64;
+    match nth.a as i64 {
+        0 => offset == 0,
+        a => offset % a == 0 && offset / a >= 0,
+    }
+}
+
+fn matches_attribute_selector(
+    document: &Document,
+    element: &ElementData,
+    selector: &AttributeSelector,
+) -> bool {
+    // attribute names of html elements in HTML documents are lowercase
+    let case_insensitive_names = document.has_case_insensitive_names(element);
+    let value = if case_insensitive_names {
+        element.attrs.get(&selector.name.to_ascii_lowercase())
+    } else {
+        element.attrs.get(&selector.name)
+    };
+    let Some(value) = value else {
+        return false;
+    };
+    let Some((operator, expected)) = &selector.operator else {
+        return true;
+    };
+    let ignore_case = match selector.case {
+        AttributeCase::Insensitive => true,
+        AttributeCase::Sensitive => false,
+        AttributeCase::Default => {
+            case_insensitive_names
+                && CASE_INSENSITIVE_ATTRIBUTES
+                    .contains(&selector.name.to_ascii_lowercase().as_str())
+        }
+    };
+    let (value, expected) = if ignore_case {
+        (value.to_ascii_lowercase(), expected.to_ascii_lowercase())
+    } else {
+        (value.clone(), expected.clone())
+    };
+    match operator {
+        AttributeOperator::Equals => value == expected,
+        // the word can't be empty or contain whitespace
+        AttributeOperator::Includes => {
+            !expected.is_empty()
+                && !expected.contains(char::is_whitespace)
+                && value.split_whitespace().any(|word| word == expected)
+        }
+        AttributeOperator::DashMatch => {
+            value == expected
+                || value
+                    .strip_prefix(&expected)
+                    .is_some_and(|rest| rest.starts_with('-'))
+        }
+        // an empty string would match everything, it matches nothing instead
+        AttributeOperator::Prefix => !expected.is_empty() && value.starts_with(&expected),
+        AttributeOperator::Suffix => !expected.is_empty() && value.ends_with(&expected),
+        AttributeOperator::Substring => !expected.is_empty() && value.contains(&expected),
+    }
+}
+
+// Does `element` inherit property `name` from its parent?
+fn inherits(element: &ElementData, name: &str, quirks_mode: QuirksMode) -> bool {
+    INHERITED_PROPERTY.contains_key(name)
+        && !(quirks_mode == QuirksMode::Quirks
+            && element.tag_name == "table"
+            && QUIRKS_TABLE_NOT_INHERITED.contains(&name))
+}
+
+// Declarations of the element's style attribute, invalid ones are dropped
+fn inline_declarations(element: &ElementData) -> Vec<Declaration> {
+    match element.attrs.get("style") {
+        Some(style) => css::parse_declarations(style.clone(), CSSOrigin::Author).0,
+        None => Vec::new(),
+    }
+}
+
+// Precedence of a declaration in the cascade, later levels win
+fn cascade_level(origin: CSSOrigin, is_important: bool) -> u8 {
+    match (origin, is_important) {
+        (CSSOrigin::User, false) => 0,
+        (CSSOrigin::Author, false) => 1,
+        (CSSOrigin::Author, true) => 2,
+        (CSSOrigin::User, true) => 3,
+    }
 }
 
 // apply styles to the single element, retuning the specified values
 fn specified_values(
+    document: &Document,
+    id: NodeId,
     element: &ElementData,
     stylesheets: &[StylesSheet],
     parent_specified_values: Option<&PropertyMap>,
+    quirks_mode: QuirksMode,
 ) -> PropertyMap {
     let mut values = HashMap::new();
     let mut author_rules: Vec<MatchedRule> = Vec::new();
@@ -132,10 +489,10 @@ fn specified_values(
 
     stylesheets.iter().for_each(|s| match s.origin {
         CSSOrigin::User => {
-            user_rules.append(&mut matching_rules(element, CSSOrigin::User, s));
+            user_rules.append(&mut matching_rules(document, id, CSSOrigin::User, s));
         }
         CSSOrigin::Author => {
-            author_rules.append(&mut matching_rules(element, CSSOrigin::Author, s));
+            author_rules.append(&mut matching_rules(document, id, CSSOrigin::Author, s));
         }
     });
 
@@ -158,21 +515,12 @@ fn specified_values(
         declarations.extend(rule.declarations.iter().cloned());
     });
 
-    declarations.sort_by(|a, b| {
-        if a.origin == CSSOrigin::User && a.is_important {
-            Ordering::Greater
-        } else if (b.origin == CSSOrigin::User && b.is_important)
-            || (a.origin == CSSOrigin::User && b.origin == CSSOrigin::Author)
-        {
-            Ordering::Less
-        } else if (a.origin == CSSOrigin::Author && b.origin == CSSOrigin::User)
-            || (a.is_important && !b.is_important)
-        {
-            Ordering::Greater
-        } else {
-            Ordering::Less
-        }
-    });
+    // The style attribute is more specific than any author rule, its !important declarations
+    // also come after the author ones when the stable sort below groups them
+    declarations.extend(inline_declarations(element));
+
+    // Rules are already in cascade order, the stable sort only moves !important declarations up
+    declarations.sort_by_key(|d| cascade_level(d.origin, d.is_important));
 
     for dec in declarations {
         values.insert(dec.name.clone(), dec.value.clone());
@@ -180,7 +528,7 @@ fn specified_values(
 
     if let Some(parent_values) = parent_specified_values {
         for (k, v) in parent_values {
-            if !values.contains_key(k) && INHERITED_PROPERTY.contains_key(k) {
+            if !values.contains_key(k) && inherits(element, k, quirks_mode) {
                 values.insert(k.clone(), v.clone());
             }
         }
@@ -190,23 +538,30 @@ fn specified_values(
 }
 
 // apply a stylesheet to an entire DOM tree and return style node tree
-pub fn style_tree<'a>(
-    root: &'a Node,
-    stylesheets: &'a Vec<StylesSheet>,
+// The document's quirks mode is used for the whole tree.
+pub fn style_tree(
+    document: &Document,
+    root: NodeId,
+    stylesheets: &[StylesSheet],
     parent_specified_values: Option<&PropertyMap>,
-) -> StyledNode<'a> {
-    let specified_values = match root.node_type {
-        NodeType::Element(ref element) => {
-            specified_values(element, stylesheets, parent_specified_values)
-        }
+) -> StyledNode {
+    let specified_values = match document[root].node_type {
+        NodeType::Element(ref element) => specified_values(
+            document,
+            root,
+            element,
+            stylesheets,
+            parent_specified_values,
+            document.quirks_mode(),
+        ),
         NodeType::Text(_) => HashMap::new(),
         NodeType::Comment(_) => HashMap::new(),
+        NodeType::Document(_) => HashMap::new(),
     };
 
-    let children = root
-        .children
-        .iter()
-        .map(|child| style_tree(child, stylesheets, Some(&specified_values)))
+    let children = document
+        .children(root)
+        .map(|child| style_tree(document, child, stylesheets, Some(&specified_values)))
         .collect();
 
     StyledNode {
@@ -215,3 +570,74 @@ pub fn style_tree<'a>(
         children,
     }
 }
+
+// Where the author styles of a document come from
+#[derive(Debug, PartialEq)]
+pub enum StyleSource {
+    // Text of a <style> element
+    Inline(String),
+    // href of a <link rel="stylesheet">, unresolved
+    External(String),
+}
+
+// Is `element` a <link> pointing at a style sheet? The rel attribute is a space separated,
+// case-insensitive list of keywords and alternate style sheets are not applied.
+fn stylesheet_link(element: &ElementData) -> Option<&String> {
+    let rel = element.attrs.get("rel")?;
+    let mut keywords = rel.split_ascii_whitespace();
+    let is_stylesheet = keywords
+        .clone()
+        .any(|k| k.eq_ignore_ascii_case("stylesheet"));
+    let is_alternate = keywords.any(|k| k.eq_ignore_ascii_case("alternate"));
+    if !is_stylesheet || is_alternate {
+        return None;
+    }
+    element
+        .attrs
+        .get("href")
+        .filter(|href| !href.trim().is_empty())
+}
+
+// Style elements in other languages are skipped, a missing or empty type means css
+fn is_css(element: &ElementData) -> bool {
+    element
+        .attrs
+        .get("type")
+        .is_none_or(|t| t.is_empty() || t.eq_ignore_ascii_case("text/css"))
+}
+
+fn collect_style_sources(document: &Document, node: NodeId, sources: &mut Vec<StyleSource>) {
+    if let Some(element) = document.element(node) {
+        match element.tag_name.as_str() {
+            "style" if is_css(element) => {
+                let text = document
+                    .children(node)
+                    .filter_map(|child| match document[child].node_type {
+                        NodeType::Text(ref text) => Some(text.as_str()),
+                        _ => None,
+                    })
+                    .collect();
+                sources.push(StyleSource::Inline(text));
+                return;
+            }
+            "link" if is_css(element) => {
+                if let Some(href) = stylesheet_link(element) {
+                    sources.push(StyleSource::External(href.trim().to_string()));
+                }
+            }
+            _ => {}
+        }
+    }
+    for child in document.children(node) {
+        collect_style_sources(document, child, sources);
+    }
+}
+
+// The <style> and <link rel="stylesheet"> elements of a document, in document order.
+// Loading external sheets is left to the caller, each source becomes its own author sheet and
+// the cascade keeps their rules in the order the sheets are given.
+pub fn style_sources(document: &Document) -> Vec<StyleSource> {
+    let mut sources = Vec::new();
+    collect_style_sources(document, document.root(), &mut sources);
+    sources
+}
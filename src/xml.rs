@@ -0,0 +1,715 @@
+// XML parser for XHTML and other well-formed XML documents
+// https://www.w3.org/TR/xml/ and https://www.w3.org/TR/xml-names/
+// Supported syntax:
+// 1. Elements with case-sensitive names and namespace prefixes like <svg:rect>. `xmlns` and
+//    `xmlns:prefix` attributes declare namespaces for the element they are on and its descendants.
+// 2. Any element can be empty and written as <br/>
+// 3. Attributes with single or double quoted values, whitespace in values becomes spaces
+// 4. The predefined entities (&lt; &gt; &amp; &apos; &quot;) and numeric character references.
+//    Like in browsers, documents with an XHTML or MathML doctype can use the named references of
+//    HTML as well.
+// 5. Comments, and <![CDATA[ .. ]]> sections which become text
+// 6. A doctype, its internal subset is skipped
+// 7. Processing instructions and the <?xml ...?> declaration, which are dropped
+// Elements in the XHTML namespace are html elements, so the document is styled and laid out like
+// a parsed HTML document.
+// There is no error recovery: as XML requires, parsing stops at the first well-formedness error
+// and the document parsed up to there is returned.
+
+use std::io::{self, Read};
+
+use crate::{
+    diagnostics::{Diagnostic, DiagnosticKind, LineIndex, Location, Severity, Span},
+    dom::{
+        AttrMap, Doctype, Document, DocumentData, DocumentKind, ElementData, Namespace, NodeId,
+        NodeType, QuirksMode, MAX_DEPTH,
+    },
+    encoding::{self, Encoding},
+    html,
+};
+
+pub const XHTML_NAMESPACE: &str = "http://www.w3.org/1999/xhtml";
+pub const SVG_NAMESPACE: &str = "http://www.w3.org/2000/svg";
+pub const MATHML_NAMESPACE: &str = "http://www.w3.org/1998/Math/MathML";
+const XML_NAMESPACE: &str = "http://www.w3.org/XML/1998/namespace";
+
+// A problem and the byte offset it was found at, parsing stops there
+type XmlError = (usize, DiagnosticKind);
+
+struct Parser {
+    pos: usize,
+    input: String,
+    document: Document,
+    // elements whose end tag hasn't been seen, innermost last
+    open_elements: Vec<OpenElement>,
+    // namespaces declared by the open elements as (prefix, uri), the default namespace has an
+    // empty prefix
+    namespaces: Vec<(String, String)>,
+    // text is collected until the next markup so references and CDATA end up in one node
+    text: String,
+    text_start: Location,
+    // named references of HTML are allowed
+    html_entities: bool,
+    seen_root: bool,
+    // line and column of a position already passed, so spans don't count lines from the start
+    location: Location,
+}
+
+struct OpenElement {
+    id: NodeId,
+    // name as written in the start tag, the end tag has to match it
+    name: String,
+    start: Location,
+    // length of the namespace list before the element's declarations
+    namespaces: usize,
+}
+
+// Characters allowed at the start of a name, roughly the ranges of the XML spec
+fn is_name_start_char(c: char) -> bool {
+    c.is_ascii_alphabetic() || c == '_' || c == ':' || (c as u32 >= 0xC0 && c != '\u{D7}')
+}
+
+fn is_name_char(c: char) -> bool {
+    is_name_start_char(c) || c.is_ascii_digit() || matches!(c, '-' | '.' | '\u{B7}')
+}
+
+fn is_whitespace(c: char) -> bool {
+    matches!(c, ' ' | '\t' | '\n' | '\r')
+}
+
+// `prefix:local` split at the colon, names without a colon have no prefix
+fn split_name(name: &str) -> (Option<&str>, &str) {
+    match name.split_once(':') {
+        Some((prefix, local)) if !prefix.is_empty() && !local.is_empty() => (Some(prefix), local),
+        _ => (None, name),
+    }
+}
+
+impl Parser {
+    // Read the next character without consuming it, None at the end of input.
+    fn next_char(&self) -> Option<char> {
+        self.input[self.pos..].chars().next()
+    }
+
+    fn starts_with(&self, s: &str) -> bool {
+        self.input[self.pos..].starts_with(s)
+    }
+
+    // If the exact string `s` is found at the current position consume it.
+    fn expect(&mut self, s: &str) -> bool {
+        if self.starts_with(s) {
+            self.pos += s.len();
+            true
+        } else {
+            false
+        }
+    }
+
+    // Consume the character `c` or fail
+    fn expect_char(&mut self, c: char) -> Result<(), XmlError> {
+        match self.next_char() {
+            Some(next) if next == c => {
+                self.pos += c.len_utf8();
+                Ok(())
+            }
+            _ => Err((self.pos, DiagnosticKind::ExpectedCharacter(c))),
+        }
+    }
+
+    // consume & discard zero / more whitespace chars, returns whether there were any
+    fn consume_whitespace(&mut self) -> bool {
+        let start = self.pos;
+        while self.next_char().is_some_and(is_whitespace) {
+            self.pos += 1;
+        }
+        self.pos > start
+    }
+
+    // Location of the current position, the parser never goes back
+    fn location(&mut self) -> Location {
+        self.location = self
+            .location
+            .advance(&self.input[self.location.offset..self.pos]);
+        self.location
+    }
+
+    // Consume everything up to `end` and `end` itself, `eof` is the error when it's missing
+    fn consume_until(&mut self, end: &str, eof: DiagnosticKind) -> Result<String, XmlError> {
+        match self.input[self.pos..].find(end) {
+            Some(len) => {
+                let content = self.input[self.pos..self.pos + len].to_string();
+                self.pos += len + end.len();
+                Ok(content)
+            }
+            None => Err((self.input.len(), eof)),
+        }
+    }
+
+    fn parse_name(&mut self) -> Option<String> {
+        let start = self.pos;
+        if !self.next_char().is_some_and(is_name_start_char) {
+            return None;
+        }
+        while let Some(c) = self.next_char().filter(|&c| is_name_char(c)) {
+            self.pos += c.len_utf8();
+        }
+        Some(self.input[start..self.pos].to_string())
+    }
+
+    // The element new nodes are appended to, the document outside the root element. What is
+    // nested deeper than MAX_DEPTH goes into the element at that depth.
+    fn current_node(&self) -> NodeId {
+        self.open_elements[..self.open_elements.len().min(MAX_DEPTH)]
+            .last()
+            .map_or(self.document.root(), |open| open.id)
+    }
+
+    fn append(&mut self, id: NodeId, span: Span) {
+        let parent = self.current_node();
+        self.document.set_span(id, span);
+        self.document.append_child_unchecked(parent, id);
+    }
+
+    // Turn the text collected so far into a node
+    fn flush_text(&mut self) {
+        if self.text.is_empty() {
+            return;
+        }
+        let text = std::mem::take(&mut self.text);
+        let span = Span {
+            start: self.text_start,
+            end: self.location(),
+        };
+        let id = self.document.create_text(text);
+        self.append(id, span);
+    }
+
+    // Add text that starts at `start` to the text collected so far
+    fn push_text(&mut self, start: Location, text: &str) {
+        if self.text.is_empty() {
+            self.text_start = start;
+        }
+        self.text.push_str(text);
+    }
+
+    fn parse_document(&mut self) -> Result<(), XmlError> {
+        while self.pos < self.input.len() {
+            if !self.starts_with("<") || self.starts_with("<![CDATA[") {
+                if self.open_elements.is_empty() {
+                    self.parse_top_level_text()?;
+                } else if self.starts_with("<") {
+                    self.parse_cdata()?;
+                } else {
+                    self.parse_text()?;
+                }
+                continue;
+            }
+            self.flush_text();
+            if self.starts_with("<!--") {
+                self.parse_comment()?;
+            } else if self.starts_with("<?") {
+                self.parse_processing_instruction()?;
+            } else if self.starts_with("<!DOCTYPE") {
+                self.parse_doctype()?;
+            } else if self.starts_with("</") {
+                self.parse_end_tag()?;
+            } else {
+                self.parse_start_tag()?;
+            }
+        }
+        self.flush_text();
+        match self.open_elements.last() {
+            Some(open) => Err((
+                self.input.len(),
+                DiagnosticKind::UnclosedElement(open.name.clone()),
+            )),
+            None if !self.seen_root => Err((self.input.len(), DiagnosticKind::MissingRootElement)),
+            None => Ok(()),
+        }
+    }
+
+    // Only whitespace is allowed outside the root element, it is dropped
+    fn parse_top_level_text(&mut self) -> Result<(), XmlError> {
+        self.consume_whitespace();
+        if (self.pos < self.input.len() && !self.starts_with("<")) || self.starts_with("<![CDATA[")
+        {
+            return Err((self.pos, DiagnosticKind::ContentOutsideRootElement));
+        }
+        Ok(())
+    }
+
+    // Character data up to the next markup, line breaks are normalized to \n
+    fn parse_text(&mut self) -> Result<(), XmlError> {
+        let start = self.location();
+        let mut text = String::new();
+        // the text before an error is kept
+        let result = loop {
+            match self.next_char() {
+                None | Some('<') => break Ok(()),
+                Some('&') => {
+                    if let Err(err) = self.parse_reference(&mut text) {
+                        break Err(err);
+                    }
+                }
+                Some('\r') => {
+                    self.pos += 1;
+                    self.expect("\n");
+                    text.push('\n');
+                }
+                Some(']') if self.starts_with("]]>") => {
+                    break Err((self.pos, DiagnosticKind::CdataEndInText));
+                }
+                Some(c) => {
+                    self.pos += c.len_utf8();
+                    text.push(c);
+                }
+            }
+        };
+        self.push_text(start, &text);
+        result
+    }
+
+    fn parse_cdata(&mut self) -> Result<(), XmlError> {
+        let start = self.location();
+        self.pos += "<![CDATA[".len();
+        let content = self.consume_until("]]>", DiagnosticKind::EofInCdata)?;
+        self.push_text(start, &content.replace("\r\n", "\n").replace('\r', "\n"));
+        Ok(())
+    }
+
+    // A reference after `&`, its characters are added to `out`
+    fn parse_reference(&mut self, out: &mut String) -> Result<(), XmlError> {
+        let start = self.pos;
+        self.pos += 1;
+        if self.expect("#") {
+            let radix = if self.expect("x") { 16 } else { 10 };
+            let digits_start = self.pos;
+            while self.next_char().is_some_and(|c| c.is_digit(radix)) {
+                self.pos += 1;
+            }
+            if self.pos == digits_start {
+                return Err((
+                    start,
+                    DiagnosticKind::AbsenceOfDigitsInNumericCharacterReference,
+                ));
+            }
+            let digits = &self.input[digits_start..self.pos];
+            let code = u32::from_str_radix(digits, radix).unwrap_or(u32::MAX);
+            if !self.expect(";") {
+                return Err((
+                    start,
+                    DiagnosticKind::MissingSemicolonAfterCharacterReference,
+                ));
+            }
+            let kind = match code {
+                0 => DiagnosticKind::NullCharacterReference,
+                0xD800..=0xDFFF => DiagnosticKind::SurrogateCharacterReference,
+                0x110000.. => DiagnosticKind::CharacterReferenceOutsideUnicodeRange,
+                0x9 | 0xA | 0xD => {
+                    out.push(char::from_u32(code).unwrap_or_default());
+                    return Ok(());
+                }
+                0x1..=0x1F | 0xFFFE | 0xFFFF => DiagnosticKind::ControlCharacterReference,
+                _ => {
+                    out.push(char::from_u32(code).unwrap_or(char::REPLACEMENT_CHARACTER));
+                    return Ok(());
+                }
+            };
+            return Err((start, kind));
+        }
+
+        let Some(name) = self.parse_name() else {
+            return Err((start, DiagnosticKind::UnknownNamedCharacterReference));
+        };
+        if !self.expect(";") {
+            return Err((
+                start,
+                DiagnosticKind::MissingSemicolonAfterCharacterReference,
+            ));
+        }
+        let value = match name.as_str() {
+            "lt" => "<",
+            "gt" => ">",
+            "amp" => "&",
+            "apos" => "'",
+            "quot" => "\"",
+            name => match html::named_char_ref(name).filter(|_| self.html_entities) {
+                Some(value) => value,
+                None => return Err((start, DiagnosticKind::UnknownNamedCharacterReference)),
+            },
+        };
+        out.push_str(value);
+        Ok(())
+    }
+
+    fn parse_comment(&mut self) -> Result<(), XmlError> {
+        let start = self.location();
+        self.pos += "<!--".len();
+        let content_start = self.pos;
+        let content = self.consume_until("--", DiagnosticKind::EofInComment)?;
+        if !self.expect(">") {
+            return Err((
+                content_start + content.len(),
+                DiagnosticKind::DoubleHyphenInComment,
+            ));
+        }
+        let span = Span {
+            start,
+            end: self.location(),
+        };
+        let id = self
+            .document
+            .create_comment(content.replace("\r\n", "\n").replace('\r', "\n"));
+        self.append(id, span);
+        Ok(())
+    }
+
+    // Processing instructions are dropped, the XML declaration was already used to decode
+    fn parse_processing_instruction(&mut self) -> Result<(), XmlError> {
+        let start = self.pos;
+        self.pos += "<?".len();
+        let target = self.parse_name().unwrap_or_default();
+        if target.eq_ignore_ascii_case("xml") && start > 0 {
+            return Err((start, DiagnosticKind::MisplacedXmlDeclaration));
+        }
+        self.consume_until("?>", DiagnosticKind::EofInTag)?;
+        Ok(())
+    }
+
+    // <!DOCTYPE name PUBLIC "public id" "system id" [internal subset]>
+    fn parse_doctype(&mut self) -> Result<(), XmlError> {
+        let start = self.pos;
+        if self.seen_root || self.document.data().doctype.is_some() {
+            return Err((start, DiagnosticKind::UnexpectedDoctype));
+        }
+        self.pos += "<!DOCTYPE".len();
+        if !self.consume_whitespace() {
+            return Err((self.pos, DiagnosticKind::MissingWhitespaceBeforeDoctypeName));
+        }
+        let Some(name) = self.parse_name() else {
+            return Err((self.pos, DiagnosticKind::MissingDoctypeName));
+        };
+        let mut doctype = Doctype {
+            name,
+            ..Default::default()
+        };
+        self.consume_whitespace();
+        if self.expect("PUBLIC") {
+            self.consume_whitespace();
+            doctype.public_id = self.parse_quoted(
+                DiagnosticKind::MissingQuoteBeforeDoctypePublicIdentifier,
+                DiagnosticKind::EofInDoctype,
+            )?;
+            self.consume_whitespace();
+            doctype.system_id = self.parse_quoted(
+                DiagnosticKind::MissingQuoteBeforeDoctypeSystemIdentifier,
+                DiagnosticKind::EofInDoctype,
+            )?;
+        } else if self.expect("SYSTEM") {
+            self.consume_whitespace();
+            doctype.system_id = self.parse_quoted(
+                DiagnosticKind::MissingQuoteBeforeDoctypeSystemIdentifier,
+                DiagnosticKind::EofInDoctype,
+            )?;
+        }
+        self.consume_whitespace();
+        if self.expect("[") {
+            self.skip_internal_subset()?;
+            self.consume_whitespace();
+        }
+        if self.pos >= self.input.len() {
+            return Err((self.pos, DiagnosticKind::EofInDoctype));
+        }
+        self.expect_char('>')?;
+        self.html_entities = doctype.public_id.starts_with("-//W3C//DTD XHTML")
+            || doctype.public_id.starts_with("-//W3C//DTD MathML");
+        self.document.data_mut().doctype = Some(doctype);
+        Ok(())
+    }
+
+    // A value in single or double quotes
+    fn parse_quoted(
+        &mut self,
+        missing_quote: DiagnosticKind,
+        eof: DiagnosticKind,
+    ) -> Result<String, XmlError> {
+        match self.next_char() {
+            Some(quote @ ('"' | '\'')) => {
+                self.pos += 1;
+                self.consume_until(quote.encode_utf8(&mut [0; 4]), eof)
+            }
+            _ => Err((self.pos, missing_quote)),
+        }
+    }
+
+    // Declarations between [ and ], quoted values and comments may contain a `]`
+    fn skip_internal_subset(&mut self) -> Result<(), XmlError> {
+        loop {
+            match self.next_char() {
+                None => return Err((self.pos, DiagnosticKind::EofInDoctype)),
+                Some(']') => {
+                    self.pos += 1;
+                    return Ok(());
+                }
+                Some(quote @ ('"' | '\'')) => {
+                    self.pos += 1;
+                    self.consume_until(
+                        quote.encode_utf8(&mut [0; 4]),
+                        DiagnosticKind::EofInDoctype,
+                    )?;
+                }
+                Some('<') if self.starts_with("<!--") => {
+                    self.pos += "<!--".len();
+                    self.consume_until("-->", DiagnosticKind::EofInComment)?;
+                }
+                Some(c) => self.pos += c.len_utf8(),
+            }
+        }
+    }
+
+    fn parse_start_tag(&mut self) -> Result<(), XmlError> {
+        let tag_start = self.pos;
+        let start = self.location();
+        self.pos += 1;
+        if self.open_elements.is_empty() && self.seen_root {
+            return Err((tag_start, DiagnosticKind::ContentOutsideRootElement));
+        }
+        let Some(name) = self.parse_name() else {
+            return Err((self.pos, DiagnosticKind::InvalidFirstCharacterOfTagName));
+        };
+        let mut attrs: Vec<(String, String)> = Vec::new();
+        let empty = loop {
+            let had_whitespace = self.consume_whitespace();
+            match self.next_char() {
+                None => return Err((self.pos, DiagnosticKind::EofInTag)),
+                Some('>') => {
+                    self.pos += 1;
+                    break false;
+                }
+                Some('/') => {
+                    self.pos += 1;
+                    self.expect_char('>')?;
+                    break true;
+                }
+                Some(_) if !had_whitespace => {
+                    return Err((self.pos, DiagnosticKind::MissingWhitespaceBetweenAttributes));
+                }
+                Some(_) => {
+                    let name_start = self.pos;
+                    let Some(attr_name) = self.parse_name() else {
+                        return Err((self.pos, DiagnosticKind::UnexpectedCharacterInAttributeName));
+                    };
+                    self.consume_whitespace();
+                    self.expect_char('=')?;
+                    self.consume_whitespace();
+                    let value = self.parse_attribute_value()?;
+                    if attrs.iter().any(|(name, _)| *name == attr_name) {
+                        return Err((name_start, DiagnosticKind::NonUniqueAttribute(attr_name)));
+                    }
+                    attrs.push((attr_name, value));
+                }
+            }
+        };
+
+        // the element's own declarations apply to its name and attributes
+        let namespaces = self.namespaces.len();
+        for (attr_name, value) in &attrs {
+            if attr_name == "xmlns" {
+                self.namespaces.push((String::new(), value.clone()));
+            } else if let Some(prefix) = attr_name.strip_prefix("xmlns:") {
+                self.namespaces.push((prefix.to_string(), value.clone()));
+            }
+        }
+        for (attr_name, _) in &attrs {
+            if let (Some(prefix), _) = split_name(attr_name) {
+                if prefix != "xmlns" && self.lookup_namespace(prefix).is_none() {
+                    return Err((tag_start, DiagnosticKind::UnboundPrefix(prefix.to_string())));
+                }
+            }
+        }
+        let (prefix, local_name) = split_name(&name);
+        let uri = match self.lookup_namespace(prefix.unwrap_or_default()) {
+            Some(uri) => uri,
+            None if prefix.is_none() => "",
+            None => {
+                let prefix = prefix.unwrap_or_default().to_string();
+                return Err((tag_start, DiagnosticKind::UnboundPrefix(prefix)));
+            }
+        };
+        let namespace = match uri {
+            XHTML_NAMESPACE => Namespace::Html,
+            SVG_NAMESPACE => Namespace::Svg,
+            MATHML_NAMESPACE => Namespace::MathMl,
+            _ => Namespace::Other,
+        };
+        let element = ElementData {
+            tag_name: local_name.to_string(),
+            attrs: attrs.into_iter().collect::<AttrMap>(),
+            namespace,
+            prefix: prefix.map(str::to_string),
+        };
+        let id = self.document.create_node(NodeType::Element(element));
+        let span = Span {
+            start,
+            end: self.location(),
+        };
+        self.append(id, span);
+        self.seen_root = true;
+        if empty {
+            self.namespaces.truncate(namespaces);
+        } else {
+            self.open_elements.push(OpenElement {
+                id,
+                name,
+                start,
+                namespaces,
+            });
+        }
+        Ok(())
+    }
+
+    // Uri of the namespace `prefix` stands for, the empty prefix is the default namespace.
+    // An empty uri undeclares the default namespace.
+    fn lookup_namespace(&self, prefix: &str) -> Option<&str> {
+        if prefix == "xml" {
+            return Some(XML_NAMESPACE);
+        }
+        self.namespaces
+            .iter()
+            .rev()
+            .find(|(declared, _)| declared == prefix)
+            .map(|(_, uri)| uri.as_str())
+    }
+
+    // A quoted attribute value, whitespace characters are replaced with spaces
+    fn parse_attribute_value(&mut self) -> Result<String, XmlError> {
+        let quote = match self.next_char() {
+            Some(quote @ ('"' | '\'')) => quote,
+            _ => return Err((self.pos, DiagnosticKind::ExpectedCharacter('"'))),
+        };
+        self.pos += 1;
+        let mut value = String::new();
+        loop {
+            match self.next_char() {
+                None => return Err((self.pos, DiagnosticKind::EofInTag)),
+                Some(c) if c == quote => {
+                    self.pos += 1;
+                    return Ok(value);
+                }
+                Some('<') => return Err((self.pos, DiagnosticKind::LessThanInAttributeValue)),
+                Some('&') => self.parse_reference(&mut value)?,
+                Some('\r') => {
+                    self.pos += 1;
+                    self.expect("\n");
+                    value.push(' ');
+                }
+                Some(c) => {
+                    self.pos += c.len_utf8();
+                    value.push(if is_whitespace(c) { ' ' } else { c });
+                }
+            }
+        }
+    }
+
+    fn parse_end_tag(&mut self) -> Result<(), XmlError> {
+        let tag_start = self.pos;
+        self.pos += "</".len();
+        let Some(name) = self.parse_name() else {
+            return Err((self.pos, DiagnosticKind::MissingEndTagName));
+        };
+        self.consume_whitespace();
+        self.expect_char('>')?;
+        let Some(open) = self.open_elements.pop() else {
+            return Err((tag_start, DiagnosticKind::UnexpectedEndTag(name)));
+        };
+        if open.name != name {
+            return Err((tag_start, DiagnosticKind::MismatchedEndTag(open.name, name)));
+        }
+        let span = Span {
+            start: open.start,
+            end: self.location(),
+        };
+        self.document.set_span(open.id, span);
+        self.namespaces.truncate(open.namespaces);
+        Ok(())
+    }
+}
+
+// Decode the bytes of an XML file. A byte order mark wins over the encoding named in the XML
+// declaration, without either the file is UTF-8. UTF-16 without a byte order mark is recognized
+// from how the declaration starts.
+// https://www.w3.org/TR/xml/#sec-guessing
+pub fn decode(bytes: &[u8]) -> (String, Encoding) {
+    let encoding = match encoding::sniff_bom(bytes) {
+        Some((encoding, _)) => encoding,
+        None => match bytes {
+            [0x3C, 0x00, 0x3F, 0x00, ..] => Encoding::Utf16Le,
+            [0x00, 0x3C, 0x00, 0x3F, ..] => Encoding::Utf16Be,
+            _ => declared_encoding(bytes).unwrap_or_default(),
+        },
+    };
+    (encoding::decode(bytes, encoding), encoding)
+}
+
+// Encoding named by `<?xml version="1.0" encoding="label"?>` at the very start
+fn declared_encoding(bytes: &[u8]) -> Option<Encoding> {
+    let declaration = bytes.strip_prefix(b"<?xml")?;
+    let end = declaration.iter().take(1024).position(|&b| b == b'>')?;
+    let declaration = String::from_utf8_lossy(&declaration[..end]);
+    let (_, rest) = declaration.split_once("encoding")?;
+    let rest = rest.trim_start().strip_prefix('=')?.trim_start();
+    let quote = rest.chars().next().filter(|&c| c == '"' || c == '\'')?;
+    let label = rest[1..].split(quote).next()?;
+    let encoding = Encoding::for_label(label)?;
+    // the declaration was read as ASCII so the file can't be UTF-16
+    Some(if encoding.is_utf16() {
+        Encoding::Utf8
+    } else {
+        encoding
+    })
+}
+
+// Parse an XML document, returning it with the first well-formedness error if there is one
+pub fn parse(source: String) -> (Document, Vec<Diagnostic>) {
+    parse_with_encoding(source, Encoding::Utf8)
+}
+
+// Parse an XML document read from `reader`, the encoding is detected from its first bytes
+pub fn parse_reader(mut reader: impl Read) -> io::Result<(Document, Vec<Diagnostic>)> {
+    let mut bytes = Vec::new();
+    reader.read_to_end(&mut bytes)?;
+    let (source, encoding) = decode(&bytes);
+    drop(bytes);
+    Ok(parse_with_encoding(source, encoding))
+}
+
+fn parse_with_encoding(source: String, encoding: Encoding) -> (Document, Vec<Diagnostic>) {
+    let mut parser = Parser {
+        pos: 0,
+        input: source,
+        document: Document::new(DocumentData {
+            kind: DocumentKind::Xml,
+            doctype: None,
+            quirks_mode: QuirksMode::NoQuirks,
+            encoding,
+        }),
+        open_elements: Vec::new(),
+        namespaces: Vec::new(),
+        text: String::new(),
+        text_start: Location::START,
+        html_entities: false,
+        seen_root: false,
+        location: Location::START,
+    };
+    let diagnostics = match parser.parse_document() {
+        Ok(()) => Vec::new(),
+        Err((offset, kind)) => {
+            parser.flush_text();
+            let mut diagnostic = LineIndex::new(&parser.input).diagnostic(kind, offset);
+            // every well-formedness error is fatal
+            diagnostic.severity = Severity::Error;
+            vec![diagnostic]
+        }
+    };
+    (parser.document, diagnostics)
+}
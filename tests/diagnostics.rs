@@ -0,0 +1,37 @@
+// Line, column and snippet of the diagnostics of the CSS parser
+
+use owl_panda::css::{self, CSSOrigin};
+use owl_panda::diagnostics::{DiagnosticKind, SNIPPET_CONTEXT};
+
+#[test]
+fn short_line_is_shown_whole() {
+    let (_, diagnostics) = css::parse("p {\n  color: #zz;\n}".to_string(), CSSOrigin::Author);
+    assert_eq!(diagnostics.len(), 1);
+    let diagnostic = &diagnostics[0];
+    assert_eq!(
+        diagnostic.kind,
+        DiagnosticKind::InvalidColor("zz".to_string())
+    );
+    assert_eq!((diagnostic.line, diagnostic.column), (2, 11));
+    assert_eq!(diagnostic.snippet, "  color: #zz;");
+    assert_eq!(diagnostic.snippet_column, 1);
+    assert_eq!(
+        diagnostic.to_string(),
+        "2:11: error[invalid-color]: invalid color `#zz`\n      color: #zz;\n              ^"
+    );
+}
+
+// A minified sheet is one long line with every error on it
+#[test]
+fn long_line_is_clipped_around_the_column() {
+    let rule = "p{color:#zz}";
+    let (_, diagnostics) = css::parse(rule.repeat(30_000), CSSOrigin::Author);
+    assert_eq!(diagnostics.len(), 30_000);
+    for (i, diagnostic) in diagnostics.iter().enumerate() {
+        let column = i * rule.len() + 10;
+        assert_eq!((diagnostic.line, diagnostic.column), (1, column));
+        assert!(diagnostic.snippet.len() <= 2 * SNIPPET_CONTEXT + 1);
+        let caret = diagnostic.column - diagnostic.snippet_column;
+        assert!(diagnostic.snippet[caret..].starts_with("zz}"));
+    }
+}
@@ -0,0 +1,67 @@
+// Case of names in the DOM API, which depends on the kind of document
+
+use owl_panda::dom::{Document, DocumentKind};
+use owl_panda::{html, xml};
+
+fn xhtml_document(body: &str) -> Document {
+    let source =
+        format!(r#"<html xmlns="http://www.w3.org/1999/xhtml"><body>{body}</body></html>"#);
+    let (document, diagnostics) = xml::parse(source);
+    assert!(diagnostics.is_empty(), "{:?}", diagnostics);
+    document
+}
+
+#[test]
+fn document_kind() {
+    assert_eq!(html::parse(String::new()).0.kind(), DocumentKind::Html);
+    assert_eq!(xhtml_document("").kind(), DocumentKind::Xml);
+}
+
+#[test]
+fn tag_names_of_html_documents_ignore_case() {
+    let (document, _) = html::parse("<P>a<svg><foreignObject/></svg>".to_string());
+    let root = document.root();
+    assert_eq!(document.get_elements_by_tag_name(root, "p").len(), 1);
+    assert_eq!(document.get_elements_by_tag_name(root, "P").len(), 1);
+    assert_eq!(
+        document
+            .get_elements_by_tag_name(root, "foreignObject")
+            .len(),
+        1
+    );
+    assert!(document
+        .get_elements_by_tag_name(root, "foreignobject")
+        .is_empty());
+}
+
+#[test]
+fn tag_names_of_xhtml_documents_are_case_sensitive() {
+    let document = xhtml_document("<P/><p/><p/>");
+    let root = document.root();
+    assert_eq!(document.get_elements_by_tag_name(root, "P").len(), 1);
+    assert_eq!(document.get_elements_by_tag_name(root, "p").len(), 2);
+}
+
+#[test]
+fn set_attribute_lowercases_names_in_html_documents() {
+    let (mut document, _) = html::parse("<p>".to_string());
+    let p = document.get_elements_by_tag_name(document.root(), "p")[0];
+    document
+        .set_attribute(p, "dataFoo", "1".to_string())
+        .unwrap();
+    let attrs = &document.element(p).unwrap().attrs;
+    assert_eq!(attrs.get("datafoo").map(String::as_str), Some("1"));
+    assert!(!attrs.contains_key("dataFoo"));
+}
+
+#[test]
+fn set_attribute_keeps_names_in_xhtml_documents() {
+    let mut document = xhtml_document("<p/>");
+    let p = document.get_elements_by_tag_name(document.root(), "p")[0];
+    document
+        .set_attribute(p, "dataFoo", "1".to_string())
+        .unwrap();
+    let attrs = &document.element(p).unwrap().attrs;
+    assert_eq!(attrs.get("dataFoo").map(String::as_str), Some("1"));
+    assert!(!attrs.contains_key("datafoo"));
+}
@@ -0,0 +1,213 @@
+// Trees built by the HTML parser
+
+use owl_panda::diagnostics::DiagnosticKind;
+use owl_panda::dom::{Document, NodeId};
+use owl_panda::html::{self, SerializeOptions};
+
+// Markup of the body of the document `source` parses to, and the kinds of the problems found
+fn parse_body(source: &str) -> (String, Vec<DiagnosticKind>) {
+    let (document, diagnostics) = html::parse(format!("<!DOCTYPE html>{source}"));
+    let body = document.get_elements_by_tag_name(document.root(), "body")[0];
+    let markup = html::inner_html(&document, body, SerializeOptions::default());
+    (markup, diagnostics.into_iter().map(|d| d.kind).collect())
+}
+
+fn body_html(source: &str) -> String {
+    parse_body(source).0
+}
+
+// Number of elements from the html element down to the deepest one
+fn depth(document: &Document) -> usize {
+    let depth_of = |id: NodeId| {
+        std::iter::successors(Some(id), |&id| document[id].parent())
+            .filter(|&id| document.element(id).is_some())
+            .count()
+    };
+    document
+        .descendants(document.root())
+        .map(depth_of)
+        .max()
+        .unwrap_or(0)
+}
+
+fn parent_tag_name(document: &Document, id: NodeId) -> String {
+    let parent = document[id].parent().unwrap();
+    document.element(parent).unwrap().tag_name.clone()
+}
+
+#[test]
+fn tokenizer_folds_tag_and_attribute_names() {
+    assert_eq!(
+        body_html("<P CLASS=A Id='b' data-x>t</P>"),
+        r#"<p class="A" data-x="" id="b">t</p>"#
+    );
+}
+
+#[test]
+fn tokenizer_decodes_character_references() {
+    assert_eq!(
+        parse_body("&amp;&lt;&copy &#x41;&#169;&notin;&notit;"),
+        (
+            "&amp;&lt;© A©∉¬it;".to_string(),
+            vec![DiagnosticKind::MissingSemicolonAfterCharacterReference; 2]
+        )
+    );
+    // `&copy=` is not a reference in an attribute value
+    assert_eq!(
+        body_html("<a title='&copy=x&copy;'></a>"),
+        r#"<a title="&amp;copy=x©"></a>"#
+    );
+}
+
+#[test]
+fn tokenizer_keeps_raw_text_and_comments() {
+    assert_eq!(
+        body_html("<textarea>&lt;b></textarea><xmp><b>&amp;</xmp><!--c-->"),
+        "<textarea>&lt;b&gt;</textarea><xmp><b>&amp;</xmp><!--c-->"
+    );
+}
+
+#[test]
+fn implied_end_tags() {
+    assert_eq!(
+        parse_body("<p>a<p>b<ul><li>c<li>d</ul><dl><dt>e<dd>f</dl><h1>g<h2>h</h2><p><div>i</div>"),
+        (
+            "<p>a</p><p>b</p><ul><li>c</li><li>d</li></ul><dl><dt>e</dt><dd>f</dd></dl>\
+             <h1>g</h1><h2>h</h2><p></p><div>i</div>"
+                .to_string(),
+            vec![]
+        )
+    );
+    assert_eq!(
+        body_html("<select><option>a<option>b</select>"),
+        "<select><option>a</option><option>b</option></select>"
+    );
+}
+
+#[test]
+fn adoption_agency_reparents_misnested_formatting() {
+    assert_eq!(
+        parse_body("<p>1<b>2<i>3</b>4</i>5</p>"),
+        (
+            "<p>1<b>2<i>3</i></b><i>4</i>5</p>".to_string(),
+            vec![DiagnosticKind::MisnestedTag("b".to_string())]
+        )
+    );
+    assert_eq!(
+        body_html("<a href=x>1<p>2</a>3</p>"),
+        r#"<a href="x">1</a><p><a href="x">2</a>3</p>"#
+    );
+    assert_eq!(
+        body_html("<div><a>1<div>2</a>3</div></div>"),
+        "<div><a>1</a><div><a>2</a>3</div></div>"
+    );
+}
+
+// Formatting elements closed with the paragraph are opened again for the text after it
+#[test]
+fn formatting_elements_are_reconstructed() {
+    assert_eq!(
+        body_html("<p><b><i>1</p>2"),
+        "<p><b><i>1</i></b></p><b><i>2</i></b>"
+    );
+}
+
+#[test]
+fn tables_get_implied_sections_and_rows() {
+    assert_eq!(
+        body_html("<table><tr><td>1<td>2<tr><td><table><td>3</table>4</table>"),
+        "<table><tbody><tr><td>1</td><td>2</td></tr><tr><td>\
+         <table><tbody><tr><td>3</td></tr></tbody></table>4</td></tr></tbody></table>"
+    );
+}
+
+// Content that can't go in a table is put before it
+#[test]
+fn table_content_is_foster_parented() {
+    assert_eq!(
+        parse_body("<p>a<table>b<tr><td>c</table>"),
+        (
+            "<p>a</p>b<table><tbody><tr><td>c</td></tr></tbody></table>".to_string(),
+            vec![DiagnosticKind::UnexpectedContentInTable]
+        )
+    );
+    assert_eq!(
+        body_html("<table><b>z</b><tr><td>1</table>"),
+        "<b>z</b><table><tbody><tr><td>1</td></tr></tbody></table>"
+    );
+}
+
+#[test]
+fn foreign_content_keeps_svg_case() {
+    assert_eq!(
+        body_html(
+            "<svg viewbox='0 0 1 1'><clippath/><foreignobject><p>x</p></foreignobject></svg>"
+        ),
+        r#"<svg viewBox="0 0 1 1"><clipPath></clipPath><foreignObject><p>x</p></foreignObject></svg>"#
+    );
+    assert_eq!(
+        body_html("<math><mi>y</mi></math>"),
+        "<math><mi>y</mi></math>"
+    );
+}
+
+// An html element that can't be in svg closes it
+#[test]
+fn html_breaks_out_of_foreign_content() {
+    assert_eq!(
+        parse_body("<svg><g><p>z</svg>"),
+        (
+            "<svg><g></g></svg><p>z</p>".to_string(),
+            vec![
+                DiagnosticKind::UnexpectedStartTag("p".to_string()),
+                DiagnosticKind::UnexpectedEndTag("svg".to_string())
+            ]
+        )
+    );
+}
+
+// The stack of open elements keeps growing past the depth of the tree, but its searches only
+// look at the top MAX_DEPTH elements, so deep documents parse in linear time
+#[test]
+fn deep_nesting_is_capped() {
+    let count = 5_000;
+    let source = format!(
+        "{}x{}<p id=after>",
+        "<div>\n".repeat(count),
+        "</div>".repeat(count)
+    );
+    let (document, _) = html::parse(source);
+    assert_eq!(depth(&document), 513);
+    let after = document.get_element_by_id("after").unwrap();
+    assert_eq!(parent_tag_name(&document, after), "body");
+}
+
+#[test]
+fn deep_inline_nesting_is_capped() {
+    let count = 5_000;
+    let source = format!(
+        "<!DOCTYPE html>{}x{}",
+        "<span>".repeat(count),
+        "</span>".repeat(count)
+    );
+    let (document, diagnostics) = html::parse(source);
+    assert_eq!(depth(&document), 513);
+    assert_eq!(document.text_content(document.root()), "x");
+    assert!(diagnostics.is_empty());
+}
+
+// Every new formatting element is checked against the ones before it: only the last MAX_DEPTH
+// of them are kept
+#[test]
+fn many_distinct_formatting_elements() {
+    let count = 5_000;
+    let tags: String = (0..count).map(|i| format!("<b id=b{i}>")).collect();
+    let (document, _) = html::parse(format!("{tags}x"));
+    assert_eq!(depth(&document), 513);
+    let bold = document
+        .descendants(document.root())
+        .filter(|&id| document.element(id).is_some_and(|e| e.tag_name == "b"))
+        .count();
+    assert_eq!(bold, count);
+    assert_eq!(document.text_content(document.root()), "x");
+}
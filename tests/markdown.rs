@@ -0,0 +1,54 @@
+// Trees built by the Markdown parser
+
+use owl_panda::dom::{Document, NodeId};
+use owl_panda::markdown;
+
+// Number of nodes from the html element down to the deepest one
+fn depth(document: &Document) -> usize {
+    let depth_of = |id: NodeId| {
+        std::iter::successors(Some(id), |&id| document[id].parent())
+            .take_while(|&id| id != document.root())
+            .count()
+    };
+    document
+        .descendants(document.root())
+        .map(depth_of)
+        .max()
+        .unwrap_or(0)
+}
+
+// Like in HTML, what is nested deeper than 512 levels follows its parent instead, so the
+// recursive passes over the tree don't overflow the stack
+#[test]
+fn deep_block_quotes_are_capped() {
+    let document = markdown::parse(">".repeat(20_000));
+    assert_eq!(depth(&document), 513);
+    let quotes = document
+        .descendants(document.root())
+        .filter(|&id| {
+            document
+                .element(id)
+                .is_some_and(|e| e.tag_name == "blockquote")
+        })
+        .count();
+    assert_eq!(quotes, 20_000);
+}
+
+#[test]
+fn deep_emphasis_is_capped() {
+    let stars = "*".repeat(20_000);
+    let document = markdown::parse(format!("{stars}a{stars}"));
+    assert_eq!(depth(&document), 513);
+    assert_eq!(document.text_content(document.root()), "a");
+}
+
+// Every line continues all the items above it
+#[test]
+fn deep_lists_are_capped() {
+    let source: String = (0..2_000)
+        .map(|i| format!("{}- a\n", "  ".repeat(i)))
+        .collect();
+    let document = markdown::parse(source);
+    assert_eq!(depth(&document), 513);
+    assert_eq!(document.text_content(document.root()), "a".repeat(2_000));
+}
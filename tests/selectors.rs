@@ -0,0 +1,218 @@
+// Matching of selectors against parsed documents, through the DOM queries
+
+use owl_panda::css::{self, Specificity};
+use owl_panda::dom::Document;
+use owl_panda::{html, xml};
+
+// The id attributes of the elements matching `selectors`, in tree order
+fn matching(document: &Document, selectors: &str) -> Vec<String> {
+    document
+        .query_selector_all(document.root(), selectors)
+        .unwrap()
+        .into_iter()
+        .map(|id| {
+            document
+                .element(id)
+                .unwrap()
+                .id()
+                .cloned()
+                .unwrap_or_default()
+        })
+        .collect()
+}
+
+fn specificity(selector: &str) -> Specificity {
+    let selectors = css::parse_selector_list(selector).unwrap();
+    assert_eq!(selectors.len(), 1);
+    selectors[0].specificity()
+}
+
+fn html_document(source: &str) -> Document {
+    html::parse(source.to_string()).0
+}
+
+fn xhtml_document(body: &str) -> Document {
+    let source =
+        format!(r#"<html xmlns="http://www.w3.org/1999/xhtml"><body>{body}</body></html>"#);
+    let (document, diagnostics) = xml::parse(source);
+    assert!(diagnostics.is_empty(), "{:?}", diagnostics);
+    document
+}
+
+#[test]
+fn attribute_names_and_values_of_html_documents_ignore_case() {
+    let document = html_document(r#"<p id=a dataFoo=1 type=A><p id=b type=a>"#);
+    assert_eq!(matching(&document, "[datafoo]"), ["a"]);
+    assert_eq!(matching(&document, "[DATAFOO]"), ["a"]);
+    assert_eq!(matching(&document, "[type=a]"), ["a", "b"]);
+    assert_eq!(matching(&document, "[type=a s]"), ["b"]);
+}
+
+// XHTML elements are html elements, but XML names and values keep their case
+#[test]
+fn attribute_names_and_values_of_xhtml_documents_are_case_sensitive() {
+    let document =
+        xhtml_document(r#"<P id="a" dataFoo="1" type="A"/><p id="b" datafoo="1" type="a"/>"#);
+    assert_eq!(matching(&document, "[dataFoo]"), ["a"]);
+    assert_eq!(matching(&document, "[datafoo]"), ["b"]);
+    assert_eq!(matching(&document, "[type=a]"), ["b"]);
+    assert_eq!(matching(&document, "[type=a i]"), ["a", "b"]);
+}
+
+#[test]
+fn type_selectors_of_html_documents_ignore_case() {
+    let document = html_document(r#"<DIV id=a><ul><li id=b></ul></DIV><P id=c>"#);
+    assert_eq!(matching(&document, "DIV li"), ["b"]);
+    assert_eq!(matching(&document, "p, Div"), ["a", "c"]);
+}
+
+// Foreign elements keep the case of their names in HTML documents too
+#[test]
+fn type_selectors_of_svg_elements_are_case_sensitive() {
+    let document = html_document(r#"<svg><foreignObject id=a></foreignObject></svg>"#);
+    assert_eq!(matching(&document, "foreignObject"), ["a"]);
+    assert!(matching(&document, "foreignobject").is_empty());
+}
+
+#[test]
+fn type_selectors_of_xhtml_documents_are_case_sensitive() {
+    let document = xhtml_document(r#"<P id="a"/><p id="b"/>"#);
+    assert_eq!(matching(&document, "p"), ["b"]);
+    assert_eq!(matching(&document, "P"), ["a"]);
+}
+
+#[test]
+fn combinators() {
+    let document = html_document(
+        r#"<div id=d><ul id=u><li id=l1><p id=p1></p></li><li id=l2></li><li id=l3></li></ul></div>
+        <h1 id=h></h1><p id=p2></p><p id=p3></p>"#,
+    );
+    assert_eq!(matching(&document, "div p"), ["p1"]);
+    assert_eq!(matching(&document, "div li p"), ["p1"]);
+    assert_eq!(matching(&document, "ul > li"), ["l1", "l2", "l3"]);
+    assert!(matching(&document, "ul > p").is_empty());
+    assert_eq!(matching(&document, "li + li"), ["l2", "l3"]);
+    assert_eq!(matching(&document, "h1 + p"), ["p2"]);
+    assert_eq!(matching(&document, "h1 ~ p"), ["p2", "p3"]);
+    assert_eq!(matching(&document, "div > ul li + li + li"), ["l3"]);
+    assert_eq!(matching(&document, "#d p, #h~#p3"), ["p1", "p3"]);
+}
+
+// When the nearest matching ancestor fails the rest of the selector, a farther one can match
+#[test]
+fn combinators_backtrack() {
+    let document = html_document(r#"<div><section><div><p id=a></p></div></section></div>"#);
+    assert_eq!(matching(&document, "div > section p"), ["a"]);
+    assert_eq!(matching(&document, "div section > div > p"), ["a"]);
+}
+
+#[test]
+fn specificity_adds_up_across_compound_selectors() {
+    assert_eq!(specificity("*"), (0, 0, 0));
+    assert_eq!(specificity("li"), (0, 0, 1));
+    assert_eq!(specificity("ul li"), (0, 0, 2));
+    assert_eq!(specificity("ul > li.red + li"), (0, 1, 3));
+    assert_eq!(specificity("#nav .item a"), (1, 1, 1));
+}
+
+#[test]
+fn attribute_operators() {
+    let document = html_document(
+        r#"<p id=a class="x yz" lang=en-US href="https://a.org/p.pdf" data-state=""></p>
+        <p id=b class=xyz lang=en href="http://b.org/p.html"></p>"#,
+    );
+    assert_eq!(matching(&document, "[data-state]"), ["a"]);
+    assert_eq!(matching(&document, "[data-state='']"), ["a"]);
+    assert_eq!(matching(&document, "[class=xyz]"), ["b"]);
+    assert_eq!(matching(&document, "[class~=yz]"), ["a"]);
+    assert!(matching(&document, "[class~='']").is_empty());
+    assert_eq!(matching(&document, "[lang|=en]"), ["a", "b"]);
+    assert!(matching(&document, "[lang|=en-u]").is_empty());
+    assert_eq!(matching(&document, "[href^='https:']"), ["a"]);
+    assert_eq!(matching(&document, "[href$='.html']"), ["b"]);
+    assert_eq!(matching(&document, r#"[href*="b.org"]"#), ["b"]);
+    // Empty values never match the substring operators
+    assert!(matching(&document, "[href^=''], [href$=''], [href*='']").is_empty());
+}
+
+#[test]
+fn attribute_case_flags() {
+    let document = html_document(r#"<p id=a data-state=Open></p><p id=b data-state=open></p>"#);
+    assert_eq!(matching(&document, "[data-state=open]"), ["b"]);
+    assert_eq!(matching(&document, "[data-state=open i]"), ["a", "b"]);
+    assert_eq!(matching(&document, "[data-state^=OP I]"), ["a", "b"]);
+    assert_eq!(matching(&document, "[data-state=Open s]"), ["a"]);
+}
+
+#[test]
+fn attribute_selectors_count_like_classes() {
+    assert_eq!(specificity("[type]"), (0, 1, 0));
+    assert_eq!(specificity("input[type=text i]"), (0, 1, 1));
+    assert_eq!(specificity("a.x[href][target]"), (0, 3, 1));
+}
+
+#[test]
+fn structural_pseudo_classes() {
+    let document = html_document(
+        r#"<html id=r><ul><li id=l1></li><li id=l2> </li><li id=l3><!--c--></li><li id=l4>x</li></ul>
+        <div id=d><p id=p></p></div>"#,
+    );
+    assert_eq!(matching(&document, ":root"), ["r"]);
+    assert_eq!(matching(&document, "li:empty"), ["l1", "l3"]);
+    assert_eq!(matching(&document, "li:first-child"), ["l1"]);
+    assert_eq!(matching(&document, "li:last-child"), ["l4"]);
+    assert_eq!(matching(&document, "#d :only-child"), ["p"]);
+    assert!(matching(&document, "li:only-child").is_empty());
+}
+
+#[test]
+fn nth_pseudo_classes() {
+    let items: String = (1..=7)
+        .map(|i| format!("<li id=l{i} class={}>", ["even", "odd"][i % 2]))
+        .collect();
+    let document = html_document(&format!("<ul>{items}</ul>"));
+    assert_eq!(
+        matching(&document, "li:nth-child(odd)"),
+        ["l1", "l3", "l5", "l7"]
+    );
+    assert_eq!(matching(&document, "li:nth-child(2n)"), ["l2", "l4", "l6"]);
+    assert_eq!(matching(&document, "li:nth-child(3)"), ["l3"]);
+    assert_eq!(matching(&document, "li:nth-child(-n+2)"), ["l1", "l2"]);
+    assert_eq!(matching(&document, "li:nth-child(n+6)"), ["l6", "l7"]);
+    assert_eq!(matching(&document, "li:nth-last-child(2)"), ["l6"]);
+    // Counts only the siblings matching the `of` selectors
+    assert_eq!(matching(&document, "li:nth-child(2 of .even)"), ["l4"]);
+    assert_eq!(matching(&document, "li:nth-last-child(2 of .odd)"), ["l5"]);
+
+    let document =
+        html_document("<div><h1 id=h></h1><p id=a></p><span id=s></span><p id=b></p></div>");
+    assert_eq!(matching(&document, "p:nth-of-type(2)"), ["b"]);
+    assert_eq!(matching(&document, "div :nth-of-type(1)"), ["h", "a", "s"]);
+}
+
+#[test]
+fn logical_pseudo_classes() {
+    let document = html_document(
+        r#"<div id=a class=box><p id=p class=x></p></div><div id=b><span id=s class=x></span></div>"#,
+    );
+    assert_eq!(matching(&document, "div:not(.box)"), ["b"]);
+    assert_eq!(matching(&document, ".x:not(p, div)"), ["s"]);
+    assert_eq!(matching(&document, ":is(p, span).x"), ["p", "s"]);
+    assert_eq!(matching(&document, ":where(.box, #b) > .x"), ["p", "s"]);
+    assert_eq!(matching(&document, "div:has(> p)"), ["a"]);
+    assert_eq!(matching(&document, "div:has(+ div)"), ["a"]);
+    assert_eq!(matching(&document, "div:has(.x):not(:has(p))"), ["b"]);
+}
+
+// Selectors Level 4: :is(), :not() and :has() count as their most specific argument, :where() as
+// nothing
+#[test]
+fn specificity_of_pseudo_classes() {
+    assert_eq!(specificity("li:first-child"), (0, 1, 1));
+    assert_eq!(specificity("li:nth-child(2n+1)"), (0, 1, 1));
+    assert_eq!(specificity("li:nth-child(2 of #a, .b)"), (1, 1, 1));
+    assert_eq!(specificity(":is(#a, .b, p)"), (1, 0, 0));
+    assert_eq!(specificity("p:not(.a, .b.c)"), (0, 2, 1));
+    assert_eq!(specificity("div:has(> p.x)"), (0, 1, 2));
+    assert_eq!(specificity(":where(#a, .b) p"), (0, 0, 1));
+}
@@ -0,0 +1,36 @@
+// Memory use of the streaming HTML parser on input that is hard on it
+
+use owl_panda::diagnostics::SNIPPET_CONTEXT;
+use owl_panda::html::StreamingParser;
+
+// Peak resident memory of the test process, in KB
+#[cfg(target_os = "linux")]
+fn peak_memory_kb() -> usize {
+    let status = std::fs::read_to_string("/proc/self/status").unwrap();
+    status
+        .lines()
+        .find_map(|line| line.strip_prefix("VmHWM:"))
+        .and_then(|value| value.trim().trim_end_matches("kB").trim().parse().ok())
+        .unwrap()
+}
+
+// A minified page with an error every few bytes: diagnostics only keep the part of the line
+// around their column, and consumed input is dropped in the middle of the line
+#[cfg(target_os = "linux")]
+#[test]
+fn error_heavy_line_stays_bounded() {
+    let chunk = "</x>".repeat(16 * 1024);
+    let mut parser = StreamingParser::new();
+    for _ in 0..12 {
+        parser.feed(&chunk);
+    }
+    let (_, diagnostics) = parser.finish();
+
+    assert!(diagnostics.len() > 12 * 16 * 1024);
+    assert!(diagnostics
+        .iter()
+        .all(|d| d.snippet.chars().count() <= 2 * SNIPPET_CONTEXT + 1));
+    let last = diagnostics.last().unwrap();
+    assert_eq!((last.line, last.column), (1, 4 * (12 * 16 * 1024 - 1) + 1));
+    assert!(peak_memory_kb() < 256 * 1024);
+}
@@ -0,0 +1,32 @@
+// Trees built by the XML parser
+
+use owl_panda::dom::{Document, NodeId};
+use owl_panda::html::{self, SerializeOptions};
+use owl_panda::xml;
+
+// Number of elements from the root element down to the deepest one
+fn depth(document: &Document) -> usize {
+    let depth_of = |id: NodeId| {
+        std::iter::successors(Some(id), |&id| document[id].parent())
+            .filter(|&id| document.element(id).is_some())
+            .count()
+    };
+    document
+        .descendants(document.root())
+        .map(depth_of)
+        .max()
+        .unwrap_or(0)
+}
+
+// Like in HTML, elements nested deeper than 512 levels go into the element at that depth, so the
+// recursive passes over the tree don't overflow the stack
+#[test]
+fn deep_nesting_is_capped() {
+    let source = format!("{}x{}", "<a>".repeat(40_000), "</a>".repeat(40_000));
+    let (document, diagnostics) = xml::parse(source);
+    assert!(diagnostics.is_empty());
+    assert_eq!(depth(&document), 513);
+    assert_eq!(document.text_content(document.root()), "x");
+    let serialized = html::outer_html(&document, document.root(), SerializeOptions::default());
+    assert!(serialized.ends_with(&format!("<a></a>x{}", "</a>".repeat(512))));
+}